@@ -0,0 +1,51 @@
+//! Generates the gRPC service in `src/grpc.rs` without requiring protoc.
+//! The messages are plain prost structs defined there; `proto/floundr.proto`
+//! describes the same service for clients in other languages.
+
+use tonic_build::manual::{Builder, Method, MethodBuilder, Service};
+
+fn method(name: &str, route: &str, input: &str, output: &str) -> MethodBuilder {
+    Method::builder()
+        .name(name)
+        .route_name(route)
+        .input_type(format!("crate::grpc::{input}"))
+        .output_type(format!("crate::grpc::{output}"))
+        .codec_path("tonic::codec::ProstCodec")
+}
+
+fn main() {
+    let registry = Service::builder()
+        .name("Registry")
+        .package("floundr")
+        .method(
+            method("push_blob", "PushBlob", "BlobChunk", "PushBlobResponse")
+                .client_streaming()
+                .build(),
+        )
+        .method(
+            method("pull_blob", "PullBlob", "PullBlobRequest", "BlobChunk")
+                .server_streaming()
+                .build(),
+        )
+        .method(
+            method(
+                "put_manifest",
+                "PutManifest",
+                "PutManifestRequest",
+                "PutManifestResponse",
+            )
+            .build(),
+        )
+        .method(
+            method(
+                "get_manifest",
+                "GetManifest",
+                "GetManifestRequest",
+                "Manifest",
+            )
+            .build(),
+        )
+        .method(method("list_tags", "ListTags", "ListTagsRequest", "TagList").build())
+        .build();
+    Builder::new().compile(&[registry]);
+}
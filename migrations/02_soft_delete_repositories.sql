@@ -0,0 +1,2 @@
+-- soft-deleted repositories keep their rows until they're purged
+ALTER TABLE repositories ADD COLUMN deleted_at TIMESTAMP DEFAULT NULL;
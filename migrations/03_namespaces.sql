@@ -0,0 +1,3 @@
+-- repository name prefixes a user, or an API key, is confined to
+ALTER TABLE users ADD COLUMN namespace TEXT DEFAULT NULL;
+ALTER TABLE clients ADD COLUMN namespace TEXT DEFAULT NULL;
@@ -0,0 +1,2 @@
+-- serialized UserScope the key is limited to, NULL keys act as an admin
+ALTER TABLE clients ADD COLUMN scope TEXT DEFAULT NULL;
@@ -0,0 +1,2 @@
+-- digest of the uncompressed layer, from the image config's rootfs
+ALTER TABLE manifest_layers ADD COLUMN diff_id TEXT DEFAULT NULL;
@@ -0,0 +1,4 @@
+-- digest of the manifest this one refers to through its `subject`
+ALTER TABLE manifests ADD COLUMN subject_digest TEXT DEFAULT NULL;
+
+CREATE INDEX IF NOT EXISTS idx_manifests_subject_digest ON manifests (subject_digest);
@@ -0,0 +1,3 @@
+-- storage quota in bytes, unlimited when NULL
+ALTER TABLE repositories ADD COLUMN max_bytes INTEGER DEFAULT NULL;
+ALTER TABLE blobs ADD COLUMN size INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,4 @@
+-- where an upload session's chunk starts within the blob
+ALTER TABLE blobs ADD COLUMN chunk_offset INTEGER NOT NULL DEFAULT 0;
+-- set once a stored chunk no longer matches what was recorded for it
+ALTER TABLE uploads ADD COLUMN corrupt BOOLEAN NOT NULL DEFAULT FALSE;
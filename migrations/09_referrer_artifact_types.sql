@@ -0,0 +1,2 @@
+-- what kind of artifact a referrer is, reported by the referrers API
+ALTER TABLE manifests ADD COLUMN artifact_type TEXT DEFAULT NULL;
@@ -0,0 +1,28 @@
+-- the same manifest may be held by several repositories, so its digest is
+-- only unique within one. SQLite can't drop a column constraint, so the
+-- table is rebuilt.
+CREATE TABLE manifests_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    repository_id INTEGER NOT NULL,
+    digest TEXT NOT NULL,
+    media_type TEXT NOT NULL,
+    file_path TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    schema_version INTEGER NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    subject_digest TEXT DEFAULT NULL,
+    artifact_type TEXT DEFAULT NULL,
+    FOREIGN KEY (repository_id) REFERENCES repositories(id) ON DELETE CASCADE,
+    UNIQUE (repository_id, digest)
+);
+
+INSERT INTO manifests_new
+SELECT id, repository_id, digest, media_type, file_path, size, schema_version,
+    created_at, subject_digest, artifact_type
+FROM manifests;
+
+DROP TABLE manifests;
+ALTER TABLE manifests_new RENAME TO manifests;
+
+CREATE INDEX IF NOT EXISTS idx_manifests_digest ON manifests (digest);
+CREATE INDEX IF NOT EXISTS idx_manifests_subject_digest ON manifests (subject_digest);
@@ -0,0 +1,3 @@
+-- the blob the client declared it is uploading, so it can find the session
+-- again by digest
+ALTER TABLE uploads ADD COLUMN digest TEXT;
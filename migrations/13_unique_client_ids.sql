@@ -0,0 +1,23 @@
+-- tokens refer to clients by client_id, which only works once it's unique.
+-- SQLite can't add a constraint to a column, so the table is rebuilt.
+CREATE TABLE clients_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    client_id TEXT NOT NULL UNIQUE,
+    user_id TEXT NOT NULL,
+    secret TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    namespace TEXT DEFAULT NULL,
+    scope TEXT DEFAULT NULL,
+    FOREIGN KEY (user_id) REFERENCES users(id)
+);
+
+-- keys minted under a duplicate id could never be told apart, the newest wins
+INSERT INTO clients_new
+SELECT id, client_id, user_id, secret, created_at, namespace, scope
+FROM clients
+WHERE id IN (SELECT MAX(id) FROM clients GROUP BY client_id);
+
+DROP TABLE clients;
+ALTER TABLE clients_new RENAME TO clients;
+
+CREATE INDEX IF NOT EXISTS idx_clients_secret ON clients (secret);
@@ -0,0 +1,52 @@
+-- a soft-deleted repository keeps its name until it's purged, only live
+-- repositories need unique names so a deleted name can be created again.
+-- SQLite can't drop the inline UNIQUE, so the table is rebuilt. The rename
+-- fails while a trigger refers to the dropped table, so both triggers are
+-- recreated as well.
+DROP TRIGGER IF EXISTS add_scopes_on_new_user;
+DROP TRIGGER IF EXISTS add_scopes_on_new_repository;
+
+CREATE TABLE repositories_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL,
+    is_public BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    deleted_at TIMESTAMP DEFAULT NULL,
+    max_bytes INTEGER DEFAULT NULL
+);
+
+INSERT INTO repositories_new
+SELECT id, name, is_public, created_at, deleted_at, max_bytes
+FROM repositories;
+
+DROP TABLE repositories;
+ALTER TABLE repositories_new RENAME TO repositories;
+
+CREATE UNIQUE INDEX IF NOT EXISTS idx_repositories_live_name
+ON repositories (name) WHERE deleted_at IS NULL;
+
+CREATE TRIGGER IF NOT EXISTS add_scopes_on_new_user
+AFTER INSERT ON users
+BEGIN
+    INSERT INTO repository_scopes (user_id, repository_id, push, pull, del)
+    SELECT
+        NEW.id,
+        repositories.id,
+        CASE WHEN NEW.is_admin THEN TRUE ELSE FALSE END,
+        CASE WHEN repositories.is_public THEN TRUE ELSE FALSE END,
+        CASE WHEN NEW.is_admin THEN TRUE ELSE FALSE END
+    FROM repositories;
+END;
+
+CREATE TRIGGER IF NOT EXISTS add_scopes_on_new_repository
+AFTER INSERT ON repositories
+BEGIN
+    INSERT INTO repository_scopes (user_id, repository_id, push, pull, del)
+    SELECT
+        users.id,
+        NEW.id,
+        CASE WHEN users.is_admin THEN TRUE ELSE FALSE END,
+        CASE WHEN repositories.is_public THEN TRUE ELSE FALSE END,
+        CASE WHEN users.is_admin THEN TRUE ELSE FALSE END
+    FROM users JOIN repositories on 1=1;
+END;
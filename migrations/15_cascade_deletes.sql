@@ -0,0 +1,59 @@
+-- with foreign keys enforced, deleting a repository or a user fails while
+-- its upload sessions, API keys or tokens still point at it. SQLite can't
+-- change a constraint in place, so each table is rebuilt with a cascade.
+CREATE TABLE uploads_new (
+    uuid TEXT NOT NULL PRIMARY KEY,
+    repository_id INTEGER NOT NULL,
+    current_chunk INTEGER NOT NULL DEFAULT 0,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    corrupt BOOLEAN NOT NULL DEFAULT FALSE,
+    digest TEXT,
+    FOREIGN KEY (repository_id) REFERENCES repositories(id) ON DELETE CASCADE,
+    UNIQUE (repository_id, uuid)
+);
+
+INSERT INTO uploads_new
+SELECT uuid, repository_id, current_chunk, created_at, corrupt, digest
+FROM uploads;
+
+DROP TABLE uploads;
+ALTER TABLE uploads_new RENAME TO uploads;
+
+CREATE INDEX IF NOT EXISTS idx_uploads_uuid ON uploads (uuid);
+
+CREATE TABLE clients_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    client_id TEXT NOT NULL UNIQUE,
+    user_id TEXT NOT NULL,
+    secret TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    namespace TEXT DEFAULT NULL,
+    scope TEXT DEFAULT NULL,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+INSERT INTO clients_new
+SELECT id, client_id, user_id, secret, created_at, namespace, scope
+FROM clients;
+
+DROP TABLE clients;
+ALTER TABLE clients_new RENAME TO clients;
+
+CREATE INDEX IF NOT EXISTS idx_clients_secret ON clients (secret);
+
+CREATE TABLE tokens_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    account INTEGER NOT NULL,
+    token TEXT NOT NULL UNIQUE,
+    client_id TEXT UNIQUE,
+    expires TIMESTAMP NOT NULL DEFAULT (datetime('now', '+1 day')),
+    FOREIGN KEY (client_id) REFERENCES clients(client_id) ON DELETE CASCADE,
+    FOREIGN KEY (account) REFERENCES users(email) ON DELETE CASCADE
+);
+
+INSERT INTO tokens_new
+SELECT id, account, token, client_id, expires
+FROM tokens;
+
+DROP TABLE tokens;
+ALTER TABLE tokens_new RENAME TO tokens;
@@ -0,0 +1,3 @@
+-- manifests pushed by digest were recorded with their digest as a tag, so
+-- they showed up in tag listings. Tags can't contain a colon, digests do.
+DELETE FROM tags WHERE tag LIKE '%:%';
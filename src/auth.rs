@@ -1,22 +1,30 @@
-use std::{fmt::Formatter, str::FromStr};
+use std::{borrow::Cow, str::FromStr, sync::Arc};
 
 use super::UserScope;
 use crate::{
     codes::{Code, ErrorResponse},
+    config::{Config, RegistrationPolicy, DEFAULT_TOKEN_TTL},
     content_discovery::DockerLogin,
-    database::DbConn,
+    database::{self, DbConn},
     get_admin_scopes, get_user_scopes,
-    util::{base64_decode, validate_registration, verify_login},
-    Action, APP_URL,
+    storage_driver::StorageError,
+    util::{
+        decode_repository_name, parse_basic_credentials, validate_registration, verify_login,
+        verify_upload_signature,
+    },
+    Action,
 };
 use axum::{
-    extract::{Query, Request},
+    extract::{Path, Query, Request},
     http::StatusCode,
     middleware::Next,
     response::{IntoResponse, Response},
-    Json,
+    Extension, Form, Json,
+};
+use http::{
+    header::{AUTHORIZATION, WWW_AUTHENTICATE},
+    HeaderMap, HeaderValue, Method, Uri,
 };
-use http::{header::WWW_AUTHENTICATE, HeaderMap};
 use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
 use serde::{Deserialize, Serialize};
 use shared::{AuthClient, RegisterUserRequest};
@@ -32,8 +40,73 @@ impl Auth {
     pub fn is_valid(&self) -> bool {
         self.claims.as_ref().is_some_and(|c| c.is_valid())
     }
-    pub fn get_user_info(&self) -> Option<UserInfo> {
-        self.claims.as_ref().and_then(|c| c.get_user_info())
+    pub fn get_user_info(&self, secret: &str) -> Option<UserInfo> {
+        self.claims.as_ref().and_then(|c| c.get_user_info(secret))
+    }
+    pub fn is_admin(&self) -> bool {
+        self.claims
+            .as_ref()
+            .is_some_and(|c| c.is_valid() && c.is_admin())
+    }
+    /// admins confined to a namespace administer its repositories, not the
+    /// registry's users or logs
+    pub fn is_global_admin(&self) -> bool {
+        self.is_admin() && self.namespace().is_none()
+    }
+    /// the subject of valid claims, requests without one are anonymous
+    pub fn subject(&self) -> Option<&str> {
+        self.claims
+            .as_ref()
+            .filter(|c| c.is_valid() && !c.sub.is_empty())
+            .map(|c| c.sub.as_str())
+    }
+    /// the namespace the caller is confined to, if any
+    pub fn namespace(&self) -> Option<&str> {
+        self.claims.as_ref().and_then(|c| c.namespace.as_deref())
+    }
+    /// whether the repository lies within the caller's namespace, callers
+    /// without one can reach every repository. The namespace covers whole
+    /// path segments, so `tenant-a` holds `tenant-a/app` but not `tenant-ab/app`.
+    pub fn in_namespace(&self, repo: &str) -> bool {
+        match self.namespace() {
+            Some(namespace) => {
+                let namespace = namespace.trim_end_matches('/');
+                repo.strip_prefix(namespace)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            }
+            None => true,
+        }
+    }
+    pub fn can_pull(&self, repo: &str) -> bool {
+        self.is_admin()
+            || self
+                .claims
+                .as_ref()
+                .is_some_and(|c| c.is_valid() && c.scopes.is_allowed(repo, Action::Pull))
+    }
+    /// the highest action the caller's credentials grant on the repository,
+    /// admins may do anything within their namespace
+    pub fn action(&self, repo: &str) -> Option<Action> {
+        if !self.in_namespace(repo) {
+            return None;
+        }
+        if self.is_admin() {
+            return Some(Action::Delete);
+        }
+        self.claims
+            .as_ref()
+            .filter(|c| c.is_valid())
+            .and_then(|c| c.scopes.action(repo))
+    }
+    /// whether the caller may perform `action` on the repository, taking the
+    /// namespace into account before admin rights, like `check_scope_middleware`
+    pub fn can(&self, repo: &str, action: Action) -> bool {
+        self.in_namespace(repo)
+            && (self.is_admin()
+                || self
+                    .claims
+                    .as_ref()
+                    .is_some_and(|c| c.is_valid() && c.scopes.is_allowed(repo, action)))
     }
 }
 
@@ -47,11 +120,19 @@ pub struct Claims {
         deserialize_with = "crate::util::vec_to_scopes"
     )]
     scopes: UserScope,
+    /// when set, only repositories within this namespace are accessible
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    namespace: Option<String>,
 }
 
 impl Claims {
+    /// `exp` is in Unix seconds, as `jsonwebtoken` expects
     pub fn is_valid(&self) -> bool {
-        self.exp > chrono::offset::Local::now().timestamp_millis() as usize
+        self.exp > chrono::Utc::now().timestamp() as usize
+    }
+    /// Makes the claims expire `ttl` seconds from now
+    pub fn expires_in(&mut self, ttl: u64) {
+        self.exp = expiry(ttl);
     }
     pub fn set(&mut self, info: &UserInfo) {
         self.sub = info.id.to_string();
@@ -66,21 +147,27 @@ impl Claims {
     pub fn set_admin(&mut self, is_admin: bool) {
         self.is_admin = is_admin
     }
+    pub fn set_namespace(&mut self, namespace: Option<String>) {
+        self.namespace = namespace
+    }
     pub fn is_admin(&self) -> bool {
         self.is_admin
     }
 }
 
+/// The Unix time, in seconds, `ttl` seconds from now
+fn expiry(ttl: u64) -> usize {
+    chrono::Utc::now().timestamp() as usize + ttl as usize
+}
+
 impl Default for Claims {
     fn default() -> Self {
         Self {
             sub: "".to_string(),
-            exp: chrono::offset::Utc::now()
-                .checked_add_days(chrono::Days::new(1))
-                .unwrap()
-                .timestamp_millis() as usize,
+            exp: expiry(DEFAULT_TOKEN_TTL),
             is_admin: false,
             scopes: UserScope::default(),
+            namespace: None,
         }
     }
 }
@@ -92,6 +179,56 @@ pub struct UserInfo {
     pub is_admin: bool,
 }
 
+/// longest Authorization header accepted, anything bigger is rejected unread
+pub const MAX_AUTH_HEADER_LEN: usize = 4096;
+
+/// Rejects malformed Authorization headers before any database work is done:
+/// oversized or non-ascii values, unknown schemes, empty credentials and
+/// Basic payloads that don't decode to `user:password`. Requests without
+/// the header pass through untouched.
+pub async fn validate_auth_header(
+    Extension(config): Extension<Arc<Config>>,
+    req: Request,
+    next: Next,
+) -> Result<Response, Response> {
+    if let Some(value) = req.headers().get(AUTHORIZATION) {
+        if let Err(err) = check_auth_header_shape(value) {
+            tracing::error!("rejecting authorization header: {}", err);
+            return Err((
+                StatusCode::UNAUTHORIZED,
+                auth_response_headers(&req, &config),
+                ErrorResponse::from_code(&Code::Unauthorized, "malformed authorization header"),
+            )
+                .into_response());
+        }
+    }
+    Ok(next.run(req).await)
+}
+
+fn check_auth_header_shape(value: &HeaderValue) -> Result<(), String> {
+    if value.len() > MAX_AUTH_HEADER_LEN {
+        return Err(format!("header longer than {MAX_AUTH_HEADER_LEN} bytes"));
+    }
+    let value = value
+        .to_str()
+        .map_err(|_| String::from("header is not valid ascii"))?;
+    let (scheme, credentials) = value
+        .trim()
+        .split_once(' ')
+        .ok_or_else(|| String::from("missing auth scheme or credentials"))?;
+    let credentials = credentials.trim();
+    if credentials.is_empty() || credentials.contains(char::is_whitespace) {
+        return Err(String::from("invalid credentials"));
+    }
+    if scheme.eq_ignore_ascii_case("basic") {
+        parse_basic_credentials(credentials).map(|_| ())
+    } else if scheme.eq_ignore_ascii_case("bearer") {
+        Ok(())
+    } else {
+        Err(format!("unsupported auth scheme: {scheme}"))
+    }
+}
+
 /// Flow: The auth middleware checks the headers for each request,
 /// it validates the user or key and passes the auth Claims to
 /// the next layer. When the token endpoint is hit, the claims
@@ -99,19 +236,41 @@ pub struct UserInfo {
 /// and if the claims has the required scope, the request is approved
 /// and the JWT is exchanged, having only the required scope, otherwise
 /// the request is denied.
-#[tracing::instrument(skip(conn), level = "trace")]
+#[tracing::instrument(skip(conn, config), level = "trace")]
 pub async fn auth_middleware(
     DbConn(mut conn): DbConn,
+    Extension(config): Extension<Arc<Config>>,
     mut req: Request,
     next: Next,
 ) -> Result<Response, Response> {
     let headers = req.headers().clone();
-    let resp_headers = auth_response_headers(&req);
-    if let Err(e) = valid_v2_repository(req.uri().path(), &mut conn).await {
-        tracing::error!("invalid repository: {}", e);
-        return Err((StatusCode::NOT_FOUND, resp_headers).into_response());
+    let resp_headers = auth_response_headers(&req, &config);
+    if !is_blob_mount(&req) {
+        if let Err(e) = valid_v2_repository(req.uri().path(), &mut conn).await {
+            tracing::error!("invalid repository: {}", e);
+            return Err((StatusCode::NOT_FOUND, resp_headers).into_response());
+        }
     }
-    match check_auth_headers(&headers, &mut conn).await {
+    // a pre-authorized upload url stands in for credentials, but only
+    // for the upload session it was issued for
+    let (method, uri) = (req.method().clone(), req.uri().clone());
+    match check_signed_upload(&method, &uri, &mut conn, &config).await {
+        Some(true) => {
+            req.extensions_mut().insert(SignedUpload);
+            req.extensions_mut().insert(Auth::default());
+            return Ok(next.run(req).await);
+        }
+        Some(false) => {
+            return Err((
+                StatusCode::UNAUTHORIZED,
+                resp_headers,
+                "invalid or expired upload signature",
+            )
+                .into_response());
+        }
+        None => {}
+    }
+    match check_auth_headers(&headers, &mut conn, &config).await {
         Ok(auth) => {
             req.extensions_mut().insert(auth);
             return Ok(next.run(req).await);
@@ -123,6 +282,7 @@ pub async fn auth_middleware(
                 return Ok(next.run(req).await);
             };
             if is_pub_repo(req.uri().path(), &mut conn).await {
+                req.extensions_mut().insert(PublicRepository);
                 req.extensions_mut().insert(Auth::default());
                 return Ok(next.run(req).await);
             }
@@ -131,23 +291,104 @@ pub async fn auth_middleware(
     }
 }
 
+/// Marks a request authorized by a signed upload url rather than credentials
+#[derive(Clone, Copy, Debug)]
+pub struct SignedUpload;
+
+/// Marks an anonymous request for a public repository, which may only pull
+#[derive(Clone, Copy, Debug)]
+pub struct PublicRepository;
+
+#[derive(Deserialize, Debug)]
+struct SignedUploadQuery {
+    expires: i64,
+    signature: String,
+}
+
+/// None when the request doesn't carry an upload signature, otherwise whether the
+/// signature is valid, unexpired and its session is still open
+async fn check_signed_upload(
+    method: &Method,
+    uri: &Uri,
+    conn: &mut SqliteConnection,
+    config: &Config,
+) -> Option<bool> {
+    if !uri.query().is_some_and(|q| q.contains("signature=")) {
+        return None;
+    }
+    if !matches!(*method, Method::PATCH | Method::PUT) {
+        return Some(false);
+    }
+    let Ok(Query(params)) = Query::<SignedUploadQuery>::try_from_uri(uri) else {
+        return Some(false);
+    };
+    let Some((name, session_id)) = uri
+        .path()
+        .strip_prefix("/v2/")
+        .and_then(|path| path.rsplit_once("/blobs/uploads/"))
+    else {
+        return Some(false);
+    };
+    // urls are signed for the decoded name, nested ones arrive percent-encoded
+    let name = decode_repository_name(name);
+    if params.expires < chrono::Utc::now().timestamp()
+        || !verify_upload_signature(
+            &config.jwt_secret,
+            &name,
+            session_id,
+            params.expires,
+            &params.signature,
+        )
+    {
+        return Some(false);
+    }
+    // sessions are closed once the blob is complete, so a url can't be replayed
+    Some(
+        query!(
+            "SELECT uuid FROM uploads WHERE uuid = ? AND repository_id = (SELECT id FROM repositories WHERE name = ? AND deleted_at IS NULL)",
+            session_id,
+            name
+        )
+        .fetch_optional(&mut *conn)
+        .await
+        .is_ok_and(|row| row.is_some()),
+    )
+}
+
 async fn is_pub_repo(path: &str, conn: &mut SqliteConnection) -> bool {
-    match path.split('/').nth(2) {
-        Some(repo) => sqlx::query!("SELECT is_public from repositories WHERE name = ?", repo)
-            .fetch_one(&mut *conn)
-            .await
-            .map(|r| r.is_public)
-            .unwrap_or(false),
+    match path.split('/').nth(2).map(decode_repository_name) {
+        Some(repo) => sqlx::query!(
+            "SELECT is_public from repositories WHERE name = ? AND deleted_at IS NULL",
+            repo
+        )
+        .fetch_one(&mut *conn)
+        .await
+        .map(|r| r.is_public)
+        .unwrap_or(false),
         None => false,
     }
 }
 
+/// Mounting a blob creates its target repository like a push would, so the
+/// target of a mount needn't exist yet
+fn is_blob_mount(req: &Request) -> bool {
+    req.method() == Method::POST
+        && req.uri().path().ends_with("/blobs/uploads/")
+        && req
+            .uri()
+            .query()
+            .is_some_and(|query| query.split('&').any(|param| param.starts_with("mount=")))
+}
+
 async fn valid_v2_repository(path: &str, conn: &mut SqliteConnection) -> Result<(), String> {
-    if path.starts_with("/v2/") && path.len() > 4 {
-        match path.split('/').nth(2) {
+    if path.starts_with("/v2/")
+        && path.len() > 4
+        && !matches!(path, "/v2/_catalog" | "/v2/auth/token")
+    {
+        match path.split('/').nth(2).map(decode_repository_name) {
             Some(repo) => sqlx::query!(
                 // check if repository exists
-                "SELECT id FROM repositories WHERE name = ?",
+                "SELECT id FROM repositories WHERE name = ? AND deleted_at IS NULL",
                 repo
             )
             .fetch_one(&mut *conn)
@@ -197,9 +438,10 @@ fn get_requested_scope(req: &Request) -> String {
     requested
 }
 
-async fn check_auth_headers(
+pub(crate) async fn check_auth_headers(
     headers: &HeaderMap,
     conn: &mut SqliteConnection,
+    config: &Config,
 ) -> Result<Auth, String> {
     let auth_header = headers
         .get("authorization")
@@ -212,7 +454,7 @@ async fn check_auth_headers(
             .split_whitespace()
             .nth(1)
             .ok_or_else(|| String::from("Invalid bearer token format"))?;
-        return validate_bearer(token, conn).await;
+        return validate_bearer(token, conn, &config.jwt_secret).await;
     }
 
     if auth_header.to_lowercase().starts_with("basic ") {
@@ -229,12 +471,49 @@ async fn check_auth_headers(
     Err(String::from("invalid auth header"))
 }
 
-#[tracing::instrument(level = "trace")]
-pub async fn check_scope_middleware(req: Request, next: Next) -> Result<Response, Response> {
-    let auth = req
-        .extensions()
-        .get::<Auth>()
-        .ok_or((StatusCode::UNAUTHORIZED, auth_response_headers(&req)).into_response())?;
+#[tracing::instrument(skip(config), level = "trace")]
+pub async fn check_scope_middleware(
+    Extension(config): Extension<Arc<Config>>,
+    req: Request,
+    next: Next,
+) -> Result<Response, Response> {
+    if req.extensions().get::<SignedUpload>().is_some() {
+        return Ok(next.run(req).await);
+    }
+    let auth = req.extensions().get::<Auth>().ok_or(
+        (
+            StatusCode::UNAUTHORIZED,
+            auth_response_headers(&req, &config),
+        )
+            .into_response(),
+    )?;
+    if let Some(name) = v2_repository_name(req.uri().path()) {
+        if !auth.in_namespace(&name) {
+            info!("{} is outside the client's namespace", name);
+            return Err(ErrorResponse::from_code(
+                &Code::Denied,
+                "repository is outside of your namespace",
+            )
+            .into_response());
+        }
+    }
+    if req.extensions().get::<PublicRepository>().is_some() && is_public_read(&req) {
+        info!("anonymous pull from a public repository");
+        return Ok(next.run(req).await);
+    }
+    // registration enforces `RegistrationPolicy` itself, users may mint
+    // their own API keys, which `generate_token` checks, a login or token
+    // refresh is authorized by the credentials it carries, `/v2/` answers
+    // anonymous callers with its own challenge and the catalog only lists
+    // what the caller can see
+    if matches!(
+        req.uri().path(),
+        "/auth/register" | "/auth/login" | "/v2/" | "/v2/_catalog"
+    ) || is_key_request(&req)
+        || is_token_refresh(&req)
+    {
+        return Ok(next.run(req).await);
+    }
     if let Some(claims) = &auth.claims {
         if claims.is_admin() {
             info!("user is administrator: {}", claims.sub);
@@ -242,18 +521,21 @@ pub async fn check_scope_middleware(req: Request, next: Next) -> Result<Response
         } else {
             match Action::from_request(&req) {
                 Some(required_scope) => {
-                    let repo_name = get_repo_name_from_path(&req).unwrap_or("*");
-                    if claims.scopes.is_allowed(repo_name, required_scope) {
+                    let repo_name = v2_repository_name(req.uri().path());
+                    if claims
+                        .scopes
+                        .is_allowed(repo_name.as_deref().unwrap_or("*"), required_scope)
+                    {
                         info!("user has required scope: {}", required_scope);
                         return Ok(next.run(req).await);
                     } else {
+                        // the caller is known, so new credentials won't help
                         info!("user does not have required scope: {}", required_scope);
-                        return Err((
-                            StatusCode::UNAUTHORIZED,
-                            auth_response_headers(&req),
-                            "requested unauthorized scope",
+                        return Err(ErrorResponse::from_code(
+                            &Code::Denied,
+                            format!("{required_scope} access is not granted"),
                         )
-                            .into_response());
+                        .into_response());
                     }
                 }
                 None => {
@@ -263,17 +545,44 @@ pub async fn check_scope_middleware(req: Request, next: Next) -> Result<Response
             }
         }
     }
-    return Err((
+    Err((
         StatusCode::UNAUTHORIZED,
-        auth_response_headers(&req),
-        "requested unauthorized scope",
+        auth_response_headers(&req, &config),
+        "authentication required",
     )
-        .into_response());
+        .into_response())
 }
 
-fn auth_response_headers(req: &Request) -> HeaderMap {
+/// What an anonymous caller may do with a public repository: pull from it,
+/// and read the details and closures of its manifests
+fn is_public_read(req: &Request) -> bool {
+    Action::from_request(req) == Some(Action::Pull)
+        || (req.method() == Method::GET
+            && req
+                .uri()
+                .path()
+                .strip_prefix("/repositories/")
+                .is_some_and(|rest| rest.split('/').nth(1) == Some("manifests")))
+}
+
+/// Scopes name repositories the way the client does, so under
+/// `--default-namespace` they need qualifying like the request paths
+pub(crate) fn qualify_scopes(scopes: &str, config: &Config) -> String {
+    scopes
+        .split(' ')
+        .map(|scope| match scope.splitn(3, ':').collect::<Vec<_>>()[..] {
+            ["repository", repo, actions] if repo != "*" => {
+                format!("repository:{}:{}", config.qualify_repository(repo), actions)
+            }
+            _ => scope.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn auth_response_headers(req: &Request, config: &Config) -> HeaderMap {
     let mut resp_headers = HeaderMap::new();
-    let app_url = APP_URL.get().unwrap();
+    let app_url = &config.app_url;
     let scope = get_requested_scope(req);
     resp_headers.insert(
         WWW_AUTHENTICATE,
@@ -288,108 +597,210 @@ fn auth_response_headers(req: &Request) -> HeaderMap {
 }
 
 async fn validate_basic_auth(token: &str, conn: &mut SqliteConnection) -> Result<Claims, String> {
-    let decoded = base64_decode(token)?;
-    let parts: Vec<&str> = decoded.split(':').collect();
-    let user = parts[0];
-    let password = parts.get(1).unwrap_or(&"");
-    let user_info = verify_login(conn, user, password)
+    let (user, password) = parse_basic_credentials(token)?;
+    if user.is_empty() || password.is_empty() {
+        return Err(StorageError::InvalidLogin.to_string());
+    }
+    let user_info = verify_login(conn, &user, &password)
         .await
         .map_err(|e| e.to_string())?;
+    user_claims(conn, &user_info).await
+}
+
+/// The claims of a signed in user, carrying every scope the user holds
+async fn user_claims(conn: &mut SqliteConnection, user_info: &UserInfo) -> Result<Claims, String> {
     let mut claims = Claims::default();
+    claims.set(user_info);
+    claims.set_namespace(
+        query!("SELECT namespace FROM users WHERE id = ?", user_info.id)
+            .fetch_one(&mut *conn)
+            .await
+            .map_err(|e| e.to_string())?
+            .namespace,
+    );
     if user_info.is_admin {
         let scopes = get_admin_scopes(conn).await;
-        claims.set_admin(true);
         claims.set_scope(scopes);
     } else {
         let scopes = get_user_scopes(conn, &user_info.id).await;
-        claims.set(&user_info);
         claims.set_scope(scopes);
     }
     tracing::info!("user scopes attached: {:?}", claims.scopes);
     Ok(claims)
 }
 
-#[tracing::instrument(skip(conn), level = "trace")]
-async fn validate_bearer(token: &str, conn: &mut SqliteConnection) -> Result<Auth, String> {
-    // check if it's an assigned API key
-    // these carry all scopes for every repository
-    if let Ok(row) = query!("SELECT client_id FROM clients WHERE secret = ?", token)
-        .fetch_one(&mut *conn)
-        .await
+#[tracing::instrument(skip(token, conn, secret), level = "trace")]
+async fn validate_bearer(
+    token: &str,
+    conn: &mut SqliteConnection,
+    secret: &str,
+) -> Result<Auth, String> {
+    // check if it's an assigned API key. keys without a scope carry all of
+    // their owner's rights, the rest only what they were issued with of them
+    if let Ok(row) = query!(
+        r#"SELECT c.client_id, c.scope, u.id as user_id, u.is_admin, COALESCE(c.namespace, u.namespace) as "namespace?: String" FROM clients c
+         JOIN users u ON u.id = c.user_id WHERE c.secret = ?"#,
+        token
+    )
+    .fetch_one(&mut *conn)
+    .await
     {
-        let scopes = get_admin_scopes(conn).await;
+        let scopes = if row.is_admin {
+            get_admin_scopes(conn).await
+        } else {
+            get_user_scopes(conn, &row.user_id).await
+        };
         let mut claims = Claims::default();
         claims.set_sub(row.client_id);
-        claims.set_admin(true);
-        claims.scopes = scopes;
+        claims.set_namespace(row.namespace);
+        match row.scope {
+            Some(scope) => {
+                let scope = serde_json::from_str::<UserScope>(&scope)
+                    .map_err(|_| String::from("invalid API key scope"))?;
+                claims.scopes = scopes.intersect(&scope);
+            }
+            None => {
+                claims.set_admin(row.is_admin);
+                claims.scopes = scopes;
+            }
+        }
         return Ok(Auth {
             claims: Some(claims),
         });
     }
-    let claims = Claims::validate_jwt(token).map_err(|e| e.to_string())?;
+    let claims = Claims::validate_jwt(token, secret).map_err(|e| e.to_string())?;
     Ok(Auth {
         claims: Some(claims),
     })
 }
 
-fn get_repo_name_from_path(req: &Request) -> Option<&str> {
-    if let Some(path) = req.uri().path().strip_prefix("/v2/") {
-        let parts: Vec<&str> = path.split('/').collect();
-        if parts.len() > 1 {
-            return Some(parts[1]);
-        }
-    }
-    None
+/// Splits a `/v2/<name>/...` path into the still encoded name and the rest
+pub(crate) fn split_v2_path(path: &str) -> Option<(&str, &str)> {
+    let path = path.strip_prefix("/v2/")?;
+    let end = ["/blobs/", "/manifests/", "/tags/", "/referrers/"]
+        .iter()
+        .filter_map(|sep| path.find(sep))
+        .min()?;
+    Some(path.split_at(end))
+}
+
+/// the repository named by a `/v2/<name>/{blobs,manifests,tags}/...` path
+fn v2_repository_name(path: &str) -> Option<Cow<'_, str>> {
+    split_v2_path(path).map(|(name, _)| decode_repository_name(name))
+}
+
+fn is_key_request(req: &Request) -> bool {
+    req.method() == Method::POST
+        && req
+            .uri()
+            .path()
+            .strip_prefix("/users/")
+            .and_then(|rest| rest.strip_suffix("/tokens"))
+            .is_some_and(|email| !email.is_empty() && !email.contains('/'))
+}
+
+fn is_token_refresh(req: &Request) -> bool {
+    req.method() == Method::POST && matches!(req.uri().path(), "/auth/token" | "/v2/auth/token")
 }
 
 fn is_public_route(path: &str) -> bool {
-    let routes = ["/repositories", "/auth/token", "/auth/login"];
+    let routes = [
+        "/v2/",
+        "/v2/_catalog",
+        "/repositories",
+        "/auth/token",
+        "/v2/auth/token",
+        "/auth/login",
+        "/auth/register",
+    ];
     routes.iter().any(|r| path.eq(*r))
 }
 
 #[derive(Serialize, Debug)]
 pub struct TokenResponse {
     token: String,
+    /// what the token grants, only sent by the token endpoint
+    #[serde(skip_serializing_if = "Option::is_none")]
+    access: Option<Vec<ResourceAccess>>,
+    /// issued for `offline_token=true`, exchanged at `POST /auth/token`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    refresh_token: Option<String>,
 }
 impl TokenResponse {
     pub fn new(token: &str) -> Self {
         Self {
             token: token.to_string(),
+            access: None,
+            refresh_token: None,
         }
     }
 }
 
-#[tracing::instrument(skip(conn), level = "trace")]
+/// One entry of the `access` list the Docker token spec echoes back
+#[derive(Serialize, Debug)]
+pub struct ResourceAccess {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    name: String,
+    actions: Vec<String>,
+}
+
+impl ResourceAccess {
+    fn granted(scopes: &UserScope) -> Vec<Self> {
+        let all = [Action::Pull, Action::Push, Action::Delete];
+        scopes
+            .0
+            .iter()
+            .map(|(repo, granted)| ResourceAccess {
+                kind: "repository",
+                name: repo.clone(),
+                // each action implies the lesser ones
+                actions: all
+                    .iter()
+                    .filter(|action| *action <= granted)
+                    .map(Action::to_string)
+                    .collect(),
+            })
+            .collect()
+    }
+}
+
+#[tracing::instrument(skip(conn, config, params, headers), level = "trace")]
 pub async fn auth_token_get(
     DbConn(mut conn): DbConn,
+    Extension(config): Extension<Arc<Config>>,
     Query(params): Query<DockerLogin>,
     headers: HeaderMap,
     req: Request,
 ) -> impl IntoResponse {
-    if let Ok(auth) = check_auth_headers(&headers, &mut conn).await {
+    if let Ok(auth) = check_auth_headers(&headers, &mut conn, &config).await {
         if let Some(ref claims) = auth.claims {
-            let requested = get_requested_scope(&req);
-            let scope = UserScope::from_str(&requested).unwrap();
-            if claims.is_valid()
-                && claims.scopes.is_allowed(
-                    scope.0.keys().next().unwrap(),
-                    *scope.0.values().next().unwrap(),
-                )
-            {
+            let scope = parse_requested_scope(&get_requested_scope(&req), &config);
+            if claims.is_valid() {
+                // only the requested subset of the granted scopes is carried over,
+                // which may be none at all: the registry refuses what's missing
+                let granted = claims.narrowed(&scope, config.token_ttl);
+                let refresh_token = match params.offline_token {
+                    Some(true) => database::create_refresh_token(&mut conn, &claims.sub)
+                        .await
+                        .unwrap_or_else(|err| {
+                            tracing::error!("unable to issue a refresh token: {}", err);
+                            None
+                        }),
+                    _ => None,
+                };
                 return (
                     StatusCode::OK,
                     serde_json::to_string(&TokenResponse {
-                        token: auth.claims.unwrap().update_jwt(),
+                        token: granted.token(&config.jwt_secret),
+                        access: Some(ResourceAccess::granted(&granted.scopes)),
+                        refresh_token,
                     })
                     .unwrap(),
                 )
                     .into_response();
             } else {
-                tracing::error!(
-                    "invalid claims or scope: {:?} valid? : {:?}",
-                    claims,
-                    claims.is_valid()
-                );
+                tracing::error!("expired claims: {:?}", claims);
             }
         }
     }
@@ -400,6 +811,91 @@ pub async fn auth_token_get(
         .into_response()
 }
 
+/// The form of an OAuth2 token request
+#[derive(Deserialize, Debug)]
+pub struct TokenRequest {
+    grant_type: String,
+    refresh_token: Option<String>,
+    scope: Option<String>,
+}
+
+/// POST /auth/token, also answered at /v2/auth/token
+/// exchanges a refresh token from `offline_token=true` for a new access
+/// token, narrowed to the requested scope like a GET. Each refresh token is
+/// good for one exchange, the response carries its replacement.
+pub async fn auth_token_post(
+    DbConn(mut conn): DbConn,
+    Extension(config): Extension<Arc<Config>>,
+    Form(req): Form<TokenRequest>,
+) -> impl IntoResponse {
+    if req.grant_type != "refresh_token" {
+        return (
+            StatusCode::BAD_REQUEST,
+            ErrorResponse::from_code(
+                &Code::Unsupported,
+                format!("unsupported grant type {}", req.grant_type),
+            ),
+        )
+            .into_response();
+    }
+    let user = match req.refresh_token.as_deref() {
+        Some(token) => database::refresh_token_user(&mut conn, token).await,
+        None => Ok(None),
+    };
+    let claims = match user {
+        Ok(Some(user)) => user_claims(&mut conn, &user).await,
+        Ok(None) => Err(String::from("unknown refresh token")),
+        Err(err) => Err(err.to_string()),
+    };
+    let refreshed = match (claims, req.refresh_token.as_deref()) {
+        (Ok(claims), Some(token)) => {
+            match database::rotate_refresh_token(&mut conn, token, &claims.sub).await {
+                Ok(Some(refresh_token)) => Ok((claims, refresh_token)),
+                Ok(None) => Err(String::from("refresh token was already exchanged")),
+                Err(err) => Err(err.to_string()),
+            }
+        }
+        (Ok(_), None) => Err(String::from("missing refresh token")),
+        (Err(err), _) => Err(err),
+    };
+    let (claims, refresh_token) = match refreshed {
+        Ok(refreshed) => refreshed,
+        Err(err) => {
+            tracing::error!("refusing token refresh: {}", err);
+            return (
+                StatusCode::UNAUTHORIZED,
+                ErrorResponse::from_code(&Code::Unauthorized, "invalid refresh token"),
+            )
+                .into_response();
+        }
+    };
+    let requested = req
+        .scope
+        .filter(|scope| !scope.trim().is_empty())
+        .unwrap_or_else(|| String::from("repository:*:*"));
+    let granted = claims.narrowed(
+        &parse_requested_scope(&requested, &config),
+        config.token_ttl,
+    );
+    Json(TokenResponse {
+        token: granted.token(&config.jwt_secret),
+        access: Some(ResourceAccess::granted(&granted.scopes)),
+        refresh_token: Some(refresh_token),
+    })
+    .into_response()
+}
+
+/// The scopes a token request asks for. Scopes naming an unknown action are
+/// dropped, like the ones the user lacks.
+fn parse_requested_scope(requested: &str, config: &Config) -> UserScope {
+    let requested = qualify_scopes(requested, config)
+        .split(' ')
+        .filter(|scope| UserScope::from_str(scope).is_ok())
+        .collect::<Vec<_>>()
+        .join(" ");
+    UserScope::from_str(&requested).unwrap_or_default()
+}
+
 #[derive(Deserialize, Debug)]
 pub struct LoginRequest {
     email: String,
@@ -407,52 +903,33 @@ pub struct LoginRequest {
 }
 pub async fn login_user(
     DbConn(mut conn): DbConn,
+    Extension(config): Extension<Arc<Config>>,
     Query(params): Query<DockerLogin>,
     Json(req): Json<Option<LoginRequest>>,
 ) -> impl IntoResponse {
-    info!("login user: {:?}", params);
-    info!("login user: {:?}", req);
-    if req.is_none() {
-        let user = params.account.unwrap();
-        let password = params.password.unwrap();
-        match verify_login(&mut conn, &user, &password).await {
-            Ok(info) => {
-                let mut claims = Claims::default();
-                claims.set(&info);
-                let token_resp = serde_json::to_string(&TokenResponse {
-                    token: claims.to_string(),
-                })
-                .unwrap();
-                (StatusCode::OK, token_resp).into_response()
-            }
-            Err(_) => {
-                tracing::error!("failed to verify password {} : {}", &user, &password);
-                (
-                    StatusCode::UNAUTHORIZED,
-                    ErrorResponse::from_code(&Code::NameUnknown, String::from("invalid login")),
-                )
-                    .into_response()
-            }
-        };
-    }
-    let req = req.unwrap();
-    info!("login user: {:?}", req);
-    match verify_login(&mut conn, &req.email, &req.password).await {
-        Ok(info) => {
-            let mut claims = Claims::default();
-            claims.set(&info);
-            let token_resp = serde_json::to_string(&TokenResponse {
-                token: claims.to_string(),
-            })
-            .unwrap();
+    let (user, password) = match req {
+        Some(req) => (req.email, req.password),
+        None => (
+            params.account.unwrap_or_default(),
+            params.password.unwrap_or_default(),
+        ),
+    };
+    info!("login user: {}", user);
+    // the token carries the user's namespace and scopes, like any other
+    let claims = match verify_login(&mut conn, &user, &password).await {
+        Ok(info) => user_claims(&mut conn, &info).await,
+        Err(err) => Err(err.to_string()),
+    };
+    match claims {
+        Ok(mut claims) => {
+            claims.expires_in(config.token_ttl);
+            let token_resp =
+                serde_json::to_string(&TokenResponse::new(&claims.token(&config.jwt_secret)))
+                    .unwrap();
             (StatusCode::OK, token_resp).into_response()
         }
-        Err(_) => {
-            tracing::error!(
-                "failed to verify password {} : {}",
-                &req.email,
-                &req.password
-            );
+        Err(err) => {
+            tracing::error!("failed to log in {}: {}", &user, err);
             (
                 StatusCode::UNAUTHORIZED,
                 ErrorResponse::from_code(&Code::NameUnknown, String::from("invalid login")),
@@ -462,7 +939,14 @@ pub async fn login_user(
     }
 }
 
-pub async fn get_auth_clients(DbConn(mut conn): DbConn) -> impl IntoResponse {
+pub async fn get_auth_clients(
+    DbConn(mut conn): DbConn,
+    Extension(auth): Extension<Auth>,
+) -> impl IntoResponse {
+    if !auth.is_admin() {
+        return ErrorResponse::from_code(&Code::Denied, String::from("admin access required"))
+            .into_response();
+    }
     if let Ok(clients) = sqlx::query_as!(
         AuthClient,
         "SELECT clients.id, client_id, secret, clients.created_at, u.email FROM clients JOIN users u ON user_id = u.id"
@@ -475,10 +959,50 @@ pub async fn get_auth_clients(DbConn(mut conn): DbConn) -> impl IntoResponse {
     (StatusCode::NOT_FOUND, "no auth clients were found").into_response()
 }
 
+/// DELETE /auth/clients/:client_id
+/// revokes an API key, only administrators may remove clients. Keys stay
+/// revocable under `--disable-delete`, which only protects stored images
+pub async fn delete_auth_client(
+    Path(client_id): Path<String>,
+    DbConn(mut conn): DbConn,
+    Extension(auth): Extension<Auth>,
+) -> impl IntoResponse {
+    if !auth.is_admin() {
+        return ErrorResponse::from_code(&Code::Denied, "only administrators may revoke API keys")
+            .into_response();
+    }
+    match sqlx::query!("DELETE FROM clients WHERE client_id = ?", client_id)
+        .execute(&mut *conn)
+        .await
+    {
+        Ok(res) if res.rows_affected() > 0 => (StatusCode::NO_CONTENT, "").into_response(),
+        Ok(_) => (StatusCode::NOT_FOUND, "auth client not found").into_response(),
+        Err(err) => {
+            tracing::error!("unable to delete auth client: {}", err);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "unable to delete auth client",
+            )
+                .into_response()
+        }
+    }
+}
+
+/// POST /auth/register
+/// availability depends on `--allow-registration`, see `RegistrationPolicy`
 pub async fn register_user(
     DbConn(mut conn): DbConn,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(auth): Extension<Auth>,
     Json(req): Json<RegisterUserRequest>,
 ) -> impl IntoResponse {
+    if config.allow_registration == RegistrationPolicy::Off {
+        return (StatusCode::NOT_FOUND, "registration is disabled").into_response();
+    }
+    // the body's is_admin is only honored for an authenticated admin under
+    // `--allow-registration admin`, anyone else gets a regular account
+    let is_admin =
+        req.is_admin && auth.is_admin() && config.allow_registration == RegistrationPolicy::Admin;
     match validate_registration(&req.email, &req.password, &req.confirm_password) {
         Ok(_) => {
             let hashed = bcrypt::hash(&req.password, bcrypt::DEFAULT_COST).unwrap();
@@ -488,7 +1012,7 @@ pub async fn register_user(
                 user_id,
                 req.email,
                 hashed,
-                req.is_admin,
+                is_admin,
             )
             .execute(&mut *conn)
             .await;
@@ -518,58 +1042,45 @@ pub async fn change_password(
     }
 }
 
-#[warn(clippy::recursive_format_impl)]
-impl std::fmt::Display for Claims {
-    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
-        let secret =
-            std::env::var("JWT_SECRET_KEY").expect("JWT_SECRET_KEY env var needs to be set");
-        let token = encode(
+impl Claims {
+    /// The claims as a JWT signed with `secret`
+    pub fn token(&self, secret: &str) -> String {
+        encode(
             &Header::default(),
             self,
             &EncodingKey::from_secret(secret.as_bytes()),
         )
-        .expect("failed to encode jwt");
-        write!(f, "{}", token)
+        .expect("failed to encode jwt")
     }
-}
 
-impl Claims {
     pub fn new(user_id: &str) -> Self {
-        let expiration = chrono::offset::Local::now()
-            .checked_add_days(chrono::Days::new(1))
-            .unwrap();
         Claims {
             sub: user_id.to_string(),
-            exp: expiration.timestamp_millis() as usize,
+            exp: expiry(DEFAULT_TOKEN_TTL),
             scopes: UserScope::default(),
             is_admin: false,
+            namespace: None,
         }
     }
 
-    fn update_jwt(&self) -> String {
-        let secret =
-            std::env::var("JWT_SECRET_KEY").expect("JWT_SECRET_KEY env var needs to be set");
-        let expiration = chrono::offset::Local::now()
-            .checked_add_days(chrono::Days::new(1))
-            .expect("date failed to add 1 day");
-        let claims = Claims {
+    /// Fresh claims narrowed to `requested ∩ granted`. Admins, unscoped API
+    /// keys included, are granted everything they request, but admin rights
+    /// only carry over when everything (`repository:*:*`) was requested.
+    fn narrowed(&self, requested: &UserScope, ttl: u64) -> Claims {
+        Claims {
             sub: self.sub.to_owned(),
-            exp: expiration.timestamp_millis() as usize,
-            is_admin: self.is_admin,
-            scopes: self.scopes.clone(),
-        };
-        let token = encode(
-            &Header::default(),
-            &claims,
-            &EncodingKey::from_secret(secret.as_bytes()),
-        )
-        .expect("failed to encode jwt");
-        token
+            exp: expiry(ttl),
+            is_admin: self.is_admin && requested.0.get("*") == Some(&Action::Delete),
+            scopes: if self.is_admin {
+                requested.clone()
+            } else {
+                self.scopes.intersect(requested)
+            },
+            namespace: self.namespace.clone(),
+        }
     }
 
-    pub fn get_user_info(&self) -> Option<UserInfo> {
-        let secret =
-            std::env::var("JWT_SECRET_KEY").expect("JWT_SECRET_KEY env var needs to be set");
+    pub fn get_user_info(&self, secret: &str) -> Option<UserInfo> {
         let claims = decode::<Claims>(
             &self.sub,
             &DecodingKey::from_secret(secret.as_bytes()),
@@ -584,14 +1095,10 @@ impl Claims {
         })
     }
 
-    fn validate_jwt(token: &str) -> Result<Self, String> {
+    fn validate_jwt(token: &str, secret: &str) -> Result<Self, String> {
         if let Ok(claims) = decode::<Claims>(
             token,
-            &DecodingKey::from_secret(
-                std::env::var("JWT_SECRET_KEY")
-                    .expect("failed to get secret key")
-                    .as_bytes(),
-            ),
+            &DecodingKey::from_secret(secret.as_bytes()),
             &Validation::default(),
         )
         .map(|data| data.claims)
@@ -1,17 +1,25 @@
 use crate::{
-    codes::{Code, ErrorResponse},
-    database::DbConn,
+    auth::Auth,
+    codes::{deletes_disabled, Code, ErrorResponse},
+    config::Config,
+    database::{self, DbConn},
+    storage::next_chunk_offset,
     storage_driver::{Backend, StorageError},
-    util::{parse_content_length, parse_content_range},
+    util::{
+        encode_repository_name, immutable_cache_control, parse_content_length, parse_content_range,
+        parse_range, repr_digest, sign_upload, DigestHasher,
+    },
+    Action,
 };
 use axum::{
+    body::Body,
     extract::{Path, Query, Request},
     http::{
-        header::{CONTENT_LENGTH, LOCATION},
-        HeaderMap, StatusCode,
+        header::{ACCEPT_RANGES, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_RANGE, LOCATION},
+        HeaderMap, HeaderValue, StatusCode,
     },
     response::{IntoResponse, Response},
-    Extension,
+    Extension, Json,
 };
 use http::header::RANGE;
 use sqlx::SqliteConnection;
@@ -26,47 +34,106 @@ pub async fn get_blob(
     Path((name, digest)): Path<(String, String)>,
     DbConn(mut conn): DbConn,
     Extension(blob_storage): Extension<Arc<Backend>>,
+    Extension(config): Extension<Arc<Config>>,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
-    match blob_storage.read_blob(&mut conn, &name, &digest).await {
-        Ok(data) => {
-            let mut headers = HeaderMap::new();
-            headers.insert("Docker-Content-Digest", digest.parse().unwrap());
-            (headers, data).into_response()
+    let range = parse_range(&headers);
+    match blob_storage
+        .read_blob(&mut conn, &name, &digest, range)
+        .await
+    {
+        Ok(blob) => {
+            let mut headers = blob_digest_headers(&digest, &config);
+            headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+            let public = database::repository_is_public(&mut conn, &name).await;
+            headers.insert(CACHE_CONTROL, immutable_cache_control(public));
+            let Some((start, end)) = blob.range else {
+                headers.insert(CONTENT_LENGTH, blob.size.into());
+                return (headers, Body::from_stream(blob.stream)).into_response();
+            };
+            headers.insert(CONTENT_LENGTH, (end - start + 1).into());
+            headers.insert(
+                CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, end, blob.size)
+                    .parse()
+                    .unwrap(),
+            );
+            (
+                StatusCode::PARTIAL_CONTENT,
+                headers,
+                Body::from_stream(blob.stream),
+            )
+                .into_response()
         }
+        Err(StorageError::RangeNotSatisfiable(size)) => (
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            [(CONTENT_RANGE, format!("bytes */{}", size))],
+        )
+            .into_response(),
         Err(_) => ErrorResponse::from_code(&Code::BlobUnknown, String::from("blob not found"))
             .into_response(),
     }
 }
 
-#[tracing::instrument(skip(conn))]
+/// HEAD /v2/:name/blobs/:digest
+/// whether the repository holds a blob, with its size as Content-Length
+#[tracing::instrument(skip(conn, storage, config))]
 pub async fn check_blob(
     Path((name, digest)): Path<(String, String)>,
     DbConn(mut conn): DbConn,
+    Extension(storage): Extension<Arc<Backend>>,
+    Extension(config): Extension<Arc<Config>>,
 ) -> impl IntoResponse {
     debug!("HEAD /v2/{}/blobs/{}", name, digest);
-    let exists = sqlx::query!("SELECT COUNT(*) as count from blobs join repositories r on r.id = (select id from repositories where name = ?) AND digest = ?", name, digest)
-       .fetch_one(&mut *conn)
+    let file_path = sqlx::query_scalar!("SELECT blobs.file_path FROM blobs JOIN repositories ON blobs.repository_id = repositories.id WHERE repositories.name = ? AND repositories.deleted_at IS NULL AND blobs.digest = ? AND blobs.upload_session_id IS NULL", name, digest)
+       .fetch_optional(&mut *conn)
        .await
-       .unwrap()
-       .count > 0;
-    if exists {
-        (StatusCode::OK, "true").into_response()
-    } else {
-        ErrorResponse::from_code(&Code::BlobUnknown, String::from("blob not found")).into_response()
+       .ok()
+       .flatten();
+    let size = match file_path {
+        Some(file_path) => storage.blob_size(&file_path).await,
+        None => None,
+    };
+    match size {
+        Some(size) => {
+            let mut headers = blob_digest_headers(&digest, &config);
+            headers.insert(CONTENT_LENGTH, size.into());
+            (StatusCode::OK, headers).into_response()
+        }
+        None => ErrorResponse::from_code(&Code::BlobUnknown, String::from("blob not found"))
+            .into_response(),
     }
 }
 
+/// `Docker-Content-Digest`, plus `Repr-Digest` when enabled with `--repr-digest`
+fn blob_digest_headers(digest: &str, config: &Config) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    if let Ok(value) = digest.parse() {
+        headers.insert("Docker-Content-Digest", value);
+    }
+    if config.repr_digest {
+        if let Some(value) = repr_digest(digest).and_then(|v| v.parse().ok()) {
+            headers.insert("Repr-Digest", value);
+        }
+    }
+    headers
+}
+
 /// DELETE /v2/:name/blobs/:digest
 /// to delete a blob from the registry
 /// spec: 705-712
-#[tracing::instrument(skip(storage, conn))]
+#[tracing::instrument(skip(storage, conn, config))]
 pub async fn delete_blob(
     Path((name, digest)): Path<(String, String)>,
     DbConn(mut conn): DbConn,
     storage: Extension<Arc<Backend>>,
+    Extension(config): Extension<Arc<Config>>,
 ) -> impl IntoResponse {
     debug!("DELETE /v2/{}/blobs/{}", name, digest);
-    if sqlx::query!("SELECT COUNT(*) as count from blobs join repositories r on r.id = (select id from repositories where name = ?) AND digest = ?", name, digest)
+    if config.disable_delete {
+        return Err(deletes_disabled());
+    }
+    if sqlx::query!("SELECT COUNT(*) as count FROM blobs JOIN repositories ON blobs.repository_id = repositories.id WHERE repositories.name = ? AND repositories.deleted_at IS NULL AND blobs.digest = ?", name, digest)
        .fetch_one(&mut *conn)
        .await
        .is_ok_and(|row| row.count > 0) {
@@ -80,7 +147,7 @@ pub async fn delete_blob(
                 )
                 .into_response()
             })?;
-        Ok((StatusCode::ACCEPTED, "deleted successfully").into_response())
+        Ok((StatusCode::ACCEPTED, [(CONTENT_LENGTH, "0")]).into_response())
     } else {
     Err(
         ErrorResponse::from_code(&Code::BlobUnknown, String::from("blob not found"))
@@ -151,8 +218,7 @@ pub async fn put_upload_session_blob(
             }
             Err(err) => {
                 error!("error uploading blob: {:?}", err);
-                let code = crate::codes::Code::BlobUploadUnknown;
-                ErrorResponse::from_code(&code, "unable to upload blob").into_response()
+                upload_failed(err, "unable to upload blob")
             }
         }
     }
@@ -171,10 +237,13 @@ async fn finish_upload_session(
 ) -> Response {
     // we will have to combine any chunks that have been uploaded in this session
     // and then calculate the digest
-    let digest = storage
-        .combine_chunks(conn, name, session_id)
-        .await
-        .unwrap();
+    let digest = match storage.combine_chunks(conn, name, session_id).await {
+        Ok(digest) => digest,
+        Err(err) => {
+            error!("error combining chunks: {:?}", err);
+            return upload_failed(err, "unable to combine chunks");
+        }
+    };
     let location = format!("/v2/{}/blobs/{}", name, digest);
     let mut return_headers = HeaderMap::new();
     return_headers.insert(LOCATION, location.parse().unwrap());
@@ -228,20 +297,12 @@ pub async fn put_upload_blob(
             }
             Err(err) => {
                 error!("error combining chunks: {:?}", err);
-                ErrorResponse::from_code(
-                    &crate::codes::Code::BlobUploadUnknown,
-                    String::from("unable to combine chunks"),
-                )
-                .into_response()
+                upload_failed(err, "unable to combine chunks")
             }
         },
         Err(err) => {
             error!("error uploading blob: {:?}", err);
-            ErrorResponse::from_code(
-                &crate::codes::Code::BlobUploadUnknown,
-                String::from("unable to upload blob"),
-            )
-            .into_response()
+            upload_failed(err, "unable to upload blob")
         }
     }
 }
@@ -265,6 +326,10 @@ async fn upload_chunk(
     .await
     {
         let current_chunk = current_session.current_chunk;
+        // a resumed upload may continue from chunks a crash left damaged
+        storage
+            .verify_upload_session(&mut *conn, name, session_id, false)
+            .await?;
         // ensure that we are not out of order
         if range.0 == current_chunk || range.0 == 0 {
             let content_len = headers
@@ -291,6 +356,12 @@ async fn upload_chunk(
     }
 }
 
+/// `Range` of an upload session that has received `received` bytes, the end
+/// is inclusive so an empty session reports `0-0`
+fn upload_range(received: i64) -> HeaderValue {
+    HeaderValue::from_str(&format!("0-{}", (received - 1).max(0))).expect("range is ascii")
+}
+
 // PATCH /v2/:name/blobs/uploads/:session_id
 // requires Content-Length & Content-Range headers
 #[tracing::instrument(skip(storage, conn))]
@@ -300,12 +371,13 @@ pub async fn handle_upload_session_chunk(
     storage: Extension<Arc<Backend>>,
     request: Request,
 ) -> impl IntoResponse {
-    let headers = request.headers().clone();
-    let range = parse_content_range(&headers);
-    let content_len = parse_content_length(&headers);
     match upload_chunk(&name, &session_id, storage.0, &mut conn, request).await {
         Ok(_) => {
-            let next_chunk = if range.1 == 0 { content_len } else { range.1 };
+            // the next chunk starts right after the bytes stored so far
+            let next_chunk = match next_chunk_offset(&mut conn, &session_id).await {
+                Ok(offset) => offset,
+                Err(err) => return upload_failed(err, "unable to record upload progress"),
+            };
             sqlx::query!(
                 "UPDATE uploads SET current_chunk = ? WHERE uuid = ?",
                 next_chunk,
@@ -321,7 +393,7 @@ pub async fn handle_upload_session_chunk(
                     .parse()
                     .unwrap(),
             );
-            headers.insert(RANGE, format!("0-{}", next_chunk).parse().unwrap());
+            headers.insert(RANGE, upload_range(next_chunk));
             headers.insert(CONTENT_LENGTH, "0".parse().unwrap());
             headers.insert("Docker-Upload-UUID", session_id.parse().unwrap());
             let resp = (StatusCode::ACCEPTED, headers).into_response();
@@ -330,8 +402,7 @@ pub async fn handle_upload_session_chunk(
         }
         Err(err) => {
             error!("error uploading blob: {:?}", err);
-            let code = crate::codes::Code::BlobUploadUnknown;
-            ErrorResponse::from_code(&code, "unable to upload blob").into_response()
+            upload_failed(err, "unable to upload blob")
         }
     }
 }
@@ -346,11 +417,13 @@ pub async fn handle_upload_session_chunk(
 /// that contains the digest of the mounted blob
 /// <location>?digest=<digest>
 /// spec: 436-460
-#[tracing::instrument(skip(storage, conn))]
+#[tracing::instrument(skip(storage, conn, auth))]
 pub async fn handle_upload_blob(
     Path(name): Path<String>,
     Query(digest): Query<QueryParams>,
     Extension(storage): Extension<Arc<Backend>>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(auth): Extension<Auth>,
     DbConn(mut conn): DbConn,
     request: Request,
 ) -> impl IntoResponse {
@@ -380,18 +453,57 @@ pub async fn handle_upload_blob(
             }
             Err(err) => {
                 error!("error uploading blob: {:?}", err);
-                return ErrorResponse::from_code(
-                    &crate::codes::Code::BlobUploadUnknown,
-                    String::from("unable to upload blob"),
-                )
-                .into_response();
+                return upload_failed(err, "unable to upload blob");
             }
         }
     }
+    let mount = match digest.mount {
+        Some(mount) if may_mount_from(&mut conn, &auth, digest.from.as_deref()).await => {
+            Some(mount)
+        }
+        _ => None,
+    };
+    if let Some(mount) = mount {
+        debug!("mounting blob {} into {}", mount, name);
+        match storage
+            .mount_blob(&mut conn, &name, &mount, digest.from.as_deref())
+            .await
+        {
+            Ok(_) => {
+                let mut headers = HeaderMap::new();
+                headers.append(
+                    LOCATION,
+                    format!("/v2/{}/blobs/{}", name, mount).parse().unwrap(),
+                );
+                headers.append("Docker-Content-Digest", mount.parse().unwrap());
+                return (StatusCode::CREATED, headers, "resource created").into_response();
+            }
+            // per the spec, an unmountable blob falls back to a regular upload session
+            Err(err) => debug!("unable to mount blob {}: {:?}", mount, err),
+        }
+    }
+    if upload_limit_reached(&mut conn, &name, &config).await {
+        return too_many_uploads();
+    }
     debug!("no digest, creating new uuid/session");
+    let declared = request
+        .headers()
+        .get("Docker-Content-Digest")
+        .and_then(|digest| digest.to_str().ok())
+        .filter(|digest| DigestHasher::for_digest(digest).is_some())
+        .map(String::from);
     let session_id = storage.new_session(&mut conn, &name).await;
     match session_id {
         Ok(session_id) => {
+            if let Some(digest) = declared {
+                if let Err(err) = database::set_upload_digest(&mut conn, &session_id, &digest).await
+                {
+                    error!(
+                        "unable to record the digest of upload {}: {}",
+                        session_id, err
+                    );
+                }
+            }
             let mut headers = HashMap::new();
             headers.insert(
                 String::from("LOCATION"),
@@ -410,3 +522,321 @@ pub async fn handle_upload_blob(
         }
     }
 }
+
+/// Mounting shares a stored blob, so the caller has to be able to pull it
+/// from the source repository. Only admins without a namespace may leave the
+/// source out and mount from wherever the blob is found.
+async fn may_mount_from(conn: &mut SqliteConnection, auth: &Auth, source: Option<&str>) -> bool {
+    let Some(source) = source else {
+        return auth.is_admin() && auth.namespace().is_none();
+    };
+    auth.can(source, Action::Pull)
+        || (auth.in_namespace(source) && database::repository_is_public(conn, source).await)
+}
+
+/// whether the repository already has `--max-concurrent-uploads` sessions open
+async fn upload_limit_reached(conn: &mut SqliteConnection, name: &str, config: &Config) -> bool {
+    let Some(limit) = config.max_concurrent_uploads else {
+        return false;
+    };
+    match database::active_upload_count(conn, name).await {
+        Ok(count) => count >= i64::from(limit),
+        Err(err) => {
+            error!("unable to count upload sessions for {}: {}", name, err);
+            false
+        }
+    }
+}
+
+/// quota, corruption and digest failures keep their own codes, anything else
+/// is an unknown upload
+fn upload_failed(err: StorageError, detail: &str) -> Response {
+    match err {
+        StorageError::QuotaExceeded(max) => ErrorResponse::from_code(
+            &Code::QuotaExceeded,
+            format!("upload would exceed the repository quota of {max} bytes"),
+        )
+        .into_response(),
+        StorageError::UploadCorrupt => (
+            StatusCode::GONE,
+            ErrorResponse::from_code(
+                &Code::BlobUploadInvalid,
+                "upload session is corrupt, start a new upload",
+            ),
+        )
+            .into_response(),
+        StorageError::DigestError => {
+            ErrorResponse::from_code(&Code::DigestInvalid, "digest did not match content")
+                .into_response()
+        }
+        _ => ErrorResponse::from_code(&Code::BlobUploadUnknown, detail).into_response(),
+    }
+}
+
+fn too_many_uploads() -> Response {
+    ErrorResponse::from_code(
+        &Code::TooManyRequests,
+        "too many concurrent uploads for this repository",
+    )
+    .into_response()
+}
+
+/// GET /v2/:name/blobs/uploads/:session_id
+/// reports how far an upload got so the client can resume it, after
+/// checking the chunks stored so far are intact
+/// spec: 338-356
+#[tracing::instrument(skip(storage, conn))]
+pub async fn get_upload_session(
+    Path((name, session_id)): Path<(String, String)>,
+    Extension(storage): Extension<Arc<Backend>>,
+    DbConn(mut conn): DbConn,
+) -> Response {
+    let current_chunk = match sqlx::query_scalar!(
+        "SELECT current_chunk FROM uploads WHERE uuid = ? AND repository_id = (SELECT id FROM repositories WHERE name = ? AND deleted_at IS NULL)",
+        session_id,
+        name
+    )
+    .fetch_optional(&mut *conn)
+    .await
+    {
+        Ok(Some(current_chunk)) => current_chunk,
+        Ok(None) => {
+            return ErrorResponse::from_code(&Code::BlobUploadUnknown, "upload session not found")
+                .into_response()
+        }
+        Err(err) => {
+            error!("unable to look up upload session {}: {}", session_id, err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "unable to look up upload").into_response();
+        }
+    };
+    if let Err(err) = storage
+        .verify_upload_session(&mut conn, &name, &session_id, true)
+        .await
+    {
+        return upload_failed(err, "unable to verify upload session");
+    }
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        LOCATION,
+        format!("/v2/{}/blobs/uploads/{}", name, session_id)
+            .parse()
+            .unwrap(),
+    );
+    headers.insert(RANGE, upload_range(current_chunk));
+    headers.insert(CONTENT_LENGTH, "0".parse().unwrap());
+    headers.insert("Docker-Upload-UUID", session_id.parse().unwrap());
+    (StatusCode::NO_CONTENT, headers).into_response()
+}
+
+/// GET /v2/:name/blobs/uploads/?digest=<digest>
+/// lets a client that lost its session id resume by digest: 201 pointing at
+/// the blob once it is stored, 202 with the progress of a session opened for
+/// it (declared with `Docker-Content-Digest` on the POST), 404 otherwise
+#[tracing::instrument(skip(conn))]
+pub async fn find_upload_session(
+    Path(name): Path<String>,
+    Query(params): Query<QueryParams>,
+    DbConn(mut conn): DbConn,
+) -> Response {
+    let Some(digest) = params
+        .digest
+        .filter(|digest| DigestHasher::for_digest(digest).is_some())
+    else {
+        return ErrorResponse::from_code(&Code::DigestInvalid, "a valid digest is required")
+            .into_response();
+    };
+    let stored = sqlx::query_scalar!("SELECT blobs.id FROM blobs JOIN repositories ON blobs.repository_id = repositories.id WHERE repositories.name = ? AND repositories.deleted_at IS NULL AND blobs.digest = ? AND blobs.upload_session_id IS NULL", name, digest)
+        .fetch_optional(&mut *conn)
+        .await;
+    let mut headers = HeaderMap::new();
+    match stored {
+        Ok(Some(_)) => {
+            headers.insert(
+                LOCATION,
+                format!("/v2/{name}/blobs/{digest}").parse().unwrap(),
+            );
+            headers.insert("Docker-Content-Digest", digest.parse().unwrap());
+            return (StatusCode::CREATED, headers).into_response();
+        }
+        Ok(None) => {}
+        Err(err) => {
+            error!("unable to look up blob {}: {}", digest, err);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "unable to look up upload",
+            )
+                .into_response();
+        }
+    }
+    match database::find_upload_by_digest(&mut conn, &name, &digest).await {
+        Ok(Some((session_id, current_chunk))) => {
+            headers.insert(
+                LOCATION,
+                format!("/v2/{name}/blobs/uploads/{session_id}")
+                    .parse()
+                    .unwrap(),
+            );
+            headers.insert(RANGE, upload_range(current_chunk));
+            headers.insert(CONTENT_LENGTH, "0".parse().unwrap());
+            headers.insert("Docker-Upload-UUID", session_id.parse().unwrap());
+            (StatusCode::ACCEPTED, headers).into_response()
+        }
+        Ok(None) => ErrorResponse::from_code(
+            &Code::BlobUploadUnknown,
+            "no stored blob or open upload for this digest",
+        )
+        .into_response(),
+        Err(err) => {
+            error!("unable to look up uploads of {}: {}", digest, err);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "unable to look up upload",
+            )
+                .into_response()
+        }
+    }
+}
+
+/// DELETE /v2/:name/blobs/uploads/:session_id
+/// cancels an upload session, freeing its slot and removing any uploaded chunks
+#[tracing::instrument(skip(storage, conn))]
+pub async fn cancel_upload_session(
+    Path((name, session_id)): Path<(String, String)>,
+    Extension(storage): Extension<Arc<Backend>>,
+    DbConn(mut conn): DbConn,
+) -> impl IntoResponse {
+    match storage.cancel_session(&mut conn, &name, &session_id).await {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => ErrorResponse::from_code(&Code::BlobUploadUnknown, "upload session not found")
+            .into_response(),
+        Err(err) => {
+            error!("error cancelling upload session: {:?}", err);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "unable to cancel upload session",
+            )
+                .into_response()
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct SignedUploadUrl {
+    pub location: String,
+    pub expires: i64,
+}
+
+/// POST /v2/:name/blobs/uploads/authorize
+/// opens an upload session and returns a signed, expiring url for it.
+/// PATCH/PUT requests to that url are accepted without credentials
+/// until the session completes or the url expires
+#[tracing::instrument(skip(storage, conn))]
+pub async fn authorize_upload(
+    Path(name): Path<String>,
+    Extension(storage): Extension<Arc<Backend>>,
+    Extension(config): Extension<Arc<Config>>,
+    DbConn(mut conn): DbConn,
+) -> impl IntoResponse {
+    if upload_limit_reached(&mut conn, &name, &config).await {
+        return too_many_uploads();
+    }
+    let session_id = match storage.new_session(&mut conn, &name).await {
+        Ok(session_id) => session_id,
+        Err(err) => {
+            error!("error creating upload session: {:?}", err);
+            return ErrorResponse::from_code(&Code::NameUnknown, "respository name not found")
+                .into_response();
+        }
+    };
+    let expires = chrono::Utc::now().timestamp() + config.upload_url_ttl as i64;
+    let signature = sign_upload(&config.jwt_secret, &name, &session_id, expires);
+    let location = format!(
+        "/v2/{}/blobs/uploads/{session_id}?expires={expires}&signature={signature}",
+        encode_repository_name(&name)
+    );
+    let mut headers = HeaderMap::new();
+    headers.insert(LOCATION, location.parse().unwrap());
+    (
+        StatusCode::ACCEPTED,
+        headers,
+        Json(SignedUploadUrl { location, expires }),
+    )
+        .into_response()
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct BlobReference {
+    pub repository: String,
+    pub file_path: String,
+    pub ref_count: i64,
+    pub deleted: bool,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct BlobInfo {
+    pub digest: String,
+    pub size: Option<u64>,
+    pub ref_count: i64,
+    pub references: Vec<BlobReference>,
+}
+
+/// GET /admin/blobs/:digest
+/// admin only: lists every repository holding the blob, along with where it
+/// is stored, to help track down why a blob is never garbage collected
+#[tracing::instrument(skip(storage, conn, auth))]
+pub async fn get_blob_references(
+    Path(digest): Path<String>,
+    Extension(storage): Extension<Arc<Backend>>,
+    DbConn(mut conn): DbConn,
+    Extension(auth): Extension<Auth>,
+) -> impl IntoResponse {
+    if !auth.is_admin() {
+        return ErrorResponse::from_code(&Code::Denied, String::from("admin access required"))
+            .into_response();
+    }
+    let rows = match sqlx::query!(
+        "SELECT r.name, b.file_path, b.ref_count, r.deleted_at IS NOT NULL as \"deleted!: bool\"
+         FROM blobs b JOIN repositories r ON b.repository_id = r.id
+         WHERE b.digest = ? AND b.upload_session_id IS NULL ORDER BY r.name",
+        digest
+    )
+    .fetch_all(&mut *conn)
+    .await
+    {
+        // a namespaced admin only learns about its own repositories
+        Ok(rows) => rows
+            .into_iter()
+            .filter(|row| auth.in_namespace(&row.name))
+            .collect::<Vec<_>>(),
+        Err(err) => {
+            error!("unable to look up blob {}: {}", digest, err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "unable to look up blob").into_response();
+        }
+    };
+    if rows.is_empty() {
+        return ErrorResponse::from_code(&Code::BlobUnknown, String::from("blob not found"))
+            .into_response();
+    }
+    let mut size = None;
+    for row in rows.iter() {
+        if let Some(len) = storage.blob_size(&row.file_path).await {
+            size = Some(len);
+            break;
+        }
+    }
+    let info = BlobInfo {
+        digest,
+        size,
+        ref_count: rows.iter().map(|row| row.ref_count).sum(),
+        references: rows
+            .into_iter()
+            .map(|row| BlobReference {
+                repository: row.name,
+                file_path: row.file_path,
+                ref_count: row.ref_count,
+                deleted: row.deleted,
+            })
+            .collect(),
+    };
+    (StatusCode::OK, Json(info)).into_response()
+}
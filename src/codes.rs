@@ -63,6 +63,7 @@ pub enum Code {
     Denied,
     Unsupported,
     TooManyRequests,
+    QuotaExceeded,
 }
 
 impl Code {
@@ -82,6 +83,7 @@ impl Code {
             "code-12" => Some(Code::Denied),
             "code-13" => Some(Code::Unsupported),
             "code-14" => Some(Code::TooManyRequests),
+            "code-15" => Some(Code::QuotaExceeded),
             _ => None,
         }
     }
@@ -106,6 +108,7 @@ impl Code {
             Code::Denied => "requested access to the resource is denied",
             Code::Unsupported => "the operation is unsupported",
             Code::TooManyRequests => "too many requests",
+            Code::QuotaExceeded => "repository storage quota exceeded",
         }
     }
 
@@ -125,6 +128,7 @@ impl Code {
             Code::Denied => StatusCode::FORBIDDEN,
             Code::Unsupported => StatusCode::NOT_IMPLEMENTED,
             Code::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+            Code::QuotaExceeded => StatusCode::PAYLOAD_TOO_LARGE,
         }
     }
     /// If included, `Warning` headers MUST specify a `warn-code` of `299` and a `warn-agent` of `-`, and MUST NOT specify a `warn-date` value.
@@ -144,6 +148,21 @@ impl Code {
     }
 }
 
+/// What every delete endpoint answers while the registry runs with
+/// `--disable-delete`
+pub fn deletes_disabled() -> Response {
+    let mut response = (
+        StatusCode::METHOD_NOT_ALLOWED,
+        ErrorResponse::from_code(&Code::Unsupported, "deletes are disabled on this registry"),
+    )
+        .into_response();
+    response.headers_mut().insert(
+        WARNING,
+        HeaderValue::from_static("299 - \"deletes are disabled on this registry\""),
+    );
+    response
+}
+
 impl<T> IntoResponse for ErrorResponse<T>
 where
     T: Serialize + std::fmt::Debug + Clone,
@@ -0,0 +1,258 @@
+use shared::{
+    DOCKER_MANIFEST_LIST_CONTENT_TYPE, MANIFEST_CONTENT_TYPE, MANIFEST_MEDIA_TYPES,
+    OCI_ARTIFACT_MANIFEST_CONTENT_TYPE, OCI_CONTENT_HEADER, OCI_MANIFEST_CONTENT_TYPE,
+};
+use std::{borrow::Cow, time::Duration};
+
+/// Runtime settings resolved from the command line/environment in `main`.
+/// Handlers receive it through an `Extension<Arc<Config>>`.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Config {
+    /// url clients reach the registry at, `APP_URL`
+    pub app_url: String,
+    /// signs tokens and upload urls, `JWT_SECRET_KEY`
+    #[serde(skip)]
+    pub jwt_secret: String,
+    /// media type stored for manifests pushed without a Content-Type header
+    pub default_media_type: String,
+    /// when set, deleted repositories are kept for this many seconds
+    /// and can be restored before being purged
+    pub repo_recovery_window: Option<u64>,
+    /// which references `DELETE /v2/:name/manifests/:reference` accepts
+    pub manifest_delete: ManifestDeletePolicy,
+    /// what happens to the referrers of a deleted manifest
+    pub referrer_delete: ReferrerDeletePolicy,
+    /// seconds a signed upload url stays valid
+    pub upload_url_ttl: u64,
+    /// open upload sessions allowed per repository, unlimited when unset
+    pub max_concurrent_uploads: Option<u32>,
+    /// also send an RFC 9530 `Repr-Digest` header with blobs
+    pub repr_digest: bool,
+    /// who may create accounts through `POST /auth/register`
+    pub allow_registration: RegistrationPolicy,
+    /// namespace prepended to single-segment repository names, so a mirror
+    /// can serve `ubuntu` from `library/ubuntu`
+    pub default_namespace: Option<String>,
+    /// largest manifest accepted on push, in bytes
+    pub max_manifest_size: u64,
+    /// record the uncompressed diff ID of each layer from the image config
+    pub store_diff_ids: bool,
+    /// serve tracing events to admins at `GET /admin/logs`
+    pub enable_log_stream: bool,
+    /// seconds between garbage collection runs, never run when unset
+    pub gc_interval: Option<u64>,
+    /// seconds a pooled database connection may sit idle before it is
+    /// closed, 0 keeps idle connections open
+    pub db_idle_timeout: u64,
+    /// seconds before a pooled database connection is replaced, 0 keeps
+    /// connections for the life of the server
+    pub db_max_lifetime: u64,
+    /// when not empty, only user agents containing one of these are served
+    pub user_agent_allow: Vec<String>,
+    /// user agents containing any of these are refused
+    pub user_agent_deny: Vec<String>,
+    /// serve the embedded browser dashboard under `/ui`
+    pub enable_ui: bool,
+    /// refuse every delete, making the registry append-only
+    pub disable_delete: bool,
+    /// re-hash a manifest pulled by digest before serving it
+    pub verify_manifests: bool,
+    /// requests and hot queries slower than this many milliseconds are
+    /// logged as warnings, nothing is logged when unset
+    pub slow_log_ms: Option<u64>,
+    /// seconds an issued access token stays valid
+    pub token_ttl: u64,
+    /// requests a client may make per minute before getting a 429,
+    /// unlimited when unset
+    pub rate_limit: Option<u32>,
+    /// refuse image manifests whose config is not stored in the repository
+    /// or has a media type that doesn't fit the manifest
+    pub validate_config: bool,
+}
+
+/// Registry policy for manifest deletion. The spec makes deleting by tag
+/// optional, so some deployments only allow digest references or disable
+/// manifest deletion altogether.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ManifestDeletePolicy {
+    #[default]
+    TagAndDigest,
+    DigestOnly,
+    Disabled,
+}
+
+impl ManifestDeletePolicy {
+    pub fn allows(&self, reference: &str) -> bool {
+        match self {
+            Self::TagAndDigest => true,
+            // tags can't contain ':', digests always do
+            Self::DigestOnly => reference.contains(':'),
+            Self::Disabled => false,
+        }
+    }
+}
+
+/// What deleting a manifest does to the manifests whose `subject` names it
+/// (signatures, SBOMs, ...). Orphaned referrers are kept, as the spec lets
+/// referrers be pushed before their subject exists.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReferrerDeletePolicy {
+    #[default]
+    Orphan,
+    Cascade,
+}
+
+/// Controls `POST /auth/register`. `User` opens self-registration for
+/// regular accounts; `Admin` additionally lets an authenticated admin create
+/// other admins. An unauthenticated request can never create an admin.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RegistrationPolicy {
+    #[default]
+    Off,
+    User,
+    Admin,
+}
+
+/// the spec asks registries to accept manifests of at least 4MiB
+pub const DEFAULT_MAX_MANIFEST_SIZE: u64 = 4 * 1024 * 1024;
+pub const DEFAULT_DB_IDLE_TIMEOUT: u64 = 600;
+pub const DEFAULT_DB_MAX_LIFETIME: u64 = 1800;
+pub const DEFAULT_TOKEN_TTL: u64 = 3600;
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            app_url: String::from("http://127.0.0.1:8080"),
+            jwt_secret: String::new(),
+            default_media_type: MANIFEST_CONTENT_TYPE.to_string(),
+            repo_recovery_window: None,
+            manifest_delete: ManifestDeletePolicy::default(),
+            referrer_delete: ReferrerDeletePolicy::default(),
+            upload_url_ttl: 900,
+            max_concurrent_uploads: None,
+            repr_digest: false,
+            allow_registration: RegistrationPolicy::default(),
+            default_namespace: None,
+            max_manifest_size: DEFAULT_MAX_MANIFEST_SIZE,
+            store_diff_ids: false,
+            enable_log_stream: false,
+            gc_interval: None,
+            db_idle_timeout: DEFAULT_DB_IDLE_TIMEOUT,
+            db_max_lifetime: DEFAULT_DB_MAX_LIFETIME,
+            user_agent_allow: Vec::new(),
+            user_agent_deny: Vec::new(),
+            enable_ui: false,
+            disable_delete: false,
+            verify_manifests: false,
+            slow_log_ms: None,
+            token_ttl: DEFAULT_TOKEN_TTL,
+            rate_limit: None,
+            validate_config: false,
+        }
+    }
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.jwt_secret.is_empty() {
+            return Err(String::from("JWT_SECRET_KEY must be set"));
+        }
+        if !is_manifest_media_type(&self.default_media_type) {
+            return Err(format!(
+                "unsupported default media type: {}",
+                self.default_media_type
+            ));
+        }
+        if self.gc_interval == Some(0) {
+            return Err(String::from("gc interval must be at least one second"));
+        }
+        if self.token_ttl == 0 {
+            return Err(String::from("token ttl must be at least one second"));
+        }
+        if self.rate_limit == Some(0) {
+            return Err(String::from("rate limit must allow at least one request"));
+        }
+        if self.slow_log_ms == Some(0) {
+            return Err(String::from(
+                "slow log threshold must be at least one millisecond",
+            ));
+        }
+        if self.disable_delete && self.gc_interval.is_some() {
+            return Err(String::from(
+                "garbage collection deletes blobs, it can't run with --disable-delete",
+            ));
+        }
+        if self
+            .user_agent_allow
+            .iter()
+            .chain(&self.user_agent_deny)
+            .any(|agent| agent.trim().is_empty())
+        {
+            return Err(String::from("user agent patterns cannot be empty"));
+        }
+        if let Some(namespace) = self.default_namespace.as_deref() {
+            if namespace.is_empty() || namespace.starts_with('/') || namespace.ends_with('/') {
+                return Err(format!("invalid default namespace: {namespace}"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `--user-agent-allow`/`--user-agent-deny` let a client in.
+    /// Patterns match anywhere in the agent, ignoring case, and a request
+    /// without one only gets through when there is no allowlist.
+    pub fn allows_user_agent(&self, agent: Option<&str>) -> bool {
+        let agent = agent.unwrap_or_default().to_ascii_lowercase();
+        let matches = |pattern: &String| agent.contains(&pattern.to_ascii_lowercase());
+        if self.user_agent_deny.iter().any(matches) {
+            return false;
+        }
+        self.user_agent_allow.is_empty() || self.user_agent_allow.iter().any(matches)
+    }
+
+    /// How long a request or query may take before `--slow-log-ms` logs it
+    pub fn slow_threshold(&self) -> Option<Duration> {
+        self.slow_log_ms.map(Duration::from_millis)
+    }
+
+    /// The repository an unqualified name refers to under `--default-namespace`,
+    /// names that already have a namespace are left alone
+    pub fn qualify_repository<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        match self.default_namespace.as_deref() {
+            Some(namespace) if !name.contains('/') => Cow::Owned(format!("{namespace}/{name}")),
+            _ => Cow::Borrowed(name),
+        }
+    }
+}
+
+pub fn is_manifest_media_type(media_type: &str) -> bool {
+    MANIFEST_MEDIA_TYPES.contains(&media_type)
+}
+
+/// Which write path a pushed manifest takes, decided by its media type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ManifestKind {
+    /// a single image: a config and its layers
+    Image,
+    /// an image index/manifest list pointing at per-platform manifests
+    Index,
+    /// an OCI artifact manifest carrying arbitrary blobs
+    Artifact,
+}
+
+impl ManifestKind {
+    pub fn from_media_type(media_type: &str) -> Option<Self> {
+        if [OCI_MANIFEST_CONTENT_TYPE, MANIFEST_CONTENT_TYPE].contains(&media_type) {
+            Some(Self::Image)
+        } else if [OCI_CONTENT_HEADER, DOCKER_MANIFEST_LIST_CONTENT_TYPE].contains(&media_type) {
+            Some(Self::Index)
+        } else if media_type == OCI_ARTIFACT_MANIFEST_CONTENT_TYPE {
+            Some(Self::Artifact)
+        } else {
+            None
+        }
+    }
+}
@@ -1,20 +1,32 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
 use crate::{
     auth::Auth,
-    codes::{Code, ErrorResponse},
-    database::DbConn,
+    codes::{deletes_disabled, Code, ErrorResponse},
+    config::Config,
+    database::{self, DbConn},
     storage_driver::{Backend, DriverType},
+    util::{
+        encode_query_value, encode_repository_name, is_valid_repository_name, log_if_slow,
+        DigestHasher,
+    },
+    Action,
 };
 use axum::{
+    body::Body,
     extract::{Path, Query, Request},
-    http::{HeaderMap, HeaderValue, StatusCode},
+    http::{
+        header::{ACCEPT, CONTENT_DISPOSITION, CONTENT_TYPE, WWW_AUTHENTICATE},
+        HeaderMap, HeaderValue, StatusCode,
+    },
     response::IntoResponse,
     Extension, Json,
 };
 use serde::{Deserialize, Serialize};
+use shared::{Descriptor, API_VERSION_HEADER, FLOUNDR_VERSION_HEADER, OCI_CONTENT_HEADER};
 use sqlx::Row;
-use tracing::debug;
+use tokio_util::io::ReaderStream;
+use tracing::{debug, error};
 #[derive(Serialize, Deserialize, Debug)]
 pub struct TagsListResponse {
     name: String,
@@ -36,15 +48,37 @@ pub struct DockerLogin {
 }
 
 /// GET /v2/
-/// Return status code 200
+/// Return status code 200, along with the API and registry versions
 /// Spec: 770
-pub async fn get_v2(headers: HeaderMap, Query(params): Query<DockerLogin>) -> StatusCode {
+/// Anonymous callers get a 401 whose bearer challenge names the token endpoint
+pub async fn get_v2(
+    headers: HeaderMap,
+    Query(params): Query<DockerLogin>,
+    Extension(auth): Extension<Auth>,
+    Extension(config): Extension<Arc<Config>>,
+) -> impl IntoResponse {
     debug!(
         "GET /v2/ Request headers: {:?}\n URI: {:?}",
         headers, params,
     );
     debug!("GET /v2/");
-    StatusCode::OK
+    let versions = [
+        (API_VERSION_HEADER, "registry/2.0"),
+        (FLOUNDR_VERSION_HEADER, env!("CARGO_PKG_VERSION")),
+    ];
+    if !auth.is_valid() {
+        let challenge = format!(
+            "Bearer realm=\"{}/v2/auth/token\",service=\"floundr\"",
+            config.app_url
+        );
+        return (
+            StatusCode::UNAUTHORIZED,
+            versions,
+            [(WWW_AUTHENTICATE, challenge)],
+        )
+            .into_response();
+    }
+    (StatusCode::OK, versions).into_response()
 }
 
 impl TagsListResponse {
@@ -79,43 +113,40 @@ pub async fn get_tags_list(
     Query(params): Query<TagsQueryParams>,
 ) -> impl IntoResponse {
     let TagsQueryParams { n, last } = params;
+    if n == Some(0) {
+        return Json(TagsListResponse::new(&name, &[])).into_response();
+    }
+    // tags are ordered bytewise and the `last` cursor compared the same way
     let mut query_string = r#"
         SELECT tags.tag
         FROM repositories r
         JOIN tags ON tags.repository_id = r.id
-        WHERE r.name = ?
+        WHERE r.name = ? AND r.deleted_at IS NULL
     "#
     .to_string();
     if last.is_some() {
-        query_string.push_str(" AND tags.tag > ?");
+        query_string.push_str(" AND tags.tag > ? COLLATE BINARY");
     }
-    query_string.push_str(" ORDER BY tags.tag COLLATE NOCASE");
+    query_string.push_str(" ORDER BY tags.tag COLLATE BINARY");
     if n.is_some() {
         query_string.push_str(" LIMIT ?");
     }
-    let mut query = sqlx::query(&query_string);
+    let mut query = sqlx::query(&query_string).bind(&name);
     if let Some(last_tag) = last {
         query = query.bind(last_tag);
     }
+    // one row past the page tells whether another page follows
     if let Some(limit) = n {
-        query = query.bind(limit as i64);
+        query = query.bind(i64::try_from(limit).unwrap_or(i64::MAX).saturating_add(1));
     }
-    query = query.bind(&name);
     match query.fetch_all(&mut *conn).await {
         Ok(rows) => {
-            let tags: Vec<String> = rows.into_iter().map(|row| row.get(0)).collect();
+            let mut tags: Vec<String> = rows.into_iter().map(|row| row.get(0)).collect();
             let mut headers = HeaderMap::new();
-            if let Some(limit) = n {
-                if tags.len() == limit {
-                    let next_tag = tags.last().unwrap();
-                    let link = format!(
-                        "</v2/{}/tags/list?n={}&last={}>; rel=\"next\"",
-                        name, limit, next_tag
-                    );
-                    headers.insert("Link", HeaderValue::from_str(&link).unwrap());
-                }
+            let path = format!("/v2/{}/tags/list", encode_repository_name(&name));
+            if let Some(link) = next_page_link(&path, n, &mut tags) {
+                headers.insert("Link", link);
             }
-
             let response = TagsListResponse::new(&name, &tags);
             (headers, Json(response)).into_response()
         }
@@ -124,6 +155,210 @@ pub async fn get_tags_list(
     }
 }
 
+/// Link header pointing at the page after `page`. The page is fetched with
+/// one result past `n`, which is dropped here, so a link is only produced
+/// when more results actually follow. `n=0` never produces a link.
+fn next_page_link(path: &str, n: Option<usize>, page: &mut Vec<String>) -> Option<HeaderValue> {
+    let limit = n.filter(|limit| *limit > 0)?;
+    if page.len() <= limit {
+        return None;
+    }
+    page.truncate(limit);
+    let last = page.last()?;
+    HeaderValue::from_str(&format!(
+        "<{}?n={}&last={}>; rel=\"next\"",
+        path,
+        limit,
+        encode_query_value(last)
+    ))
+    .ok()
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CatalogResponse {
+    repositories: Vec<String>,
+}
+
+/// GET /v2/_catalog?n=<count>&last=<name>
+/// lists the repositories visible to the caller, paginated with a Link header.
+///
+/// Names are ordered bytewise (BINARY collation), and the `last` cursor is
+/// compared the same way, so nested names sort by their full path:
+/// `app` < `app-frontend` < `app/backend`. Using one collation for both
+/// is what keeps pages from skipping or repeating names.
+pub async fn get_catalog(
+    DbConn(mut conn): DbConn,
+    Query(params): Query<TagsQueryParams>,
+    Extension(auth): Extension<Auth>,
+) -> impl IntoResponse {
+    let TagsQueryParams { n, last } = params;
+    let rows = match sqlx::query!(
+        "SELECT name, is_public FROM repositories
+         WHERE deleted_at IS NULL AND (?1 IS NULL OR name > ?1 COLLATE BINARY)
+         ORDER BY name COLLATE BINARY",
+        last
+    )
+    .fetch_all(&mut *conn)
+    .await
+    {
+        Ok(rows) => rows,
+        Err(err) => {
+            error!("unable to list catalog: {}", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "unable to list catalog").into_response();
+        }
+    };
+    let visible = rows
+        .into_iter()
+        .filter(|row| auth.in_namespace(&row.name) && (row.is_public || auth.can_pull(&row.name)))
+        .map(|row| row.name);
+    // like the tag list, `n=0` asks for an empty page
+    let mut repositories: Vec<String> = match n {
+        Some(0) => Vec::new(),
+        Some(limit) => visible.take(limit.saturating_add(1)).collect(),
+        None => visible.collect(),
+    };
+    let mut headers = HeaderMap::new();
+    if let Some(link) = next_page_link("/v2/_catalog", n, &mut repositories) {
+        headers.insert("Link", link);
+    }
+    (headers, Json(CatalogResponse { repositories })).into_response()
+}
+
+/// GET /v2/:name/manifests/:digest/tags
+/// lists the tags that currently resolve to the given manifest digest,
+/// an untagged manifest returns an empty list
+pub async fn get_manifest_tags(
+    DbConn(mut conn): DbConn,
+    Path((name, digest)): Path<(String, String)>,
+) -> impl IntoResponse {
+    let manifest = sqlx::query!(
+        "SELECT m.id FROM manifests m JOIN repositories r ON m.repository_id = r.id WHERE r.name = ? AND r.deleted_at IS NULL AND m.digest = ?",
+        name,
+        digest
+    )
+    .fetch_optional(&mut *conn)
+    .await;
+    match manifest {
+        Ok(Some(manifest)) => match sqlx::query!(
+            "SELECT tag FROM tags WHERE manifest_id = ? ORDER BY tag",
+            manifest.id
+        )
+        .fetch_all(&mut *conn)
+        .await
+        {
+            Ok(rows) => {
+                let tags = rows.into_iter().map(|row| row.tag).collect::<Vec<String>>();
+                Json(TagsListResponse::new(&name, &tags)).into_response()
+            }
+            Err(err) => {
+                error!("unable to fetch tags for manifest {}: {}", digest, err);
+                (StatusCode::INTERNAL_SERVER_ERROR, "unable to list tags").into_response()
+            }
+        },
+        Ok(None) => {
+            ErrorResponse::from_code(&Code::ManifestUnknown, String::from("manifest not found"))
+                .into_response()
+        }
+        Err(err) => {
+            error!("unable to look up manifest {}: {}", digest, err);
+            (StatusCode::INTERNAL_SERVER_ERROR, "unable to list tags").into_response()
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ReferrersParams {
+    #[serde(rename = "artifactType")]
+    pub artifact_type: Option<String>,
+}
+
+/// The image index the referrers API answers with
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageIndex {
+    pub schema_version: i32,
+    pub media_type: &'static str,
+    pub manifests: Vec<Descriptor>,
+}
+
+/// just the part of a stored manifest a referrer descriptor copies
+#[derive(Deserialize)]
+struct ManifestAnnotations {
+    annotations: Option<HashMap<String, String>>,
+}
+
+/// GET /v2/:name/referrers/:digest?artifactType=<type>
+/// lists the manifests whose `subject` is the given digest as an image
+/// index, an unknown subject simply has no referrers
+/// spec: 500-560
+pub async fn get_referrers(
+    DbConn(mut conn): DbConn,
+    Extension(storage): Extension<Arc<Backend>>,
+    Path((name, digest)): Path<(String, String)>,
+    Query(params): Query<ReferrersParams>,
+) -> impl IntoResponse {
+    if DigestHasher::for_digest(&digest).is_none() {
+        return ErrorResponse::from_code(&Code::DigestInvalid, String::from("invalid digest"))
+            .into_response();
+    }
+    let rows = sqlx::query!(
+        "SELECT m.digest, m.media_type, m.size, m.artifact_type, m.file_path
+         FROM manifests m JOIN repositories r ON m.repository_id = r.id
+         WHERE r.name = ? AND r.deleted_at IS NULL AND m.subject_digest = ? AND (?3 IS NULL OR m.artifact_type = ?3)
+         ORDER BY m.id",
+        name,
+        digest,
+        params.artifact_type
+    )
+    .fetch_all(&mut *conn)
+    .await;
+    let rows = match rows {
+        Ok(rows) => rows,
+        Err(err) => {
+            error!("unable to look up referrers of {}: {}", digest, err);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "unable to look up referrers",
+            )
+                .into_response();
+        }
+    };
+    let mut manifests = Vec::with_capacity(rows.len());
+    for row in rows {
+        let annotations = match storage.read_manifest(&row.file_path).await {
+            Ok(data) => serde_json::from_slice::<ManifestAnnotations>(&data)
+                .ok()
+                .and_then(|manifest| manifest.annotations),
+            Err(err) => {
+                error!("unable to read referrer {}: {}", row.digest, err);
+                None
+            }
+        };
+        manifests.push(Descriptor {
+            media_type: Some(row.media_type),
+            size: row.size as i32,
+            digest: row.digest,
+            artifact_type: row.artifact_type,
+            annotations,
+            ..Default::default()
+        });
+    }
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static(OCI_CONTENT_HEADER));
+    if params.artifact_type.is_some() {
+        headers.insert(
+            "OCI-Filters-Applied",
+            HeaderValue::from_static("artifactType"),
+        );
+    }
+    let index = ImageIndex {
+        schema_version: 2,
+        media_type: OCI_CONTENT_HEADER,
+        manifests,
+    };
+    (StatusCode::OK, headers, Json(index)).into_response()
+}
+
 #[derive(Debug, Serialize)]
 pub struct Repository {
     pub name: String,
@@ -136,6 +371,60 @@ pub struct Repository {
     pub disk_usage: u64,
     pub driver: DriverType,
     pub num_layers: i64,
+    /// storage quota in bytes, `None` when unlimited
+    pub max_bytes: Option<i64>,
+}
+
+/// a repository the caller can reach and the most they may do there
+#[derive(Debug, Serialize)]
+pub struct AccessibleRepository {
+    pub name: String,
+    pub is_public: bool,
+    pub action: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AccessibleRepoList {
+    pub repositories: Vec<AccessibleRepository>,
+}
+
+/// GET /repositories/mine
+/// the repositories the caller can pull from, through their own scopes or
+/// because the repository is public, unlike `/repositories` this doesn't
+/// enumerate everything for admins only
+pub async fn list_my_repositories(
+    DbConn(mut conn): DbConn,
+    Extension(auth): Extension<Auth>,
+) -> impl IntoResponse {
+    let rows = match sqlx::query!(
+        "SELECT name, is_public FROM repositories WHERE deleted_at IS NULL ORDER BY name"
+    )
+    .fetch_all(&mut *conn)
+    .await
+    {
+        Ok(rows) => rows,
+        Err(err) => {
+            error!("unable to list repositories: {}", err);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "unable to list repositories",
+            )
+                .into_response();
+        }
+    };
+    let repositories = rows
+        .into_iter()
+        .filter_map(|row| {
+            let public = (row.is_public && auth.in_namespace(&row.name)).then_some(Action::Pull);
+            let action = auth.action(&row.name).max(public)?;
+            Some(AccessibleRepository {
+                name: row.name,
+                is_public: row.is_public,
+                action: action.to_string(),
+            })
+        })
+        .collect();
+    Json(AccessibleRepoList { repositories }).into_response()
 }
 
 #[derive(Debug, Serialize)]
@@ -152,8 +441,21 @@ pub async fn create_repository(
     DbConn(mut conn): DbConn,
     Extension(storage): Extension<Arc<Backend>>,
     Path((name, public)): Path<(String, String)>,
+    Extension(auth): Extension<Auth>,
 ) -> impl IntoResponse {
     debug!("POST /repositories/{}", name);
+    if !auth.is_admin() {
+        return ErrorResponse::from_code(&Code::Denied, String::from("admin access required"))
+            .into_response();
+    }
+    if !auth.in_namespace(&name) {
+        return ErrorResponse::from_code(&Code::Denied, "repository is outside of your namespace")
+            .into_response();
+    }
+    if !is_valid_repository_name(&name) {
+        return ErrorResponse::from_code(&Code::NameInvalid, "invalid repository name")
+            .into_response();
+    }
     match storage
         .create_repository(&mut conn, &name, public.to_lowercase().eq("true"))
         .await
@@ -166,36 +468,46 @@ pub async fn create_repository(
 pub async fn list_repositories(
     DbConn(mut conn): DbConn,
     Extension(storage): Extension<Arc<Backend>>,
+    Extension(config): Extension<Arc<Config>>,
     req: Request,
 ) -> impl IntoResponse {
     let auth = req.extensions().get::<Auth>();
     let mut query = String::from(
-        r"SELECT id, name, is_public, (SELECT COUNT(*) from blobs where blobs.repository_id = repositories.id) as blob_count,
+        r"SELECT id, name, is_public, max_bytes, (SELECT COUNT(*) from blobs where blobs.repository_id = repositories.id) as blob_count,
 (SELECT COUNT(*) from tags WHERE tags.repository_id = repositories.id) as tag_count, (SELECT COUNT(m.id) from manifests m WHERE m.repository_id = id) as manifest_count,
-(SELECT COUNT(*) from manifest_layers ml JOIN manifests m ON ml.manifest_id = m.id WHERE m.repository_id = ml.id) as num_layers FROM repositories",
+(SELECT COUNT(*) from manifest_layers ml JOIN manifests m ON ml.manifest_id = m.id WHERE m.repository_id = ml.id) as num_layers,
+(SELECT COALESCE(SUM(size), 0) from blobs WHERE blobs.repository_id = repositories.id) as disk_usage FROM repositories WHERE deleted_at IS NULL",
     );
     if auth.is_some_and(|a| !a.is_valid()) {
         // list only public repos
-        query.push_str(" WHERE is_public = true");
+        query.push_str(" AND is_public = true");
     };
-    let repos = sqlx::query(&query).fetch_all(&mut *conn).await.unwrap();
+    let repos = log_if_slow(
+        config.slow_threshold(),
+        "repository disk usage scan",
+        sqlx::query(&query).fetch_all(&mut *conn),
+    )
+    .await
+    .unwrap();
     let mut names = Vec::new();
     for repo in repos {
+        let name = repo.get::<String, _>("name");
+        if auth.is_some_and(|auth| !auth.in_namespace(&name)) {
+            continue;
+        }
         let id = repo.get::<i64, _>("id");
         let row = sqlx::query!("SELECT tag from tags t WHERE t.repository_id = ?", id)
             .fetch_all(&mut *conn)
             .await
             .unwrap();
         let tags = row.iter().map(|t| t.tag.clone()).collect::<Vec<String>>();
-        let name = repo.get::<String, _>("name");
         let is_public = repo.get::<bool, _>("is_public");
         let blob_count = repo.get::<i64, _>("blob_count");
         let tag_count = repo.get::<i64, _>("tag_count");
         let manifest_count = repo.get::<i64, _>("manifest_count");
         let num_layers = repo.get::<i64, _>("num_layers");
-        let disk_usage = storage
-            .get_dir_size(storage.base_path().join(repo.get::<String, _>("name")))
-            .await;
+        let max_bytes = repo.get::<Option<i64>, _>("max_bytes");
+        let disk_usage = repo.get::<i64, _>("disk_usage").max(0) as u64;
         names.push(Repository {
             name: name.clone(),
             is_public,
@@ -206,6 +518,7 @@ pub async fn list_repositories(
             manifest_count,
             disk_usage,
             num_layers,
+            max_bytes,
             driver: storage.kind(),
         });
     }
@@ -216,11 +529,381 @@ pub async fn list_repositories(
     (StatusCode::OK, response).into_response()
 }
 
+#[derive(Deserialize, Debug, Clone)]
+pub struct DeleteRepoQuery {
+    purge: Option<bool>,
+}
+
+/// DELETE /repositories/:name?purge=<bool>
+/// when a recovery window is configured the repository is only marked deleted,
+/// and purged later once the window expires (or immediately with purge=true)
 pub async fn delete_repository(
     Path(name): Path<String>,
+    Query(params): Query<DeleteRepoQuery>,
+    DbConn(mut conn): DbConn,
+    Extension(storage): Extension<Arc<Backend>>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(auth): Extension<Auth>,
+) -> impl IntoResponse {
+    if !auth.is_admin() {
+        return ErrorResponse::from_code(&Code::Denied, String::from("admin access required"))
+            .into_response();
+    }
+    if !auth.in_namespace(&name) {
+        return ErrorResponse::from_code(&Code::Denied, "repository is outside of your namespace")
+            .into_response();
+    }
+    if config.disable_delete {
+        return deletes_disabled();
+    }
+    if config.repo_recovery_window.is_some() && !params.purge.unwrap_or(false) {
+        return match database::soft_delete_repository(&mut conn, &name).await {
+            Ok(true) => (StatusCode::OK, "repository deleted").into_response(),
+            Ok(false) => {
+                ErrorResponse::from_code(&Code::NameUnknown, String::from("repository not found"))
+                    .into_response()
+            }
+            Err(err) => {
+                error!("unable to delete repository {}: {}", name, err);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "unable to delete repository",
+                )
+                    .into_response()
+            }
+        };
+    }
+    let id = match database::repository_id(&mut conn, &name).await {
+        Ok(Some(id)) => id,
+        Ok(None) => {
+            return ErrorResponse::from_code(
+                &Code::NameUnknown,
+                String::from("repository not found"),
+            )
+            .into_response()
+        }
+        Err(err) => {
+            error!("unable to look up repository {}: {}", name, err);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "unable to delete repository",
+            )
+                .into_response();
+        }
+    };
+    match storage.delete_repository(id, &mut conn).await {
+        Ok(failed) if failed.is_empty() => (StatusCode::OK, "repository deleted").into_response(),
+        Ok(failed) => (
+            StatusCode::OK,
+            Json(DeleteRepoResult {
+                message: String::from("repository deleted, some files could not be removed"),
+                failed_files: failed,
+            }),
+        )
+            .into_response(),
+        Err(err) => {
+            error!("unable to delete repository {}: {:?}", name, err);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "unable to delete repository",
+            )
+                .into_response()
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeleteRepoResult {
+    pub message: String,
+    pub failed_files: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ManifestClosure {
+    name: String,
+    reference: String,
+    digests: Vec<String>,
+}
+
+/// GET /repositories/:name/manifests/:reference/closure
+/// every digest (manifests, configs and layers, walking through indexes)
+/// needed to pull the reference. Responds with newline-delimited digests
+/// when the client accepts `text/plain`, JSON otherwise.
+pub async fn get_manifest_closure(
+    Path((name, reference)): Path<(String, String)>,
     DbConn(mut conn): DbConn,
     Extension(storage): Extension<Arc<Backend>>,
+    Extension(auth): Extension<Auth>,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
-    let _ = storage.delete_repository(&name, &mut conn).await;
-    (StatusCode::OK, "repository deleted").into_response()
+    let is_public = sqlx::query!(
+        "SELECT is_public FROM repositories WHERE name = ? AND deleted_at IS NULL",
+        name
+    )
+    .fetch_optional(&mut *conn)
+    .await
+    .ok()
+    .flatten()
+    .map(|row| row.is_public);
+    match is_public {
+        None => {
+            return ErrorResponse::from_code(
+                &Code::NameUnknown,
+                String::from("repository not found"),
+            )
+            .into_response()
+        }
+        Some(is_public)
+            if !(auth.can(&name, Action::Pull) || is_public && auth.in_namespace(&name)) =>
+        {
+            return ErrorResponse::from_code(&Code::Denied, String::from("pull access required"))
+                .into_response()
+        }
+        _ => {}
+    }
+    let digests = match storage.manifest_closure(&mut conn, &name, &reference).await {
+        Ok(digests) => digests,
+        Err(err) => {
+            debug!(
+                "unable to resolve closure of {}:{}: {:?}",
+                name, reference, err
+            );
+            return ErrorResponse::from_code(
+                &Code::ManifestUnknown,
+                String::from("manifest not found"),
+            )
+            .into_response();
+        }
+    };
+    let wants_text = headers
+        .get(ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("text/plain"));
+    if wants_text {
+        let mut body = digests.join("\n");
+        body.push('\n');
+        return (
+            StatusCode::OK,
+            [(CONTENT_TYPE, HeaderValue::from_static("text/plain"))],
+            body,
+        )
+            .into_response();
+    }
+    Json(ManifestClosure {
+        name,
+        reference,
+        digests,
+    })
+    .into_response()
+}
+
+#[derive(Serialize, Debug)]
+pub struct LayerDetails {
+    digest: String,
+    size: i64,
+    media_type: String,
+    /// digest of the uncompressed layer, recorded with `--store-diff-ids`
+    diff_id: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ManifestDetails {
+    name: String,
+    digest: String,
+    media_type: String,
+    size: i64,
+    schema_version: i64,
+    created_at: Option<String>,
+    tags: Vec<String>,
+    layers: Vec<LayerDetails>,
+}
+
+/// GET /repositories/:name/manifests/:reference
+/// what the registry recorded about a manifest: its tags and layers,
+/// including each layer's diff ID when it was captured on push
+pub async fn get_manifest_details(
+    Path((name, reference)): Path<(String, String)>,
+    DbConn(mut conn): DbConn,
+    Extension(auth): Extension<Auth>,
+) -> impl IntoResponse {
+    let manifest = sqlx::query!(
+        r#"SELECT m.id, m.digest, m.media_type, m.size, m.schema_version,
+         CAST(m.created_at AS TEXT) as "created_at?: String", r.is_public
+         FROM manifests m JOIN repositories r ON m.repository_id = r.id
+         LEFT JOIN tags t ON t.manifest_id = m.id
+         WHERE r.name = ?1 AND r.deleted_at IS NULL AND (m.digest = ?2 OR t.tag = ?2)"#,
+        name,
+        reference
+    )
+    .fetch_optional(&mut *conn)
+    .await;
+    let manifest = match manifest {
+        Ok(Some(manifest)) => manifest,
+        Ok(None) => {
+            return ErrorResponse::from_code(
+                &Code::ManifestUnknown,
+                String::from("manifest not found"),
+            )
+            .into_response()
+        }
+        Err(err) => {
+            error!("unable to look up manifest {}:{}: {}", name, reference, err);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "unable to look up manifest",
+            )
+                .into_response();
+        }
+    };
+    if !(auth.can(&name, Action::Pull) || manifest.is_public && auth.in_namespace(&name)) {
+        return ErrorResponse::from_code(&Code::Denied, String::from("pull access required"))
+            .into_response();
+    }
+    let tags = sqlx::query!(
+        "SELECT tag FROM tags WHERE manifest_id = ? ORDER BY tag",
+        manifest.id
+    )
+    .fetch_all(&mut *conn)
+    .await;
+    let layers = sqlx::query_as!(
+        LayerDetails,
+        "SELECT digest, size, media_type, diff_id FROM manifest_layers WHERE manifest_id = ? ORDER BY id",
+        manifest.id
+    )
+    .fetch_all(&mut *conn)
+    .await;
+    let (tags, layers) = match (tags, layers) {
+        (Ok(tags), Ok(layers)) => (tags.into_iter().map(|row| row.tag).collect(), layers),
+        (Err(err), _) | (_, Err(err)) => {
+            error!("unable to look up manifest {}:{}: {}", name, reference, err);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "unable to look up manifest",
+            )
+                .into_response();
+        }
+    };
+    Json(ManifestDetails {
+        name,
+        digest: manifest.digest,
+        media_type: manifest.media_type,
+        size: manifest.size,
+        schema_version: manifest.schema_version,
+        created_at: manifest.created_at,
+        tags,
+        layers,
+    })
+    .into_response()
+}
+
+/// GET /repositories/:name/export
+/// admin only: streams an OCI image layout tarball of the whole repository
+pub async fn export_repository(
+    Path(name): Path<String>,
+    DbConn(mut conn): DbConn,
+    Extension(storage): Extension<Arc<Backend>>,
+    Extension(auth): Extension<Auth>,
+) -> impl IntoResponse {
+    if !auth.is_admin() {
+        return ErrorResponse::from_code(&Code::Denied, String::from("admin access required"))
+            .into_response();
+    }
+    if !auth.in_namespace(&name) {
+        return ErrorResponse::from_code(&Code::Denied, "repository is outside of your namespace")
+            .into_response();
+    }
+    if !sqlx::query!(
+        "SELECT id FROM repositories WHERE name = ? AND deleted_at IS NULL",
+        name
+    )
+    .fetch_optional(&mut *conn)
+    .await
+    .is_ok_and(|row| row.is_some())
+    {
+        return ErrorResponse::from_code(&Code::NameUnknown, String::from("repository not found"))
+            .into_response();
+    }
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/x-tar"));
+    if let Ok(disposition) =
+        HeaderValue::from_str(&format!("attachment; filename=\"{}.tar\"", name))
+    {
+        headers.insert(CONTENT_DISPOSITION, disposition);
+    }
+    // the archive is written into one end of an in-memory pipe while the
+    // response body streams from the other, so it's never fully buffered
+    let (writer, reader) = tokio::io::duplex(64 * 1024);
+    tokio::spawn(async move {
+        if let Err(err) = storage.export_repository(&mut conn, &name, writer).await {
+            error!("error exporting repository {}: {}", name, err);
+        }
+    });
+    (
+        StatusCode::OK,
+        headers,
+        Body::from_stream(ReaderStream::new(reader)),
+    )
+        .into_response()
+}
+
+/// POST /repositories/:name/restore
+/// admin only: undelete a soft-deleted repository within the recovery window
+pub async fn restore_repository(
+    Path(name): Path<String>,
+    DbConn(mut conn): DbConn,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(auth): Extension<Auth>,
+) -> impl IntoResponse {
+    if !auth.is_admin() {
+        return ErrorResponse::from_code(&Code::Denied, String::from("admin access required"))
+            .into_response();
+    }
+    if !auth.in_namespace(&name) {
+        return ErrorResponse::from_code(&Code::Denied, "repository is outside of your namespace")
+            .into_response();
+    }
+    let window = match config.repo_recovery_window {
+        Some(window) => window,
+        None => {
+            return ErrorResponse::from_code(
+                &Code::Unsupported,
+                String::from("soft deletion is not enabled"),
+            )
+            .into_response()
+        }
+    };
+    match database::repository_exists(&mut conn, &name).await {
+        Ok(false) => {}
+        Ok(true) => {
+            return (
+                StatusCode::CONFLICT,
+                "a repository with that name already exists",
+            )
+                .into_response()
+        }
+        Err(err) => {
+            error!("unable to look up repository {}: {}", name, err);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "unable to restore repository",
+            )
+                .into_response();
+        }
+    }
+    match database::restore_repository(&mut conn, &name, window).await {
+        Ok(true) => (StatusCode::OK, "repository restored").into_response(),
+        Ok(false) => ErrorResponse::from_code(
+            &Code::NameUnknown,
+            String::from("no deleted repository within the recovery window"),
+        )
+        .into_response(),
+        Err(err) => {
+            error!("unable to restore repository {}: {}", name, err);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "unable to restore repository",
+            )
+                .into_response()
+        }
+    }
 }
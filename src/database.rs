@@ -1,42 +1,216 @@
 use axum::{
     async_trait,
-    extract::{FromRef, FromRequestParts},
+    extract::{FromRef, FromRequestParts, State},
     http::{request::Parts, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
 };
-use sqlx::{query, sqlite::SqlitePoolOptions, Acquire, Executor, SqliteConnection, SqlitePool};
+use lazy_static::lazy_static;
+use sha2::{Digest, Sha256};
+use sqlx::{
+    query,
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions},
+    Acquire, Executor, SqliteConnection, SqlitePool,
+};
+use std::{path::Path, str::FromStr, time::Duration};
+use tokio::sync::Mutex;
 use tracing::{error, info};
 
-use crate::Repo;
-
-pub static TABLES: [&str; 8] = [
-    "repositories",
-    "blobs",
-    "tags",
-    "uploads",
-    "manifests",
-    "repository_permissions",
-    "users",
-    "tokens",
+use crate::{
+    auth::{Auth, UserInfo},
+    codes::{Code, ErrorResponse},
+    config::Config,
+    Repo, UserScope,
+};
+
+/// Held for the duration of any maintenance task (garbage collection,
+/// repository purges, migrations, database optimization) so they never
+/// run on top of each other
+pub static MAINTENANCE: Mutex<()> = Mutex::const_new(());
+
+/// how long a connection waits on a locked database before giving up,
+/// parallel layer uploads all write to it
+pub const DB_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Every migration in the order they're applied, embedded so migrations work
+/// from any working directory. A database counts the ones it has run in
+/// `PRAGMA user_version`, so each runs once. The first only creates what's
+/// missing, databases from before migrations were counted replay it safely.
+pub static MIGRATIONS: [&str; 17] = [
+    include_str!("../migrations/01_createtables.sql"),
+    include_str!("../migrations/02_soft_delete_repositories.sql"),
+    include_str!("../migrations/03_namespaces.sql"),
+    include_str!("../migrations/04_api_key_scopes.sql"),
+    include_str!("../migrations/05_layer_diff_ids.sql"),
+    include_str!("../migrations/06_manifest_subjects.sql"),
+    include_str!("../migrations/07_repository_quotas.sql"),
+    include_str!("../migrations/08_upload_chunk_checks.sql"),
+    include_str!("../migrations/09_referrer_artifact_types.sql"),
+    include_str!("../migrations/10_shared_manifests.sql"),
+    include_str!("../migrations/11_upload_digests.sql"),
+    include_str!("../migrations/12_refresh_tokens.sql"),
+    include_str!("../migrations/13_unique_client_ids.sql"),
+    include_str!("../migrations/14_reuse_deleted_repository_names.sql"),
+    include_str!("../migrations/15_cascade_deletes.sql"),
+    include_str!("../migrations/16_hash_refresh_tokens.sql"),
+    include_str!("../migrations/17_drop_digest_tags.sql"),
+];
+
+lazy_static! {
+    /// Every table `MIGRATIONS` create, in the order they create them
+    pub static ref TABLES: Vec<&'static str> = MIGRATIONS
+        .iter()
+        .flat_map(|migration| migration.lines())
+        .filter_map(|line| line.trim().strip_prefix("CREATE TABLE IF NOT EXISTS "))
+        .filter_map(|rest| rest.split_whitespace().next())
+        .collect();
+}
+
+/// Every table and column the queries are compiled against, checked once
+/// migrations ran so a database that was changed by hand, or by a newer
+/// release, is caught before the first query that relies on it.
+pub static EXPECTED_SCHEMA: [(&str, &[&str]); 11] = [
+    (
+        "repositories",
+        &[
+            "id",
+            "name",
+            "is_public",
+            "deleted_at",
+            "max_bytes",
+            "created_at",
+        ],
+    ),
+    (
+        "blobs",
+        &[
+            "id",
+            "repository_id",
+            "digest",
+            "file_path",
+            "upload_session_id",
+            "ref_count",
+            "chunk_count",
+            "size",
+            "chunk_offset",
+            "created_at",
+        ],
+    ),
+    (
+        "tags",
+        &["id", "manifest_id", "repository_id", "tag", "created_at"],
+    ),
+    (
+        "manifests",
+        &[
+            "id",
+            "repository_id",
+            "digest",
+            "media_type",
+            "file_path",
+            "size",
+            "schema_version",
+            "subject_digest",
+            "artifact_type",
+            "created_at",
+        ],
+    ),
+    (
+        "manifest_layers",
+        &[
+            "id",
+            "repository_id",
+            "manifest_id",
+            "digest",
+            "size",
+            "media_type",
+            "diff_id",
+            "created_at",
+        ],
+    ),
+    (
+        "uploads",
+        &[
+            "uuid",
+            "repository_id",
+            "current_chunk",
+            "corrupt",
+            "digest",
+            "created_at",
+        ],
+    ),
+    (
+        "users",
+        &[
+            "id",
+            "email",
+            "password",
+            "is_admin",
+            "namespace",
+            "created_at",
+        ],
+    ),
+    (
+        "repository_scopes",
+        &["id", "user_id", "repository_id", "push", "pull", "del"],
+    ),
+    (
+        "clients",
+        &[
+            "id",
+            "client_id",
+            "user_id",
+            "secret",
+            "namespace",
+            "scope",
+            "created_at",
+        ],
+    ),
+    (
+        "tokens",
+        &["id", "account", "token", "client_id", "expires"],
+    ),
+    (
+        "refresh_tokens",
+        &["id", "user_id", "token_hash", "created_at", "expires_at"],
+    ),
 ];
 
 pub struct DbConn(pub sqlx::pool::PoolConnection<sqlx::Sqlite>);
 
-pub async fn initdb(path: &str) -> sqlx::Pool<sqlx::Sqlite> {
+/// Opens the pool and brings the schema up to date. Idle connections are
+/// recycled per `--db-idle-timeout`/`--db-max-lifetime`, so none pins an
+/// old WAL snapshot or file handle for the life of the server. Every
+/// connection runs in WAL mode, with foreign keys enforced and lock waits
+/// bounded by `DB_BUSY_TIMEOUT`.
+pub async fn initdb(path: &str, config: &Config) -> sqlx::Pool<sqlx::Sqlite> {
     println!("connecting to sqlite db at: {}", path);
     if !std::path::PathBuf::from(path).exists() {
         tokio::fs::File::create(path)
             .await
             .expect("unable to create sqlite db");
     }
+    let seconds = |secs: u64| (secs > 0).then(|| Duration::from_secs(secs));
+    let options = SqliteConnectOptions::from_str(path)
+        .expect("invalid sqlite db path")
+        .journal_mode(SqliteJournalMode::Wal)
+        .foreign_keys(true)
+        .busy_timeout(DB_BUSY_TIMEOUT);
     let pool = SqlitePoolOptions::new()
         .max_connections(8)
-        .connect(path)
+        .idle_timeout(seconds(config.db_idle_timeout))
+        .max_lifetime(seconds(config.db_max_lifetime))
+        .connect_with(options)
         .await
         .expect("unable to connect to sqlite db pool");
     let mut conn = pool.acquire().await.expect("unable to acquire connection");
     migrate(&mut conn, None, None)
         .await
         .expect("unable to migrate db");
+    if let Err(err) = verify_schema(&mut conn).await {
+        eprintln!("database at {path} does not match this version of floundr: {err}");
+        std::process::exit(1);
+    }
     if query!("SELECT COUNT(*) as client_count from clients")
         .fetch_one(&mut *conn)
         .await
@@ -51,6 +225,25 @@ pub async fn initdb(path: &str) -> sqlx::Pool<sqlx::Sqlite> {
     pool
 }
 
+/// Checks the database has every table and column in `EXPECTED_SCHEMA`,
+/// naming the first one that's missing
+pub async fn verify_schema(conn: &mut SqliteConnection) -> Result<(), String> {
+    for (table, columns) in EXPECTED_SCHEMA {
+        let existing = sqlx::query_scalar::<_, String>("SELECT name FROM pragma_table_info(?)")
+            .bind(table)
+            .fetch_all(&mut *conn)
+            .await
+            .map_err(|err| format!("unable to inspect table `{table}`: {err}"))?;
+        if existing.is_empty() {
+            return Err(format!("missing table `{table}`"));
+        }
+        if let Some(column) = columns.iter().find(|c| !existing.iter().any(|e| e == *c)) {
+            return Err(format!("table `{table}` is missing column `{column}`"));
+        }
+    }
+    Ok(())
+}
+
 pub async fn seed_default_user(
     pool: &mut SqliteConnection,
     email: Option<String>,
@@ -71,6 +264,27 @@ pub async fn seed_default_user(
     Ok(())
 }
 
+/// Creates a user with a freshly hashed password, returning its id
+pub async fn create_user(
+    conn: &mut SqliteConnection,
+    email: &str,
+    password: &str,
+    is_admin: bool,
+) -> Result<String, sqlx::Error> {
+    let id = uuid::Uuid::new_v4().to_string();
+    let hashed = bcrypt::hash(password, bcrypt::DEFAULT_COST).expect("unable to hash password");
+    query!(
+        "INSERT INTO users (id, email, password, is_admin) VALUES (?, ?, ?, ?)",
+        id,
+        email,
+        hashed,
+        is_admin
+    )
+    .execute(conn)
+    .await?;
+    Ok(id)
+}
+
 pub async fn seed_default_client(pool: &mut SqliteConnection) -> Result<(), sqlx::Error> {
     let secret = uuid::Uuid::new_v4().to_string();
     let id = "floundr_tui";
@@ -88,20 +302,118 @@ pub async fn generate_secret(
     pool: &mut SqliteConnection,
     client_id: Option<String>,
     email: &str,
+    namespace: Option<&str>,
+    scope: Option<&UserScope>,
 ) -> Result<String, sqlx::Error> {
     let secret = uuid::Uuid::new_v4().to_string();
     let id = client_id.unwrap_or(uuid::Uuid::new_v4().to_string());
+    let scope = scope
+        .map(serde_json::to_string)
+        .transpose()
+        .map_err(|err| sqlx::Error::Encode(Box::new(err)))?;
     query!(
-        "INSERT INTO clients (client_id, secret, user_id) VALUES (?, ?, (SELECT id from users where email = ?))",
+        "INSERT INTO clients (client_id, secret, user_id, namespace, scope) VALUES (?, ?, (SELECT id from users where email = ?), ?, ?)",
         id,
         secret,
-        email
+        email,
+        namespace,
+        scope
     )
     .execute(&mut *pool)
     .await?;
     Ok(secret)
 }
 
+/// how long a refresh token can be exchanged, in seconds
+pub const REFRESH_TOKEN_TTL: i64 = 30 * 24 * 60 * 60;
+
+/// Refresh tokens are only stored hashed, so a leaked database holds none
+fn hash_refresh_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// Issues a refresh token to a user, None when `user_id` names no user,
+/// as for the claims of an API key
+pub async fn create_refresh_token(
+    conn: &mut SqliteConnection,
+    user_id: &str,
+) -> Result<Option<String>, sqlx::Error> {
+    let token = uuid::Uuid::new_v4().to_string();
+    let token_hash = hash_refresh_token(&token);
+    let inserted = query!(
+        "INSERT INTO refresh_tokens (user_id, token_hash, expires_at)
+         SELECT id, ?, datetime('now', '+' || ? || ' seconds') FROM users WHERE id = ?",
+        token_hash,
+        REFRESH_TOKEN_TTL,
+        user_id
+    )
+    .execute(conn)
+    .await?
+    .rows_affected();
+    Ok((inserted > 0).then_some(token))
+}
+
+/// The user an unexpired refresh token was issued to
+pub async fn refresh_token_user(
+    conn: &mut SqliteConnection,
+    token: &str,
+) -> Result<Option<UserInfo>, sqlx::Error> {
+    let token_hash = hash_refresh_token(token);
+    let user = query!(
+        "SELECT u.id, u.email, u.is_admin FROM refresh_tokens t JOIN users u ON u.id = t.user_id
+         WHERE t.token_hash = ? AND t.expires_at > datetime('now')",
+        token_hash
+    )
+    .fetch_optional(conn)
+    .await?;
+    Ok(user.map(|user| UserInfo {
+        id: user.id,
+        email: user.email,
+        is_admin: user.is_admin,
+    }))
+}
+
+/// Replaces a refresh token with a new one for the same user. None when it
+/// was already exchanged, so a token can't be redeemed twice.
+pub async fn rotate_refresh_token(
+    conn: &mut SqliteConnection,
+    token: &str,
+    user_id: &str,
+) -> Result<Option<String>, sqlx::Error> {
+    let token_hash = hash_refresh_token(token);
+    let mut tx = conn.begin().await?;
+    let removed = query!(
+        "DELETE FROM refresh_tokens WHERE token_hash = ? AND user_id = ?",
+        token_hash,
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+    if removed == 0 {
+        return Ok(None);
+    }
+    let token = create_refresh_token(&mut tx, user_id).await?;
+    tx.commit().await?;
+    Ok(token)
+}
+
+/// Confines a user to the repositories within `namespace`, e.g. `team` for `team/app`
+pub async fn set_user_namespace(
+    pool: &mut SqliteConnection,
+    email: &str,
+    namespace: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    query!(
+        "UPDATE users SET namespace = ? WHERE email = ?",
+        namespace,
+        email
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
 impl std::ops::Deref for DbConn {
     type Target = sqlx::pool::PoolConnection<sqlx::Sqlite>;
     fn deref(&self) -> &Self::Target {
@@ -149,20 +461,35 @@ pub async fn migrate_fresh(
     email: Option<String>,
     psw: Option<String>,
 ) -> Result<(), sqlx::Error> {
+    let _maintenance = MAINTENANCE.lock().await;
     drop_tables(pool).await?;
-    migrate(pool, email, psw).await?;
-    Ok(())
+    run_migrations(pool, email, psw).await
 }
 
 pub async fn migrate(
     pool: &mut SqliteConnection,
     email: Option<String>,
     psw: Option<String>,
+) -> Result<(), sqlx::Error> {
+    let _maintenance = MAINTENANCE.lock().await;
+    run_migrations(pool, email, psw).await
+}
+
+async fn run_migrations(
+    pool: &mut SqliteConnection,
+    email: Option<String>,
+    psw: Option<String>,
 ) -> Result<(), sqlx::Error> {
     let conn = pool.acquire().await?;
-    sqlx::query(&tokio::fs::read_to_string("migrations/01_createtables.sql").await?)
-        .execute(&mut *conn)
+    let applied: i64 = sqlx::query_scalar("PRAGMA user_version")
+        .fetch_one(&mut *conn)
         .await?;
+    // rebuilding a table drops the old one, which would cascade into every
+    // table referring to it while foreign keys are enforced
+    conn.execute("PRAGMA foreign_keys = OFF").await?;
+    let migrated = apply_migrations(conn, applied).await;
+    conn.execute("PRAGMA foreign_keys = ON").await?;
+    migrated?;
     if sqlx::query!("SELECT COUNT(*) as user_count from users")
         .fetch_one(&mut *conn)
         .await?
@@ -174,21 +501,41 @@ pub async fn migrate(
     Ok(())
 }
 
+/// Runs every migration past the first `applied`, each in its own
+/// transaction along with the bump of `user_version`
+async fn apply_migrations(conn: &mut SqliteConnection, applied: i64) -> Result<(), sqlx::Error> {
+    let applied = usize::try_from(applied).unwrap_or_default();
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(applied) {
+        let mut tx = conn.begin().await?;
+        tx.execute(sqlx::query(migration)).await?;
+        tx.execute(sqlx::query(&format!(
+            "PRAGMA user_version = {}",
+            version + 1
+        )))
+        .await?;
+        tx.commit().await?;
+        info!("applied migration {}", version + 1);
+    }
+    Ok(())
+}
+
 pub async fn drop_tables(pool: &mut SqliteConnection) -> Result<(), sqlx::Error> {
     let mut tx = pool.begin().await?;
-    for table in TABLES {
-        let _ = tx
-            .execute(sqlx::query(&format!("DROP TABLE {};", table)))
-            .await;
+    // dependent tables are created last, so they go first
+    for table in TABLES.iter().rev() {
+        tx.execute(sqlx::query(&format!("DROP TABLE IF EXISTS {table};")))
+            .await?;
     }
-    Ok(())
+    tx.execute("PRAGMA user_version = 0").await?;
+    tx.commit().await
 }
 
 pub async fn get_repositories(conn: &mut SqliteConnection, pub_only: bool) -> Vec<Repo> {
-    let repos = sqlx::query!("SELECT id, name, is_public FROM repositories")
-        .fetch_all(&mut *conn)
-        .await
-        .expect("unable to fetch public repositories");
+    let repos =
+        sqlx::query!("SELECT id, name, is_public FROM repositories WHERE deleted_at IS NULL")
+            .fetch_all(&mut *conn)
+            .await
+            .expect("unable to fetch public repositories");
     if pub_only {
         return repos
             .iter()
@@ -206,18 +553,207 @@ pub async fn get_repositories(conn: &mut SqliteConnection, pub_only: bool) -> Ve
         .filter_map(|row| row.name.parse().ok())
         .collect()
 }
+/// Marks a repository as deleted without touching its files, it is hidden
+/// from listings and /v2/ access until restored or purged.
+pub async fn soft_delete_repository(
+    conn: &mut SqliteConnection,
+    name: &str,
+) -> Result<bool, sqlx::Error> {
+    let result = query!(
+        "UPDATE repositories SET deleted_at = CURRENT_TIMESTAMP WHERE name = ? AND deleted_at IS NULL",
+        name
+    )
+    .execute(conn)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Undeletes the most recently soft-deleted repository with this name,
+/// provided it was deleted less than `window` seconds ago.
+pub async fn restore_repository(
+    conn: &mut SqliteConnection,
+    name: &str,
+    window: u64,
+) -> Result<bool, sqlx::Error> {
+    let cutoff = format!("-{} seconds", window);
+    let result = query!(
+        "UPDATE repositories SET deleted_at = NULL WHERE id = (
+            SELECT id FROM repositories WHERE name = ? AND deleted_at IS NOT NULL
+            AND deleted_at > datetime('now', ?) ORDER BY deleted_at DESC, id DESC LIMIT 1)",
+        name,
+        cutoff
+    )
+    .execute(conn)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Ids and names of soft-deleted repositories whose recovery window has
+/// passed. A name may be listed more than once, and may be in use again.
+pub async fn expired_repositories(
+    conn: &mut SqliteConnection,
+    window: u64,
+) -> Result<Vec<(i64, Repo)>, sqlx::Error> {
+    let cutoff = format!("-{} seconds", window);
+    let rows = query!(
+        "SELECT id, name FROM repositories WHERE deleted_at IS NOT NULL AND deleted_at <= datetime('now', ?)",
+        cutoff
+    )
+    .fetch_all(conn)
+    .await?;
+    Ok(rows.into_iter().map(|row| (row.id, row.name)).collect())
+}
+
+/// Whether a live repository has this name, soft-deleted ones give theirs up
+/// so it can be created again.
+pub async fn repository_exists(
+    conn: &mut SqliteConnection,
+    name: &str,
+) -> Result<bool, sqlx::Error> {
+    Ok(repository_id(conn, name).await?.is_some())
+}
+
+/// The id of the live repository with this name
+pub async fn repository_id(
+    conn: &mut SqliteConnection,
+    name: &str,
+) -> Result<Option<i64>, sqlx::Error> {
+    sqlx::query_scalar!(
+        "SELECT id as \"id!\" FROM repositories WHERE name = ? AND deleted_at IS NULL",
+        name
+    )
+    .fetch_optional(conn)
+    .await
+}
+
+/// Whether anyone may pull from the repository, false for unknown names
+pub async fn repository_is_public(conn: &mut SqliteConnection, name: &str) -> bool {
+    sqlx::query_scalar!(
+        "SELECT is_public FROM repositories WHERE name = ? AND deleted_at IS NULL",
+        name
+    )
+    .fetch_optional(conn)
+    .await
+    .is_ok_and(|public| public == Some(true))
+}
+
+pub async fn user_exists(conn: &mut SqliteConnection, email: &str) -> Result<bool, sqlx::Error> {
+    Ok(user_id(conn, email).await?.is_some())
+}
+
+/// The id of the user with this email
+pub async fn user_id(
+    conn: &mut SqliteConnection,
+    email: &str,
+) -> Result<Option<String>, sqlx::Error> {
+    sqlx::query_scalar!("SELECT id FROM users WHERE email = ?", email)
+        .fetch_optional(conn)
+        .await
+}
+
+/// Sum of the sizes recorded for a repository's blobs, `None` if there is
+/// no such repository.
+pub async fn repository_disk_usage(
+    conn: &mut SqliteConnection,
+    name: &str,
+) -> Result<Option<u64>, sqlx::Error> {
+    let row = query!(
+        "SELECT (SELECT COALESCE(SUM(size), 0) FROM blobs WHERE blobs.repository_id = repositories.id) as \"usage!: i64\" FROM repositories WHERE name = ? AND deleted_at IS NULL",
+        name
+    )
+    .fetch_optional(conn)
+    .await?;
+    Ok(row.map(|row| row.usage.max(0) as u64))
+}
+
+/// Caps the bytes a repository may store, `None` lifts the cap. Returns
+/// false if there is no such repository.
+pub async fn set_repository_quota(
+    conn: &mut SqliteConnection,
+    name: &str,
+    max_bytes: Option<i64>,
+) -> Result<bool, sqlx::Error> {
+    let result = query!(
+        "UPDATE repositories SET max_bytes = ? WHERE name = ? AND deleted_at IS NULL",
+        max_bytes,
+        name
+    )
+    .execute(conn)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Upload sessions idle longer than this no longer count as active.
+pub const UPLOAD_SESSION_EXPIRY_SECS: u64 = 3600;
+
+/// Number of open upload sessions for a repository, ignoring expired ones.
+pub async fn active_upload_count(
+    conn: &mut SqliteConnection,
+    name: &str,
+) -> Result<i64, sqlx::Error> {
+    let cutoff = format!("-{} seconds", UPLOAD_SESSION_EXPIRY_SECS);
+    let row = query!(
+        "SELECT COUNT(*) as count FROM uploads WHERE repository_id = (SELECT id FROM repositories WHERE name = ? AND deleted_at IS NULL) AND created_at > datetime('now', ?)",
+        name,
+        cutoff
+    )
+    .fetch_one(conn)
+    .await?;
+    Ok(row.count)
+}
+
+/// Records the digest a client declared when opening an upload session
+pub async fn set_upload_digest(
+    conn: &mut SqliteConnection,
+    session_id: &str,
+    digest: &str,
+) -> Result<(), sqlx::Error> {
+    query!(
+        "UPDATE uploads SET digest = ? WHERE uuid = ?",
+        digest,
+        session_id
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+/// The newest unexpired upload session of a repository declared for `digest`,
+/// along with its current offset
+pub async fn find_upload_by_digest(
+    conn: &mut SqliteConnection,
+    name: &str,
+    digest: &str,
+) -> Result<Option<(String, i64)>, sqlx::Error> {
+    let cutoff = format!("-{} seconds", UPLOAD_SESSION_EXPIRY_SECS);
+    let row = query!(
+        "SELECT uuid, current_chunk FROM uploads WHERE repository_id = (SELECT id FROM repositories WHERE name = ? AND deleted_at IS NULL) AND digest = ? AND corrupt = FALSE AND created_at > datetime('now', ?) ORDER BY created_at DESC LIMIT 1",
+        name,
+        digest,
+        cutoff
+    )
+    .fetch_optional(conn)
+    .await?;
+    Ok(row.map(|row| (row.uuid, row.current_chunk)))
+}
+
 impl DbConn {
+    /// Deletes a manifest by tag or digest, returning the paths of the
+    /// deleted manifests to remove from storage, leaving out those another
+    /// manifest row still refers to. With `cascade_referrers`,
+    /// manifests whose `subject` names it are deleted too, recursively.
     pub async fn delete_manifest(
         &mut self,
         name: &str,
         reference: &str,
-    ) -> Result<String, sqlx::Error> {
+        cascade_referrers: bool,
+    ) -> Result<Vec<String>, sqlx::Error> {
         let mut tx = self.begin().await?;
         match sqlx::query!(
-            "SELECT m.file_path, m.id, m.digest FROM manifests m
+            "SELECT m.file_path, m.id as \"id!\", m.digest FROM manifests m
         JOIN repositories r ON m.repository_id = r.id
         LEFT JOIN tags t ON m.id = t.manifest_id
-        WHERE (m.digest = $1 OR t.tag = $1) AND r.name = $2",
+        WHERE (m.digest = $1 OR t.tag = $1) AND r.name = $2 AND r.deleted_at IS NULL",
             reference,
             name
         )
@@ -226,47 +762,46 @@ impl DbConn {
         {
             Ok(found) => {
                 info!("found manifest with ref: {}", reference);
-                sqlx::query!(
-                    "DELETE FROM manifest_layers WHERE manifest_id = ?",
-                    found.id
-                )
-                .execute(&mut *tx)
-                .await?;
-                sqlx::query!("DELETE FROM tags WHERE manifest_id = ?", found.id)
-                    .execute(&mut *tx)
+                remove_manifest(&mut tx, found.id).await?;
+                let mut deleted = vec![found.file_path];
+                let mut subjects = Vec::new();
+                if cascade_referrers {
+                    subjects.push(found.digest);
+                }
+                while let Some(subject) = subjects.pop() {
+                    let referrers = sqlx::query!(
+                        "SELECT m.id as \"id!\", m.digest, m.file_path FROM manifests m
+                        JOIN repositories r ON m.repository_id = r.id
+                        WHERE m.subject_digest = ? AND r.name = ? AND r.deleted_at IS NULL",
+                        subject,
+                        name
+                    )
+                    .fetch_all(&mut *tx)
                     .await?;
-                if let Ok(layers) = sqlx::query!(
-                    "SELECT digest FROM manifest_layers WHERE manifest_id = ?",
-                    found.id
-                )
-                .fetch_all(&mut *tx)
-                .await
-                {
-                    for layer in layers {
-                        if let Err(err) = sqlx::query!(
-                            "UPDATE blobs SET ref_count = ref_count - 1 WHERE digest = ?",
-                            layer.digest
-                        )
-                        .execute(&mut *tx)
-                        .await
-                        {
-                            error!("unable to update blob ref_count: {}", err);
-                        }
-                        if let Err(err) = sqlx::query!(
-                            "DELETE FROM blobs WHERE digest = ? AND ref_count <= 0",
-                            layer.digest
-                        )
-                        .execute(&mut *tx)
-                        .await
-                        {
-                            error!("unable to delete blob: {}", err);
-                        }
+                    for referrer in referrers {
+                        info!("deleting referrer {} of {}", referrer.digest, subject);
+                        remove_manifest(&mut tx, referrer.id).await?;
+                        deleted.push(referrer.file_path);
+                        subjects.push(referrer.digest);
                     }
                 }
-                sqlx::query!("DELETE FROM manifests WHERE id = ?", found.id)
-                    .execute(&mut *tx)
-                    .await?;
-                Ok(found.file_path)
+                // promoted copies, and soft-deleted repositories whose name was
+                // reused, share the stored manifest, which stays until its last row goes
+                let mut unshared = Vec::with_capacity(deleted.len());
+                for file_path in deleted {
+                    let shared = sqlx::query_scalar!(
+                        "SELECT COUNT(*) FROM manifests WHERE file_path = ?",
+                        file_path
+                    )
+                    .fetch_one(&mut *tx)
+                    .await?
+                        > 0;
+                    if !shared {
+                        unshared.push(file_path);
+                    }
+                }
+                tx.commit().await?;
+                Ok(unshared)
             }
             Err(err) => {
                 error!("unable to find manifest with reference: {}", reference);
@@ -276,6 +811,38 @@ impl DbConn {
     }
 }
 
+/// Deletes a manifest's row along with its tags and layers, releasing its
+/// reference to each layer. Blobs left unreferenced stay in place until
+/// garbage collection removes them.
+async fn remove_manifest(conn: &mut SqliteConnection, id: i64) -> Result<(), sqlx::Error> {
+    let layers = sqlx::query!(
+        "SELECT digest, repository_id FROM manifest_layers WHERE manifest_id = ?",
+        id
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    // one release per listed layer, mirroring how the push counted them
+    for layer in layers {
+        sqlx::query!(
+            "UPDATE blobs SET ref_count = MAX(ref_count - 1, 0) WHERE digest = ? AND repository_id = ?",
+            layer.digest,
+            layer.repository_id
+        )
+        .execute(&mut *conn)
+        .await?;
+    }
+    sqlx::query!("DELETE FROM manifest_layers WHERE manifest_id = ?", id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query!("DELETE FROM tags WHERE manifest_id = ?", id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query!("DELETE FROM manifests WHERE id = ?", id)
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
 pub async fn init_testing_db() -> sqlx::SqlitePool {
     let pool = SqlitePoolOptions::new()
         .max_connections(8)
@@ -292,3 +859,87 @@ pub async fn init_testing_db() -> sqlx::SqlitePool {
     .expect("unable to migrate db");
     pool
 }
+
+#[derive(Debug, serde::Serialize)]
+pub struct OptimizeResult {
+    pub message: String,
+    pub size_before: Option<u64>,
+    pub size_after: Option<u64>,
+}
+
+/// size of the database file plus its write-ahead log, if any
+async fn database_size(path: &Path) -> Option<u64> {
+    let db = tokio::fs::metadata(path)
+        .await
+        .ok()
+        .filter(|m| m.is_file())?;
+    let mut wal = path.as_os_str().to_owned();
+    wal.push("-wal");
+    let wal = tokio::fs::metadata(&wal)
+        .await
+        .map(|m| m.len())
+        .unwrap_or(0);
+    Some(db.len() + wal)
+}
+
+/// POST /admin/optimize
+/// admin only: checkpoints the write-ahead log, refreshes the query planner
+/// statistics and rebuilds the database file to reclaim free pages
+pub async fn optimize_database(
+    State(pool): State<SqlitePool>,
+    Extension(auth): Extension<Auth>,
+) -> Response {
+    if !auth.is_admin() {
+        return ErrorResponse::from_code(&Code::Denied, String::from("admin access required"))
+            .into_response();
+    }
+    let Ok(_maintenance) = MAINTENANCE.try_lock() else {
+        return (
+            StatusCode::CONFLICT,
+            "another maintenance task is running, try again later",
+        )
+            .into_response();
+    };
+    let options = pool.connect_options();
+    let path = options.get_filename();
+    let Some(size_before) = database_size(path).await else {
+        return Json(OptimizeResult {
+            message: String::from("database is not file backed, nothing to optimize"),
+            size_before: None,
+            size_after: None,
+        })
+        .into_response();
+    };
+    let mut conn = match pool.acquire().await {
+        Ok(conn) => conn,
+        Err(err) => {
+            error!("unable to acquire connection to optimize database: {}", err);
+            return internal_error(err).into_response();
+        }
+    };
+    // VACUUM cannot run inside a transaction, so each statement is issued on
+    // its own. Checkpointing first lets VACUUM see every committed page, and
+    // truncating afterwards stops the WAL holding a copy of the whole database.
+    for statement in [
+        "PRAGMA wal_checkpoint(TRUNCATE)",
+        "PRAGMA optimize",
+        "VACUUM",
+        "PRAGMA wal_checkpoint(TRUNCATE)",
+    ] {
+        if let Err(err) = sqlx::query(statement).execute(&mut *conn).await {
+            error!("unable to optimize database ({}): {}", statement, err);
+            return internal_error(err).into_response();
+        }
+    }
+    let size_after = database_size(path).await;
+    info!(
+        "optimized database: {} -> {:?} bytes",
+        size_before, size_after
+    );
+    Json(OptimizeResult {
+        message: String::from("database optimized"),
+        size_before: Some(size_before),
+        size_after,
+    })
+    .into_response()
+}
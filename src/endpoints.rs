@@ -1,33 +1,48 @@
 use crate::{
     auth::{
-        auth_middleware, auth_token_get, check_scope_middleware, get_auth_clients, login_user,
-        register_user, Auth,
+        auth_middleware, auth_token_get, auth_token_post, check_scope_middleware,
+        delete_auth_client, get_auth_clients, login_user, register_user, split_v2_path,
+        validate_auth_header, Auth,
     },
     blobs::{
-        check_blob, delete_blob, get_blob, handle_upload_blob, handle_upload_session_chunk,
-        put_upload_blob, put_upload_session_blob,
+        authorize_upload, cancel_upload_session, check_blob, delete_blob, find_upload_session,
+        get_blob, get_blob_references, get_upload_session, handle_upload_blob,
+        handle_upload_session_chunk, put_upload_blob, put_upload_session_blob,
     },
+    codes::{Code, ErrorResponse},
+    config::Config,
     content_discovery::{
-        create_repository, delete_repository, get_tags_list, get_v2, list_repositories,
+        create_repository, delete_repository, export_repository, get_catalog, get_manifest_closure,
+        get_manifest_details, get_manifest_tags, get_referrers, get_tags_list, get_v2,
+        list_my_repositories, list_repositories, restore_repository,
     },
-    manifests::{delete_manifest, get_manifest, push_manifest},
+    database::optimize_database,
+    log_stream::stream_logs,
+    manifests::{delete_manifest, get_manifest, promote_tags, push_manifest},
+    rate_limit::{rate_limit, rate_limit_subject, RateLimiter},
     storage_driver::Backend,
-    users::{delete_user, generate_token, get_users},
+    ui::{serve_ui, serve_ui_index},
+    users::{create_user, delete_user, generate_token, get_users},
+    util::{
+        decode_repository_name, encode_repository_name, is_valid_reference,
+        is_valid_repository_name, DigestHasher,
+    },
 };
 use axum::{
-    extract::{Extension, Host},
+    extract::{Extension, Host, State},
     handler::HandlerWithoutStateExt,
     http::{StatusCode, Uri},
-    middleware::from_fn,
-    response::Redirect,
+    middleware::{from_fn, map_request_with_state, Next},
+    response::{IntoResponse, Redirect, Response},
     routing::{delete, get, head, patch, post, put},
     BoxError, Router,
 };
-use http::Request;
+use http::{header::USER_AGENT, Method, Request};
 use sqlx::SqlitePool;
-use std::{net::SocketAddr, sync::Arc};
+use std::{net::SocketAddr, sync::Arc, time::Instant};
 use tower::ServiceBuilder;
 use tower_http::trace::TraceLayer;
+use tracing::{debug, warn};
 
 #[derive(Clone, Copy)]
 pub struct Ports(pub u16, pub u16);
@@ -95,7 +110,7 @@ impl Endpoint {
             Endpoint::GetV2 => get(get_v2),
             Endpoint::HeadBlobs => head(check_blob),
             Endpoint::GetBlobs => get(get_blob),
-            Endpoint::HeadManifests => head(check_blob),
+            Endpoint::HeadManifests => head(get_manifest),
             Endpoint::GetManifests => get(get_manifest),
             Endpoint::PostBlobsUploads => post(handle_upload_blob),
             Endpoint::PostBlobsUploadsWithDigest => post(handle_upload_blob),
@@ -108,33 +123,213 @@ impl Endpoint {
             Endpoint::DeleteManifests => delete(delete_manifest),
             Endpoint::DeleteBlobs => delete(delete_blob),
             Endpoint::PostBlobsUploadsMount => post(handle_upload_blob),
-            Endpoint::GetReferrers => get(get_v2),
-            Endpoint::GetReferrersWithArtifactType => get(get_v2),
-            Endpoint::GetBlobsUploads => get(get_v2),
+            Endpoint::GetReferrers => get(get_referrers),
+            Endpoint::GetReferrersWithArtifactType => get(get_referrers),
+            Endpoint::GetBlobsUploads => get(get_upload_session),
         }
     }
 }
 
-pub fn register_routes(pool: SqlitePool, storage: Arc<Backend>) -> Router {
-    Router::new()
+/// GET /healthz
+/// liveness probe, succeeds as long as the server is accepting requests
+pub async fn healthz() -> impl IntoResponse {
+    (StatusCode::OK, "ok")
+}
+
+/// GET /readyz
+/// readiness probe, fails while the storage backend is not writable
+pub async fn readyz(Extension(storage): Extension<Arc<Backend>>) -> impl IntoResponse {
+    if storage.is_healthy() {
+        (StatusCode::OK, "ready")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "storage unavailable")
+    }
+}
+
+/// Fallback for paths no route matches, so clients get a structured
+/// error body instead of an empty 404
+async fn unknown_route(method: Method, uri: Uri) -> Response {
+    (
+        StatusCode::NOT_FOUND,
+        ErrorResponse::from_code(
+            &Code::Unsupported,
+            format!("no route for {} {}", method, uri.path()),
+        ),
+    )
+        .into_response()
+}
+
+/// Under `--default-namespace`, points `/v2/<name>/...` at the qualified
+/// repository before routing. The name's slashes are percent-encoded so it
+/// still matches the single `:name` segment of the routes.
+async fn qualify_repository_path(
+    State(config): State<Arc<Config>>,
+    mut req: axum::extract::Request,
+) -> axum::extract::Request {
+    let rewritten = split_v2_path(req.uri().path()).and_then(|(name, rest)| {
+        let qualified =
+            encode_repository_name(&config.qualify_repository(&decode_repository_name(name)));
+        (qualified != name).then(|| match req.uri().query() {
+            Some(query) => format!("/v2/{qualified}{rest}?{query}"),
+            None => format!("/v2/{qualified}{rest}"),
+        })
+    });
+    if let Some(uri) = rewritten.and_then(|uri| uri.parse().ok()) {
+        *req.uri_mut() = uri;
+    }
+    req
+}
+
+/// Refuses requests that would write to storage while the last probe
+/// found it unwritable, rather than letting them fail halfway with a 500
+async fn require_writable_storage(
+    Extension(storage): Extension<Arc<Backend>>,
+    req: axum::extract::Request,
+    next: Next,
+) -> Response {
+    if writes_to_storage(&req) && !storage.is_healthy() {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "storage is currently unavailable, try again later",
+        )
+            .into_response();
+    }
+    next.run(req).await
+}
+
+/// Blob and manifest uploads and promotions, everything else only touches
+/// the database, like token refreshes and repository restores
+fn writes_to_storage(req: &axum::extract::Request) -> bool {
+    if !matches!(*req.method(), Method::PUT | Method::POST | Method::PATCH) {
+        return false;
+    }
+    let path = req.uri().path();
+    match split_v2_path(path) {
+        Some((_, rest)) => rest.starts_with("/blobs/") || rest.starts_with("/manifests/"),
+        None => path
+            .strip_prefix("/repositories/")
+            .is_some_and(|rest| rest.ends_with("/promote")),
+    }
+}
+
+/// Refuses clients ruled out by `--user-agent-allow`/`--user-agent-deny`,
+/// before any credentials are looked at
+async fn filter_user_agent(
+    Extension(config): Extension<Arc<Config>>,
+    req: axum::extract::Request,
+    next: Next,
+) -> Response {
+    let agent = req
+        .headers()
+        .get(USER_AGENT)
+        .and_then(|agent| agent.to_str().ok());
+    if !config.allows_user_agent(agent) {
+        debug!("refusing user agent {:?}", agent);
+        return ErrorResponse::from_code(&Code::Denied, "user agent is not allowed")
+            .into_response();
+    }
+    next.run(req).await
+}
+
+/// Rejects `/v2/` paths whose name or reference breaks the spec grammar
+/// before any handler turns them into database keys or storage paths
+async fn validate_v2_path(req: axum::extract::Request, next: Next) -> Response {
+    let Some((name, rest)) = split_v2_path(req.uri().path()) else {
+        return next.run(req).await;
+    };
+    if !is_valid_repository_name(&decode_repository_name(name)) {
+        return ErrorResponse::from_code(&Code::NameInvalid, "invalid repository name")
+            .into_response();
+    }
+    let segment = |prefix: &str| {
+        rest.strip_prefix(prefix)
+            .and_then(|rest| rest.split('/').next())
+            .map(|segment| segment.replace("%3A", ":").replace("%3a", ":"))
+    };
+    if let Some(reference) = segment("/manifests/") {
+        if !is_valid_reference(&reference) {
+            return ErrorResponse::from_code(&Code::ManifestInvalid, "invalid manifest reference")
+                .into_response();
+        }
+    }
+    // `PUT /v2/<name>/blobs/<id>` carries an upload session rather than a digest
+    let addresses_blob = matches!(*req.method(), Method::GET | Method::HEAD | Method::DELETE);
+    if let Some(digest) = segment("/blobs/").filter(|_| addresses_blob) {
+        if digest != "uploads" && DigestHasher::for_digest(&digest).is_none() {
+            return ErrorResponse::from_code(&Code::DigestInvalid, "invalid blob digest")
+                .into_response();
+        }
+    }
+    next.run(req).await
+}
+
+/// Warns about requests slower than `--slow-log-ms`
+async fn log_slow_requests(
+    Extension(config): Extension<Arc<Config>>,
+    req: axum::extract::Request,
+    next: Next,
+) -> Response {
+    let Some(threshold) = config.slow_threshold() else {
+        return next.run(req).await;
+    };
+    let (method, uri) = (req.method().clone(), req.uri().clone());
+    let started = Instant::now();
+    let response = next.run(req).await;
+    let elapsed = started.elapsed();
+    if elapsed > threshold {
+        warn!(
+            method = %method,
+            uri = %uri,
+            latency_ms = elapsed.as_millis() as u64,
+            "slow request"
+        );
+    }
+    response
+}
+
+pub fn register_routes(pool: SqlitePool, storage: Arc<Backend>, config: Arc<Config>) -> Router {
+    let limiter = Arc::new(RateLimiter::default());
+    let routes = Router::new()
         .route("/auth/login", post(login_user))
-        .route("/auth/token", get(auth_token_get))
+        .route("/auth/token", get(auth_token_get).post(auth_token_post))
+        .route("/v2/auth/token", get(auth_token_get).post(auth_token_post))
         .route("/auth/register", post(register_user))
         .route("/auth/clients", get(get_auth_clients))
+        .route("/auth/clients/:client_id", delete(delete_auth_client))
         .route("/repositories", get(list_repositories))
+        .route("/repositories/mine", get(list_my_repositories))
         .route("/repositories/:name/:public", post(create_repository))
         .route("/repositories/:name", delete(delete_repository))
-        .route("/users", get(get_users))
+        .route("/repositories/:name/restore", post(restore_repository))
+        .route("/repositories/:name/export", get(export_repository))
+        .route("/repositories/:name/promote", post(promote_tags))
+        .route(
+            "/repositories/:name/manifests/:reference",
+            get(get_manifest_details),
+        )
+        .route(
+            "/repositories/:name/manifests/:reference/closure",
+            get(get_manifest_closure),
+        )
+        .route("/admin/blobs/:digest", get(get_blob_references))
+        .route("/admin/optimize", post(optimize_database))
+        .route("/admin/logs", get(stream_logs))
+        .route("/users", get(get_users).post(create_user))
         .route("/users/:email", delete(delete_user))
         .route("/users/:email/tokens", post(generate_token))
         .route("/v2/", Endpoint::GetV2.to_handler())
+        .route("/v2/_catalog", get(get_catalog))
         .route(
             "/v2/:name/blobs/:digest",
             Endpoint::PutBlobsNoSession.to_handler(),
         )
         .route("/v2/:name/blobs/:digest", Endpoint::GetBlobs.to_handler())
         .route("/v2/:name/blobs/:digest", Endpoint::HeadBlobs.to_handler())
-        .route("/v2/:name/blobs/uploads/", post(handle_upload_blob))
+        .route(
+            "/v2/:name/blobs/uploads/",
+            post(handle_upload_blob).get(find_upload_session),
+        )
+        .route("/v2/:name/blobs/uploads/authorize", post(authorize_upload))
         .route(
             "/v2/:name/blobs/uploads/:session_id",
             Endpoint::PutBlobsUploadsWithDigest.to_handler(),
@@ -143,6 +338,14 @@ pub fn register_routes(pool: SqlitePool, storage: Arc<Backend>) -> Router {
             "/v2/:name/blobs/uploads/:session_id",
             Endpoint::PatchBlobsUploads.to_handler(),
         )
+        .route(
+            "/v2/:name/blobs/uploads/:session_id",
+            delete(cancel_upload_session),
+        )
+        .route(
+            "/v2/:name/blobs/uploads/:session_id",
+            Endpoint::GetBlobsUploads.to_handler(),
+        )
         .route(
             "/v2/:name/blobs/:digest",
             Endpoint::DeleteBlobs.to_handler(),
@@ -152,6 +355,10 @@ pub fn register_routes(pool: SqlitePool, storage: Arc<Backend>) -> Router {
             "/v2/:name/manifests/:reference",
             Endpoint::GetManifests.to_handler(),
         )
+        .route(
+            "/v2/:name/manifests/:reference",
+            Endpoint::HeadManifests.to_handler(),
+        )
         .route(
             "/v2/:name/manifests/:reference",
             Endpoint::PutManifests.to_handler(),
@@ -160,12 +367,47 @@ pub fn register_routes(pool: SqlitePool, storage: Arc<Backend>) -> Router {
             "/v2/:name/manifests/:reference",
             Endpoint::DeleteManifests.to_handler(),
         )
+        .route(
+            "/v2/:name/manifests/:reference/tags",
+            get(get_manifest_tags),
+        )
+        .route(
+            "/v2/:name/referrers/:digest",
+            Endpoint::GetReferrers.to_handler(),
+        )
         .layer(from_fn(check_scope_middleware))
+        // after authentication, so clients are told apart by their token
+        .layer(axum::middleware::from_fn_with_state(
+            Arc::clone(&limiter),
+            rate_limit_subject,
+        ))
         .layer(axum::middleware::from_fn_with_state(
             pool.clone(),
             auth_middleware,
         ))
+        // and before it, so failed logins are limited too
+        .layer(axum::middleware::from_fn_with_state(limiter, rate_limit))
+        .layer(from_fn(validate_auth_header))
+        .layer(from_fn(require_writable_storage))
+        .layer(from_fn(filter_user_agent))
+        .layer(from_fn(validate_v2_path))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz));
+    // the dashboard only calls the JSON endpoints, so its assets need no auth
+    let routes = if config.enable_ui {
+        routes
+            .route("/", get(|| async { Redirect::permanent("/ui/") }))
+            .route("/ui", get(|| async { Redirect::permanent("/ui/") }))
+            .route("/ui/", get(serve_ui_index))
+            .route("/ui/*path", get(serve_ui))
+    } else {
+        routes
+    };
+    let routes = routes
+        .fallback(unknown_route)
+        .layer(from_fn(log_slow_requests))
         .layer(Extension(storage))
+        .layer(Extension(Arc::clone(&config)))
         .layer(
             ServiceBuilder::new().layer(TraceLayer::new_for_http().make_span_with(
                 |request: &Request<_>| {
@@ -178,5 +420,12 @@ pub fn register_routes(pool: SqlitePool, storage: Arc<Backend>) -> Router {
             )),
         )
         .layer(Extension(axum::middleware::from_extractor::<Auth>()))
-        .with_state(pool)
+        .with_state(pool);
+    if config.default_namespace.is_none() {
+        return routes;
+    }
+    // paths have to be rewritten before they are routed
+    Router::new()
+        .fallback_service(routes)
+        .layer(map_request_with_state(config, qualify_repository_path))
 }
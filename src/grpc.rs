@@ -0,0 +1,407 @@
+//! gRPC interface to the registry, for internal clients that would rather
+//! stream blobs than speak the OCI HTTP API. Served on `--grpc-port` and
+//! authenticated with the same bearer tokens, passed as `authorization`
+//! metadata. The service itself is generated by `build.rs`.
+
+// tonic's handlers return `Status` directly, boxing it here would only add conversions
+#![allow(clippy::result_large_err)]
+
+use crate::{
+    auth::check_auth_headers,
+    config::{is_manifest_media_type, Config},
+    manifests::write_manifest,
+    storage::ensure_repository,
+    storage_driver::{Backend, StorageError},
+    util::{is_valid_reference, is_valid_repository_name, DigestHasher},
+    Action,
+};
+use axum::body::Body;
+use bytes::Bytes;
+use futures::{Stream, StreamExt, TryStreamExt};
+use sqlx::{pool::PoolConnection, Sqlite, SqlitePool};
+use std::{
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tonic::{metadata::MetadataMap, Request, Response, Status, Streaming};
+use tracing::{error, info};
+
+include!(concat!(env!("OUT_DIR"), "/floundr.Registry.rs"));
+
+pub use registry_client::RegistryClient;
+pub use registry_server::RegistryServer;
+
+/// size of the chunks `PullBlob` streams a blob back in
+pub const PULL_CHUNK_SIZE: usize = 64 * 1024;
+
+/// A piece of a blob. When pushing, the first chunk names the repository
+/// and the digest of the whole blob; later chunks only carry data.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct BlobChunk {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub digest: String,
+    #[prost(bytes = "bytes", tag = "3")]
+    pub data: Bytes,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PushBlobResponse {
+    #[prost(string, tag = "1")]
+    pub digest: String,
+    #[prost(uint64, tag = "2")]
+    pub size: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PullBlobRequest {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub digest: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PutManifestRequest {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub reference: String,
+    /// falls back to `--default-media-type` when empty
+    #[prost(string, tag = "3")]
+    pub media_type: String,
+    #[prost(bytes = "bytes", tag = "4")]
+    pub content: Bytes,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PutManifestResponse {
+    #[prost(string, tag = "1")]
+    pub digest: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetManifestRequest {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    /// tag or digest
+    #[prost(string, tag = "2")]
+    pub reference: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Manifest {
+    #[prost(string, tag = "1")]
+    pub digest: String,
+    #[prost(string, tag = "2")]
+    pub media_type: String,
+    #[prost(bytes = "bytes", tag = "3")]
+    pub content: Bytes,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ListTagsRequest {
+    #[prost(string, tag = "1")]
+    pub name: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TagList {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, repeated, tag = "2")]
+    pub tags: Vec<String>,
+}
+
+pub struct RegistryService {
+    pool: SqlitePool,
+    storage: Arc<Backend>,
+    config: Arc<Config>,
+}
+
+impl RegistryService {
+    pub fn new(pool: SqlitePool, storage: Arc<Backend>, config: Arc<Config>) -> Self {
+        Self {
+            pool,
+            storage,
+            config,
+        }
+    }
+
+    async fn conn(&self) -> Result<PoolConnection<Sqlite>, Status> {
+        self.pool.acquire().await.map_err(internal)
+    }
+
+    /// Checks the caller's credentials allow `action` on the repository,
+    /// following the same rules as the HTTP scope middleware
+    async fn authorize(
+        &self,
+        metadata: &MetadataMap,
+        name: &str,
+        action: Action,
+    ) -> Result<(), Status> {
+        let mut conn = self.conn().await?;
+        let auth = check_auth_headers(&metadata.clone().into_headers(), &mut conn, &self.config)
+            .await
+            .map_err(Status::unauthenticated)?;
+        if !auth.can(name, action) {
+            info!("grpc caller lacks {} on {}", action, name);
+            return Err(Status::permission_denied(format!(
+                "{} access to {} denied",
+                action, name
+            )));
+        }
+        Ok(())
+    }
+
+    /// Validates a repository name from a request and resolves it under
+    /// `--default-namespace`, like the HTTP routes do
+    fn repository(&self, name: &str) -> Result<String, Status> {
+        if !is_valid_repository_name(name) {
+            return Err(Status::invalid_argument("invalid repository name"));
+        }
+        Ok(self.config.qualify_repository(name).into_owned())
+    }
+
+    fn require_writable(&self) -> Result<(), Status> {
+        if self.storage.is_healthy() {
+            Ok(())
+        } else {
+            Err(Status::unavailable(
+                "storage is currently unavailable, try again later",
+            ))
+        }
+    }
+}
+
+/// Digests end up in file paths, so they're held to the same grammar as
+/// over HTTP
+fn require_digest(digest: &str) -> Result<(), Status> {
+    if DigestHasher::for_digest(digest).is_none() {
+        return Err(Status::invalid_argument("invalid digest"));
+    }
+    Ok(())
+}
+
+fn internal(err: impl std::fmt::Display) -> Status {
+    error!("grpc request failed: {}", err);
+    Status::internal(err.to_string())
+}
+
+fn storage_status(err: StorageError) -> Status {
+    match err {
+        StorageError::DigestError => Status::invalid_argument("digest mismatch"),
+        StorageError::SqlxError(sqlx::Error::RowNotFound) => Status::not_found("not found"),
+        StorageError::QuotaExceeded(max) => {
+            Status::resource_exhausted(format!("repository quota of {max} bytes exceeded"))
+        }
+        StorageError::BlobUnknown(digest) => Status::not_found(format!("blob unknown: {digest}")),
+        StorageError::UploadCorrupt => {
+            Status::data_loss("upload session is corrupt, start a new upload")
+        }
+        StorageError::IoError(ref e) if e.kind() == std::io::ErrorKind::InvalidData => {
+            Status::invalid_argument(e.to_string())
+        }
+        err => internal(err),
+    }
+}
+
+type BlobStream = Pin<Box<dyn Stream<Item = Result<BlobChunk, Status>> + Send + 'static>>;
+
+#[tonic::async_trait]
+impl registry_server::Registry for RegistryService {
+    async fn push_blob(
+        &self,
+        request: Request<Streaming<BlobChunk>>,
+    ) -> Result<Response<PushBlobResponse>, Status> {
+        self.require_writable()?;
+        let metadata = request.metadata().clone();
+        let mut chunks = request.into_inner();
+        let first = chunks
+            .message()
+            .await?
+            .ok_or_else(|| Status::invalid_argument("empty blob upload"))?;
+        let name = self.repository(&first.name)?;
+        require_digest(&first.digest)?;
+        self.authorize(&metadata, &name, Action::Push).await?;
+
+        let mut conn = self.conn().await?;
+        ensure_repository(&mut conn, &name)
+            .await
+            .map_err(internal)?;
+        let size = Arc::new(AtomicU64::new(0));
+        let counted = Arc::clone(&size);
+        let data = futures::stream::once(futures::future::ok::<_, Status>(first.data))
+            .chain(chunks.map_ok(|chunk| chunk.data))
+            .inspect_ok(move |data| {
+                counted.fetch_add(data.len() as u64, Ordering::Relaxed);
+            });
+        let digest = self
+            .storage
+            .write_blob_without_session_id(
+                &mut conn,
+                &name,
+                &first.digest,
+                Body::from_stream(data).into_data_stream(),
+            )
+            .await
+            .map_err(storage_status)?;
+        info!("blob {} pushed to {} over grpc", digest, name);
+        Ok(Response::new(PushBlobResponse {
+            digest,
+            size: size.load(Ordering::Relaxed),
+        }))
+    }
+
+    type PullBlobStream = BlobStream;
+
+    async fn pull_blob(
+        &self,
+        request: Request<PullBlobRequest>,
+    ) -> Result<Response<Self::PullBlobStream>, Status> {
+        let PullBlobRequest { name, digest } = request.get_ref().clone();
+        let name = self.repository(&name)?;
+        require_digest(&digest)?;
+        self.authorize(request.metadata(), &name, Action::Pull)
+            .await?;
+        let mut conn = self.conn().await?;
+        let blob = self
+            .storage
+            .read_blob(&mut conn, &name, &digest, None)
+            .await
+            .map_err(storage_status)?;
+        let chunks = blob.stream.flat_map(|data| {
+            let chunks: Vec<Result<BlobChunk, Status>> = match data {
+                Ok(data) => (0..data.len())
+                    .step_by(PULL_CHUNK_SIZE)
+                    .map(|start| {
+                        let end = (start + PULL_CHUNK_SIZE).min(data.len());
+                        Ok(BlobChunk {
+                            data: data.slice(start..end),
+                            ..Default::default()
+                        })
+                    })
+                    .collect(),
+                Err(err) => vec![Err(internal(err))],
+            };
+            futures::stream::iter(chunks)
+        });
+        Ok(Response::new(Box::pin(chunks)))
+    }
+
+    async fn put_manifest(
+        &self,
+        request: Request<PutManifestRequest>,
+    ) -> Result<Response<PutManifestResponse>, Status> {
+        self.require_writable()?;
+        let metadata = request.metadata().clone();
+        let req = request.into_inner();
+        let name = self.repository(&req.name)?;
+        if !is_valid_reference(&req.reference) {
+            return Err(Status::invalid_argument("invalid manifest reference"));
+        }
+        self.authorize(&metadata, &name, Action::Push).await?;
+        let media_type = match req.media_type.trim() {
+            "" => self.config.default_media_type.clone(),
+            media_type => media_type.to_lowercase(),
+        };
+        if !is_manifest_media_type(&media_type) {
+            return Err(Status::invalid_argument("unsupported manifest media type"));
+        }
+        if req.content.len() as u64 > self.config.max_manifest_size {
+            return Err(Status::resource_exhausted(format!(
+                "manifest exceeds the {} byte limit",
+                self.config.max_manifest_size
+            )));
+        }
+        let mut conn = self.conn().await?;
+        ensure_repository(&mut conn, &name)
+            .await
+            .map_err(internal)?;
+        let digest = write_manifest(
+            &self.storage,
+            &mut conn,
+            &self.config,
+            &name,
+            &req.reference,
+            &media_type,
+            &req.content,
+        )
+        .await
+        .map_err(storage_status)?;
+        Ok(Response::new(PutManifestResponse { digest }))
+    }
+
+    async fn get_manifest(
+        &self,
+        request: Request<GetManifestRequest>,
+    ) -> Result<Response<Manifest>, Status> {
+        let GetManifestRequest { name, reference } = request.get_ref().clone();
+        let name = self.repository(&name)?;
+        if !is_valid_reference(&reference) {
+            return Err(Status::invalid_argument("invalid manifest reference"));
+        }
+        self.authorize(request.metadata(), &name, Action::Pull)
+            .await?;
+        let mut conn = self.conn().await?;
+        // untagged manifests are still pulled by digest
+        let record = sqlx::query!("SELECT file_path, digest, media_type FROM manifests LEFT JOIN tags on tags.manifest_id = manifests.id WHERE manifests.repository_id = (SELECT id FROM repositories WHERE name = ? AND deleted_at IS NULL) AND (digest = $2 OR tags.tag = $2)", name, reference)
+            .fetch_optional(&mut *conn)
+            .await
+            .map_err(internal)?
+            .ok_or_else(|| Status::not_found("unable to find manifest for image"))?;
+        let content = self
+            .storage
+            .read_manifest(&record.file_path)
+            .await
+            .map_err(storage_status)?;
+        Ok(Response::new(Manifest {
+            digest: record.digest,
+            media_type: record.media_type,
+            content: content.into(),
+        }))
+    }
+
+    async fn list_tags(
+        &self,
+        request: Request<ListTagsRequest>,
+    ) -> Result<Response<TagList>, Status> {
+        let name = self.repository(&request.get_ref().name)?;
+        self.authorize(request.metadata(), &name, Action::Pull)
+            .await?;
+        let mut conn = self.conn().await?;
+        let tags = sqlx::query!(
+            "SELECT tags.tag FROM tags JOIN repositories r ON tags.repository_id = r.id
+             WHERE r.name = ? AND r.deleted_at IS NULL ORDER BY tags.tag COLLATE BINARY",
+            name
+        )
+        .fetch_all(&mut *conn)
+        .await
+        .map_err(internal)?
+        .into_iter()
+        .map(|row| row.tag)
+        .collect();
+        Ok(Response::new(TagList { name, tags }))
+    }
+}
+
+/// Serves the registry over gRPC until the server errors
+pub async fn serve(
+    addr: SocketAddr,
+    pool: SqlitePool,
+    storage: Arc<Backend>,
+    config: Arc<Config>,
+) -> Result<(), tonic::transport::Error> {
+    info!("serving grpc on {}", addr);
+    tonic::transport::Server::builder()
+        .add_service(RegistryServer::new(RegistryService::new(
+            pool, storage, config,
+        )))
+        .serve(addr)
+        .await
+}
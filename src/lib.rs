@@ -1,29 +1,31 @@
 pub mod auth;
 pub mod blobs;
 pub mod codes;
+pub mod config;
 pub mod content_discovery;
 pub mod database;
 pub mod endpoints;
+pub mod grpc;
+pub mod log_stream;
 pub mod manifests;
+pub mod rate_limit;
+pub mod s3;
 pub mod storage;
 pub mod storage_driver;
+pub mod ui;
 pub mod users;
 pub mod util;
 use std::{collections::HashMap, str::FromStr};
 
 use axum::extract::Request;
 use http::Method;
-use lazy_static::lazy_static;
+use log_stream::{LogStreamLayer, LOG_EVENTS};
 use sqlx::SqliteConnection;
-use tokio::sync::OnceCell;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-lazy_static! {
-    pub static ref APP_URL: OnceCell<String> = OnceCell::new();
-    pub static ref JWT_SECRET: OnceCell<String> = OnceCell::new();
-}
-
-pub fn set_env() {
+/// Installs the tracing subscriber, the level is read from `LOG_LEVEL`.
+/// With `log_stream`, events are also copied to `log_stream::LOG_EVENTS`.
+pub fn init_tracing(log_stream: bool) {
     let level = match std::env::var("LOG_LEVEL").unwrap_or_else(|_| "info".to_string()) {
         s if s.eq_ignore_ascii_case("trace") => tracing::Level::TRACE,
         s if s.eq_ignore_ascii_case("debug") => tracing::Level::DEBUG,
@@ -37,11 +39,10 @@ pub fn set_env() {
         .with_ansi(true)
         .pretty()
         .finish();
-    subscriber.with(tracing_subscriber::fmt::layer()).init();
-    let app_url = std::env::var("APP_URL").expect("APP_URL must be set");
-    APP_URL.set(app_url).unwrap();
-    let jwt_secret = std::env::var("JWT_SECRET_KEY").expect("JWT_SECRET_KEY must be set");
-    JWT_SECRET.set(jwt_secret).unwrap();
+    subscriber
+        .with(tracing_subscriber::fmt::layer())
+        .with(log_stream.then(|| LogStreamLayer::new(LOG_EVENTS.clone())))
+        .init();
 }
 
 #[derive(serde::Serialize, PartialEq, Eq, serde::Deserialize, Clone, Copy, Debug)]
@@ -117,17 +118,19 @@ impl std::fmt::Display for Action {
 impl std::str::FromStr for Action {
     type Err = String;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            // grant the highest level of access.
-            // delete can always push, push can always pull
-            "pull" => Ok(Action::Pull),
-            "push" => Ok(Action::Push),
-            "delete" => Ok(Action::Delete),
-            "push,pull" => Ok(Action::Push),
-            "push,pull,delete" => Ok(Action::Delete),
-            "*" => Ok(Action::Delete),
-            _ => Err(format!("invalid action: {}", s)),
+        // a list of actions grants the highest level of access, in any order.
+        // delete can always push, push can always pull
+        let mut greatest = None;
+        for action in s.split(',') {
+            let action = match action.trim() {
+                "pull" => Action::Pull,
+                "push" => Action::Push,
+                "delete" | "*" => Action::Delete,
+                _ => return Err(format!("invalid action: {}", s)),
+            };
+            greatest = greatest.max(Some(action));
         }
+        greatest.ok_or_else(|| format!("invalid action: {}", s))
     }
 }
 pub type Repo = String;
@@ -138,10 +141,12 @@ impl UserScope {
     pub fn is_allowed(&self, repo: &str, action: Action) -> bool {
         tracing::info!("checking scope: {} {}", repo, action);
         if repo == "*" {
-            if self
-                .0
-                .values()
-                .any(|available_action| !available_action.check_permission(action))
+            // no grants at all can't vouch for every repository
+            if self.0.is_empty()
+                || self
+                    .0
+                    .values()
+                    .any(|available_action| !available_action.check_permission(action))
             {
                 return false;
             }
@@ -154,6 +159,32 @@ impl UserScope {
         }
         true
     }
+
+    /// The subset of these scopes covered by `requested`: each repository keeps
+    /// the lesser of the granted and requested actions, and a `*` request
+    /// applies to every granted repository.
+    pub fn intersect(&self, requested: &UserScope) -> UserScope {
+        let mut scopes = HashMap::new();
+        for (repo, granted) in self.0.iter() {
+            let wanted = [requested.0.get(repo), requested.0.get("*")]
+                .into_iter()
+                .flatten()
+                .max();
+            if let Some(wanted) = wanted {
+                scopes.insert(repo.clone(), *granted.min(wanted));
+            }
+        }
+        UserScope(scopes)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// the highest action granted on the repository
+    pub fn action(&self, repo: &str) -> Option<Action> {
+        self.0.get(repo).copied()
+    }
 }
 
 impl FromStr for UserScope {
@@ -166,7 +197,7 @@ impl FromStr for UserScope {
                 return Err(format!("invalid scope: {}", scope));
             }
             let repo = parts[1];
-            let action = parts[2].parse::<Action>().unwrap();
+            let action = parts[2].parse::<Action>()?;
             scopes
                 .entry(repo.to_string())
                 .and_modify(|existing_action| {
@@ -187,7 +218,7 @@ pub async fn get_user_scopes(conn: &mut SqliteConnection, user_id: &str) -> User
         SELECT r.id, r.name, r.is_public, rs.push, rs.pull, rs.del
         FROM repositories r
         JOIN repository_scopes rs ON r.id = rs.repository_id
-        WHERE rs.user_id = ?"#,
+        WHERE rs.user_id = ? AND r.deleted_at IS NULL"#,
         user_id
     )
     .fetch_all(conn)
@@ -199,8 +230,11 @@ pub async fn get_user_scopes(conn: &mut SqliteConnection, user_id: &str) -> User
             Action::Delete
         } else if row.push {
             Action::Push
-        } else {
+        } else if row.pull {
             Action::Pull
+        } else {
+            // a row without any permission grants nothing
+            continue;
         };
         scopes.0.insert(row.name, highest_action);
     }
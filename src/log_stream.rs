@@ -0,0 +1,218 @@
+//! Live tracing events for `GET /admin/logs`, enabled with
+//! `--enable-log-stream`. A tracing layer copies each event into a bounded
+//! broadcast channel that the endpoint subscribes to over server-sent events.
+use crate::{
+    auth::Auth,
+    codes::{Code, ErrorResponse},
+    config::Config,
+};
+use axum::{
+    extract::Query,
+    http::StatusCode,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    Extension,
+};
+use lazy_static::lazy_static;
+use std::{fmt::Write, str::FromStr, sync::Arc};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{
+    field::{Field, Visit},
+    Level, Subscriber,
+};
+use tracing_subscriber::{layer::Context, Layer};
+
+/// events kept for slow subscribers before the oldest are dropped
+pub const LOG_STREAM_CAPACITY: usize = 1024;
+
+/// field names whose values are never streamed
+static SECRET_FIELDS: [&str; 5] = ["password", "secret", "token", "authorization", "key"];
+
+lazy_static! {
+    pub static ref LOG_EVENTS: broadcast::Sender<Arc<LogEvent>> =
+        broadcast::channel(LOG_STREAM_CAPACITY).0;
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct LogEvent {
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    #[serde(serialize_with = "serialize_level")]
+    pub level: Level,
+    pub target: String,
+    pub message: String,
+    #[serde(skip_serializing_if = "serde_json::Map::is_empty")]
+    pub fields: serde_json::Map<String, serde_json::Value>,
+}
+
+fn serialize_level<S: serde::Serializer>(level: &Level, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(level.as_str())
+}
+
+/// Copies tracing events into `LOG_EVENTS`. Sending never waits, and events
+/// are not even formatted while nobody is subscribed.
+pub struct LogStreamLayer {
+    sender: broadcast::Sender<Arc<LogEvent>>,
+}
+
+impl LogStreamLayer {
+    pub fn new(sender: broadcast::Sender<Arc<LogEvent>>) -> Self {
+        Self { sender }
+    }
+}
+
+impl<S: Subscriber> Layer<S> for LogStreamLayer {
+    fn on_event(&self, event: &tracing::Event<'_>, _ctx: Context<'_, S>) {
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+        let mut visitor = EventVisitor::default();
+        event.record(&mut visitor);
+        let metadata = event.metadata();
+        // only fails when the last subscriber left in the meantime
+        let _ = self.sender.send(Arc::new(LogEvent {
+            timestamp: chrono::Utc::now(),
+            level: *metadata.level(),
+            target: metadata.target().to_string(),
+            message: redact(&visitor.message),
+            fields: visitor.fields,
+        }));
+    }
+}
+
+#[derive(Default)]
+struct EventVisitor {
+    message: String,
+    fields: serde_json::Map<String, serde_json::Value>,
+}
+
+impl EventVisitor {
+    fn insert(&mut self, field: &Field, value: serde_json::Value) {
+        let name = field.name();
+        let value = if is_secret_field(name) {
+            serde_json::Value::from("<redacted>")
+        } else {
+            value
+        };
+        self.fields.insert(name.to_string(), value);
+    }
+}
+
+impl Visit for EventVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = value.to_string();
+        } else {
+            self.insert(field, redact(value).into());
+        }
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{:?}", value);
+        } else {
+            self.insert(field, redact(&format!("{:?}", value)).into());
+        }
+    }
+}
+
+fn is_secret_field(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    SECRET_FIELDS.iter().any(|secret| name.contains(secret))
+}
+
+/// Masks credentials inside free text: the token after a `Bearer` or
+/// `Basic` scheme, and the value of `name=value` / `name: value` pairs
+/// whose name looks secret
+pub fn redact(text: &str) -> String {
+    let mut redacted = Vec::new();
+    let mut mask_next = false;
+    for word in text.split(' ') {
+        if mask_next && !word.is_empty() {
+            redacted.push(String::from("<redacted>"));
+            mask_next = false;
+            continue;
+        }
+        let bare = word.trim_matches(|c: char| !c.is_ascii_alphanumeric());
+        if bare.eq_ignore_ascii_case("bearer") || bare.eq_ignore_ascii_case("basic") {
+            mask_next = true;
+        }
+        match word.split_once(['=', ':']) {
+            Some((name, value)) if is_secret_field(name) => {
+                let separator = &word[name.len()..name.len() + 1];
+                if value.is_empty() {
+                    // `password: hunter2`, the value is the next word
+                    mask_next = true;
+                    redacted.push(word.to_string());
+                } else {
+                    redacted.push(format!("{name}{separator}<redacted>"));
+                }
+            }
+            _ => redacted.push(word.to_string()),
+        }
+    }
+    redacted.join(" ")
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct LogStreamParams {
+    /// least severe level streamed, `info` when unset
+    pub level: Option<String>,
+}
+
+/// GET /admin/logs?level=<level>
+/// streams tracing events as server-sent events, one JSON `LogEvent` each
+pub async fn stream_logs(
+    Extension(auth): Extension<Auth>,
+    Extension(config): Extension<Arc<Config>>,
+    Query(params): Query<LogStreamParams>,
+) -> Response {
+    if !config.enable_log_stream {
+        return (StatusCode::NOT_FOUND, "log streaming is not enabled").into_response();
+    }
+    if !auth.is_global_admin() {
+        return ErrorResponse::from_code(&Code::Denied, String::from("admin access required"))
+            .into_response();
+    }
+    let level = match params.level.as_deref().map(Level::from_str).transpose() {
+        Ok(level) => level.unwrap_or(Level::INFO),
+        Err(_) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                "level must be one of trace, debug, info, warn or error",
+            )
+                .into_response()
+        }
+    };
+    let receiver = LOG_EVENTS.subscribe();
+    let events = futures::stream::unfold(receiver, move |mut receiver| async move {
+        loop {
+            let event = match receiver.recv().await {
+                // more verbose levels compare greater
+                Ok(event) if event.level > level => continue,
+                Ok(event) => Event::default().event("log").json_data(&*event),
+                Err(RecvError::Lagged(missed)) => {
+                    Ok(Event::default().event("lagged").data(missed.to_string()))
+                }
+                Err(RecvError::Closed) => return None,
+            };
+            return Some((event, receiver));
+        }
+    });
+    Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
@@ -1,14 +1,24 @@
 use axum_server::tls_rustls::RustlsConfig;
 use clap::{Parser, Subcommand};
 use floundr::{
+    config::{
+        Config, ManifestDeletePolicy, ReferrerDeletePolicy, RegistrationPolicy,
+        DEFAULT_DB_IDLE_TIMEOUT, DEFAULT_DB_MAX_LIFETIME, DEFAULT_MAX_MANIFEST_SIZE,
+        DEFAULT_TOKEN_TTL,
+    },
     database::{self, initdb, migrate_fresh},
     endpoints::{redirect_http_to_https, register_routes, Ports},
-    set_env,
+    grpc, init_tracing,
+    s3::S3Settings,
+    storage::STORAGE_PROBE_INTERVAL,
     storage_driver::{Backend, DriverType},
+    util::{is_valid_repository_name, log_if_slow},
+    UserScope,
 };
-use sqlx::SqliteConnection;
-use std::{net::SocketAddr, path::PathBuf, str::FromStr, sync::Arc};
-use tracing::info;
+use shared::RegisterUserRequest;
+use sqlx::{SqliteConnection, SqlitePool};
+use std::{net::SocketAddr, path::PathBuf, str::FromStr, sync::Arc, time::Duration};
+use tracing::{debug, error, info};
 
 #[derive(Parser)]
 #[command(name = "floundr")]
@@ -44,16 +54,223 @@ struct App {
         help = "port to serve tls on"
     )]
     https_port: Option<u16>,
+    #[arg(
+        long = "grpc-port",
+        help = "also serve the registry over grpc on this port"
+    )]
+    grpc_port: Option<u16>,
     #[arg(long = "db-path", short = 'd', help = "path to the sqlite database")]
     db_path: Option<String>,
     #[arg(long, default_value = "local", value_enum)]
     driver: DriverType,
+    #[arg(
+        long = "s3-bucket",
+        help = "bucket for the s3 driver (default is $S3_BUCKET)"
+    )]
+    s3_bucket: Option<String>,
+    #[arg(
+        long = "s3-region",
+        help = "region of the s3 bucket (default is $S3_REGION or $AWS_REGION, then us-east-1)"
+    )]
+    s3_region: Option<String>,
+    #[arg(
+        long = "s3-endpoint",
+        help = "endpoint of an S3 compatible store such as minio (default is $S3_ENDPOINT, then AWS)"
+    )]
+    s3_endpoint: Option<String>,
+    #[arg(
+        long = "s3-access-key",
+        help = "access key for the s3 bucket (default is $AWS_ACCESS_KEY_ID)"
+    )]
+    s3_access_key: Option<String>,
+    #[arg(
+        long = "s3-secret-key",
+        help = "secret key for the s3 bucket (default is $AWS_SECRET_ACCESS_KEY)"
+    )]
+    s3_secret_key: Option<String>,
     #[arg(long, default_value = "false", help = "Enable debug mode")]
     debug: bool,
+    #[arg(
+        long = "default-media-type",
+        default_value = shared::MANIFEST_CONTENT_TYPE,
+        help = "media type assumed for manifests pushed without a Content-Type"
+    )]
+    default_media_type: String,
+    #[arg(
+        long = "repo-recovery-window",
+        help = "keep deleted repositories restorable for this many seconds before purging them"
+    )]
+    repo_recovery_window: Option<u64>,
+    #[arg(
+        long = "manifest-delete",
+        default_value = "tag-and-digest",
+        value_enum,
+        help = "which references manifests may be deleted by"
+    )]
+    manifest_delete: ManifestDeletePolicy,
+    #[arg(
+        long = "referrer-delete",
+        default_value = "orphan",
+        value_enum,
+        help = "whether deleting a manifest also deletes the manifests referring to it through their subject"
+    )]
+    referrer_delete: ReferrerDeletePolicy,
+    #[arg(
+        long = "upload-url-ttl",
+        default_value = "900",
+        help = "seconds a signed blob upload url remains valid"
+    )]
+    upload_url_ttl: u64,
+    #[arg(
+        long = "max-concurrent-uploads",
+        help = "maximum open blob upload sessions per repository"
+    )]
+    max_concurrent_uploads: Option<u32>,
+    #[arg(
+        long = "repr-digest",
+        default_value = "false",
+        help = "send a Repr-Digest (RFC 9530) header alongside Docker-Content-Digest on blobs"
+    )]
+    repr_digest: bool,
+    #[arg(
+        long = "allow-registration",
+        default_value = "off",
+        value_enum,
+        help = "who may create accounts via /auth/register: off, user (non-admin self-registration), admin (admins may also create admins)"
+    )]
+    allow_registration: RegistrationPolicy,
+    #[arg(
+        long = "default-namespace",
+        help = "namespace for repository names without one, e.g. `library` to serve `ubuntu` from `library/ubuntu`"
+    )]
+    default_namespace: Option<String>,
+    #[arg(
+        long = "max-manifest-size",
+        default_value_t = DEFAULT_MAX_MANIFEST_SIZE,
+        help = "largest manifest accepted on push, in bytes"
+    )]
+    max_manifest_size: u64,
+    #[arg(
+        long = "store-diff-ids",
+        default_value = "false",
+        help = "read the config of pushed images and record each layer's uncompressed diff ID"
+    )]
+    store_diff_ids: bool,
+    #[arg(
+        long = "enable-log-stream",
+        default_value = "false",
+        help = "let admins stream server logs as server-sent events from /admin/logs"
+    )]
+    enable_log_stream: bool,
+    #[arg(
+        long = "gc-interval",
+        help = "remove unreferenced blobs every this many seconds (default is $GC_INTERVAL, unset disables it)"
+    )]
+    gc_interval: Option<u64>,
+    #[arg(
+        long = "db-idle-timeout",
+        default_value_t = DEFAULT_DB_IDLE_TIMEOUT,
+        help = "close database connections idle for this many seconds, 0 keeps them open"
+    )]
+    db_idle_timeout: u64,
+    #[arg(
+        long = "db-max-lifetime",
+        default_value_t = DEFAULT_DB_MAX_LIFETIME,
+        help = "replace database connections after this many seconds, 0 keeps them indefinitely"
+    )]
+    db_max_lifetime: u64,
+    #[arg(
+        long = "user-agent-allow",
+        value_delimiter = ',',
+        help = "only serve clients whose User-Agent contains one of these, comma separated"
+    )]
+    user_agent_allow: Vec<String>,
+    #[arg(
+        long = "user-agent-deny",
+        value_delimiter = ',',
+        help = "refuse clients whose User-Agent contains any of these, comma separated"
+    )]
+    user_agent_deny: Vec<String>,
+    #[arg(
+        long = "enable-ui",
+        default_value = "false",
+        help = "serve a browser dashboard from /ui"
+    )]
+    enable_ui: bool,
+    #[arg(
+        long = "disable-delete",
+        default_value = "false",
+        help = "refuse to delete blobs, manifests, repositories and users"
+    )]
+    disable_delete: bool,
+    #[arg(
+        long = "verify-manifests",
+        default_value = "false",
+        help = "check a manifest pulled by digest still matches it before serving it"
+    )]
+    verify_manifests: bool,
+    #[arg(
+        long = "slow-log-ms",
+        help = "log requests and storage queries that take longer than this many milliseconds"
+    )]
+    slow_log_ms: Option<u64>,
+    #[arg(
+        long = "token-ttl",
+        default_value_t = DEFAULT_TOKEN_TTL,
+        help = "seconds an issued access token stays valid"
+    )]
+    token_ttl: u64,
+    #[arg(
+        long = "rate-limit",
+        help = "requests a client may make per minute, unlimited when unset [env: RATE_LIMIT]"
+    )]
+    rate_limit: Option<u32>,
+    #[arg(
+        long = "validate-config",
+        default_value = "false",
+        help = "reject image manifests whose config blob is missing or of the wrong media type"
+    )]
+    validate_config: bool,
+    #[arg(
+        long = "print-config",
+        default_value = "false",
+        help = "print the resolved configuration as JSON and exit"
+    )]
+    print_config: bool,
     #[command(subcommand)]
     command: Option<Box<Command>>,
 }
 
+/// Resolved settings reported at startup and by `--print-config`.
+/// Secrets are never included, only whether they are set.
+#[derive(serde::Serialize)]
+struct Settings<'a> {
+    driver: DriverType,
+    storage_path: String,
+    database_url: String,
+    http_addr: String,
+    https_addr: Option<String>,
+    grpc_addr: Option<String>,
+    tls: bool,
+    jwt_secret: &'static str,
+    debug: bool,
+    soft_delete: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    s3: Option<&'a S3Settings>,
+    #[serde(flatten)]
+    config: &'a Config,
+}
+
+/// strips any `user:password@` credentials from a database url
+fn redact_url(url: &str) -> String {
+    match (url.find("://"), url.rfind('@')) {
+        (Some(scheme), Some(at)) if at > scheme => {
+            format!("{}://<redacted>@{}", &url[..scheme], &url[at + 1..])
+        }
+        _ => url.to_string(),
+    }
+}
+
 #[derive(Subcommand)]
 enum Command {
     #[command(about = "Migrate the database to a fresh state")]
@@ -69,6 +286,24 @@ enum Command {
             help = "whether the new repository is public"
         )]
         public: bool,
+        #[arg(long, help = "only check that the repository can be created")]
+        dry_run: bool,
+    },
+
+    #[command(
+        about = "Compare the disk usage recorded for a repository with the size of its files in storage"
+    )]
+    DiskUsage {
+        #[arg(help = "name of the repository", required(true))]
+        name: String,
+    },
+
+    #[command(about = "Set the storage quota of a repository in bytes, 0 removes it")]
+    SetQuota {
+        #[arg(help = "name of the repository", required(true))]
+        name: String,
+        #[arg(help = "maximum bytes the repository may store", required(true))]
+        bytes: u64,
     },
 
     #[command(
@@ -80,9 +315,18 @@ enum Command {
         email: String,
         #[arg(long, requires = "email", help = "new user password", required(true))]
         password: String,
+        #[arg(
+            long,
+            help = "restrict the user to repositories within this namespace, e.g. `team` for `team/app`"
+        )]
+        namespace: Option<String>,
+        #[arg(long, help = "only check that the user can be created")]
+        dry_run: bool,
     },
 
-    #[command(about = "Generate a new API key for a user with administrative privileges")]
+    #[command(
+        about = "Generate a new API key for a user, with administrative privileges unless --scope is given"
+    )]
     GenKey {
         email: String,
         #[arg(
@@ -91,6 +335,16 @@ enum Command {
             help = "Output file for the generated key"
         )]
         output_file: String,
+        #[arg(
+            long,
+            help = "restrict the key to repositories within this namespace, e.g. `team` for `team/app`"
+        )]
+        namespace: Option<String>,
+        #[arg(
+            long,
+            help = "limit the key to these scopes, e.g. \"repository:foo:pull repository:bar:push\""
+        )]
+        scope: Option<String>,
     },
 }
 
@@ -116,24 +370,203 @@ async fn main() {
         )
         .parse::<PathBuf>()
         .expect("unable to parse home dir");
-    let storage = Backend::new(
-        DriverType::Local,
+    let defaults = S3Settings::from_env();
+    let s3 = S3Settings {
+        bucket: args.s3_bucket.clone().or(defaults.bucket),
+        region: args.s3_region.clone().unwrap_or(defaults.region),
+        endpoint: args.s3_endpoint.clone().or(defaults.endpoint),
+        access_key: args.s3_access_key.clone().or(defaults.access_key),
+        secret_key: args.s3_secret_key.clone().or(defaults.secret_key),
+    };
+    let storage = match Backend::new(
+        args.driver.clone(),
         args.storage_path.as_ref().unwrap_or(&home),
-    );
-    info!("storage path home: {:?}", storage.base_path());
+        &s3,
+    ) {
+        Ok(storage) => storage,
+        Err(err) => {
+            eprintln!("unable to set up {:?} storage: {err}", args.driver);
+            std::process::exit(1);
+        }
+    };
     let db_url = args
         .db_path
         .as_ref()
         .cloned()
         .unwrap_or_else(|| std::env::var("DB_PATH").unwrap_or("db.sqlite3".to_string()));
-    let pool = initdb(&db_url).await;
+    let host = std::env::var("HOST").unwrap_or("127.0.0.1".to_string());
+    let ports = Ports(args.port.unwrap_or(8080), args.https_port.unwrap_or(443));
+
+    let gc_interval = match args.gc_interval {
+        Some(interval) => Some(interval),
+        None => match std::env::var("GC_INTERVAL") {
+            Ok(interval) => match interval.parse() {
+                Ok(interval) => Some(interval),
+                Err(_) => {
+                    eprintln!("invalid GC_INTERVAL: {interval}");
+                    std::process::exit(1);
+                }
+            },
+            Err(_) => None,
+        },
+    };
+    let rate_limit = match args.rate_limit {
+        Some(limit) => Some(limit),
+        None => match std::env::var("RATE_LIMIT") {
+            Ok(limit) => match limit.parse() {
+                Ok(limit) => Some(limit),
+                Err(_) => {
+                    eprintln!("invalid RATE_LIMIT: {limit}");
+                    std::process::exit(1);
+                }
+            },
+            Err(_) => None,
+        },
+    };
+    let config = Config {
+        app_url: std::env::var("APP_URL").unwrap_or_else(|_| {
+            let scheme = if args.ssl { "https" } else { "http" };
+            let port = if args.ssl { ports.1 } else { ports.0 };
+            format!("{scheme}://{host}:{port}")
+        }),
+        jwt_secret: std::env::var("JWT_SECRET_KEY").unwrap_or_default(),
+        default_media_type: args.default_media_type.to_lowercase(),
+        repo_recovery_window: args.repo_recovery_window,
+        manifest_delete: args.manifest_delete,
+        referrer_delete: args.referrer_delete,
+        upload_url_ttl: args.upload_url_ttl,
+        max_concurrent_uploads: args.max_concurrent_uploads,
+        repr_digest: args.repr_digest,
+        allow_registration: args.allow_registration,
+        default_namespace: args.default_namespace.clone(),
+        max_manifest_size: args.max_manifest_size,
+        store_diff_ids: args.store_diff_ids,
+        enable_log_stream: args.enable_log_stream,
+        gc_interval,
+        db_idle_timeout: args.db_idle_timeout,
+        db_max_lifetime: args.db_max_lifetime,
+        user_agent_allow: args.user_agent_allow.clone(),
+        user_agent_deny: args.user_agent_deny.clone(),
+        enable_ui: args.enable_ui,
+        disable_delete: args.disable_delete,
+        verify_manifests: args.verify_manifests,
+        slow_log_ms: args.slow_log_ms,
+        token_ttl: args.token_ttl,
+        rate_limit,
+        validate_config: args.validate_config,
+    };
+    if let Err(err) = config.validate() {
+        eprintln!("{err}");
+        std::process::exit(1);
+    }
+    let settings = Settings {
+        driver: storage.kind(),
+        storage_path: storage.base_path().to_string_lossy().to_string(),
+        database_url: redact_url(&db_url),
+        http_addr: format!("{host}:{}", ports.0),
+        https_addr: args.ssl.then(|| format!("{host}:{}", ports.1)),
+        grpc_addr: args.grpc_port.map(|port| format!("{host}:{port}")),
+        tls: args.ssl,
+        jwt_secret: "<redacted>",
+        debug: args.debug,
+        soft_delete: config.repo_recovery_window.is_some(),
+        s3: matches!(args.driver, DriverType::S3).then_some(&s3),
+        config: &config,
+    };
+    if args.print_config {
+        match serde_json::to_string_pretty(&settings) {
+            Ok(json) => println!("{json}"),
+            Err(err) => {
+                eprintln!("unable to serialize config: {err}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let pool = initdb(&db_url, &config).await;
     let mut conn = pool.acquire().await.expect("unable to acquire connection");
-    set_env();
+    init_tracing(config.enable_log_stream);
+    info!("starting floundr {}", env!("CARGO_PKG_VERSION"));
+    info!(
+        driver = ?settings.driver,
+        storage_path = %settings.storage_path,
+        database_url = %settings.database_url,
+        http_addr = %settings.http_addr,
+        https_addr = ?settings.https_addr,
+        grpc_addr = ?settings.grpc_addr,
+        tls = settings.tls,
+        app_url = %settings.config.app_url,
+        jwt_secret = settings.jwt_secret,
+        token_ttl = settings.config.token_ttl,
+        validate_config = settings.config.validate_config,
+        soft_delete = settings.soft_delete,
+        s3_bucket = ?settings.s3.and_then(|s3| s3.bucket.as_deref()),
+        s3_region = ?settings.s3.map(|s3| &s3.region),
+        s3_endpoint = ?settings.s3.and_then(|s3| s3.endpoint.as_deref()),
+        repo_recovery_window = ?settings.config.repo_recovery_window,
+        manifest_delete = ?settings.config.manifest_delete,
+        referrer_delete = ?settings.config.referrer_delete,
+        default_media_type = %settings.config.default_media_type,
+        upload_url_ttl = settings.config.upload_url_ttl,
+        max_concurrent_uploads = ?settings.config.max_concurrent_uploads,
+        repr_digest = settings.config.repr_digest,
+        allow_registration = ?settings.config.allow_registration,
+        default_namespace = ?settings.config.default_namespace,
+        max_manifest_size = settings.config.max_manifest_size,
+        store_diff_ids = settings.config.store_diff_ids,
+        enable_log_stream = settings.config.enable_log_stream,
+        gc_interval = ?settings.config.gc_interval,
+        db_idle_timeout = settings.config.db_idle_timeout,
+        db_max_lifetime = settings.config.db_max_lifetime,
+        user_agent_allow = ?settings.config.user_agent_allow,
+        user_agent_deny = ?settings.config.user_agent_deny,
+        enable_ui = settings.config.enable_ui,
+        disable_delete = settings.config.disable_delete,
+        verify_manifests = settings.config.verify_manifests,
+        slow_log_ms = ?settings.config.slow_log_ms,
+        rate_limit = ?settings.config.rate_limit,
+        "effective config"
+    );
+    info!(
+        idle_timeout = ?pool.options().get_idle_timeout(),
+        max_lifetime = ?pool.options().get_max_lifetime(),
+        "database pool ready"
+    );
     let _ = handle_args(&args, &mut conn, &storage).await;
-    let host = std::env::var("HOST").unwrap_or("127.0.0.1".to_string());
 
-    let routes = register_routes(pool, Arc::new(storage));
-    let ports = Ports(args.port.unwrap_or(8080), args.https_port.unwrap_or(443));
+    let storage = Arc::new(storage);
+    tokio::spawn(probe_storage(Arc::clone(&storage)));
+    if let Some(window) = config.repo_recovery_window {
+        tokio::spawn(purge_deleted_repositories(
+            pool.clone(),
+            Arc::clone(&storage),
+            window,
+        ));
+    }
+    if let Some(interval) = config.gc_interval {
+        tokio::spawn(collect_garbage(
+            pool.clone(),
+            Arc::clone(&storage),
+            interval,
+        ));
+    }
+
+    let config = Arc::new(config);
+    if let Some(port) = args.grpc_port {
+        let addr = SocketAddr::from_str(&format!("{host}:{port}")).unwrap_or_else(|_| {
+            eprintln!("Invalid address: {host}:{port}");
+            std::process::exit(1);
+        });
+        let (pool, storage, config) = (pool.clone(), Arc::clone(&storage), Arc::clone(&config));
+        tokio::spawn(async move {
+            if let Err(err) = grpc::serve(addr, pool, storage, config).await {
+                error!("grpc server stopped: {}", err);
+            }
+        });
+    }
+
+    let routes = register_routes(pool, storage, config);
 
     if args.ssl {
         let addr = SocketAddr::from_str(&format!("{host}:{}", ports.1)).unwrap_or_else(|_| {
@@ -155,7 +588,7 @@ async fn main() {
         tokio::spawn(redirect_http_to_https(ports));
 
         axum_server::bind_rustls(addr, config)
-            .serve(routes.into_make_service())
+            .serve(routes.into_make_service_with_connect_info::<SocketAddr>())
             .await
             .expect("unable to start server");
         info!("Listening on {}", addr);
@@ -167,13 +600,75 @@ async fn main() {
         let listener = tokio::net::TcpListener::bind(&addr)
             .await
             .expect("unable to bind to port");
-        axum::serve(listener, routes.into_make_service())
-            .await
-            .expect("unable to start server");
+        axum::serve(
+            listener,
+            routes.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await
+        .expect("unable to start server");
         info!("Listening on {}", addr);
     }
 }
 
+/// Periodically checks the storage backend is writable, see `/readyz`
+async fn probe_storage(storage: Arc<Backend>) {
+    let mut interval = tokio::time::interval(STORAGE_PROBE_INTERVAL);
+    loop {
+        interval.tick().await;
+        storage.probe_health().await;
+    }
+}
+
+/// Periodically purges soft-deleted repositories once their recovery window expires
+async fn purge_deleted_repositories(pool: SqlitePool, storage: Arc<Backend>, window: u64) {
+    let mut interval = tokio::time::interval(Duration::from_secs(window.clamp(1, 60)));
+    loop {
+        interval.tick().await;
+        // skip this round rather than queue behind a long running task
+        let Ok(_maintenance) = database::MAINTENANCE.try_lock() else {
+            continue;
+        };
+        let mut conn = match pool.acquire().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                error!("unable to acquire connection for repository purge: {err}");
+                continue;
+            }
+        };
+        match storage.purge_expired_repositories(&mut conn, window).await {
+            Ok(0) => {}
+            Ok(purged) => info!("purged {purged} expired repositories"),
+            Err(err) => error!("error purging expired repositories: {err}"),
+        }
+    }
+}
+
+/// Periodically removes blobs no manifest references, see `--gc-interval`
+async fn collect_garbage(pool: SqlitePool, storage: Arc<Backend>, interval: u64) {
+    let mut interval = tokio::time::interval(Duration::from_secs(interval));
+    // the first tick completes immediately, don't collect while starting up
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        // skip this round rather than queue behind a long running task
+        let Ok(_maintenance) = database::MAINTENANCE.try_lock() else {
+            continue;
+        };
+        let mut conn = match pool.acquire().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                error!("unable to acquire connection for garbage collection: {err}");
+                continue;
+            }
+        };
+        match storage.run_garbage_collection(&mut conn).await {
+            Ok(0) => debug!("garbage collection removed 0 blobs"),
+            Ok(removed) => info!("garbage collection removed {removed} blobs"),
+            Err(err) => error!("error collecting garbage: {err}"),
+        }
+    }
+}
+
 async fn handle_args(args: &App, conn: &mut SqliteConnection, storage: &Backend) {
     match args.command.as_deref() {
         Some(Command::MigrateFresh) => {
@@ -182,25 +677,113 @@ async fn handle_args(args: &App, conn: &mut SqliteConnection, storage: &Backend)
                 .expect("unable to migrate database");
             info!("Migrating the database to a fresh state...");
         }
-        Some(Command::NewRepo { name, public }) => {
-            let _ = storage.create_repository(conn, name, *public).await;
+        Some(Command::NewRepo {
+            name,
+            public,
+            dry_run,
+        }) => {
+            if let Err(err) = check_new_repo(conn, name).await {
+                eprintln!("Cannot create repository {}: {}", name, err);
+                std::process::exit(1);
+            }
+            if *dry_run {
+                println!("Repository {} can be created (public: {})", name, public);
+                std::process::exit(0);
+            }
+            if let Err(err) = storage.create_repository(conn, name, *public).await {
+                eprintln!("Unable to create repository {}: {}", name, err);
+                std::process::exit(1);
+            }
             println!("Created new repository: {} (public: {})", name, public);
             std::process::exit(0);
         }
-        Some(Command::NewUser { email, password }) => {
-            let _ = database::seed_default_user(
-                conn,
-                Some(email.to_owned()),
-                Some(password.to_owned()),
+        Some(Command::DiskUsage { name }) => {
+            let recorded = match database::repository_disk_usage(conn, name).await {
+                Ok(Some(recorded)) => recorded,
+                Ok(None) => {
+                    eprintln!("No repository named: {}", name);
+                    std::process::exit(1);
+                }
+                Err(err) => {
+                    eprintln!("Unable to read the disk usage of {}: {}", name, err);
+                    std::process::exit(1);
+                }
+            };
+            // walks every file, which is why listings rely on the recorded sizes
+            let stored = log_if_slow(
+                args.slow_log_ms.map(Duration::from_millis),
+                "storage disk usage walk",
+                storage.get_dir_size(storage.base_path().join(name)),
             )
             .await;
-            println!("Creating new user: {} with password: {}", email, password);
+            println!(
+                "{}: {} bytes recorded, {} bytes in storage",
+                name, recorded, stored
+            );
             std::process::exit(0);
         }
-        Some(Command::GenKey { email, output_file }) => {
-            let secret = database::generate_secret(conn, None, email)
+        Some(Command::SetQuota { name, bytes }) => {
+            let max_bytes = match *bytes {
+                0 => None,
+                bytes => Some(i64::try_from(bytes).expect("quota is too large")),
+            };
+            let found = database::set_repository_quota(conn, name, max_bytes)
                 .await
-                .expect("unable to generate secret");
+                .expect("unable to set repository quota");
+            if !found {
+                eprintln!("No repository named: {}", name);
+                std::process::exit(1);
+            }
+            match max_bytes {
+                Some(bytes) => println!("Set quota of {} to {} bytes", name, bytes),
+                None => println!("Removed the quota of {}", name),
+            }
+            std::process::exit(0);
+        }
+        Some(Command::NewUser {
+            email,
+            password,
+            namespace,
+            dry_run,
+        }) => {
+            if let Err(err) = check_new_user(conn, email, password).await {
+                eprintln!("Cannot create user {}: {}", email, err);
+                std::process::exit(1);
+            }
+            if *dry_run {
+                println!("User {} can be created", email);
+                std::process::exit(0);
+            }
+            if let Err(err) =
+                database::seed_default_user(conn, Some(email.to_owned()), Some(password.to_owned()))
+                    .await
+            {
+                eprintln!("Unable to create user {}: {}", email, err);
+                std::process::exit(1);
+            }
+            if namespace.is_some() {
+                database::set_user_namespace(conn, email, namespace.as_deref())
+                    .await
+                    .expect("unable to set user namespace");
+            }
+            println!("Creating new user: {} with password: {}", email, password);
+            std::process::exit(0);
+        }
+        Some(Command::GenKey {
+            email,
+            output_file,
+            namespace,
+            scope,
+        }) => {
+            let scope = scope
+                .as_deref()
+                .map(str::parse::<UserScope>)
+                .transpose()
+                .expect("invalid --scope");
+            let secret =
+                database::generate_secret(conn, None, email, namespace.as_deref(), scope.as_ref())
+                    .await
+                    .expect("unable to generate secret");
             tokio::fs::write(output_file, secret).await.unwrap();
             info!(
                 "Generated new API key for: {} and saving to: {}",
@@ -213,3 +796,35 @@ async fn handle_args(args: &App, conn: &mut SqliteConnection, storage: &Backend)
         }
     }
 }
+
+/// Why `new-repo` can't create `name`, if it can't
+async fn check_new_repo(conn: &mut SqliteConnection, name: &str) -> Result<(), String> {
+    if !is_valid_repository_name(name) {
+        return Err(String::from(
+            "names are lowercase letters and digits, separated by '.', '_', '-' or '/'",
+        ));
+    }
+    match database::repository_exists(conn, name).await {
+        Ok(false) => Ok(()),
+        Ok(true) => Err(String::from("a repository with that name already exists")),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/// Why `new-user` can't create `email`, if it can't
+async fn check_new_user(
+    conn: &mut SqliteConnection,
+    email: &str,
+    password: &str,
+) -> Result<(), String> {
+    if !RegisterUserRequest::new(email, password, password, false).validate() {
+        return Err(String::from(
+            "emails must be ascii and passwords longer than 8 characters with a digit",
+        ));
+    }
+    match database::user_exists(conn, email).await {
+        Ok(false) => Ok(()),
+        Ok(true) => Err(String::from("a user with that email already exists")),
+        Err(err) => Err(err.to_string()),
+    }
+}
@@ -1,30 +1,179 @@
-use crate::{codes::ErrorResponse, database::DbConn, storage_driver::Backend};
+use crate::{
+    auth::Auth,
+    codes::{deletes_disabled, Code, ErrorResponse},
+    config::{is_manifest_media_type, Config, ManifestKind, ReferrerDeletePolicy},
+    database::{self, DbConn},
+    storage::{check_image_config, is_foreign_layer},
+    storage_driver::{Backend, StorageError},
+    util::{
+        immutable_cache_control, is_valid_repository_name, log_if_slow, validate_digest,
+        REVALIDATE_CACHE_CONTROL,
+    },
+    Action,
+};
 use axum::{
-    extract::{Path, Request},
-    http::{HeaderMap, StatusCode},
+    extract::{Path, Query, Request},
+    http::{HeaderMap, HeaderValue, StatusCode},
     response::IntoResponse,
-    Extension,
+    Extension, Json,
+};
+use http::header::{
+    ACCEPT, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE, ETAG, IF_NONE_MATCH, USER_AGENT, WARNING,
 };
-use http::header::{CONTENT_LENGTH, CONTENT_TYPE};
-use shared::{DOCKER_DIGEST, MANIFEST_CONTENT_TYPE};
+use serde::{Deserialize, Serialize};
+use shared::{Descriptor, ImageManifest, Platform, DOCKER_DIGEST};
+use sqlx::{Connection, SqliteConnection};
 use std::sync::Arc;
 use tracing::{error, info};
 
+/// Resolves the media type of a pushed manifest from its Content-Type header,
+/// falling back to the configured default when the header is absent.
+/// Returns None for media types the registry doesn't recognize.
+fn manifest_media_type(headers: &HeaderMap, default: &str) -> Option<String> {
+    let media_type = match headers.get(CONTENT_TYPE) {
+        Some(value) => value
+            .to_str()
+            .ok()?
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_lowercase(),
+        None => default.to_string(),
+    };
+    is_manifest_media_type(&media_type).then_some(media_type)
+}
+
+/// the `subject` any kind of manifest may carry
+#[derive(Deserialize)]
+struct ManifestSubject {
+    subject: Option<Descriptor>,
+}
+
+/// Hands a buffered manifest to the write path for its media type, each of
+/// which validates it before the shared digest/storage step
+pub(crate) async fn write_manifest(
+    storage: &Backend,
+    conn: &mut SqliteConnection,
+    config: &Config,
+    name: &str,
+    reference: &str,
+    media_type: &str,
+    data: &[u8],
+) -> Result<String, StorageError> {
+    let Some(kind) = ManifestKind::from_media_type(media_type) else {
+        return Err(StorageError::IoError(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "unsupported manifest media type",
+        )));
+    };
+    match kind {
+        ManifestKind::Image => {
+            if config.validate_config {
+                check_image_config(conn, name, media_type, data).await?;
+            }
+            storage
+                .write_image_manifest(
+                    conn,
+                    name,
+                    reference,
+                    media_type,
+                    data,
+                    config.store_diff_ids,
+                )
+                .await
+        }
+        ManifestKind::Index => {
+            storage
+                .write_image_index(conn, name, reference, media_type, data)
+                .await
+        }
+        ManifestKind::Artifact => {
+            storage
+                .write_artifact_manifest(conn, name, reference, media_type, data)
+                .await
+        }
+    }
+}
+
 /// PUT /v2/:name/manifests/:reference
 pub async fn push_manifest(
     Path((name, reference)): Path<(String, String)>,
     Extension(storage): Extension<Arc<Backend>>,
+    Extension(config): Extension<Arc<Config>>,
     DbConn(mut conn): DbConn,
     body: Request,
 ) -> impl IntoResponse {
-    match storage
-        .write_manifest(
-            &mut conn,
-            &name,
-            &reference,
-            body.into_body().into_data_stream(),
+    let media_type = match manifest_media_type(body.headers(), &config.default_media_type) {
+        Some(media_type) => media_type,
+        None => {
+            error!("rejecting manifest with unsupported content type");
+            return ErrorResponse::from_code(
+                &Code::ManifestInvalid,
+                "unsupported manifest media type",
+            )
+            .into_response();
+        }
+    };
+    let limit = config.max_manifest_size;
+    let too_large = || {
+        (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorResponse::from_code(
+                &Code::SizeInvalid,
+                format!("manifest exceeds the {limit} byte limit"),
+            ),
         )
-        .await
+            .into_response()
+    };
+    let declared = body
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok()?.parse::<u64>().ok());
+    if declared.is_some_and(|len| len > limit) {
+        return too_large();
+    }
+    // the digest the client computed, checked before anything is stored
+    let computed = body
+        .headers()
+        .get(DOCKER_DIGEST)
+        .map(|digest| digest.to_str().unwrap_or_default().to_string());
+    let data = match axum::body::to_bytes(body.into_body(), limit as usize).await {
+        Ok(data) => data,
+        Err(err) => {
+            if std::error::Error::source(&err)
+                .is_some_and(|source| source.is::<http_body_util::LengthLimitError>())
+            {
+                return too_large();
+            }
+            error!("unable to read manifest body: {:?}", err);
+            return ErrorResponse::from_code(&Code::ManifestInvalid, "unable to read manifest")
+                .into_response();
+        }
+    };
+    // tags can't contain ':', so such a reference is a digest the manifest
+    // has to match as well
+    let by_digest = reference.contains(':').then(|| reference.clone());
+    for expected in computed.into_iter().chain(by_digest) {
+        if validate_digest(&data, &expected).is_err() {
+            info!("manifest does not match the digest {}", expected);
+            return ErrorResponse::from_code(
+                &Code::DigestInvalid,
+                format!("manifest does not match the provided digest {expected}"),
+            )
+            .into_response();
+        }
+    }
+    match write_manifest(
+        &storage,
+        &mut conn,
+        &config,
+        &name,
+        &reference,
+        &media_type,
+        &data,
+    )
+    .await
     {
         Ok(digest) => {
             let mut headers = HeaderMap::new();
@@ -39,8 +188,24 @@ pub async fn push_manifest(
                     .unwrap(),
             );
             headers.insert(DOCKER_DIGEST, digest.parse().unwrap());
+            // tells the client the referrers API picked this manifest up
+            let subject = serde_json::from_slice::<ManifestSubject>(&data)
+                .ok()
+                .and_then(|manifest| manifest.subject);
+            if let Some(Ok(value)) = subject.map(|subject| subject.digest.parse()) {
+                headers.insert("OCI-Subject", value);
+            }
             (StatusCode::CREATED, headers).into_response()
         }
+        Err(StorageError::IoError(err)) if err.kind() == std::io::ErrorKind::InvalidData => {
+            info!("rejecting invalid manifest: {}", err);
+            ErrorResponse::from_code(&Code::ManifestInvalid, err.to_string()).into_response()
+        }
+        Err(StorageError::BlobUnknown(digest)) => {
+            info!("rejecting manifest referencing unknown blob: {}", digest);
+            ErrorResponse::from_code(&Code::ManifestBlobUnknown, format!("unknown blob {digest}"))
+                .into_response()
+        }
         Err(err) => {
             error!("Error writing manifest: {:?}", err);
             let code = crate::codes::Code::ManifestUnknown;
@@ -49,69 +214,396 @@ pub async fn push_manifest(
     }
 }
 
+/// Media types a client will take for a manifest, from its `Accept`
+/// headers, with their q-values. A missing header accepts anything.
+struct AcceptedTypes(Vec<(String, f32)>);
+
+impl AcceptedTypes {
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let mut types = Vec::new();
+        for value in headers
+            .get_all(ACCEPT)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+        {
+            for entry in value.split(',') {
+                let mut params = entry.split(';');
+                let media_type = params.next().unwrap_or_default().trim().to_lowercase();
+                if media_type.is_empty() {
+                    continue;
+                }
+                let quality = params
+                    .find_map(|param| param.trim().strip_prefix("q="))
+                    .and_then(|q| q.trim().parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                types.push((media_type, quality));
+            }
+        }
+        Self(types)
+    }
+
+    /// Whether the client accepts `media_type`. The most specific matching
+    /// range decides, so `*/*;q=0` can be overridden by naming a type.
+    fn accepts(&self, media_type: &str) -> bool {
+        if self.0.is_empty() {
+            return true;
+        }
+        let family = format!("{}/*", media_type.split('/').next().unwrap_or_default());
+        self.0
+            .iter()
+            .filter_map(|(range, quality)| {
+                let specificity = match range.as_str() {
+                    range if range == media_type => 2,
+                    range if range == family => 1,
+                    "*/*" => 0,
+                    _ => return None,
+                };
+                Some((specificity, *quality))
+            })
+            .max_by(|a, b| a.0.cmp(&b.0).then(a.1.total_cmp(&b.1)))
+            .is_some_and(|(_, quality)| quality > 0.0)
+    }
+}
+
+/// The platform a client pulls for
+struct ClientPlatform {
+    os: String,
+    architecture: String,
+    variant: Option<String>,
+}
+
+impl ClientPlatform {
+    /// from a `?platform=os/arch[/variant]` query, e.g. `linux/arm64/v8`
+    fn parse(platform: &str) -> Option<Self> {
+        let mut parts = platform.split('/').map(str::to_lowercase);
+        let os = parts.next().filter(|os| !os.is_empty())?;
+        let architecture = parts.next().filter(|arch| !arch.is_empty())?;
+        let variant = parts.next();
+        if parts.next().is_some() || variant.as_deref() == Some("") {
+            return None;
+        }
+        Some(Self {
+            os,
+            architecture,
+            variant,
+        })
+    }
+
+    /// when its User-Agent says, as docker's does with `os/linux arch/amd64`
+    fn from_user_agent(headers: &HeaderMap) -> Option<Self> {
+        let agent = headers.get(USER_AGENT)?.to_str().ok()?;
+        let field = |key: &str| {
+            agent
+                .split_whitespace()
+                .find_map(|part| part.strip_prefix(key))
+                .map(str::to_lowercase)
+        };
+        Some(Self {
+            os: field("os/")?,
+            architecture: field("arch/")?,
+            variant: None,
+        })
+    }
+
+    /// a variant is only compared when the client asked for one
+    fn matches(&self, platform: &Platform) -> bool {
+        platform.os.eq_ignore_ascii_case(&self.os)
+            && platform
+                .architecture
+                .eq_ignore_ascii_case(&self.architecture)
+            && self.variant.as_deref().is_none_or(|variant| {
+                platform
+                    .variant
+                    .as_deref()
+                    .is_some_and(|v| v.eq_ignore_ascii_case(variant))
+            })
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ManifestQuery {
+    /// `os/arch[/variant]` to resolve an index to
+    platform: Option<String>,
+}
+
+struct StoredManifest {
+    file_path: String,
+    digest: String,
+    media_type: String,
+    size: i64,
+}
+
+/// The child manifest of an index the client would have chosen: one of an
+/// acceptable media type for the client's platform, or the only acceptable
+/// one when the platform is unknown.
+async fn platform_manifest(
+    storage: &Backend,
+    conn: &mut SqliteConnection,
+    name: &str,
+    index: &StoredManifest,
+    platform: Option<&ClientPlatform>,
+    accepted: &AcceptedTypes,
+) -> Option<StoredManifest> {
+    let data = storage.read_manifest(&index.file_path).await.ok()?;
+    let index: ImageManifest = serde_json::from_slice(&data).ok()?;
+    let mut candidates = index.manifests?.into_iter().filter(|child| {
+        child
+            .media_type
+            .as_deref()
+            .is_some_and(|media_type| accepted.accepts(media_type))
+    });
+    let child = match platform {
+        Some(wanted) => candidates.find(|child| {
+            child
+                .platform
+                .as_ref()
+                .is_some_and(|platform| wanted.matches(platform))
+        })?,
+        None => {
+            let only = candidates.next()?;
+            if candidates.next().is_some() {
+                return None;
+            }
+            only
+        }
+    };
+    sqlx::query_as!(
+        StoredManifest,
+        "SELECT file_path, digest, media_type, size FROM manifests
+         WHERE repository_id = (SELECT id FROM repositories WHERE name = ? AND deleted_at IS NULL) AND digest = ?",
+        name,
+        child.digest
+    )
+    .fetch_optional(conn)
+    .await
+    .ok()?
+}
+
 /// To pull an image from the registry, the client must send a GET request to the `/v2/<name>/manifests/<reference>`
 /// endpoint. The server must return the manifest of the image specified by the name and reference.
+/// The stored manifest is returned when the client's `Accept` allows its media type. An index the
+/// client can't take is resolved to the child manifest for its platform, if one can be chosen.
+/// `?platform=os/arch[/variant]` resolves an index to that platform's manifest instead.
 /// GET /v2/:name/manifests/:reference
+/// HEAD /v2/:name/manifests/:reference answers with the same headers and no body
 /// spec: 145-184
-#[tracing::instrument(skip(conn, blob_storage))]
+#[tracing::instrument(skip(conn, blob_storage, config))]
 pub async fn get_manifest(
     Path((name, reference)): Path<(String, String)>,
+    Query(params): Query<ManifestQuery>,
     Extension(blob_storage): Extension<Arc<Backend>>,
+    Extension(config): Extension<Arc<Config>>,
     DbConn(mut conn): DbConn,
     req: Request,
 ) -> impl IntoResponse {
+    let requested = match params.platform.as_deref() {
+        Some(platform) => match ClientPlatform::parse(platform) {
+            Some(platform) => Some(platform),
+            None => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    ErrorResponse::from_code(
+                        &Code::Unsupported,
+                        format!("invalid platform {platform}, expected os/arch[/variant]"),
+                    ),
+                )
+                    .into_response()
+            }
+        },
+        None => None,
+    };
+    let lookup = sqlx::query_as!(StoredManifest, "SELECT file_path, digest, media_type, size FROM manifests LEFT JOIN tags on tags.manifest_id = manifests.id WHERE manifests.repository_id = (SELECT id FROM repositories WHERE name = ? AND deleted_at IS NULL) AND (digest = $2 OR tags.tag = $2)", name, reference)
+        .fetch_one(&mut *conn);
+    let Ok(stored) = log_if_slow(config.slow_threshold(), "manifest resolution", lookup).await
+    else {
+        return ErrorResponse::from_code(
+            &crate::codes::Code::ManifestUnknown,
+            "unable to find manifest for image",
+        )
+        .into_response();
+    };
+    info!(
+        "found manifest for image reference: {} with file path : {:?}",
+        reference, stored.file_path
+    );
+    let accepted = AcceptedTypes::from_headers(req.headers());
+    let is_index = ManifestKind::from_media_type(&stored.media_type) == Some(ManifestKind::Index);
+    let record = if let Some(platform) = requested.as_ref().filter(|_| is_index) {
+        let Some(child) = platform_manifest(
+            &blob_storage,
+            &mut conn,
+            &name,
+            &stored,
+            Some(platform),
+            &accepted,
+        )
+        .await
+        else {
+            return ErrorResponse::from_code(
+                &Code::ManifestUnknown,
+                format!(
+                    "no acceptable manifest for {}",
+                    params.platform.unwrap_or_default()
+                ),
+            )
+            .into_response();
+        };
+        info!(
+            "resolved index {} to {} for the requested platform",
+            stored.digest, child.digest
+        );
+        child
+    } else if accepted.accepts(&stored.media_type) {
+        stored
+    } else if is_index {
+        match platform_manifest(
+            &blob_storage,
+            &mut conn,
+            &name,
+            &stored,
+            ClientPlatform::from_user_agent(req.headers()).as_ref(),
+            &accepted,
+        )
+        .await
+        {
+            Some(child) => {
+                info!(
+                    "resolved index {} to {} for the client",
+                    stored.digest, child.digest
+                );
+                child
+            }
+            None => return not_acceptable(&stored.media_type),
+        }
+    } else {
+        return not_acceptable(&stored.media_type);
+    };
     let mut headers = HeaderMap::new();
-    if let Ok(record) = sqlx::query!("SELECT file_path, digest, tags.tag FROM manifests JOIN tags on tags.manifest_id = manifests.id WHERE manifests.repository_id = (SELECT id FROM repositories WHERE name = ?) AND (digest = $2 OR tags.tag = $2)", name, reference)
-          .fetch_one(&mut *conn)
-          .await {
-        info!("found manifest for image reference: {} with file path : {:?}", reference, record.file_path);
-        headers.insert(DOCKER_DIGEST, record.digest.parse().unwrap());
-        headers.insert(CONTENT_TYPE, MANIFEST_CONTENT_TYPE.parse().unwrap());
-        match *req.method() {
-            http::Method::HEAD => {
-            return (StatusCode::OK, headers).into_response();
+    headers.insert(DOCKER_DIGEST, record.digest.parse().unwrap());
+    if let Ok(content_type) = record.media_type.parse() {
+        headers.insert(CONTENT_TYPE, content_type);
+    }
+    let etag = format!("\"{}\"", record.digest);
+    // a tag can move and an index can resolve to a platform's manifest, only
+    // a pull by the served digest is guaranteed to never change
+    let cache_control = if record.digest == reference {
+        immutable_cache_control(database::repository_is_public(&mut conn, &name).await)
+    } else {
+        REVALIDATE_CACHE_CONTROL
+    };
+    headers.insert(CACHE_CONTROL, cache_control);
+    if let Ok(value) = etag.parse() {
+        headers.insert(ETAG, value);
+    }
+    let revalidated = req
+        .headers()
+        .get(IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|tags| {
+            tags.split(',')
+                .any(|tag| tag.trim() == etag || tag.trim() == "*")
+        });
+    if revalidated {
+        return (StatusCode::NOT_MODIFIED, headers).into_response();
+    }
+    if req.method() == http::Method::HEAD {
+        headers.insert(CONTENT_LENGTH, record.size.into());
+        return (StatusCode::OK, headers).into_response();
+    }
+    match blob_storage.read_manifest(&record.file_path).await {
+        Ok(data) => {
+            info!("manifest read from storage for image: {}", reference);
+            if config.verify_manifests
+                && record.digest == reference
+                && validate_digest(&data, &record.digest).is_err()
+            {
+                error!(
+                    "stored manifest {} no longer matches its digest",
+                    record.file_path
+                );
+                return manifest_drifted(&record.digest);
             }
-            _ => {
-            match blob_storage.read_manifest(&record.file_path).await {
-                Ok(data) => {
-                    info!("manifest read from storage for image: {}", reference);
-                    headers.insert(CONTENT_LENGTH, data.len().into());
-                    return (StatusCode::OK, headers, data).into_response();
-                }
-                Err(_) => {
-                    error!("unable to find manifest with provided file_path and digest {} : {}", record.file_path, record.digest);
-                    let code = crate::codes::Code::ManifestUnknown;
-                    return ErrorResponse::from_code(&code, "unable to find manifest for image").into_response();
-                }
-            };
-           }
-       };
+            headers.insert(CONTENT_LENGTH, data.len().into());
+            (StatusCode::OK, headers, data).into_response()
+        }
+        Err(_) => {
+            error!(
+                "unable to find manifest with provided file_path and digest {} : {}",
+                record.file_path, record.digest
+            );
+            let code = crate::codes::Code::ManifestUnknown;
+            ErrorResponse::from_code(&code, "unable to find manifest for image").into_response()
+        }
     }
-    ErrorResponse::from_code(
-        &crate::codes::Code::ManifestUnknown,
-        "unable to find manifest for image",
+}
+
+/// answer for a manifest whose stored bytes changed since it was pushed
+fn manifest_drifted(digest: &str) -> axum::response::Response {
+    let mut response = (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        ErrorResponse::from_code(
+            &Code::ManifestInvalid,
+            format!("stored manifest no longer matches {digest}"),
+        ),
     )
-    .into_response()
+        .into_response();
+    response.headers_mut().insert(
+        WARNING,
+        HeaderValue::from_static("299 - \"stored manifest failed its integrity check\""),
+    );
+    response
+}
+
+fn not_acceptable(media_type: &str) -> axum::response::Response {
+    info!(
+        "client does not accept the stored manifest type {}",
+        media_type
+    );
+    (
+        StatusCode::NOT_ACCEPTABLE,
+        ErrorResponse::from_code(
+            &Code::ManifestUnknown,
+            format!("manifest is stored as {media_type}, which the Accept header excludes"),
+        ),
+    )
+        .into_response()
 }
 
 /// DELETE /v2/:name/manifests/:reference
-/// digest or tag can be used as reference
+/// digest or tag can be used as reference, subject to `--manifest-delete`
 /// spec: 688-715
 pub async fn delete_manifest(
     Path((name, reference)): Path<(String, String)>,
     Extension(storage): Extension<Arc<Backend>>,
+    Extension(config): Extension<Arc<Config>>,
     mut conn: DbConn,
 ) -> impl IntoResponse {
-    match conn.delete_manifest(&name, &reference).await {
-        Ok(file_path) => {
-            if let Err(err) = storage.delete_manifest(&file_path).await {
-                error!(
-                    "unable to delete manifest for image: {} \n {err}",
-                    reference
-                );
+    if config.disable_delete {
+        return deletes_disabled();
+    }
+    if !config.manifest_delete.allows(&reference) {
+        return (
+            StatusCode::METHOD_NOT_ALLOWED,
+            ErrorResponse::from_code(
+                &crate::codes::Code::Unsupported,
+                "manifest deletion by this reference is disabled",
+            ),
+        )
+            .into_response();
+    }
+    let cascade = config.referrer_delete == ReferrerDeletePolicy::Cascade;
+    match conn.delete_manifest(&name, &reference, cascade).await {
+        Ok(file_paths) => {
+            for file_path in file_paths {
+                if let Err(err) = storage.delete_manifest(&file_path).await {
+                    error!(
+                        "unable to delete manifest for image: {} \n {err}",
+                        reference
+                    );
+                }
             }
             info!("deleted manifest for image: {}", reference);
-            (StatusCode::NO_CONTENT).into_response()
+            (StatusCode::ACCEPTED, [(CONTENT_LENGTH, "0")]).into_response()
         }
         Err(e) => {
             error!("unable to delete manifest for image: {} \n {e}", reference);
@@ -123,3 +615,227 @@ pub async fn delete_manifest(
         }
     }
 }
+
+#[derive(Deserialize, Debug)]
+pub struct PromoteRequest {
+    to_repo: String,
+    tags: Vec<String>,
+    #[serde(default)]
+    overwrite: bool,
+}
+
+/// the outcome for one promoted tag, `error` is set when it failed
+#[derive(Serialize, Debug)]
+pub struct PromotedTag {
+    tag: String,
+    digest: Option<String>,
+    error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PromoteResponse {
+    promoted: bool,
+    tags: Vec<PromotedTag>,
+}
+
+/// POST /repositories/:name/promote
+/// copies tags to another repository in a single transaction, mounting the
+/// blobs each manifest needs. Either every tag is promoted, or nothing is
+/// and the response says which tags failed.
+pub async fn promote_tags(
+    Path(name): Path<String>,
+    Extension(storage): Extension<Arc<Backend>>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(auth): Extension<Auth>,
+    DbConn(mut conn): DbConn,
+    Json(req): Json<PromoteRequest>,
+) -> impl IntoResponse {
+    if !auth.can(&name, Action::Pull) || !auth.can(&req.to_repo, Action::Push) {
+        return ErrorResponse::from_code(
+            &Code::Denied,
+            "promoting needs pull access to the source and push access to the target",
+        )
+        .into_response();
+    }
+    if !is_valid_repository_name(&req.to_repo) || req.to_repo == name {
+        return (
+            StatusCode::BAD_REQUEST,
+            ErrorResponse::from_code(
+                &Code::NameInvalid,
+                format!("cannot promote into {}", req.to_repo),
+            ),
+        )
+            .into_response();
+    }
+    let mut tx = match conn.begin().await {
+        Ok(tx) => tx,
+        Err(err) => {
+            error!("unable to start promotion: {}", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "unable to promote tags").into_response();
+        }
+    };
+    let mut tags = Vec::with_capacity(req.tags.len());
+    for tag in req.tags {
+        let result = promote_tag(
+            &storage,
+            &mut tx,
+            &config,
+            &name,
+            &req.to_repo,
+            &tag,
+            req.overwrite,
+        )
+        .await;
+        let (digest, error) = match result {
+            Ok(digest) => (Some(digest), None),
+            Err(err) => {
+                info!("unable to promote {}:{}: {}", name, tag, err);
+                (None, Some(err))
+            }
+        };
+        tags.push(PromotedTag { tag, digest, error });
+    }
+    let promoted = tags.iter().all(|tag| tag.error.is_none());
+    if !promoted {
+        // dropping the transaction rolls back the tags that did succeed
+        return (
+            StatusCode::CONFLICT,
+            Json(PromoteResponse { promoted, tags }),
+        )
+            .into_response();
+    }
+    if let Err(err) = tx.commit().await {
+        error!("unable to commit promotion: {}", err);
+        return (StatusCode::INTERNAL_SERVER_ERROR, "unable to promote tags").into_response();
+    }
+    info!(
+        "promoted {} tags from {} to {}",
+        tags.len(),
+        name,
+        req.to_repo
+    );
+    Json(PromoteResponse { promoted, tags }).into_response()
+}
+
+/// Points `tag` in `target` at the manifest it names in `source`. An
+/// existing tag is only moved with `overwrite`.
+async fn promote_tag(
+    storage: &Backend,
+    conn: &mut SqliteConnection,
+    config: &Config,
+    source: &str,
+    target: &str,
+    tag: &str,
+    overwrite: bool,
+) -> Result<String, String> {
+    let stored = sqlx::query_as!(
+        StoredManifest,
+        "SELECT m.file_path, m.digest, m.media_type, m.size FROM manifests m
+         JOIN tags t ON t.manifest_id = m.id JOIN repositories r ON m.repository_id = r.id
+         WHERE r.name = ? AND r.deleted_at IS NULL AND t.tag = ?",
+        source,
+        tag
+    )
+    .fetch_optional(&mut *conn)
+    .await
+    .map_err(|err| err.to_string())?
+    .ok_or_else(|| format!("tag not found in {source}"))?;
+    let current = sqlx::query_scalar!(
+        "SELECT m.digest FROM manifests m
+         JOIN tags t ON t.manifest_id = m.id JOIN repositories r ON m.repository_id = r.id
+         WHERE r.name = ? AND r.deleted_at IS NULL AND t.tag = ?",
+        target,
+        tag
+    )
+    .fetch_optional(&mut *conn)
+    .await
+    .map_err(|err| err.to_string())?;
+    match current {
+        Some(digest) if digest == stored.digest => return Ok(digest),
+        Some(_) if !overwrite => {
+            return Err(format!(
+                "tag already exists in {target}, set overwrite to replace it"
+            ))
+        }
+        _ => {}
+    }
+    copy_manifest(storage, conn, config, source, target, &stored, tag)
+        .await
+        .map_err(|err| err.to_string())
+}
+
+/// what a manifest of any kind needs present in a repository to be pulled
+#[derive(Deserialize)]
+struct ManifestContent {
+    config: Option<Descriptor>,
+    #[serde(default)]
+    layers: Vec<Descriptor>,
+    #[serde(default)]
+    blobs: Vec<Descriptor>,
+    #[serde(default)]
+    manifests: Vec<Descriptor>,
+}
+
+/// Stores a manifest of `source` in `target` under `reference`, through the
+/// same write path as a push. Its blobs are mounted first, and the children
+/// of an index are copied by digest.
+async fn copy_manifest(
+    storage: &Backend,
+    conn: &mut SqliteConnection,
+    config: &Config,
+    source: &str,
+    target: &str,
+    stored: &StoredManifest,
+    reference: &str,
+) -> Result<String, StorageError> {
+    let data = storage.read_manifest(&stored.file_path).await?;
+    let content: ManifestContent = serde_json::from_slice(&data).map_err(|err| {
+        StorageError::IoError(std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+    })?;
+    for child in content.manifests {
+        let Some(child_stored) = sqlx::query_as!(
+            StoredManifest,
+            "SELECT file_path, digest, media_type, size FROM manifests
+             WHERE repository_id = (SELECT id FROM repositories WHERE name = ? AND deleted_at IS NULL) AND digest = ?",
+            source,
+            child.digest
+        )
+        .fetch_optional(&mut *conn)
+        .await?
+        else {
+            return Err(StorageError::BlobUnknown(child.digest));
+        };
+        Box::pin(copy_manifest(
+            storage,
+            conn,
+            config,
+            source,
+            target,
+            &child_stored,
+            &child.digest,
+        ))
+        .await?;
+    }
+    let blobs = content
+        .config
+        .into_iter()
+        .chain(content.layers)
+        .chain(content.blobs)
+        .filter(|blob| !is_foreign_layer(blob.media_type.as_deref()));
+    for blob in blobs {
+        storage
+            .mount_blob(conn, target, &blob.digest, Some(source))
+            .await
+            .map_err(|_| StorageError::BlobUnknown(blob.digest))?;
+    }
+    write_manifest(
+        storage,
+        conn,
+        config,
+        target,
+        reference,
+        &stored.media_type,
+        &data,
+    )
+    .await
+}
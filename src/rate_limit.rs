@@ -0,0 +1,152 @@
+//! Per-client request limits for `--rate-limit`. Requests are counted in a
+//! sliding window per token subject, or per peer address for anonymous
+//! callers and failed logins, and rejected with `TooManyRequests` once the
+//! limit is reached.
+use crate::{
+    auth::Auth,
+    codes::{Code, ErrorResponse},
+    config::Config,
+};
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use dashmap::DashMap;
+use http::{header::RETRY_AFTER, HeaderValue};
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::debug;
+
+/// the window `--rate-limit` counts requests in
+pub const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+/// clients tracked before idle ones are swept out
+const SWEEP_THRESHOLD: usize = 4096;
+
+#[derive(Default)]
+pub struct RateLimiter {
+    hits: DashMap<String, VecDeque<Instant>>,
+}
+
+impl RateLimiter {
+    /// Records a request from `client`, or returns how long it has to wait
+    /// when `limit` requests already fall within the window
+    pub fn check(&self, client: &str, limit: u32) -> Result<(), Duration> {
+        self.reserve(client, limit).map(|_| ())
+    }
+
+    /// Like `check`, returning the recorded hit so it can be handed back
+    /// with `release` if the request turns out to be charged elsewhere
+    pub fn reserve(&self, client: &str, limit: u32) -> Result<Instant, Duration> {
+        let now = Instant::now();
+        if self.hits.len() > SWEEP_THRESHOLD {
+            self.hits.retain(|_, hits| {
+                hits.back()
+                    .is_some_and(|last| now.duration_since(*last) < RATE_LIMIT_WINDOW)
+            });
+        }
+        let mut hits = self.hits.entry(client.to_string()).or_default();
+        while hits
+            .front()
+            .is_some_and(|first| now.duration_since(*first) >= RATE_LIMIT_WINDOW)
+        {
+            hits.pop_front();
+        }
+        if hits.len() >= limit as usize {
+            let oldest = hits.front().copied().unwrap_or(now);
+            return Err(RATE_LIMIT_WINDOW.saturating_sub(now.duration_since(oldest)));
+        }
+        hits.push_back(now);
+        Ok(now)
+    }
+
+    /// Gives back a hit recorded by `reserve`
+    pub fn release(&self, client: &str, hit: Instant) {
+        if let Some(mut hits) = self.hits.get_mut(client) {
+            if let Some(idx) = hits.iter().rposition(|at| *at == hit) {
+                hits.remove(idx);
+            }
+        }
+    }
+}
+
+/// Marks a response already counted against the caller's token subject
+#[derive(Clone, Copy, Debug)]
+struct ChargedToSubject;
+
+/// Rejects peer addresses over `--rate-limit` with a `Retry-After` header,
+/// requests go through untouched when no limit is set. This runs before
+/// authentication, so a refused login is charged to the address it came
+/// from, along with every other request not charged to a token subject.
+pub async fn rate_limit(
+    State(limiter): State<Arc<RateLimiter>>,
+    Extension(config): Extension<Arc<Config>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some(limit) = config.rate_limit else {
+        return next.run(req).await;
+    };
+    let client = match req.extensions().get::<ConnectInfo<SocketAddr>>() {
+        Some(ConnectInfo(addr)) => format!("ip:{}", addr.ip()),
+        None => String::from("anonymous"),
+    };
+    // the slot is taken before the request runs, so concurrent requests
+    // can't all slip in under the limit
+    let hit = match limiter.reserve(&client, limit) {
+        Ok(hit) => hit,
+        Err(wait) => {
+            debug!("rate limiting {}", client);
+            return too_many_requests(wait);
+        }
+    };
+    let response = next.run(req).await;
+    if response.extensions().get::<ChargedToSubject>().is_some() {
+        limiter.release(&client, hit);
+    }
+    response
+}
+
+/// Rejects authenticated callers over `--rate-limit` by their token subject,
+/// leaving everyone else to `rate_limit`
+pub async fn rate_limit_subject(
+    State(limiter): State<Arc<RateLimiter>>,
+    Extension(config): Extension<Arc<Config>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let client = req
+        .extensions()
+        .get::<Auth>()
+        .and_then(|auth| auth.subject())
+        .map(|sub| format!("sub:{sub}"));
+    let (Some(limit), Some(client)) = (config.rate_limit, client) else {
+        return next.run(req).await;
+    };
+    let mut response = match limiter.check(&client, limit) {
+        Ok(()) => next.run(req).await,
+        Err(wait) => {
+            debug!("rate limiting {}", client);
+            too_many_requests(wait)
+        }
+    };
+    response.extensions_mut().insert(ChargedToSubject);
+    response
+}
+
+fn too_many_requests(wait: Duration) -> Response {
+    let mut response =
+        ErrorResponse::from_code(&Code::TooManyRequests, "rate limit exceeded").into_response();
+    // round up so clients never retry a moment too early
+    let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    response
+        .headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(secs.max(1)));
+    response
+}
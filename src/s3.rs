@@ -0,0 +1,888 @@
+//! S3 storage driver, selected with `--driver s3`. Objects are kept in one
+//! bucket with the same layout the local driver uses on disk, keyed
+//! `<repo>/blobs/<digest>` and `<repo>/manifests/<digest>`, and the
+//! object key is what gets recorded as `file_path`. Requests are presigned
+//! with rusty-s3 and sent with reqwest, so any S3 compatible store works
+//! given `--s3-endpoint`.
+
+use crate::{
+    storage::{self, ensure_repository, ObjectStore, DIR_SIZE_TTL},
+    storage_driver::{BlobReader, StorageError},
+    util::{ByteRange, DigestHasher},
+};
+use axum::body::BodyDataStream;
+use bytes::Bytes;
+use futures::TryStreamExt;
+use reqwest::{header::ETAG, RequestBuilder, Response, StatusCode};
+use rusty_s3::{actions::CreateMultipartUpload, Bucket, Credentials, S3Action, UrlStyle};
+use sha2::{Digest, Sha256};
+use sqlx::{query, SqliteConnection};
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::io::StreamReader;
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+
+/// how long a presigned request stays valid, it only has to outlive sending it
+static PRESIGN_TTL: Duration = Duration::from_secs(300);
+/// uploads are buffered into parts of this size, above S3's 5MiB minimum
+static PART_SIZE: usize = 8 * 1024 * 1024;
+/// largest object a single CopyObject can copy
+static MAX_COPY_SIZE: u64 = 5 * 1024 * 1024 * 1024;
+
+/// Where the S3 driver keeps its objects. Read from the environment by
+/// `from_env` and overridden by the `--s3-*` flags.
+#[derive(Clone, Default, serde::Serialize)]
+pub struct S3Settings {
+    pub bucket: Option<String>,
+    pub region: String,
+    /// custom endpoint for S3 compatible stores, addressed path-style.
+    /// AWS is used when unset.
+    pub endpoint: Option<String>,
+    #[serde(serialize_with = "redacted")]
+    pub access_key: Option<String>,
+    #[serde(serialize_with = "redacted")]
+    pub secret_key: Option<String>,
+}
+
+fn redacted<S: serde::Serializer>(value: &Option<String>, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(match value {
+        Some(_) => "<redacted>",
+        None => "<unset>",
+    })
+}
+
+impl S3Settings {
+    /// Reads `S3_BUCKET`, `S3_REGION` (or `AWS_REGION`, defaulting to
+    /// us-east-1), `S3_ENDPOINT`, `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`
+    pub fn from_env() -> Self {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+        Self {
+            bucket: var("S3_BUCKET"),
+            region: var("S3_REGION")
+                .or_else(|| var("AWS_REGION"))
+                .unwrap_or_else(|| "us-east-1".to_string()),
+            endpoint: var("S3_ENDPOINT"),
+            access_key: var("AWS_ACCESS_KEY_ID"),
+            secret_key: var("AWS_SECRET_ACCESS_KEY"),
+        }
+    }
+}
+
+/// A blob being written as a multipart upload. Data is buffered until a
+/// full part is ready, and the upload itself is only created once the
+/// first part is, so a blob smaller than a part is written with one put.
+struct MultipartUpload {
+    /// key the parts are assembled under, before moving to the blob's key
+    key: String,
+    upload_id: Option<String>,
+    etags: Vec<String>,
+    buffer: Vec<u8>,
+    hasher: DigestHasher,
+    size: u64,
+}
+
+impl MultipartUpload {
+    fn new(name: &str, id: &str, hasher: DigestHasher) -> Self {
+        Self {
+            key: format!("{name}/blobs/uploads/{id}"),
+            upload_id: None,
+            etags: Vec::new(),
+            buffer: Vec::new(),
+            hasher,
+            size: 0,
+        }
+    }
+}
+
+fn blob_key(name: &str, digest: &str) -> String {
+    format!("{name}/blobs/{digest}")
+}
+
+/// Percent-encodes a key for the `x-amz-copy-source` header
+fn encode_key(key: &str) -> String {
+    key.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+/// The text of every `<tag>` element in an XML response. Listings are read
+/// this way rather than with a full parser, as S3 compatible stores differ
+/// in which of the other fields they include.
+fn xml_values<'a>(body: &'a str, tag: &str) -> Vec<&'a str> {
+    let (open, close) = (format!("<{tag}>"), format!("</{tag}>"));
+    body.split(open.as_str())
+        .skip(1)
+        .filter_map(|rest| rest.split_once(close.as_str()).map(|(value, _)| value))
+        .collect()
+}
+
+fn not_in_progress(session_id: &str) -> StorageError {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("upload session {session_id} is not in progress"),
+    )
+    .into()
+}
+
+pub struct S3StorageDriver {
+    bucket: Bucket,
+    credentials: Option<Credentials>,
+    client: reqwest::Client,
+    /// `s3://<bucket>`, what repository sizes and paths are reported under
+    base_path: PathBuf,
+    /// chunked uploads in progress, by session id. These only live in
+    /// memory, so sessions don't survive a restart.
+    uploads: Mutex<HashMap<String, Arc<tokio::sync::Mutex<MultipartUpload>>>>,
+    dir_sizes: Mutex<HashMap<PathBuf, (Instant, u64)>>,
+    /// result of the last storage probe, writes are refused while false
+    healthy: AtomicBool,
+}
+
+impl ObjectStore for S3StorageDriver {
+    async fn read_object(&self, path: &str) -> Result<Vec<u8>, StorageError> {
+        let url = self
+            .bucket
+            .get_object(self.credentials(), path)
+            .sign(PRESIGN_TTL);
+        let response = self.send(self.client.get(url)).await?;
+        Ok(response.bytes().await.map_err(io::Error::other)?.to_vec())
+    }
+
+    async fn open_object(
+        &self,
+        path: &str,
+    ) -> Result<(u64, impl AsyncRead + Unpin + Send + 'static), StorageError> {
+        let url = self
+            .bucket
+            .get_object(self.credentials(), path)
+            .sign(PRESIGN_TTL);
+        let response = self.send(self.client.get(url)).await?;
+        let size = response.content_length().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("no content length for {path}"),
+            )
+        })?;
+        Ok((
+            size,
+            StreamReader::new(Box::pin(response.bytes_stream().map_err(io::Error::other))),
+        ))
+    }
+
+    async fn open_object_range(
+        &self,
+        path: &str,
+        start: u64,
+        end: u64,
+    ) -> Result<impl AsyncRead + Unpin + Send + 'static, StorageError> {
+        let url = self
+            .bucket
+            .get_object(self.credentials(), path)
+            .sign(PRESIGN_TTL);
+        let response = self
+            .send(
+                self.client
+                    .get(url)
+                    .header(reqwest::header::RANGE, format!("bytes={start}-{end}")),
+            )
+            .await?;
+        Ok(StreamReader::new(Box::pin(
+            response.bytes_stream().map_err(io::Error::other),
+        )))
+    }
+
+    async fn object_size(&self, path: &str) -> Result<u64, StorageError> {
+        let url = self
+            .bucket
+            .head_object(self.credentials(), path)
+            .sign(PRESIGN_TTL);
+        let response = self.send(self.client.head(url)).await?;
+        response
+            .headers()
+            .get(reqwest::header::CONTENT_LENGTH)
+            .and_then(|size| size.to_str().ok()?.parse().ok())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("no content length for {path}"),
+                )
+                .into()
+            })
+    }
+
+    async fn remove_object(&self, path: &str) -> io::Result<()> {
+        let url = self
+            .bucket
+            .delete_object(self.credentials(), path)
+            .sign(PRESIGN_TTL);
+        match self.send(self.client.delete(url)).await {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                debug!("{} was already removed", path);
+                Ok(())
+            }
+            result => result.map(|_| ()),
+        }
+    }
+
+    async fn put_manifest_object(
+        &self,
+        name: &str,
+        digest: &str,
+        data: &[u8],
+    ) -> Result<String, StorageError> {
+        let key = format!("{name}/manifests/{digest}");
+        self.put(&key, Bytes::copy_from_slice(data)).await?;
+        Ok(key)
+    }
+}
+
+impl S3StorageDriver {
+    pub fn new(settings: &S3Settings) -> Result<Self, StorageError> {
+        let invalid = |reason: String| io::Error::new(io::ErrorKind::InvalidInput, reason);
+        let name = settings
+            .bucket
+            .clone()
+            .ok_or_else(|| invalid("the s3 driver needs a bucket, set --s3-bucket".into()))?;
+        let (endpoint, style) = match &settings.endpoint {
+            Some(endpoint) => (endpoint.clone(), UrlStyle::Path),
+            None => (
+                format!("https://s3.{}.amazonaws.com", settings.region),
+                UrlStyle::VirtualHost,
+            ),
+        };
+        let endpoint = endpoint
+            .parse()
+            .map_err(|err| invalid(format!("invalid s3 endpoint {endpoint}: {err}")))?;
+        let bucket = Bucket::new(endpoint, style, name.clone(), settings.region.clone())
+            .map_err(|err| invalid(format!("invalid s3 bucket {name}: {err}")))?;
+        let credentials = match (&settings.access_key, &settings.secret_key) {
+            (Some(key), Some(secret)) => Some(Credentials::new(key, secret)),
+            (None, None) => None,
+            _ => {
+                return Err(
+                    invalid("s3 access key and secret key must be given together".into()).into(),
+                )
+            }
+        };
+        Ok(Self {
+            bucket,
+            credentials,
+            client: reqwest::Client::new(),
+            base_path: PathBuf::from(format!("s3://{name}")),
+            uploads: Mutex::new(HashMap::new()),
+            dir_sizes: Mutex::new(HashMap::new()),
+            healthy: AtomicBool::new(true),
+        })
+    }
+
+    fn credentials(&self) -> Option<&Credentials> {
+        self.credentials.as_ref()
+    }
+
+    /// Sends a request, turning an error response into an io error that
+    /// keeps S3's explanation. A missing object is `NotFound`.
+    async fn send(&self, request: RequestBuilder) -> io::Result<Response> {
+        let response = request.send().await.map_err(io::Error::other)?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let body = response.text().await.unwrap_or_default();
+        let kind = match status {
+            StatusCode::NOT_FOUND => io::ErrorKind::NotFound,
+            _ => io::ErrorKind::Other,
+        };
+        Err(io::Error::new(
+            kind,
+            format!("s3 returned {status}: {body}"),
+        ))
+    }
+
+    async fn put(&self, key: &str, data: Bytes) -> io::Result<()> {
+        let url = self
+            .bucket
+            .put_object(self.credentials(), key)
+            .sign(PRESIGN_TTL);
+        self.send(self.client.put(url).body(data)).await?;
+        Ok(())
+    }
+
+    /// Copies an object within the bucket. S3 can report a failed copy in
+    /// the body of a 200, so that is checked too.
+    async fn copy(&self, from: &str, to: &str) -> io::Result<()> {
+        let source = format!("/{}/{}", self.bucket.name(), encode_key(from));
+        let mut action = self.bucket.put_object(self.credentials(), to);
+        action
+            .headers_mut()
+            .insert("x-amz-copy-source", source.clone());
+        let url = action.sign(PRESIGN_TTL);
+        let response = self
+            .send(self.client.put(url).header("x-amz-copy-source", source))
+            .await?;
+        let body = response.text().await.map_err(io::Error::other)?;
+        if body.contains("<Error>") {
+            return Err(io::Error::other(format!("copying {from} failed: {body}")));
+        }
+        Ok(())
+    }
+
+    /// Adds data to an upload, sending a part each time a full one is buffered
+    async fn push(&self, upload: &mut MultipartUpload, data: &[u8]) -> io::Result<()> {
+        upload.hasher.update(data);
+        upload.size += data.len() as u64;
+        upload.buffer.extend_from_slice(data);
+        while upload.buffer.len() >= PART_SIZE {
+            let rest = upload.buffer.split_off(PART_SIZE);
+            let part = std::mem::replace(&mut upload.buffer, rest);
+            self.upload_part(upload, part).await?;
+        }
+        Ok(())
+    }
+
+    async fn upload_part(&self, upload: &mut MultipartUpload, part: Vec<u8>) -> io::Result<()> {
+        let upload_id = match &upload.upload_id {
+            Some(upload_id) => upload_id.clone(),
+            None => {
+                let url = self
+                    .bucket
+                    .create_multipart_upload(self.credentials(), &upload.key)
+                    .sign(PRESIGN_TTL);
+                let body = self
+                    .send(self.client.post(url))
+                    .await?
+                    .text()
+                    .await
+                    .map_err(io::Error::other)?;
+                let created = CreateMultipartUpload::parse_response(&body)
+                    .map_err(|err| io::Error::other(err.to_string()))?;
+                debug!("started multipart upload of {}", upload.key);
+                upload
+                    .upload_id
+                    .insert(created.upload_id().to_owned())
+                    .clone()
+            }
+        };
+        let part_number = u16::try_from(upload.etags.len() + 1)
+            .map_err(|_| io::Error::other("blob has too many parts"))?;
+        let url = self
+            .bucket
+            .upload_part(self.credentials(), &upload.key, part_number, &upload_id)
+            .sign(PRESIGN_TTL);
+        let response = self.send(self.client.put(url).body(part)).await?;
+        let etag = response
+            .headers()
+            .get(ETAG)
+            .and_then(|etag| etag.to_str().ok())
+            .ok_or_else(|| io::Error::other("s3 returned no etag for part"))?;
+        upload.etags.push(etag.to_owned());
+        Ok(())
+    }
+
+    /// Completes an upload as the blob it hashes to, returning the digest
+    /// and the key it was stored under. With `expected` the digest is
+    /// checked first, and nothing is stored under the blob's key on a
+    /// mismatch. Blobs too large to copy keep their upload key.
+    async fn finish(
+        &self,
+        name: &str,
+        upload: &mut MultipartUpload,
+        expected: Option<&str>,
+    ) -> Result<(String, String), StorageError> {
+        let digest = std::mem::take(&mut upload.hasher).finalize();
+        let result = async {
+            if expected.is_some_and(|expected| expected != digest) {
+                return Err(StorageError::DigestError);
+            }
+            let key = blob_key(name, &digest);
+            let buffer = std::mem::take(&mut upload.buffer);
+            let Some(upload_id) = upload.upload_id.clone() else {
+                self.put(&key, buffer.into()).await?;
+                return Ok(key);
+            };
+            if !buffer.is_empty() {
+                self.upload_part(upload, buffer).await?;
+            }
+            let action = self.bucket.complete_multipart_upload(
+                self.credentials(),
+                &upload.key,
+                &upload_id,
+                upload.etags.iter().map(String::as_str),
+            );
+            let url = action.sign(PRESIGN_TTL);
+            let body = self
+                .send(self.client.post(url).body(action.body()))
+                .await?
+                .text()
+                .await
+                .map_err(io::Error::other)?;
+            if body.contains("<Error>") {
+                return Err(
+                    io::Error::other(format!("completing {} failed: {body}", upload.key)).into(),
+                );
+            }
+            // the upload is complete, there is nothing left to abort
+            upload.upload_id = None;
+            if upload.size > MAX_COPY_SIZE {
+                info!("{} is too large to copy, keeping {}", digest, upload.key);
+                return Ok(upload.key.clone());
+            }
+            self.copy(&upload.key, &key).await?;
+            if let Err(err) = self.remove_object(&upload.key).await {
+                warn!("unable to remove upload {}: {}", upload.key, err);
+            }
+            Ok(key)
+        }
+        .await;
+        if result.is_err() {
+            self.abort(upload).await;
+        }
+        result.map(|key| (digest, key))
+    }
+
+    /// Abandons an upload, dropping any parts S3 already holds for it
+    async fn abort(&self, upload: &MultipartUpload) {
+        let Some(upload_id) = &upload.upload_id else {
+            return;
+        };
+        let url = self
+            .bucket
+            .abort_multipart_upload(self.credentials(), &upload.key, upload_id)
+            .sign(PRESIGN_TTL);
+        if let Err(err) = self.send(self.client.delete(url)).await {
+            error!("unable to abort upload of {}: {}", upload.key, err);
+        }
+    }
+
+    async fn push_stream(
+        &self,
+        upload: &mut MultipartUpload,
+        data: BodyDataStream,
+    ) -> Result<String, StorageError> {
+        let mut hasher = Sha256::new();
+        let mut data = data.map_err(io::Error::other);
+        while let Some(bytes) = data.try_next().await? {
+            hasher.update(&bytes);
+            self.push(upload, &bytes).await?;
+        }
+        Ok(format!("sha256:{:x}", hasher.finalize()))
+    }
+
+    fn session(
+        &self,
+        session_id: &str,
+    ) -> Result<Arc<tokio::sync::Mutex<MultipartUpload>>, StorageError> {
+        self.uploads
+            .lock()
+            .ok()
+            .and_then(|uploads| uploads.get(session_id).cloned())
+            .ok_or_else(|| not_in_progress(session_id))
+    }
+
+    fn take_session(
+        &self,
+        session_id: &str,
+    ) -> Result<Arc<tokio::sync::Mutex<MultipartUpload>>, StorageError> {
+        self.uploads
+            .lock()
+            .ok()
+            .and_then(|mut uploads| uploads.remove(session_id))
+            .ok_or_else(|| not_in_progress(session_id))
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    /// Checks the bucket is still writable by putting and removing a small
+    /// object, and records the result for `is_healthy`.
+    pub async fn probe_health(&self) -> bool {
+        let probe = ".floundr-probe";
+        let result = async {
+            self.put(probe, Bytes::from_static(b"ok")).await?;
+            self.remove_object(probe).await
+        }
+        .await;
+        let healthy = match result {
+            Ok(()) => true,
+            Err(err) => {
+                error!("storage at {:?} is not writable: {}", self.base_path, err);
+                false
+            }
+        };
+        if self.healthy.swap(healthy, Ordering::Relaxed) != healthy && healthy {
+            info!("storage at {:?} is writable again", self.base_path);
+        }
+        healthy
+    }
+
+    pub fn base_path(&self) -> &PathBuf {
+        &self.base_path
+    }
+
+    /// Total size of the objects under `path`, which is relative to
+    /// `base_path` like the local driver's directories
+    pub async fn get_dir_size(&self, path: impl Into<PathBuf>) -> u64 {
+        let path = path.into();
+        if let Some((computed, size)) = self
+            .dir_sizes
+            .lock()
+            .ok()
+            .and_then(|sizes| sizes.get(&path).copied())
+        {
+            if computed.elapsed() < DIR_SIZE_TTL {
+                return size;
+            }
+        }
+        let prefix = format!(
+            "{}/",
+            path.strip_prefix(&self.base_path)
+                .unwrap_or(&path)
+                .to_string_lossy()
+        );
+        let size = match self.prefix_size(&prefix).await {
+            Ok(size) => size,
+            Err(err) => {
+                error!("error listing {}: {}", prefix, err);
+                0
+            }
+        };
+        if let Ok(mut sizes) = self.dir_sizes.lock() {
+            sizes.retain(|_, (computed, _)| computed.elapsed() < DIR_SIZE_TTL);
+            sizes.insert(path, (Instant::now(), size));
+        }
+        size
+    }
+
+    async fn prefix_size(&self, prefix: &str) -> io::Result<u64> {
+        let mut total = 0;
+        let mut token = None;
+        loop {
+            let mut action = self.bucket.list_objects_v2(self.credentials());
+            action.with_prefix(prefix);
+            if let Some(token) = token.take() {
+                action.with_continuation_token(token);
+            }
+            let url = action.sign(PRESIGN_TTL);
+            let body = self
+                .send(self.client.get(url))
+                .await?
+                .text()
+                .await
+                .map_err(io::Error::other)?;
+            total += xml_values(&body, "Size")
+                .into_iter()
+                .filter_map(|size| size.parse::<u64>().ok())
+                .sum::<u64>();
+            match xml_values(&body, "NextContinuationToken").first() {
+                Some(next) => token = Some(next.to_string()),
+                None => return Ok(total),
+            }
+        }
+    }
+
+    /// Size of a stored blob, if it's still there
+    pub async fn blob_size(&self, file_path: &str) -> Option<u64> {
+        self.object_size(file_path).await.ok()
+    }
+
+    /// Adds a chunk to the session's upload. Chunks are appended in the
+    /// order they arrive, which the upload handlers already enforce.
+    pub async fn write_blob(
+        &self,
+        name: &str,
+        session_id: &str,
+        _chunk: i64,
+        pool: &mut SqliteConnection,
+        data: BodyDataStream,
+    ) -> Result<String, StorageError> {
+        let upload = self.session(session_id)?;
+        let mut upload = upload.lock().await;
+        let before = upload.size;
+        let digest = match self.push_stream(&mut upload, data).await {
+            Ok(digest) => digest,
+            Err(err) => {
+                // part of the chunk may already be hashed and sent, so the
+                // upload can't be resumed from where the chunk started
+                error!("chunk of upload {} failed: {}", session_id, err);
+                drop(upload);
+                if let Ok(upload) = self.take_session(session_id) {
+                    self.abort(&*upload.lock().await).await;
+                }
+                storage::mark_upload_corrupt(pool, session_id).await?;
+                return Err(err);
+            }
+        };
+        let size = upload.size - before;
+        if let Err(err) = storage::check_quota(pool, name, size, None).await {
+            // the upload can't complete within the quota, so end it here
+            drop(upload);
+            self.cancel_session(pool, name, session_id).await?;
+            return Err(err);
+        }
+        let (size, offset) = (size as i64, before as i64);
+        query!("INSERT INTO blobs (repository_id, digest, file_path, upload_session_id, size, chunk_offset) VALUES ((SELECT id FROM repositories WHERE name = ? AND deleted_at IS NULL), ?, ?, ?, ?, ?)", name, digest, upload.key, session_id, size, offset)
+        .execute(pool)
+        .await?;
+        Ok(digest)
+    }
+
+    pub async fn write_blob_without_session_id(
+        &self,
+        pool: &mut SqliteConnection,
+        name: &str,
+        digest: &str,
+        data: BodyDataStream,
+    ) -> Result<String, StorageError> {
+        let hasher = DigestHasher::for_digest(digest).ok_or(StorageError::DigestError)?;
+        let mut upload = MultipartUpload::new(name, &Uuid::new_v4().to_string(), hasher);
+        let pushed = async {
+            self.push_stream(&mut upload, data).await?;
+            storage::check_quota(pool, name, upload.size, None).await
+        }
+        .await;
+        if let Err(err) = pushed {
+            self.abort(&upload).await;
+            return Err(err);
+        }
+        let size = upload.size as i64;
+        let (digest, file_path) = match self.finish(name, &mut upload, Some(digest)).await {
+            Ok(stored) => stored,
+            Err(err) => {
+                error!("monolithic upload failed validation: {err}");
+                return Err(err);
+            }
+        };
+        query!("INSERT INTO blobs (repository_id, digest, file_path, size) VALUES ((SELECT id FROM repositories WHERE name = ? AND deleted_at IS NULL), ?, ?, ?)", name, digest, file_path, size)
+        .execute(pool)
+        .await?;
+        Ok(digest)
+    }
+
+    pub async fn read_blob(
+        &self,
+        pool: &mut SqliteConnection,
+        name: &str,
+        digest: &str,
+        range: Option<ByteRange>,
+    ) -> Result<BlobReader, StorageError> {
+        storage::read_blob(self, pool, name, digest, range).await
+    }
+
+    pub async fn read_manifest(&self, path: &str) -> Result<Vec<u8>, StorageError> {
+        self.read_object(path).await
+    }
+
+    pub async fn new_session(
+        &self,
+        conn: &mut SqliteConnection,
+        name: &str,
+    ) -> Result<String, StorageError> {
+        ensure_repository(conn, name).await?;
+        let session_id = Uuid::new_v4().to_string();
+        info!("creating new session with id: {}", session_id);
+        query!("INSERT INTO uploads (repository_id, uuid) VALUES ((SELECT id FROM repositories WHERE name = ? AND deleted_at IS NULL), ?)", name, session_id)
+            .execute(conn)
+            .await?;
+        if let Ok(mut uploads) = self.uploads.lock() {
+            uploads.insert(
+                session_id.clone(),
+                Arc::new(tokio::sync::Mutex::new(MultipartUpload::new(
+                    name,
+                    &session_id,
+                    DigestHasher::default(),
+                ))),
+            );
+        }
+        Ok(session_id)
+    }
+
+    /// Drops an upload session along with any parts already sent for it
+    pub async fn cancel_session(
+        &self,
+        conn: &mut SqliteConnection,
+        name: &str,
+        session_id: &str,
+    ) -> Result<bool, StorageError> {
+        if !storage::drop_upload_session(conn, name, session_id).await? {
+            return Ok(false);
+        }
+        if let Ok(upload) = self.take_session(session_id) {
+            self.abort(&*upload.lock().await).await;
+        }
+        Ok(true)
+    }
+
+    /// Chunks are parts of a multipart upload held by S3 and can't be read
+    /// back, so this only checks the upload is still in progress. Its parts
+    /// are tracked in memory, a session that outlived a restart can't go on.
+    pub async fn verify_upload_session(
+        &self,
+        pool: &mut SqliteConnection,
+        name: &str,
+        session_id: &str,
+        _full: bool,
+    ) -> Result<(), StorageError> {
+        storage::ensure_upload_intact(pool, name, session_id).await?;
+        if self.session(session_id).is_err() {
+            storage::mark_upload_corrupt(pool, session_id).await?;
+            return Err(StorageError::UploadCorrupt);
+        }
+        Ok(())
+    }
+
+    pub async fn combine_chunks(
+        &self,
+        pool: &mut SqliteConnection,
+        name: &str,
+        session_id: &str,
+    ) -> Result<String, StorageError> {
+        storage::ensure_upload_intact(pool, name, session_id).await?;
+        // the session ends here either way, a failed upload is aborted
+        let upload = self.take_session(session_id)?;
+        let mut upload = upload.lock().await;
+        let size = upload.size;
+        if let Err(err) = storage::check_quota(pool, name, size, Some(session_id)).await {
+            self.abort(&upload).await;
+            storage::drop_upload_session(pool, name, session_id).await?;
+            return Err(err);
+        }
+        let (digest, file_path) = self.finish(name, &mut upload, None).await?;
+        let size = size as i64;
+        query!("DELETE FROM blobs WHERE upload_session_id = ?", session_id)
+            .execute(&mut *pool)
+            .await?;
+        query!("INSERT INTO blobs (repository_id, digest, file_path, size) VALUES ((SELECT id FROM repositories WHERE name = ? AND deleted_at IS NULL), ?, ?, ?)", name, digest, file_path, size)
+        .execute(&mut *pool)
+        .await?;
+        query!("DELETE FROM uploads WHERE uuid = ?", session_id)
+            .execute(pool)
+            .await?;
+        Ok(digest)
+    }
+
+    pub async fn mount_blob(
+        &self,
+        pool: &mut SqliteConnection,
+        target_name: &str,
+        digest: &str,
+        source_name: Option<&str>,
+    ) -> Result<String, StorageError> {
+        storage::mount_blob(pool, target_name, digest, source_name).await
+    }
+
+    pub async fn write_image_manifest(
+        &self,
+        pool: &mut SqliteConnection,
+        name: &str,
+        reference: &str,
+        media_type: &str,
+        data: &[u8],
+        with_diff_ids: bool,
+    ) -> Result<String, StorageError> {
+        storage::write_image_manifest(self, pool, name, reference, media_type, data, with_diff_ids)
+            .await
+    }
+
+    pub async fn write_image_index(
+        &self,
+        pool: &mut SqliteConnection,
+        name: &str,
+        reference: &str,
+        media_type: &str,
+        data: &[u8],
+    ) -> Result<String, StorageError> {
+        storage::write_image_index(self, pool, name, reference, media_type, data).await
+    }
+
+    pub async fn write_artifact_manifest(
+        &self,
+        pool: &mut SqliteConnection,
+        name: &str,
+        reference: &str,
+        media_type: &str,
+        data: &[u8],
+    ) -> Result<String, StorageError> {
+        storage::write_artifact_manifest(self, pool, name, reference, media_type, data).await
+    }
+
+    pub async fn manifest_closure(
+        &self,
+        pool: &mut SqliteConnection,
+        name: &str,
+        reference: &str,
+    ) -> Result<Vec<String>, StorageError> {
+        storage::manifest_closure(self, pool, name, reference).await
+    }
+
+    pub async fn delete_blob(
+        &self,
+        pool: &mut SqliteConnection,
+        name: &str,
+        digest: &str,
+    ) -> Result<(), StorageError> {
+        storage::delete_blob(self, pool, name, digest).await
+    }
+
+    pub async fn export_repository<W>(
+        &self,
+        pool: &mut SqliteConnection,
+        name: &str,
+        writer: W,
+    ) -> Result<(), StorageError>
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        storage::export_repository(self, pool, name, writer).await
+    }
+
+    pub async fn delete_manifest(&self, file_path: &str) -> Result<(), StorageError> {
+        self.remove_object(file_path).await?;
+        Ok(())
+    }
+
+    /// Buckets have no directories, so only the row is needed
+    pub async fn create_repository(
+        &self,
+        pool: &mut SqliteConnection,
+        name: &str,
+        is_pub: bool,
+    ) -> Result<(), StorageError> {
+        query!(
+            "INSERT INTO repositories (name, is_public) VALUES (?, ?)",
+            name,
+            is_pub
+        )
+        .execute(pool)
+        .await?;
+        debug!("Created new repository: {}", name);
+        Ok(())
+    }
+
+    pub async fn delete_repository(
+        &self,
+        id: i64,
+        conn: &mut SqliteConnection,
+    ) -> Result<Vec<String>, StorageError> {
+        storage::delete_repository(self, id, conn).await
+    }
+
+    pub async fn run_garbage_collection(
+        &self,
+        pool: &mut SqliteConnection,
+    ) -> Result<usize, StorageError> {
+        storage::collect_garbage(self, pool).await
+    }
+}
@@ -1,6 +1,21 @@
 pub static OCI_CONTENT_HEADER: &str = "application/vnd.oci.image.index.v1+json";
 pub static DOCKER_DIGEST: &str = "Docker-Content-Digest";
+pub static API_VERSION_HEADER: &str = "Docker-Distribution-API-Version";
+pub static FLOUNDR_VERSION_HEADER: &str = "Floundr-Version";
 pub static MANIFEST_CONTENT_TYPE: &str = "application/vnd.docker.distribution.manifest.v2+json";
+pub static OCI_MANIFEST_CONTENT_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+pub static DOCKER_MANIFEST_LIST_CONTENT_TYPE: &str =
+    "application/vnd.docker.distribution.manifest.list.v2+json";
+pub static OCI_ARTIFACT_MANIFEST_CONTENT_TYPE: &str =
+    "application/vnd.oci.artifact.manifest.v1+json";
+/// every manifest media type the registry will accept on push
+pub static MANIFEST_MEDIA_TYPES: [&str; 5] = [
+    OCI_MANIFEST_CONTENT_TYPE,
+    OCI_CONTENT_HEADER,
+    MANIFEST_CONTENT_TYPE,
+    DOCKER_MANIFEST_LIST_CONTENT_TYPE,
+    OCI_ARTIFACT_MANIFEST_CONTENT_TYPE,
+];
 use chrono::NaiveDateTime;
 use serde::{self, Deserialize, Serialize};
 use std::collections::HashMap;
@@ -17,6 +32,8 @@ pub struct Repo {
     pub disk_usage: usize,
     pub driver: String,
     pub num_layers: i64,
+    #[serde(default)]
+    pub max_bytes: Option<i64>,
 }
 impl Repo {
     pub fn calculate_mb(&self) -> f64 {
@@ -30,8 +47,18 @@ impl Repo {
 pub struct ImageManifest {
     pub schema_version: i32,
     pub media_type: Option<String>,
+    /// the kind of artifact an image manifest or index describes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artifact_type: Option<String>,
     pub config: Option<Descriptor>,
+    #[serde(default)]
     pub layers: Vec<Descriptor>,
+    /// child manifests, only present on image indexes/manifest lists
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manifests: Option<Vec<Descriptor>>,
+    /// the manifest this one refers to, e.g. the image a signature signs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject: Option<Descriptor>,
     pub annotations: Option<HashMap<String, String>>,
 }
 impl Default for ImageManifest {
@@ -39,23 +66,56 @@ impl Default for ImageManifest {
         ImageManifest {
             schema_version: 2,
             media_type: Some(MANIFEST_CONTENT_TYPE.to_string()),
+            artifact_type: None,
             config: Some(Descriptor {
                 media_type: Some("application/vnd.oci.image.config.v2+json".to_string()),
                 size: 0,
                 digest: "".to_string(),
+                ..Default::default()
             }),
             layers: Vec::new(),
+            manifests: None,
+            subject: None,
             annotations: None,
         }
     }
 }
 
+/// An OCI artifact manifest: arbitrary blobs, optionally attached to
+/// another manifest through `subject` (signatures, SBOMs, ...)
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ArtifactManifest {
+    pub media_type: Option<String>,
+    pub artifact_type: String,
+    #[serde(default)]
+    pub blobs: Vec<Descriptor>,
+    pub subject: Option<Descriptor>,
+    pub annotations: Option<HashMap<String, String>>,
+}
+
 #[derive(Deserialize, Serialize, Default, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Descriptor {
     pub media_type: Option<String>,
     pub size: i32,
     pub digest: String,
+    /// the platform a child manifest of an index is built for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<Platform>,
+    /// set on the referrers listed by the referrers API
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artifact_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<HashMap<String, String>>,
+}
+
+#[derive(Deserialize, Serialize, Default, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Platform {
+    pub architecture: String,
+    pub os: String,
+    pub variant: Option<String>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -79,9 +139,10 @@ pub struct Config {
     pub labels: Option<HashMap<String, String>>,
 }
 
+/// the image spec names these fields in snake case, unlike the rest of the config
 #[derive(Deserialize, Serialize, Debug, Clone)]
-#[serde(rename_all = "camelCase")]
 pub struct RootFS {
+    #[serde(rename = "type")]
     pub type_: String,
     pub diff_ids: Vec<String>,
 }
@@ -103,6 +164,8 @@ pub struct User {
     pub email: String,
     #[serde(skip_serializing, default = "String::new")]
     pub password: String,
+    #[serde(default)]
+    pub namespace: Option<String>,
     #[serde(skip)]
     pub created_at: NaiveDateTime,
 }
@@ -125,6 +188,7 @@ impl User {
             is_admin: admin,
             email: email.to_string(),
             password: password.to_string(),
+            namespace: None,
             created_at: chrono::Utc::now().naive_utc(),
         }
     }
@@ -135,7 +199,15 @@ impl User {
     }
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+/// One repository an API key is limited to, with the actions it may perform
+/// there, e.g. `{"repo": "x", "scope": ["pull"]}`
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct KeyScope {
+    pub repo: String,
+    pub scope: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct AuthClient {
     #[serde(skip)]
     pub id: i64,
@@ -168,11 +240,26 @@ impl Default for AuthClient {
     }
 }
 
+/// Body of the admin `POST /users` endpoint
+#[derive(Deserialize, Serialize, Debug)]
+pub struct CreateUserRequest {
+    pub email: String,
+    pub password: String,
+    #[serde(default)]
+    pub is_admin: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct CreatedUser {
+    pub id: String,
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 pub struct RegisterUserRequest {
     pub email: String,
     pub password: String,
     pub confirm_password: String,
+    #[serde(default)]
     pub is_admin: bool,
 }
 impl RegisterUserRequest {
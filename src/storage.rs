@@ -1,6 +1,6 @@
 use crate::{
-    storage_driver::StorageError,
-    util::{calculate_digest, validate_digest},
+    storage_driver::{BlobReader, StorageError},
+    util::{calculate_digest, path_is_valid, ByteRange, DigestHasher},
 };
 use axum::body::BodyDataStream;
 use axum::extract::{FromRef, FromRequestParts};
@@ -9,19 +9,30 @@ use axum::http::StatusCode;
 use axum::{async_trait, BoxError};
 use bytes::Bytes;
 use futures::{Stream, StreamExt, TryStreamExt};
-use shared::ImageManifest;
-use sqlx::{query, SqliteConnection};
+use shared::{
+    ArtifactManifest, Descriptor, ImageManifest, RootFS, MANIFEST_CONTENT_TYPE, OCI_CONTENT_HEADER,
+};
+use sqlx::{query, query_scalar, Connection, SqliteConnection};
+use std::collections::HashMap;
 use std::io::{self};
 use std::path::{Path, PathBuf};
-use tokio::io::AsyncReadExt;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
 use tokio::{fs::File, io::BufWriter};
-use tokio_util::io::StreamReader;
-use tracing::{debug, error, info};
+use tokio_util::io::ReaderStream;
+use tracing::{debug, error, info, warn};
 use uuid::Uuid;
 
 #[derive(Clone, Debug)]
 pub struct LocalStorageDriver {
     base_path: PathBuf,
+    dir_sizes: Arc<Mutex<HashMap<PathBuf, (Instant, u64)>>>,
+    /// result of the last storage probe, writes are refused while false
+    healthy: Arc<AtomicBool>,
 }
 
 #[async_trait]
@@ -37,116 +48,1240 @@ where
     }
 }
 
-fn visit(path: impl Into<PathBuf>) -> impl Stream<Item = io::Result<u64>> + Send + 'static {
-    async fn one_level(path: PathBuf, to_visit: &mut Vec<PathBuf>) -> io::Result<Vec<u64>> {
-        let mut dir = tokio::fs::read_dir(&path).await?;
-        let mut files = Vec::new();
+/// directories read concurrently while sizing a tree
+static SCAN_CONCURRENCY: usize = 8;
+/// guards against pathological trees stalling the repository listing
+static SCAN_MAX_DEPTH: usize = 32;
+static SCAN_MAX_ENTRIES: usize = 100_000;
+/// how often the storage base path is checked for writability
+pub static STORAGE_PROBE_INTERVAL: Duration = Duration::from_secs(10);
+/// A body written by `LocalStorageDriver::stream_to_file`
+struct StreamedFile {
+    path: PathBuf,
+    digest: String,
+    size: u64,
+}
 
-        while let Some(child) = dir.next_entry().await? {
-            let metadata = child.metadata().await?;
-            if metadata.is_dir() {
-                to_visit.push(child.path());
-            } else {
-                let size = metadata.len();
-                files.push(size);
-            }
+/// how long a computed directory size is reused
+pub(crate) static DIR_SIZE_TTL: Duration = Duration::from_secs(30);
+/// largest piece a blob is streamed back in
+pub(crate) static BLOB_READ_BUFFER: usize = 64 * 1024;
+/// attempts made to remove each file when deleting a repository
+static REMOVE_ATTEMPTS: u32 = 3;
+
+/// Reads a single directory, returning its subdirectories, the combined
+/// size of its files and how many entries were seen (at most `limit`).
+async fn one_level(path: PathBuf, limit: usize) -> io::Result<(Vec<PathBuf>, u64, usize)> {
+    let mut dir = tokio::fs::read_dir(&path).await?;
+    let mut dirs = Vec::new();
+    let mut size = 0;
+    let mut entries = 0;
+    while let Some(child) = dir.next_entry().await? {
+        entries += 1;
+        let metadata = child.metadata().await?;
+        if metadata.is_dir() {
+            dirs.push(child.path());
+        } else {
+            size += metadata.len();
+        }
+        if entries >= limit {
+            break;
         }
+    }
+    Ok((dirs, size, entries))
+}
 
-        Ok(files)
+/// Sums the size of all files under `root` one level at a time, reading at most
+/// SCAN_CONCURRENCY directories at once. Stops early (returning the partial total)
+/// once SCAN_MAX_DEPTH or SCAN_MAX_ENTRIES is exceeded.
+async fn scan_dir_size(root: PathBuf) -> u64 {
+    let mut total = 0;
+    let mut entries = 0;
+    let mut level = vec![root.clone()];
+    for depth in 0.. {
+        if level.is_empty() {
+            break;
+        }
+        if depth > SCAN_MAX_DEPTH {
+            warn!(
+                "stopped sizing {:?}: deeper than {SCAN_MAX_DEPTH} levels",
+                root
+            );
+            break;
+        }
+        let limit = SCAN_MAX_ENTRIES.saturating_sub(entries).max(1);
+        let mut next = Vec::new();
+        let mut results = futures::stream::iter(level)
+            .map(|dir| one_level(dir, limit))
+            .buffer_unordered(SCAN_CONCURRENCY);
+        while let Some(result) = results.next().await {
+            match result {
+                Ok((dirs, size, count)) => {
+                    next.extend(dirs);
+                    total += size;
+                    entries += count;
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    debug!("directory vanished while sizing: {e}")
+                }
+                Err(e) => error!("error getting directory size: {e}"),
+            }
+        }
+        if entries >= SCAN_MAX_ENTRIES {
+            warn!(
+                "stopped sizing {:?}: more than {SCAN_MAX_ENTRIES} entries",
+                root
+            );
+            break;
+        }
+        level = next;
     }
-    futures::stream::unfold(vec![path.into()], |mut to_visit| async {
-        let path = to_visit.pop()?;
-        let file_stream = match one_level(path, &mut to_visit).await {
-            Ok(files) => futures::stream::iter(files).map(Ok).left_stream(),
-            Err(e) => futures::stream::once(async { Err(e) }).right_stream(),
-        };
+    total
+}
 
-        Some((file_stream, to_visit))
-    })
-    .flatten()
+/// Creates the repository if it doesn't exist yet
+pub(crate) async fn ensure_repository(
+    conn: &mut SqliteConnection,
+    name: &str,
+) -> Result<(), sqlx::Error> {
+    query!(
+        "INSERT INTO repositories (name) SELECT ? WHERE NOT EXISTS (SELECT 1 FROM repositories WHERE name = ? AND deleted_at IS NULL)",
+        name,
+        name
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
 }
 
-impl LocalStorageDriver {
-    pub fn new(base_path: &Path) -> Self {
-        Self {
-            base_path: PathBuf::from(base_path),
+/// Removes a file, retrying transient failures. A file that is already
+/// gone counts as removed.
+async fn remove_file_with_retry(path: &str) -> io::Result<()> {
+    let mut attempt = 1;
+    loop {
+        match tokio::fs::remove_file(path).await {
+            Ok(()) => return Ok(()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                debug!("{} was already removed", path);
+                return Ok(());
+            }
+            Err(err) if attempt >= REMOVE_ATTEMPTS => return Err(err),
+            Err(err) => {
+                debug!("retrying removal of {} after error: {}", path, err);
+                tokio::time::sleep(Duration::from_millis(100 * attempt as u64)).await;
+                attempt += 1;
+            }
         }
     }
+}
 
-    pub async fn delete_repository(
+/// The object operations a storage driver provides. Everything else about
+/// blobs and manifests lives in sqlite, so the logic built on top of these
+/// is shared between drivers. Paths are whatever the driver recorded as
+/// `file_path`: a file on disk, or an object key.
+pub(crate) trait ObjectStore {
+    /// Reads a whole object, for manifests and configs
+    async fn read_object(&self, path: &str) -> Result<Vec<u8>, StorageError>;
+    /// Opens an object for streaming, along with its size
+    async fn open_object(
+        &self,
+        path: &str,
+    ) -> Result<(u64, impl AsyncRead + Unpin + Send + 'static), StorageError>;
+    /// Opens the inclusive byte window `start..=end` of an object for streaming
+    async fn open_object_range(
+        &self,
+        path: &str,
+        start: u64,
+        end: u64,
+    ) -> Result<impl AsyncRead + Unpin + Send + 'static, StorageError>;
+    async fn object_size(&self, path: &str) -> Result<u64, StorageError>;
+    /// Removes an object. One that is already gone counts as removed.
+    async fn remove_object(&self, path: &str) -> io::Result<()>;
+    /// Writes a manifest of the repository under its digest, returning the
+    /// path it was stored at
+    async fn put_manifest_object(
         &self,
         name: &str,
-        conn: &mut SqliteConnection,
-    ) -> Result<(), StorageError> {
-        let rows = query!("SELECT file_path FROM blobs JOIN repositories ON blobs.repository_id = repositories.id WHERE repositories.name = ?", name)
-            .fetch_all(&mut *conn)
-            .await?;
-        for row in rows.iter() {
-            tokio::fs::remove_file(&row.file_path).await?;
-        }
+        digest: &str,
+        data: &[u8],
+    ) -> Result<String, StorageError>;
+}
+
+/// A manifest that passed the checks for its kind, ready for `store_manifest`
+struct ValidatedManifest<'a> {
+    media_type: &'a str,
+    data: &'a [u8],
+    schema_version: i32,
+    /// blobs the manifest holds a reference to
+    blobs: Vec<Descriptor>,
+    /// uncompressed digests matching `blobs` by position, empty if unknown
+    diff_ids: Vec<String>,
+    /// digest of the manifest named by `subject`, making this a referrer
+    subject_digest: Option<String>,
+    artifact_type: Option<String>,
+}
+
+fn invalid_manifest(reason: &str) -> StorageError {
+    StorageError::IoError(io::Error::new(io::ErrorKind::InvalidData, reason))
+}
+
+/// Refuses names that would resolve outside the storage root once joined
+/// onto it, such as `../x` or `/etc`
+fn check_relative_path(path: &str) -> Result<(), StorageError> {
+    if path_is_valid(path) {
+        return Ok(());
+    }
+    Err(StorageError::IoError(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("invalid storage path: {path}"),
+    )))
+}
+
+fn parse_manifest<'a, T: serde::Deserialize<'a>>(
+    data: &'a [u8],
+    kind: &str,
+) -> Result<T, StorageError> {
+    serde_json::from_slice(data).map_err(|err| invalid_manifest(&format!("invalid {kind}: {err}")))
+}
+
+async fn remove_partial_file(path: &Path) {
+    if let Err(err) = tokio::fs::remove_file(path).await {
+        error!("unable to remove partial upload {:?}: {err}", path);
+    }
+}
+
+/// Appends the file at `path` to `out` through `hasher`, returning its size
+async fn copy_hashed(
+    path: &str,
+    hasher: &mut DigestHasher,
+    out: &mut (impl AsyncWrite + Unpin),
+) -> io::Result<u64> {
+    let mut chunk = ReaderStream::new(File::open(path).await?);
+    let mut size = 0;
+    while let Some(bytes) = chunk.try_next().await? {
+        hasher.update(&bytes);
+        size += bytes.len() as u64;
+        out.write_all(&bytes).await?;
+    }
+    Ok(size)
+}
+
+/// Where a finished blob of the repository is stored. The chunks of an open
+/// upload session are left out, an empty chunk would otherwise be served
+/// as the empty blob they share a digest with.
+async fn blob_path(
+    pool: &mut SqliteConnection,
+    name: &str,
+    digest: &str,
+) -> Result<String, StorageError> {
+    let row = query!("SELECT file_path FROM blobs JOIN repositories ON blobs.repository_id = repositories.id WHERE digest = ? AND repositories.name = ? AND repositories.deleted_at IS NULL AND blobs.upload_session_id IS NULL", digest, name)
+        .fetch_one(pool)
+        .await?;
+    Ok(row.file_path)
+}
+
+/// Reads a small blob, such as an image config, fully into memory
+async fn read_blob_data(
+    store: &impl ObjectStore,
+    pool: &mut SqliteConnection,
+    name: &str,
+    digest: &str,
+) -> Result<Vec<u8>, StorageError> {
+    store
+        .read_object(&blob_path(pool, name, digest).await?)
+        .await
+}
+
+/// Opens a blob of the repository for streaming, or only the part of it
+/// `range` covers
+pub(crate) async fn read_blob(
+    store: &impl ObjectStore,
+    pool: &mut SqliteConnection,
+    name: &str,
+    digest: &str,
+    range: Option<ByteRange>,
+) -> Result<BlobReader, StorageError> {
+    let path = blob_path(pool, name, digest).await?;
+    let Some(range) = range else {
+        let (size, reader) = store.open_object(&path).await?;
+        return Ok(BlobReader {
+            size,
+            range: None,
+            stream: ReaderStream::with_capacity(reader, BLOB_READ_BUFFER).boxed(),
+        });
+    };
+    let size = store.object_size(&path).await?;
+    let (start, end) = range
+        .resolve(size)
+        .ok_or(StorageError::RangeNotSatisfiable(size))?;
+    let reader = store.open_object_range(&path, start, end).await?;
+    Ok(BlobReader {
+        size,
+        range: Some((start, end)),
+        stream: ReaderStream::with_capacity(reader, BLOB_READ_BUFFER).boxed(),
+    })
+}
+
+/// Checks `incoming` more bytes fit in the repository's quota, counting
+/// every blob and uploaded chunk it holds except the chunks of
+/// `combining`, a session whose chunks are becoming the incoming blob
+pub(crate) async fn check_quota(
+    pool: &mut SqliteConnection,
+    name: &str,
+    incoming: u64,
+    combining: Option<&str>,
+) -> Result<(), StorageError> {
+    let Some(max) = query_scalar!(
+        "SELECT max_bytes FROM repositories WHERE name = ? AND deleted_at IS NULL",
+        name
+    )
+    .fetch_optional(&mut *pool)
+    .await?
+    .flatten() else {
+        return Ok(());
+    };
+    let used = query_scalar!(
+        "SELECT COALESCE(SUM(b.size), 0) FROM blobs b JOIN repositories r ON b.repository_id = r.id
+         WHERE r.name = ? AND r.deleted_at IS NULL AND (b.upload_session_id IS NULL OR b.upload_session_id IS NOT ?)",
+        name,
+        combining
+    )
+    .fetch_one(&mut *pool)
+    .await?;
+    let max = max.max(0) as u64;
+    if (used.max(0) as u64).saturating_add(incoming) > max {
+        info!("{} would exceed its quota of {} bytes", name, max);
+        return Err(StorageError::QuotaExceeded(max));
+    }
+    Ok(())
+}
+
+/// Drops an upload session and the rows of any chunks already written for
+/// it, returning false if there was no such session. The driver cleans up
+/// whatever it stored for the chunks.
+pub(crate) async fn drop_upload_session(
+    conn: &mut SqliteConnection,
+    name: &str,
+    session_id: &str,
+) -> Result<bool, StorageError> {
+    let result = query!(
+        "DELETE FROM uploads WHERE uuid = ? AND repository_id = (SELECT id FROM repositories WHERE name = ? AND deleted_at IS NULL)",
+        session_id,
+        name
+    )
+    .execute(&mut *conn)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Ok(false);
+    }
+    query!("DELETE FROM blobs WHERE upload_session_id = ?", session_id)
+        .execute(&mut *conn)
+        .await?;
+    Ok(true)
+}
+
+/// Where the next chunk of an upload session starts, the bytes stored so far
+pub(crate) async fn next_chunk_offset(
+    conn: &mut SqliteConnection,
+    session_id: &str,
+) -> Result<i64, StorageError> {
+    Ok(query_scalar!(
+        "SELECT COALESCE(SUM(size), 0) FROM blobs WHERE upload_session_id = ?",
+        session_id
+    )
+    .fetch_one(conn)
+    .await?)
+}
+
+/// Fails with `UploadCorrupt` once an upload session has been marked corrupt
+pub(crate) async fn ensure_upload_intact(
+    conn: &mut SqliteConnection,
+    name: &str,
+    session_id: &str,
+) -> Result<(), StorageError> {
+    let corrupt = query_scalar!(
+        "SELECT corrupt FROM uploads WHERE uuid = ? AND repository_id = (SELECT id FROM repositories WHERE name = ? AND deleted_at IS NULL)",
+        session_id,
+        name
+    )
+    .fetch_one(conn)
+    .await?;
+    if corrupt {
+        return Err(StorageError::UploadCorrupt);
+    }
+    Ok(())
+}
+
+/// Flags an upload session so it can't be continued, the client has to
+/// start over
+pub(crate) async fn mark_upload_corrupt(
+    conn: &mut SqliteConnection,
+    session_id: &str,
+) -> Result<(), StorageError> {
+    warn!("upload session {} is corrupt", session_id);
+    query!(
+        "UPDATE uploads SET corrupt = TRUE WHERE uuid = ?",
+        session_id
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+/// Records a blob already stored at `file_path` in another repository as
+/// belonging to `target_name` too, sharing the stored object
+pub(crate) async fn mount_blob(
+    pool: &mut SqliteConnection,
+    target_name: &str,
+    digest: &str,
+    source_name: Option<&str>,
+) -> Result<String, StorageError> {
+    let (row, size) = if let Some(source_name) = source_name {
+        let source = sqlx::query!(
+            "SELECT file_path, size FROM blobs JOIN repositories ON blobs.repository_id = repositories.id WHERE digest = ? AND repositories.name = ? AND repositories.deleted_at IS NULL AND upload_session_id IS NULL",
+            digest, source_name
+        )
+        .fetch_one(&mut *pool)
+        .await?;
+        (source.file_path, source.size)
+    } else {
+        let source = sqlx::query!(
+            "SELECT file_path, size FROM blobs WHERE digest = ? AND upload_session_id IS NULL",
+            digest
+        )
+        .fetch_one(&mut *pool)
+        .await?;
+        (source.file_path, source.size)
+    };
+
+    let target_exists = query!("SELECT COUNT(*) as count FROM blobs JOIN repositories ON blobs.repository_id = repositories.id WHERE digest = ? AND repositories.name = ? AND repositories.deleted_at IS NULL", digest, target_name)
+        .fetch_optional(&mut *pool)
+        .await?
+        .is_some_and(|row| row.count > 0);
+    if !target_exists {
+        // like a regular push, mounting into a new repository creates it
+        ensure_repository(pool, target_name).await?;
+        let target_repository_id = query!(
+            "SELECT id FROM repositories WHERE name = ? AND deleted_at IS NULL",
+            target_name
+        )
+        .fetch_one(&mut *pool)
+        .await?
+        .id;
+
         query!(
-            "DELETE FROM blobs WHERE repository_id = (SELECT id from repositories WHERE name = ?)",
+            "INSERT INTO blobs (repository_id, digest, file_path, size) VALUES (?, ?, ?, ?)",
+            target_repository_id,
+            digest,
+            row,
+            size
+        )
+        .execute(pool)
+        .await?;
+    }
+    Ok(row)
+}
+
+/// Stores an image manifest. It must name a config and may not list
+/// child manifests, which belong in an index. With `with_diff_ids` the
+/// config blob is read to record each layer's uncompressed digest.
+pub(crate) async fn write_image_manifest(
+    store: &impl ObjectStore,
+    pool: &mut SqliteConnection,
+    name: &str,
+    reference: &str,
+    media_type: &str,
+    data: &[u8],
+    with_diff_ids: bool,
+) -> Result<String, StorageError> {
+    let img: ImageManifest = parse_manifest(data, "image manifest")?;
+    let Some(config) = img.config else {
+        return Err(invalid_manifest("image manifest is missing its config"));
+    };
+    if img.manifests.is_some() {
+        return Err(invalid_manifest(
+            "image manifest cannot list child manifests",
+        ));
+    }
+    let diff_ids = if with_diff_ids {
+        layer_diff_ids(store, pool, name, &config.digest, img.layers.len()).await
+    } else {
+        Vec::new()
+    };
+    store_manifest(
+        store,
+        pool,
+        name,
+        reference,
+        ValidatedManifest {
+            media_type,
+            data,
+            schema_version: img.schema_version,
+            blobs: img.layers,
+            diff_ids,
+            subject_digest: img.subject.map(|subject| subject.digest),
+            // the spec falls back to the config media type for images
+            artifact_type: img.artifact_type.or(config.media_type),
+        },
+    )
+    .await
+}
+
+/// Config media types a Docker image manifest may carry
+const DOCKER_CONFIG_MEDIA_TYPES: [&str; 2] = [
+    "application/vnd.docker.container.image.v1+json",
+    "application/vnd.docker.plugin.v1+json",
+];
+/// The config artifacts without one of their own point at, the JSON `{}`
+const EMPTY_CONFIG_MEDIA_TYPE: &str = "application/vnd.oci.empty.v1+json";
+const EMPTY_CONFIG_DIGEST: &str =
+    "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a";
+
+/// Checks the config an image manifest names: its media type has to fit the
+/// manifest's and the blob has to be stored in the repository. The empty
+/// config of an artifact only has to describe `{}`, as not every client
+/// uploads it.
+pub(crate) async fn check_image_config(
+    pool: &mut SqliteConnection,
+    name: &str,
+    media_type: &str,
+    data: &[u8],
+) -> Result<(), StorageError> {
+    let img: ImageManifest = parse_manifest(data, "image manifest")?;
+    // a manifest without a config is refused by the write path itself
+    let Some(config) = img.config else {
+        return Ok(());
+    };
+    let config_type = config.media_type.as_deref().unwrap_or_default();
+    let fits = if media_type == MANIFEST_CONTENT_TYPE {
+        DOCKER_CONFIG_MEDIA_TYPES.contains(&config_type)
+    } else {
+        // OCI artifacts may use any config type, just not a Docker one
+        !config_type.is_empty() && !config_type.starts_with("application/vnd.docker.")
+    };
+    if !fits {
+        return Err(invalid_manifest(&format!(
+            "config media type {config_type:?} does not fit manifest media type {media_type}"
+        )));
+    }
+    if config_type == EMPTY_CONFIG_MEDIA_TYPE {
+        if config.digest != EMPTY_CONFIG_DIGEST || config.size != 2 {
+            return Err(invalid_manifest("the empty config has to describe {}"));
+        }
+        return Ok(());
+    }
+    let stored = query_scalar!(
+        "SELECT COUNT(*) FROM blobs WHERE digest = ? AND upload_session_id IS NULL AND repository_id = (SELECT id FROM repositories WHERE name = ? AND deleted_at IS NULL)",
+        config.digest,
+        name
+    )
+    .fetch_one(pool)
+    .await?;
+    if stored == 0 {
+        return Err(StorageError::BlobUnknown(config.digest));
+    }
+    Ok(())
+}
+
+/// The `rootfs.diff_ids` of an image config, one per layer. Diff IDs are
+/// optional metadata, so a config that is missing, has no rootfs or
+/// doesn't line up with the layers yields none rather than an error.
+async fn layer_diff_ids(
+    store: &impl ObjectStore,
+    pool: &mut SqliteConnection,
+    name: &str,
+    config_digest: &str,
+    layers: usize,
+) -> Vec<String> {
+    #[derive(serde::Deserialize)]
+    struct ConfigRootFS {
+        rootfs: Option<RootFS>,
+    }
+    let config = match read_blob_data(store, pool, name, config_digest).await {
+        Ok(config) => config,
+        Err(err) => {
+            warn!(
+                "unable to read config {} for diff ids: {}",
+                config_digest, err
+            );
+            return Vec::new();
+        }
+    };
+    match serde_json::from_slice::<ConfigRootFS>(&config) {
+        Ok(ConfigRootFS {
+            rootfs: Some(rootfs),
+        }) if rootfs.diff_ids.len() == layers => rootfs.diff_ids,
+        Ok(ConfigRootFS {
+            rootfs: Some(rootfs),
+        }) => {
+            warn!(
+                "config {} lists {} diff ids for {} layers",
+                config_digest,
+                rootfs.diff_ids.len(),
+                layers
+            );
+            Vec::new()
+        }
+        Ok(ConfigRootFS { rootfs: None }) => {
+            debug!("config {} has no rootfs", config_digest);
+            Vec::new()
+        }
+        Err(err) => {
+            warn!(
+                "unable to parse config {} for diff ids: {}",
+                config_digest, err
+            );
+            Vec::new()
+        }
+    }
+}
+
+/// Stores an image index. It must list at least one child manifest and
+/// carries no blobs of its own.
+pub(crate) async fn write_image_index(
+    store: &impl ObjectStore,
+    pool: &mut SqliteConnection,
+    name: &str,
+    reference: &str,
+    media_type: &str,
+    data: &[u8],
+) -> Result<String, StorageError> {
+    let index: ImageManifest = parse_manifest(data, "image index")?;
+    if index.manifests.as_ref().is_none_or(Vec::is_empty) {
+        return Err(invalid_manifest("image index lists no manifests"));
+    }
+    if index.config.is_some() || !index.layers.is_empty() {
+        return Err(invalid_manifest("image index cannot reference blobs"));
+    }
+    store_manifest(
+        store,
+        pool,
+        name,
+        reference,
+        ValidatedManifest {
+            media_type,
+            data,
+            schema_version: index.schema_version,
+            blobs: Vec::new(),
+            diff_ids: Vec::new(),
+            subject_digest: index.subject.map(|subject| subject.digest),
+            artifact_type: index.artifact_type,
+        },
+    )
+    .await
+}
+
+/// Stores an artifact manifest, counting a reference to each of its blobs
+pub(crate) async fn write_artifact_manifest(
+    store: &impl ObjectStore,
+    pool: &mut SqliteConnection,
+    name: &str,
+    reference: &str,
+    media_type: &str,
+    data: &[u8],
+) -> Result<String, StorageError> {
+    let artifact: ArtifactManifest = parse_manifest(data, "artifact manifest")?;
+    if artifact.artifact_type.is_empty() {
+        return Err(invalid_manifest(
+            "artifact manifest is missing its artifactType",
+        ));
+    }
+    store_manifest(
+        store,
+        pool,
+        name,
+        reference,
+        ValidatedManifest {
+            media_type,
+            data,
+            // artifact manifests have no schemaVersion, record the
+            // version of the image spec they were introduced alongside
+            schema_version: 2,
+            blobs: artifact.blobs,
+            diff_ids: Vec::new(),
+            subject_digest: artifact.subject.map(|subject| subject.digest),
+            artifact_type: Some(artifact.artifact_type),
+        },
+    )
+    .await
+}
+
+/// Layers clients never upload, whose content is fetched from their `urls`:
+/// Docker foreign layers and OCI non-distributable layers
+pub(crate) fn is_foreign_layer(media_type: Option<&str>) -> bool {
+    media_type.is_some_and(|media_type| {
+        media_type.starts_with("application/vnd.docker.image.rootfs.foreign.")
+            || media_type.starts_with("application/vnd.oci.image.layer.nondistributable.")
+    })
+}
+
+/// The step every manifest kind shares once validated: hash it, store it
+/// once, record it with the layers/blobs it references and tag it, unless
+/// it was pushed by digest.
+/// The caller has already buffered `data`, which bounds its size.
+/// Every referenced blob must already be in the repository, except foreign
+/// layers, otherwise nothing is stored and `BlobUnknown` is returned.
+async fn store_manifest(
+    store: &impl ObjectStore,
+    pool: &mut SqliteConnection,
+    name: &str,
+    reference: &str,
+    manifest: ValidatedManifest<'_>,
+) -> Result<String, StorageError> {
+    let digest = calculate_digest(manifest.data);
+    let size = manifest.data.len() as i64;
+    // a manifest pushed by digest is left untagged
+    let tag = (!reference.contains(':')).then_some(reference);
+    // ref counts are rolled back along with everything else if any blob is missing
+    let mut tx = pool.begin().await?;
+    let existing = query_scalar!(
+        "SELECT m.id FROM manifests m JOIN repositories r ON m.repository_id = r.id
+         WHERE r.name = ? AND r.deleted_at IS NULL AND m.digest = ?",
+        name,
+        digest
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    if let Some(id) = existing {
+        // the repository already holds this manifest, it only gains a tag
+        if let Some(tag) = tag {
+            query!("INSERT OR REPLACE INTO tags (repository_id, tag, manifest_id) VALUES ((SELECT id FROM repositories WHERE name = ? AND deleted_at IS NULL), ?, ?)", name, tag, id).execute(&mut *tx).await?;
+        }
+        tx.commit().await?;
+        return Ok(digest);
+    }
+    for blob in manifest.blobs.iter() {
+        if is_foreign_layer(blob.media_type.as_deref()) {
+            debug!("not counting a reference to foreign layer {}", blob.digest);
+            continue;
+        }
+        let counted = query!(
+            "UPDATE blobs SET ref_count = ref_count + 1 WHERE digest = ? AND repository_id = (SELECT id FROM repositories WHERE name = ? AND deleted_at IS NULL)",
+            blob.digest,
             name
         )
-        .execute(&mut *conn)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        if counted == 0 {
+            return Err(StorageError::BlobUnknown(blob.digest.clone()));
+        }
+    }
+    // stored by digest, a tag moving on mustn't overwrite what it pointed at
+    let file_path = store
+        .put_manifest_object(name, &digest, manifest.data)
+        .await?;
+    info!("successfully wrote manifest to path: {}", file_path);
+    let record = query!(
+        "INSERT INTO manifests (repository_id, digest, file_path, media_type, size, schema_version, subject_digest, artifact_type)
+         VALUES ((SELECT id FROM repositories WHERE name = ? AND deleted_at IS NULL), ?, ?, ?, ?, ?, ?, ?)",
+        name,
+        digest,
+        file_path,
+        manifest.media_type,
+        size,
+        manifest.schema_version,
+        manifest.subject_digest,
+        manifest.artifact_type
+    )
+    .execute(&mut *tx)
+    .await?;
+    let id = record.last_insert_rowid();
+    for (i, blob) in manifest.blobs.into_iter().enumerate() {
+        let diff_id = manifest.diff_ids.get(i);
+        query!("INSERT INTO manifest_layers (manifest_id, repository_id, digest, size, media_type, diff_id) VALUES (?, (SELECT id FROM repositories WHERE name = ? AND deleted_at IS NULL), ?, ?, ?, ?)", id, name, blob.digest, blob.size, blob.media_type, diff_id).execute(&mut *tx).await?;
+    }
+    if let Some(tag) = tag {
+        query!("INSERT OR REPLACE INTO tags (repository_id, tag, manifest_id) VALUES ((SELECT id FROM repositories WHERE name = ? AND deleted_at IS NULL), ?, ?)", name, tag, id).execute(&mut *tx).await?;
+    }
+    tx.commit().await?;
+    Ok(digest)
+}
+
+/// Every digest needed to pull `reference`: the manifest itself, its
+/// config and layers, recursing through the child manifests of an index.
+/// Digests are listed once each, parents before their children.
+pub(crate) async fn manifest_closure(
+    store: &impl ObjectStore,
+    pool: &mut SqliteConnection,
+    name: &str,
+    reference: &str,
+) -> Result<Vec<String>, StorageError> {
+    let root = query!(
+        "SELECT m.digest FROM manifests m JOIN repositories r ON m.repository_id = r.id
+         LEFT JOIN tags t ON t.manifest_id = m.id
+         WHERE r.name = ?1 AND r.deleted_at IS NULL AND (m.digest = ?2 OR t.tag = ?2)",
+        name,
+        reference
+    )
+    .fetch_one(&mut *pool)
+    .await?
+    .digest;
+    let mut closure = vec![root.clone()];
+    let mut seen = std::collections::HashSet::from([root.clone()]);
+    let mut pending = vec![root];
+    while let Some(digest) = pending.pop() {
+        let Some(row) = query!(
+            "SELECT m.file_path FROM manifests m JOIN repositories r ON m.repository_id = r.id WHERE r.name = ? AND r.deleted_at IS NULL AND m.digest = ?",
+            name,
+            digest
+        )
+        .fetch_optional(&mut *pool)
+        .await?
+        else {
+            warn!("manifest {} referenced in {} is not stored", digest, name);
+            continue;
+        };
+        let manifest: ImageManifest =
+            serde_json::from_slice(&store.read_object(&row.file_path).await?).map_err(|_| {
+                StorageError::IoError(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "error deserializing into ImageManifest",
+                ))
+            })?;
+        for child in manifest.manifests.unwrap_or_default() {
+            if seen.insert(child.digest.clone()) {
+                closure.push(child.digest.clone());
+                pending.push(child.digest);
+            }
+        }
+        for blob in manifest.config.into_iter().chain(manifest.layers) {
+            if seen.insert(blob.digest.clone()) {
+                closure.push(blob.digest);
+            }
+        }
+    }
+    Ok(closure)
+}
+
+/// Deletes the repository's blob. Mounted and promoted copies share the
+/// stored object, so it is only removed along with its last row.
+pub(crate) async fn delete_blob(
+    store: &impl ObjectStore,
+    pool: &mut SqliteConnection,
+    name: &str,
+    digest: &str,
+) -> Result<(), StorageError> {
+    let row = query!("SELECT file_path, blobs.id FROM blobs JOIN repositories ON blobs.repository_id = repositories.id WHERE digest = ? AND repositories.name = ? AND repositories.deleted_at IS NULL", digest, name)
+        .fetch_one(&mut *pool)
+        .await?;
+    let shared = query!(
+        "SELECT COUNT(*) as count FROM blobs WHERE file_path = ? AND id != ?",
+        row.file_path,
+        row.id
+    )
+    .fetch_one(&mut *pool)
+    .await?
+    .count
+        > 0;
+    if !shared {
+        store.remove_object(&row.file_path).await?;
+    }
+    query!("DELETE FROM blobs WHERE id = ?", row.id)
+        .execute(&mut *pool)
         .await?;
-        let rows = query!("SELECT file_path FROM manifests JOIN repositories ON manifests.repository_id = repositories.id WHERE repositories.name = ?", name)
-            .fetch_all(&mut *conn)
+    Ok(())
+}
+
+/// Removes blobs no manifest references, returning how many were removed.
+/// Blobs still being uploaded, blobs younger than the upload session expiry
+/// (likely pushed ahead of their manifest), image configs and blobs of
+/// soft-deleted repositories are kept. An object shared with a mounted
+/// copy is only removed along with its last row. Callers hold
+/// `database::MAINTENANCE`.
+pub(crate) async fn collect_garbage(
+    store: &impl ObjectStore,
+    pool: &mut SqliteConnection,
+) -> Result<usize, StorageError> {
+    let cutoff = format!("-{} seconds", crate::database::UPLOAD_SESSION_EXPIRY_SECS);
+    let candidates = query!(
+        "SELECT b.id, b.repository_id, b.digest, b.file_path FROM blobs b
+         JOIN repositories r ON b.repository_id = r.id
+         WHERE b.ref_count <= 0 AND b.upload_session_id IS NULL AND r.deleted_at IS NULL
+         AND b.created_at <= datetime('now', ?)
+         AND NOT EXISTS (SELECT 1 FROM manifest_layers ml
+            WHERE ml.repository_id = b.repository_id AND ml.digest = b.digest)",
+        cutoff
+    )
+    .fetch_all(&mut *pool)
+    .await?;
+    if candidates.is_empty() {
+        return Ok(0);
+    }
+    // configs aren't reference counted, they're only named by the manifests.
+    // A manifest that can't be read could name any of them, so give up.
+    let mut configs = std::collections::HashSet::new();
+    let manifests = query!("SELECT repository_id, file_path FROM manifests")
+        .fetch_all(&mut *pool)
+        .await?;
+    for manifest in manifests {
+        let data = store.read_object(&manifest.file_path).await?;
+        if let Some(config) = serde_json::from_slice::<ImageManifest>(&data)
+            .ok()
+            .and_then(|manifest| manifest.config)
+        {
+            configs.insert((manifest.repository_id, config.digest));
+        }
+    }
+    let mut removed = 0;
+    for blob in candidates {
+        if configs.contains(&(blob.repository_id, blob.digest.clone())) {
+            continue;
+        }
+        let shared = query!(
+            "SELECT COUNT(*) as count FROM blobs WHERE file_path = ? AND id != ?",
+            blob.file_path,
+            blob.id
+        )
+        .fetch_one(&mut *pool)
+        .await?
+        .count
+            > 0;
+        if !shared {
+            store.remove_object(&blob.file_path).await?;
+        }
+        query!("DELETE FROM blobs WHERE id = ?", blob.id)
+            .execute(&mut *pool)
             .await?;
-        for row in rows.iter() {
-            tokio::fs::remove_file(&row.file_path).await?;
+        debug!("collected unreferenced blob {}", blob.digest);
+        removed += 1;
+    }
+    Ok(removed)
+}
+
+/// Deletes the rows of the repository with this id, then every object
+/// belonging to it. The rows go first so a failed delete leaves the
+/// repository intact; object removal is best effort: failures are logged
+/// and returned rather than aborting. Objects
+/// another repository refers to are kept, whether it mounted a blob or
+/// reuses the name of a soft-deleted repository and so its paths.
+pub(crate) async fn delete_repository(
+    store: &impl ObjectStore,
+    id: i64,
+    conn: &mut SqliteConnection,
+) -> Result<Vec<String>, StorageError> {
+    let blobs = query!(
+        "SELECT DISTINCT b.file_path FROM blobs b WHERE b.repository_id = ? AND NOT EXISTS (
+            SELECT 1 FROM blobs o WHERE o.file_path = b.file_path AND o.repository_id != b.repository_id)",
+        id
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    let manifests = query!(
+        "SELECT DISTINCT m.file_path FROM manifests m WHERE m.repository_id = ? AND NOT EXISTS (
+            SELECT 1 FROM manifests o WHERE o.file_path = m.file_path AND o.repository_id != m.repository_id)",
+        id
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    let mut tx = conn.begin().await?;
+    query!("DELETE FROM blobs WHERE repository_id = ?", id)
+        .execute(&mut *tx)
+        .await?;
+    query!("DELETE FROM manifests WHERE repository_id = ?", id)
+        .execute(&mut *tx)
+        .await?;
+    query!("DELETE FROM repositories WHERE id = ?", id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    let mut failed = Vec::new();
+    let paths = blobs
+        .into_iter()
+        .map(|row| row.file_path)
+        .chain(manifests.into_iter().map(|row| row.file_path));
+    for path in paths {
+        if let Err(err) = store.remove_object(&path).await {
+            error!("unable to remove {} from repository {}: {}", path, id, err);
+            failed.push(path);
         }
-        query!("DELETE FROM manifests WHERE repository_id = (SELECT id from repositories WHERE name = ?)", name)
-            .execute(&mut *conn)
+    }
+    Ok(failed)
+}
+
+/// Writes an OCI image layout tarball of the repository to `writer`:
+/// `oci-layout`, `index.json` and every manifest and blob under
+/// `blobs/<alg>/<hex>`, streaming each blob from storage.
+pub(crate) async fn export_repository<W>(
+    store: &impl ObjectStore,
+    pool: &mut SqliteConnection,
+    name: &str,
+    writer: W,
+) -> Result<(), StorageError>
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let manifests = query!(
+        r#"SELECT m.digest, m.media_type, m.file_path, t.tag as "tag?" FROM manifests m
+        JOIN repositories r ON m.repository_id = r.id
+        LEFT JOIN tags t ON t.manifest_id = m.id
+        WHERE r.name = ? AND r.deleted_at IS NULL"#,
+        name
+    )
+    .fetch_all(&mut *pool)
+    .await?;
+    let blobs = query!(
+        "SELECT DISTINCT digest, file_path FROM blobs
+        JOIN repositories r ON blobs.repository_id = r.id
+        WHERE r.name = ? AND r.deleted_at IS NULL AND upload_session_id IS NULL",
+        name
+    )
+    .fetch_all(&mut *pool)
+    .await?;
+
+    // manifests are small, so they're read up front to size the index
+    let mut manifest_data: Vec<(String, Vec<u8>)> = Vec::new();
+    let mut index = Vec::new();
+    for manifest in manifests.iter() {
+        let data = match manifest_data
+            .iter()
+            .find(|(digest, _)| digest.eq(&manifest.digest))
+        {
+            Some((_, data)) => data.len(),
+            None => {
+                let data = store.read_object(&manifest.file_path).await?;
+                let size = data.len();
+                manifest_data.push((manifest.digest.clone(), data));
+                size
+            }
+        };
+        let mut descriptor = serde_json::json!({
+            "mediaType": manifest.media_type,
+            "digest": manifest.digest,
+            "size": data,
+        });
+        if let Some(tag) = &manifest.tag {
+            descriptor["annotations"] =
+                serde_json::json!({ "org.opencontainers.image.ref.name": tag });
+        }
+        index.push(descriptor);
+    }
+    let mut files: Vec<(String, String)> = Vec::new();
+    for blob in blobs {
+        if !manifest_data
+            .iter()
+            .any(|(digest, _)| digest.eq(&blob.digest))
+            && !files.iter().any(|(digest, _)| digest.eq(&blob.digest))
+        {
+            files.push((blob.digest, blob.file_path));
+        }
+    }
+    let index = serde_json::to_vec(&serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": OCI_CONTENT_HEADER,
+        "manifests": index,
+    }))
+    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    let layout = br#"{"imageLayoutVersion":"1.0.0"}"#;
+
+    let mut archive = tokio_tar::Builder::new(writer);
+    let mut header = tokio_tar::Header::new_gnu();
+    header.set_mode(0o644);
+    header.set_size(layout.len() as u64);
+    archive
+        .append_data(&mut header, "oci-layout", &layout[..])
+        .await?;
+    header.set_size(index.len() as u64);
+    archive
+        .append_data(&mut header, "index.json", index.as_slice())
+        .await?;
+    for (digest, data) in manifest_data {
+        header.set_size(data.len() as u64);
+        archive
+            .append_data(&mut header, layout_path(&digest), data.as_slice())
             .await?;
-        query!("DELETE FROM repositories WHERE name = ?", name)
-            .execute(&mut *conn)
+    }
+    for (digest, file_path) in files {
+        let (size, reader) = store.open_object(&file_path).await?;
+        header.set_size(size);
+        archive
+            .append_data(&mut header, layout_path(&digest), reader)
             .await?;
-        Ok(())
+    }
+    archive.into_inner().await?;
+    Ok(())
+}
+
+fn layout_path(digest: &str) -> String {
+    format!("blobs/{}", digest.replacen(':', "/", 1))
+}
+
+impl ObjectStore for LocalStorageDriver {
+    async fn read_object(&self, path: &str) -> Result<Vec<u8>, StorageError> {
+        let mut data = Vec::new();
+        match File::open(path).await?.read_to_end(&mut data).await {
+            Ok(_) => Ok(data),
+            Err(e) => {
+                error!("error reading {}: {:?}", path, e);
+                Err(StorageError::IoError(e))
+            }
+        }
+    }
+
+    async fn open_object(
+        &self,
+        path: &str,
+    ) -> Result<(u64, impl AsyncRead + Unpin + Send + 'static), StorageError> {
+        let file = File::open(path).await?;
+        Ok((file.metadata().await?.len(), file))
+    }
+
+    async fn open_object_range(
+        &self,
+        path: &str,
+        start: u64,
+        end: u64,
+    ) -> Result<impl AsyncRead + Unpin + Send + 'static, StorageError> {
+        let mut file = File::open(path).await?;
+        file.seek(io::SeekFrom::Start(start)).await?;
+        Ok(file.take(end - start + 1))
+    }
+
+    async fn object_size(&self, path: &str) -> Result<u64, StorageError> {
+        Ok(tokio::fs::metadata(path).await?.len())
+    }
+
+    async fn remove_object(&self, path: &str) -> io::Result<()> {
+        remove_file_with_retry(path).await
+    }
+
+    async fn put_manifest_object(
+        &self,
+        name: &str,
+        digest: &str,
+        data: &[u8],
+    ) -> Result<String, StorageError> {
+        let dir = self.base_path.join(name).join("manifests");
+        tokio::fs::create_dir_all(&dir).await?;
+        let path = dir.join(digest);
+        tokio::fs::write(&path, data).await?;
+        Ok(path.to_string_lossy().to_string())
+    }
+}
+
+impl LocalStorageDriver {
+    pub fn new(base_path: &Path) -> Self {
+        Self {
+            base_path: PathBuf::from(base_path),
+            dir_sizes: Arc::new(Mutex::new(HashMap::new())),
+            healthy: Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    /// Checks the base path is still writable by creating and removing a
+    /// small file, and records the result for `is_healthy`.
+    pub async fn probe_health(&self) -> bool {
+        let probe = self.base_path.join(".floundr-probe");
+        let result = async {
+            tokio::fs::create_dir_all(&self.base_path).await?;
+            tokio::fs::write(&probe, b"ok").await?;
+            tokio::fs::remove_file(&probe).await
+        }
+        .await;
+        let healthy = match result {
+            Ok(()) => true,
+            Err(err) => {
+                error!("storage at {:?} is not writable: {}", self.base_path, err);
+                false
+            }
+        };
+        if self.healthy.swap(healthy, Ordering::Relaxed) != healthy && healthy {
+            info!("storage at {:?} is writable again", self.base_path);
+        }
+        healthy
+    }
+
+    pub async fn delete_repository(
+        &self,
+        id: i64,
+        conn: &mut SqliteConnection,
+    ) -> Result<Vec<String>, StorageError> {
+        delete_repository(self, id, conn).await
     }
 
     pub async fn get_dir_size(&self, path: impl Into<PathBuf>) -> u64 {
-        visit(path)
-            .fold(0u64, |acc, entry| async move {
-                acc + entry.unwrap_or_else(|e| {
-                    error!("error getting directory size: {e}");
-                    0
-                })
-            })
-            .await
+        let path = path.into();
+        if let Some((computed, size)) = self
+            .dir_sizes
+            .lock()
+            .ok()
+            .and_then(|sizes| sizes.get(&path).copied())
+        {
+            if computed.elapsed() < DIR_SIZE_TTL {
+                return size;
+            }
+        }
+        let size = scan_dir_size(path.clone()).await;
+        if let Ok(mut sizes) = self.dir_sizes.lock() {
+            sizes.retain(|_, (computed, _)| computed.elapsed() < DIR_SIZE_TTL);
+            sizes.insert(path, (Instant::now(), size));
+        }
+        size
     }
+
+    /// Size of a stored blob, if it's still there
+    pub async fn blob_size(&self, file_path: &str) -> Option<u64> {
+        tokio::fs::metadata(file_path).await.ok().map(|m| m.len())
+    }
+
+    /// Writes a body to `path/filename`, hashing it on the way. With an
+    /// `expected` digest the body is hashed with that digest's algorithm,
+    /// and a digest that no content could match is refused before writing.
     async fn stream_to_file<S, E>(
         &self,
         path: &str,
         filename: &str,
         stream: S,
-    ) -> Result<PathBuf, StorageError>
+        expected: Option<&str>,
+    ) -> Result<StreamedFile, StorageError>
     where
         S: Stream<Item = Result<Bytes, E>>,
         E: Into<BoxError>,
     {
-        async {
-            let body_with_io_error =
-                stream.map_err(|err| io::Error::new(io::ErrorKind::Other, err));
-            let body_reader = StreamReader::new(body_with_io_error);
-            futures::pin_mut!(body_reader);
-            let mut pathbuf = PathBuf::new();
-            for new_path in path.split('/') {
-                pathbuf = pathbuf.join(new_path);
+        let mut hasher = match expected {
+            Some(digest) => DigestHasher::for_digest(digest).ok_or(StorageError::DigestError)?,
+            None => DigestHasher::default(),
+        };
+        check_relative_path(path)?;
+        check_relative_path(filename)?;
+        if !self.base_path.join(path).exists() {
+            tokio::fs::create_dir_all(self.base_path.join(path)).await?;
+        }
+        let path = self.base_path.join(path).join(filename);
+        debug!("streaming to file: {:?}", path);
+        let written = async {
+            let mut file = BufWriter::new(File::create(path.clone()).await?);
+            let body = stream.map_err(io::Error::other);
+            futures::pin_mut!(body);
+            let mut size = 0;
+            while let Some(bytes) = body.try_next().await? {
+                hasher.update(&bytes);
+                size += bytes.len() as u64;
+                file.write_all(&bytes).await?;
             }
-            if !self.base_path.join(path).exists() {
-                tokio::fs::create_dir_all(self.base_path.join(path)).await?;
+            file.flush().await?;
+            Ok::<_, io::Error>(size)
+        }
+        .await;
+        match written {
+            Ok(size) => {
+                debug!("finished streaming to file completed: {:?}", path);
+                Ok(StreamedFile {
+                    path,
+                    digest: hasher.finalize(),
+                    size,
+                })
+            }
+            Err(err) => {
+                // don't leave a truncated file behind when the body errors mid-stream
+                remove_partial_file(&path).await;
+                Err(StorageError::IoError(err))
             }
-            let path = self.base_path.join(path).join(filename);
-            debug!("streaming to file: {:?}", path);
-            let mut file = BufWriter::new(File::create(path.clone()).await?);
-
-            tokio::io::copy(&mut body_reader, &mut file).await?;
-            debug!("finished streaming to file completed: {:?}", path);
-            Ok::<_, io::Error>(path)
         }
-        .await
-        .map_err(StorageError::IoError)
     }
 
     pub fn base_path(&self) -> &PathBuf {
@@ -166,19 +1301,25 @@ impl LocalStorageDriver {
             .join(session_id)
             .to_string_lossy()
             .to_string();
-        let path = self
-            .stream_to_file(&rel_path, &format!("{}", chunk), data)
+        let offset = next_chunk_offset(pool, session_id).await?;
+        let streamed = self
+            .stream_to_file(&rel_path, &format!("{}", chunk), data, None)
             .await?;
-        let digest = calculate_digest(&tokio::fs::read(&path).await?);
-        let file_path = self
-            .base_path
-            .join(rel_path)
-            .join(format!("{chunk}"))
-            .to_string_lossy()
-            .to_string();
-        let _ = query!("INSERT INTO blobs (repository_id, digest, file_path, upload_session_id) VALUES ((select id from repositories where name = ?), ?, ?, ?)", name, digest, file_path, session_id)
+        if let Err(err) = check_quota(pool, name, streamed.size, None).await {
+            // the upload can't complete within the quota, so end it here
+            self.cancel_session(pool, name, session_id).await?;
+            return Err(err);
+        }
+        let (digest, size) = (streamed.digest, streamed.size as i64);
+        let file_path = streamed.path.to_string_lossy().to_string();
+        if let Err(err) = query!("INSERT INTO blobs (repository_id, digest, file_path, upload_session_id, size, chunk_offset) VALUES ((SELECT id FROM repositories WHERE name = ? AND deleted_at IS NULL), ?, ?, ?, ?, ?)", name, digest, file_path, session_id, size, offset)
         .execute(pool)
-        .await;
+        .await
+        {
+            // a chunk without its row would never be combined
+            remove_partial_file(&streamed.path).await;
+            return Err(err.into());
+        }
         Ok(digest)
     }
 
@@ -189,15 +1330,38 @@ impl LocalStorageDriver {
         digest: &str,
         data: BodyDataStream,
     ) -> Result<String, StorageError> {
-        let path = self.stream_to_file("blobs", digest, data).await?;
-        validate_digest(&std::fs::read(&path)?, digest)?;
-        let file_path = self
-            .base_path
-            .join(name)
-            .join(digest)
+        // stream into a uniquely named temp file first, so a bad upload can never
+        // clobber an existing blob with the same declared digest
+        let rel_path = PathBuf::from(name)
+            .join("blobs")
             .to_string_lossy()
             .to_string();
-        query!("INSERT INTO blobs (repository_id, digest, file_path) VALUES ((select id from repositories where name = ?), ?, ?)", name, digest, file_path)
+        let streamed = self
+            .stream_to_file(
+                &rel_path,
+                &format!("{}.partial", Uuid::new_v4()),
+                data,
+                Some(digest),
+            )
+            .await?;
+        let file_path = self.base_path.join(&rel_path).join(digest);
+        let finalized = async {
+            if streamed.digest != digest {
+                return Err(StorageError::DigestError);
+            }
+            check_quota(pool, name, streamed.size, None).await?;
+            tokio::fs::rename(&streamed.path, &file_path).await?;
+            Ok(())
+        }
+        .await;
+        if let Err(err) = finalized {
+            error!("monolithic upload failed validation: {err}");
+            remove_partial_file(&streamed.path).await;
+            return Err(err);
+        }
+        let size = streamed.size as i64;
+        let file_path = file_path.to_string_lossy().to_string();
+        query!("INSERT INTO blobs (repository_id, digest, file_path, size) VALUES ((SELECT id FROM repositories WHERE name = ? AND deleted_at IS NULL), ?, ?, ?)", name, digest, file_path, size)
         .execute(pool)
         .await?;
         Ok(digest.to_owned())
@@ -208,27 +1372,13 @@ impl LocalStorageDriver {
         pool: &mut SqliteConnection,
         name: &str,
         digest: &str,
-    ) -> Result<Vec<u8>, StorageError> {
-        // Retrieve the file path from the database
-        let row = query!("SELECT file_path FROM blobs JOIN repositories ON blobs.repository_id = repositories.id WHERE digest = ? AND repositories.name = ?", digest, name)
-            .fetch_one(pool)
-            .await?;
-
-        let mut file = tokio::fs::File::open(row.file_path).await?;
-        let mut data = Vec::new();
-        file.read_to_end(&mut data).await?;
-        Ok(data)
+        range: Option<ByteRange>,
+    ) -> Result<BlobReader, StorageError> {
+        read_blob(self, pool, name, digest, range).await
     }
 
     pub async fn read_manifest(&self, path: &str) -> Result<Vec<u8>, StorageError> {
-        let mut data = Vec::new();
-        match File::open(path).await?.read_to_end(&mut data).await {
-            Ok(_) => Ok(data),
-            Err(e) => {
-                error!("error reading manifest: {:?}", e);
-                Err(StorageError::IoError(e))
-            }
-        }
+        self.read_object(path).await
     }
 
     pub async fn new_session(
@@ -236,19 +1386,8 @@ impl LocalStorageDriver {
         conn: &mut SqliteConnection,
         name: &str,
     ) -> Result<String, StorageError> {
-        if query!(
-            "SELECT COUNT(*) as count FROM repositories WHERE name = ?",
-            name
-        )
-        .fetch_one(&mut *conn)
-        .await?
-        .count
-            == 0
-        {
-            query!("INSERT INTO repositories (name) VALUES (?)", name)
-                .execute(&mut *conn)
-                .await?;
-        }
+        check_relative_path(name)?;
+        ensure_repository(conn, name).await?;
         let session_id = Uuid::new_v4().to_string();
         info!("creating new session with id: {}", session_id);
         let new_dir = self.base_path.join(name).join("blobs").join(&session_id);
@@ -257,53 +1396,151 @@ impl LocalStorageDriver {
             error!("Error creating directory: {:?}", err);
             return Err(StorageError::IoError(err));
         }
-        query!("INSERT INTO uploads (repository_id, uuid) VALUES ((SELECT id FROM repositories WHERE name = ?), ?)", name, session_id)
+        query!("INSERT INTO uploads (repository_id, uuid) VALUES ((SELECT id FROM repositories WHERE name = ? AND deleted_at IS NULL), ?)", name, session_id)
             .execute(conn)
             .await?;
         Ok(session_id)
     }
 
+    /// Drops an upload session along with any chunks already written for it
+    pub async fn cancel_session(
+        &self,
+        conn: &mut SqliteConnection,
+        name: &str,
+        session_id: &str,
+    ) -> Result<bool, StorageError> {
+        if !drop_upload_session(conn, name, session_id).await? {
+            return Ok(false);
+        }
+        self.remove_session_dir(name, session_id).await;
+        Ok(true)
+    }
+
+    /// Removes the directory holding a session's chunks, once they're
+    /// dropped or combined
+    async fn remove_session_dir(&self, name: &str, session_id: &str) {
+        let dir = self.base_path.join(name).join("blobs").join(session_id);
+        if let Err(err) = tokio::fs::remove_dir_all(&dir).await {
+            if err.kind() != io::ErrorKind::NotFound {
+                error!("unable to remove upload session dir {:?}: {}", dir, err);
+            }
+        }
+    }
+
     pub async fn combine_chunks(
         &self,
         pool: &mut SqliteConnection,
         name: &str,
         session_id: &str,
     ) -> Result<String, StorageError> {
+        ensure_upload_intact(pool, name, session_id).await?;
         let rows = query!(
-            "SELECT file_path, chunk_count FROM blobs JOIN repositories ON blobs.repository_id = repositories.id WHERE upload_session_id = ? AND repositories.name = ? ORDER BY chunk_count ASC",
+            "SELECT file_path, digest, size FROM blobs JOIN repositories ON blobs.repository_id = repositories.id WHERE upload_session_id = ? AND repositories.name = ? AND repositories.deleted_at IS NULL ORDER BY chunk_offset ASC",
             session_id, name
         )
         .fetch_all(&mut *pool)
         .await?;
-        let mut data = Vec::new();
+        let total = rows.iter().map(|row| row.size.max(0) as u64).sum();
+        if let Err(err) = check_quota(pool, name, total, Some(session_id)).await {
+            self.cancel_session(pool, name, session_id).await?;
+            return Err(err);
+        }
+        // every chunk is checked before anything is written, a corrupt one
+        // leaves the chunks and their rows as they were
         for row in rows.iter() {
-            let mut file = tokio::fs::File::open(&row.file_path).await?;
-            let mut chunk_data = Vec::new();
-            file.read_to_end(&mut chunk_data).await?;
-            data.extend(chunk_data);
-            sqlx::query!(
-                "DELETE FROM blobs WHERE upload_session_id = ? AND chunk_count = ?",
-                session_id,
-                row.chunk_count
-            )
-            .execute(&mut *pool)
-            .await?;
+            let mut hasher = DigestHasher::default();
+            let read = copy_hashed(&row.file_path, &mut hasher, &mut tokio::io::sink()).await;
+            if read.is_err() || hasher.finalize() != row.digest {
+                mark_upload_corrupt(pool, session_id).await?;
+                return Err(StorageError::UploadCorrupt);
+            }
+        }
+        // each chunk is appended to the final file as it's read, so only one
+        // buffer is held in memory at a time
+        let blobs_dir = self.base_path.join(name).join("blobs");
+        let partial = blobs_dir.join(format!("{session_id}.partial"));
+        let mut hasher = DigestHasher::default();
+        let written = async {
+            let mut file = BufWriter::new(File::create(&partial).await?);
+            let mut size = 0;
+            for row in rows.iter() {
+                size += copy_hashed(&row.file_path, &mut hasher, &mut file).await?;
+            }
+            file.flush().await?;
+            Ok::<_, io::Error>(size)
         }
-        let digest = calculate_digest(&data);
-        let file_path = self
-            .base_path
-            .join(name)
-            .join("blobs")
-            .join(&digest)
-            .to_string_lossy()
-            .to_string();
-        tokio::fs::write(&file_path, &mut data).await?;
-        let _ = query!("INSERT INTO blobs (repository_id, digest, file_path) VALUES ((select id from repositories where name = ?), ?, ?)", name, digest, file_path)
-        .execute(pool)
         .await;
+        let size = match written {
+            Ok(size) => size as i64,
+            Err(err) => {
+                remove_partial_file(&partial).await;
+                return Err(err.into());
+            }
+        };
+        let digest = hasher.finalize();
+        let file_path = blobs_dir.join(&digest);
+        if let Err(err) = tokio::fs::rename(&partial, &file_path).await {
+            remove_partial_file(&partial).await;
+            return Err(err.into());
+        }
+        let file_path = file_path.to_string_lossy().to_string();
+        // the chunk rows give way to the blob's row together, or not at all
+        let mut tx = pool.begin().await?;
+        query!("DELETE FROM blobs WHERE upload_session_id = ?", session_id)
+            .execute(&mut *tx)
+            .await?;
+        query!("INSERT INTO blobs (repository_id, digest, file_path, size) VALUES ((SELECT id FROM repositories WHERE name = ? AND deleted_at IS NULL), ?, ?, ?)", name, digest, file_path, size)
+        .execute(&mut *tx)
+        .await?;
+        query!("DELETE FROM uploads WHERE uuid = ?", session_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        self.remove_session_dir(name, session_id).await;
         Ok(digest)
     }
 
+    /// Compares each stored chunk with its recorded offset and size, and
+    /// with `full` its digest too, marking the session corrupt on a mismatch
+    pub async fn verify_upload_session(
+        &self,
+        pool: &mut SqliteConnection,
+        name: &str,
+        session_id: &str,
+        full: bool,
+    ) -> Result<(), StorageError> {
+        ensure_upload_intact(pool, name, session_id).await?;
+        let rows = query!(
+            "SELECT file_path, digest, size, chunk_offset FROM blobs WHERE upload_session_id = ? ORDER BY chunk_offset ASC",
+            session_id
+        )
+        .fetch_all(&mut *pool)
+        .await?;
+        let mut expected_offset = 0;
+        for row in rows {
+            let intact = row.chunk_offset == expected_offset
+                && match tokio::fs::metadata(&row.file_path).await {
+                    Ok(meta) if meta.len() == row.size as u64 => {
+                        !full
+                            || tokio::fs::read(&row.file_path)
+                                .await
+                                .is_ok_and(|data| calculate_digest(&data) == row.digest)
+                    }
+                    _ => false,
+                };
+            if !intact {
+                error!(
+                    "chunk {} of upload {} is damaged",
+                    row.file_path, session_id
+                );
+                mark_upload_corrupt(pool, session_id).await?;
+                return Err(StorageError::UploadCorrupt);
+            }
+            expected_offset += row.size;
+        }
+        Ok(())
+    }
+
     pub async fn mount_blob(
         &self,
         pool: &mut SqliteConnection,
@@ -311,88 +1548,50 @@ impl LocalStorageDriver {
         digest: &str,
         source_name: Option<&str>,
     ) -> Result<String, StorageError> {
-        let row = if let Some(source_name) = source_name {
-            sqlx::query!(
-                "SELECT file_path FROM blobs JOIN repositories ON blobs.repository_id = repositories.id WHERE digest = ? AND repositories.name = ?",
-                digest, source_name
-            )
-            .fetch_one(&mut *pool)
-            .await?.file_path
-        } else {
-            sqlx::query!("SELECT file_path FROM blobs WHERE digest = ?", digest)
-                .fetch_one(&mut *pool)
-                .await?
-                .file_path
-        };
+        mount_blob(pool, target_name, digest, source_name).await
+    }
 
-        let target_exists = query!("SELECT COUNT(*) as count FROM blobs JOIN repositories ON blobs.repository_id = repositories.id WHERE digest = ? AND repositories.name = ?", digest, target_name)
-            .fetch_optional(&mut *pool)
-            .await?
-            .is_some_and(|row| row.count > 0);
-        if !target_exists {
-            let target_repository_id =
-                query!("SELECT id FROM repositories WHERE name = ?", target_name)
-                    .fetch_one(&mut *pool)
-                    .await?
-                    .id;
-
-            query!(
-                "INSERT INTO blobs (repository_id, digest, file_path) VALUES (?, ?, ?)",
-                target_repository_id,
-                digest,
-                row
-            )
-            .execute(pool)
-            .await?;
-        }
-        Ok(row)
+    pub async fn write_image_manifest(
+        &self,
+        pool: &mut SqliteConnection,
+        name: &str,
+        reference: &str,
+        media_type: &str,
+        data: &[u8],
+        with_diff_ids: bool,
+    ) -> Result<String, StorageError> {
+        write_image_manifest(self, pool, name, reference, media_type, data, with_diff_ids).await
     }
 
-    pub async fn write_manifest(
+    pub async fn write_image_index(
         &self,
         pool: &mut SqliteConnection,
         name: &str,
         reference: &str,
-        data: BodyDataStream,
+        media_type: &str,
+        data: &[u8],
     ) -> Result<String, StorageError> {
-        let path = self
-            .stream_to_file(
-                std::path::PathBuf::from(name)
-                    .join("manifests")
-                    .to_str()
-                    .expect("invalid path"),
-                reference,
-                data,
-            )
-            .await?;
-        info!("successfully wrote manifest to path: {:?}", path);
-        let digest = calculate_digest(&tokio::fs::read(&path).await?);
-        let img: ImageManifest = serde_json::from_str(&tokio::fs::read_to_string(&path).await?)
-            .map_err(|_| {
-                StorageError::IoError(std::io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    "error deserializing into ImageManifest",
-                ))
-            })?;
-        let cfg = img.config.unwrap_or_default();
-        let file_path = path.to_string_lossy().to_string();
-        let record = query!("INSERT INTO manifests (repository_id, digest, file_path, media_type, size, schema_version)
-             VALUES ((select id from repositories where name = ?), ?, ?, ?, ?, ?)",
-            name, digest, file_path, img.media_type, cfg.size, img.schema_version)
-        .execute(&mut *pool)
-        .await?;
-        for layer in img.layers {
-            query!("INSERT INTO manifest_layers (manifest_id, repository_id, digest, size, media_type) VALUES ((SELECT id from manifests WHERE digest = ?), (SELECT id from repositories where name = ?), ?, ?, ?)", digest, name, layer.digest, layer.size, layer.media_type).execute(&mut *pool).await?;
-            query!(
-                "UPDATE blobs SET ref_count = ref_count + 1 WHERE digest = ?",
-                layer.digest
-            )
-            .execute(&mut *pool)
-            .await?;
-        }
-        let id = record.last_insert_rowid();
-        query!("INSERT OR REPLACE INTO tags (repository_id, tag, manifest_id) VALUES ((SELECT id from repositories where name = ?), ?, ?)", name, reference, id).execute(pool).await?;
-        Ok(digest)
+        write_image_index(self, pool, name, reference, media_type, data).await
+    }
+
+    pub async fn write_artifact_manifest(
+        &self,
+        pool: &mut SqliteConnection,
+        name: &str,
+        reference: &str,
+        media_type: &str,
+        data: &[u8],
+    ) -> Result<String, StorageError> {
+        write_artifact_manifest(self, pool, name, reference, media_type, data).await
+    }
+
+    pub async fn manifest_closure(
+        &self,
+        pool: &mut SqliteConnection,
+        name: &str,
+        reference: &str,
+    ) -> Result<Vec<String>, StorageError> {
+        manifest_closure(self, pool, name, reference).await
     }
 
     pub async fn delete_blob(
@@ -401,14 +1600,19 @@ impl LocalStorageDriver {
         name: &str,
         digest: &str,
     ) -> Result<(), StorageError> {
-        let row = query!("SELECT file_path, blobs.id FROM blobs JOIN repositories ON blobs.repository_id = repositories.id WHERE digest = ? AND repositories.name = ?", digest, name)
-            .fetch_one(&mut *pool)
-            .await?;
-        std::fs::remove_file(row.file_path)?;
-        query!("DELETE FROM blobs WHERE id = ?", row.id)
-            .execute(&mut *pool)
-            .await?;
-        Ok(())
+        delete_blob(self, pool, name, digest).await
+    }
+
+    pub async fn export_repository<W>(
+        &self,
+        pool: &mut SqliteConnection,
+        name: &str,
+        writer: W,
+    ) -> Result<(), StorageError>
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        export_repository(self, pool, name, writer).await
     }
 
     pub async fn delete_manifest(&self, file_path: &str) -> Result<(), StorageError> {
@@ -422,6 +1626,7 @@ impl LocalStorageDriver {
         name: &str,
         is_pub: bool,
     ) -> Result<(), StorageError> {
+        check_relative_path(name)?;
         query!(
             "INSERT INTO repositories (name, is_public) VALUES (?, ?)",
             name,
@@ -444,22 +1649,7 @@ impl LocalStorageDriver {
     pub async fn run_garbage_collection(
         &self,
         pool: &mut SqliteConnection,
-    ) -> Result<(), StorageError> {
-        let rows = query!("SELECT digest, ref_count FROM blobs")
-            .fetch_all(&mut *pool)
-            .await?;
-        for row in rows.iter() {
-            if row.ref_count == 0 {
-                let path = query!("SELECT file_path FROM blobs WHERE digest = ?", row.digest)
-                    .fetch_one(&mut *pool)
-                    .await?
-                    .file_path;
-                tokio::fs::remove_file(path).await?;
-                query!("DELETE FROM blobs WHERE digest = ?", row.digest)
-                    .execute(&mut *pool)
-                    .await?;
-            }
-        }
-        Ok(())
+    ) -> Result<usize, StorageError> {
+        collect_garbage(self, pool).await
     }
 }
@@ -1,13 +1,17 @@
 use axum::async_trait;
 use axum::body::BodyDataStream;
 use axum::extract::{FromRef, FromRequestParts};
+use bytes::Bytes;
 use clap::ValueEnum;
+use futures::stream::BoxStream;
 use http::request::Parts;
 use http::StatusCode;
 use sqlx::SqliteConnection;
 use std::path::PathBuf;
 
+use crate::s3::{S3Settings, S3StorageDriver};
 use crate::storage::LocalStorageDriver;
+use crate::util::ByteRange;
 
 #[async_trait]
 impl<S> FromRequestParts<S> for Backend
@@ -22,6 +26,15 @@ where
     }
 }
 
+/// A blob read back from storage: its size, and its content streamed
+/// rather than held in memory
+pub struct BlobReader {
+    pub size: u64,
+    /// inclusive window of the blob being streamed, when a range was asked for
+    pub range: Option<(u64, u64)>,
+    pub stream: BoxStream<'static, std::io::Result<Bytes>>,
+}
+
 #[derive(Debug)]
 pub enum StorageError {
     IoError(std::io::Error),
@@ -29,6 +42,14 @@ pub enum StorageError {
     DigestError,
     InvalidLogin,
     OutOfOrder,
+    /// a requested byte range lies outside a blob of this size
+    RangeNotSatisfiable(u64),
+    /// a manifest references a blob the repository doesn't hold
+    BlobUnknown(String),
+    /// storing the upload would take the repository past its quota in bytes
+    QuotaExceeded(u64),
+    /// a stored chunk of the upload session is missing or was altered
+    UploadCorrupt,
 }
 impl std::error::Error for StorageError {}
 impl std::fmt::Display for StorageError {
@@ -39,6 +60,12 @@ impl std::fmt::Display for StorageError {
             Self::DigestError => write!(f, "Digest mismatch"),
             Self::InvalidLogin => write!(f, "Login failed"),
             Self::OutOfOrder => write!(f, "Chunk out of order"),
+            Self::RangeNotSatisfiable(size) => {
+                write!(f, "Range not satisfiable for {} bytes", size)
+            }
+            Self::BlobUnknown(digest) => write!(f, "Blob unknown: {}", digest),
+            Self::QuotaExceeded(max) => write!(f, "Repository quota of {} bytes exceeded", max),
+            Self::UploadCorrupt => write!(f, "Upload session is corrupt"),
         }
     }
 }
@@ -87,7 +114,7 @@ impl ValueEnum for DriverType {
 
 pub enum Backend {
     Local(LocalStorageDriver),
-    // S3(S3StorageDriver),
+    S3(Box<S3StorageDriver>),
 }
 // all this just because we can't use trait objects or impl trait async 😥
 macro_rules! backend_methods {
@@ -103,11 +130,26 @@ macro_rules! backend_methods {
                     $(Self::$variant(driver) => driver.base_path(),)+
                 }
             }
+            pub fn is_healthy(&self) -> bool {
+                match self {
+                    $(Self::$variant(driver) => driver.is_healthy(),)+
+                }
+            }
+            pub async fn probe_health(&self) -> bool {
+                match self {
+                    $(Self::$variant(driver) => driver.probe_health().await,)+
+                }
+            }
             pub async fn get_dir_size(&self, path: impl Into<PathBuf>) -> u64 {
                 match self {
                     $(Self::$variant(driver) => driver.get_dir_size(path).await,)+
                 }
             }
+            pub async fn blob_size(&self, file_path: &str) -> Option<u64> {
+                match self {
+                    $(Self::$variant(driver) => driver.blob_size(file_path).await,)+
+                }
+            }
 
             pub async fn write_blob(
                 &self,
@@ -139,9 +181,10 @@ macro_rules! backend_methods {
                 pool: &mut SqliteConnection,
                 name: &str,
                 digest: &str,
-            ) -> Result<Vec<u8>, StorageError> {
+                range: Option<ByteRange>,
+            ) -> Result<BlobReader, StorageError> {
                 match self {
-                    $(Self::$variant(driver) => driver.read_blob(pool, name, digest).await,)+
+                    $(Self::$variant(driver) => driver.read_blob(pool, name, digest, range).await,)+
                 }
             }
 
@@ -164,6 +207,28 @@ macro_rules! backend_methods {
                 }
             }
 
+            pub async fn manifest_closure(
+                &self,
+                pool: &mut SqliteConnection,
+                name: &str,
+                reference: &str,
+            ) -> Result<Vec<String>, StorageError> {
+                match self {
+                    $(Self::$variant(driver) => driver.manifest_closure(pool, name, reference).await,)+
+                }
+            }
+
+            pub async fn cancel_session(
+                &self,
+                conn: &mut SqliteConnection,
+                name: &str,
+                session_id: &str,
+            ) -> Result<bool, StorageError> {
+                match self {
+                    $(Self::$variant(driver) => driver.cancel_session(conn, name, session_id).await,)+
+                }
+            }
+
             pub async fn mount_blob(
                 &self,
                 pool: &mut SqliteConnection,
@@ -176,15 +241,43 @@ macro_rules! backend_methods {
                 }
             }
 
-            pub async fn write_manifest(
+            pub async fn write_image_manifest(
                 &self,
                 pool: &mut SqliteConnection,
                 name: &str,
                 reference: &str,
-                data: BodyDataStream,
+                media_type: &str,
+                data: &[u8],
+                with_diff_ids: bool,
             ) -> Result<String, StorageError> {
                 match self {
-                    $(Self::$variant(driver) => driver.write_manifest(pool, name, reference, data).await,)+
+                    $(Self::$variant(driver) => driver.write_image_manifest(pool, name, reference, media_type, data, with_diff_ids).await,)+
+                }
+            }
+
+            pub async fn write_image_index(
+                &self,
+                pool: &mut SqliteConnection,
+                name: &str,
+                reference: &str,
+                media_type: &str,
+                data: &[u8],
+            ) -> Result<String, StorageError> {
+                match self {
+                    $(Self::$variant(driver) => driver.write_image_index(pool, name, reference, media_type, data).await,)+
+                }
+            }
+
+            pub async fn write_artifact_manifest(
+                &self,
+                pool: &mut SqliteConnection,
+                name: &str,
+                reference: &str,
+                media_type: &str,
+                data: &[u8],
+            ) -> Result<String, StorageError> {
+                match self {
+                    $(Self::$variant(driver) => driver.write_artifact_manifest(pool, name, reference, media_type, data).await,)+
                 }
             }
 
@@ -199,6 +292,20 @@ macro_rules! backend_methods {
                 }
             }
 
+            pub async fn export_repository<W>(
+                &self,
+                pool: &mut SqliteConnection,
+                name: &str,
+                writer: W,
+            ) -> Result<(), StorageError>
+            where
+                W: tokio::io::AsyncWrite + Unpin + Send + 'static,
+            {
+                match self {
+                    $(Self::$variant(driver) => driver.export_repository(pool, name, writer).await,)+
+                }
+            }
+
             pub async fn delete_manifest(
                 &self,
                 file_path: &str,
@@ -224,25 +331,64 @@ macro_rules! backend_methods {
                     $(Self::$variant(driver) => driver.combine_chunks(pool, name, session_id).await,)+
                }
             }
-            pub async fn delete_repository(&self, name: &str, pool: &mut SqliteConnection) -> Result<(), StorageError> {
+            pub async fn delete_repository(&self, id: i64, pool: &mut SqliteConnection) -> Result<Vec<String>, StorageError> {
                 match self {
-                    $(Self::$variant(driver) => driver.delete_repository(name, pool).await,)+
+                    $(Self::$variant(driver) => driver.delete_repository(id, pool).await,)+
                 }
            }
+
+            /// Checks the chunks stored so far for an upload session still match
+            /// what was recorded for them, reading them back when `full`
+            pub async fn verify_upload_session(&self, pool: &mut SqliteConnection, name: &str, session_id: &str, full: bool) -> Result<(), StorageError> {
+                match self {
+                    $(Self::$variant(driver) => driver.verify_upload_session(pool, name, session_id, full).await,)+
+                }
+            }
+
+            /// Removes unreferenced blobs, returning how many were removed
+            pub async fn run_garbage_collection(&self, pool: &mut SqliteConnection) -> Result<usize, StorageError> {
+                match self {
+                    $(Self::$variant(driver) => driver.run_garbage_collection(pool).await,)+
+                }
+            }
         }
     };
 }
 
-backend_methods!(Backend, Local /*, S3 */);
+backend_methods!(Backend, Local, S3);
 impl Backend {
-    pub fn new(driver: DriverType, base_path: &std::path::Path) -> Self {
+    /// Permanently removes soft-deleted repositories whose recovery window has passed
+    pub async fn purge_expired_repositories(
+        &self,
+        pool: &mut SqliteConnection,
+        window: u64,
+    ) -> Result<usize, StorageError> {
+        let expired = crate::database::expired_repositories(pool, window).await?;
+        for (id, name) in expired.iter() {
+            let failed = self.delete_repository(*id, pool).await?;
+            if !failed.is_empty() {
+                tracing::warn!("purged {} but left {} files behind", name, failed.len());
+            }
+        }
+        Ok(expired.len())
+    }
+
+    /// Builds the backend selected by `--driver`. Local storage lives under
+    /// `base_path`, S3 storage in the bucket `s3` names.
+    pub fn new(
+        driver: DriverType,
+        base_path: &std::path::Path,
+        s3: &S3Settings,
+    ) -> Result<Self, StorageError> {
         match driver {
-            DriverType::Local => Self::Local(LocalStorageDriver::new(base_path)),
-            DriverType::S3 => todo!(),
+            DriverType::Local => Ok(Self::Local(LocalStorageDriver::new(base_path))),
+            DriverType::S3 => Ok(Self::S3(Box::new(S3StorageDriver::new(s3)?))),
         }
     }
 }
 
 pub fn init_testing_storage() -> Backend {
-    Backend::new(DriverType::Local, &std::path::PathBuf::from("./tests"))
+    Backend::Local(LocalStorageDriver::new(&std::path::PathBuf::from(
+        "./tests",
+    )))
 }
@@ -0,0 +1,35 @@
+//! The browser dashboard served under `/ui` with `--enable-ui`. The assets in
+//! `ui/` are compiled into the binary; the page itself only talks to the
+//! existing JSON endpoints, logging in through `/auth/token`.
+use axum::{
+    extract::Path,
+    http::{header::CONTENT_TYPE, StatusCode},
+    response::{IntoResponse, Response},
+};
+use rust_embed::RustEmbed;
+
+#[derive(RustEmbed)]
+#[folder = "ui/"]
+struct Assets;
+
+/// GET /ui/
+pub async fn serve_ui_index() -> Response {
+    asset("index.html")
+}
+
+/// GET /ui/*path
+pub async fn serve_ui(Path(path): Path<String>) -> Response {
+    asset(&path)
+}
+
+fn asset(path: &str) -> Response {
+    match Assets::get(path) {
+        Some(file) => (
+            StatusCode::OK,
+            [(CONTENT_TYPE, file.metadata.mimetype().to_string())],
+            file.data,
+        )
+            .into_response(),
+        None => (StatusCode::NOT_FOUND, "not found").into_response(),
+    }
+}
@@ -1,14 +1,36 @@
-use crate::database::DbConn;
-use axum::{extract::Path, http::StatusCode, response::IntoResponse, Json};
+use crate::{
+    auth::{qualify_scopes, Auth},
+    codes::{deletes_disabled, Code, ErrorResponse},
+    config::Config,
+    database::{self, DbConn},
+    UserScope,
+};
+use axum::{
+    body::Bytes,
+    extract::{Path, Query},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
 use shared::User;
-use shared::{RepoScope, UserResponse};
+use shared::{
+    CreateUserRequest, CreatedUser, KeyScope, RegisterUserRequest, RepoScope, UserResponse,
+};
+use std::sync::Arc;
 
-pub async fn get_users(DbConn(mut conn): DbConn) -> impl IntoResponse {
+pub async fn get_users(
+    DbConn(mut conn): DbConn,
+    Extension(auth): Extension<Auth>,
+) -> impl IntoResponse {
+    if !auth.is_global_admin() {
+        return ErrorResponse::from_code(&Code::Denied, String::from("admin access required"))
+            .into_response();
+    }
     let users = sqlx::query_as!(User, "SELECT * FROM users")
         .fetch_all(&mut *conn)
         .await
         .expect("unable to fetch users");
-    let repositories = sqlx::query!("SELECT * FROM repositories")
+    let repositories = sqlx::query!("SELECT * FROM repositories WHERE deleted_at IS NULL")
         .fetch_all(&mut *conn)
         .await
         .expect("unable to fetch repositories");
@@ -49,12 +71,75 @@ pub async fn get_users(DbConn(mut conn): DbConn) -> impl IntoResponse {
     (StatusCode::OK, Json(user_resp)).into_response()
 }
 
-pub async fn delete_user(Path(email): Path<String>, DbConn(mut conn): DbConn) -> impl IntoResponse {
-    let _ = sqlx::query!("DELETE FROM users WHERE email = ?", email)
+/// POST /users
+/// creates a user like the `new-user` command, responding with its id
+pub async fn create_user(
+    DbConn(mut conn): DbConn,
+    Extension(auth): Extension<Auth>,
+    Json(req): Json<CreateUserRequest>,
+) -> impl IntoResponse {
+    if !auth.is_global_admin() {
+        return ErrorResponse::from_code(&Code::Denied, String::from("admin access required"))
+            .into_response();
+    }
+    if !RegisterUserRequest::new(&req.email, &req.password, &req.password, req.is_admin).validate()
+    {
+        return (
+            StatusCode::BAD_REQUEST,
+            "emails must be ascii and passwords longer than 8 characters with a digit",
+        )
+            .into_response();
+    }
+    match database::user_exists(&mut conn, &req.email).await {
+        Ok(false) => {}
+        Ok(true) => {
+            return (
+                StatusCode::CONFLICT,
+                "a user with that email already exists",
+            )
+                .into_response()
+        }
+        Err(err) => {
+            tracing::error!("unable to look up user {}: {}", req.email, err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "unable to create user").into_response();
+        }
+    }
+    match database::create_user(&mut conn, &req.email, &req.password, req.is_admin).await {
+        Ok(id) => (StatusCode::CREATED, Json(CreatedUser { id })).into_response(),
+        Err(err) => {
+            tracing::error!("unable to create user {}: {}", req.email, err);
+            (StatusCode::INTERNAL_SERVER_ERROR, "unable to create user").into_response()
+        }
+    }
+}
+
+pub async fn delete_user(
+    Path(email): Path<String>,
+    DbConn(mut conn): DbConn,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(auth): Extension<Auth>,
+) -> impl IntoResponse {
+    if !auth.is_global_admin() {
+        return ErrorResponse::from_code(&Code::Denied, String::from("admin access required"))
+            .into_response();
+    }
+    if config.disable_delete {
+        return deletes_disabled();
+    }
+    // the user's API keys, tokens and scopes go with it
+    match sqlx::query!("DELETE FROM users WHERE email = ?", email)
         .execute(&mut *conn)
         .await
-        .expect("unable to delete user");
-    (StatusCode::NO_CONTENT, "").into_response()
+    {
+        Ok(res) if res.rows_affected() == 0 => {
+            (StatusCode::NOT_FOUND, "user not found").into_response()
+        }
+        Ok(_) => (StatusCode::NO_CONTENT, "").into_response(),
+        Err(err) => {
+            tracing::error!("unable to delete user {}: {}", email, err);
+            (StatusCode::INTERNAL_SERVER_ERROR, "unable to delete user").into_response()
+        }
+    }
 }
 
 pub async fn add_scope(
@@ -62,7 +147,7 @@ pub async fn add_scope(
     DbConn(mut conn): DbConn,
 ) -> impl IntoResponse {
     let scopes = sqlx::query!(
-        "SELECT * FROM repository_scopes WHERE user_id = (SELECT id FROM users WHERE email = ?) AND repository_id = (SELECT id FROM repositories WHERE name = ?)",
+        "SELECT * FROM repository_scopes WHERE user_id = (SELECT id FROM users WHERE email = ?) AND repository_id = (SELECT id FROM repositories WHERE name = ? AND deleted_at IS NULL)",
         email,
         repo,
     )
@@ -94,16 +179,90 @@ pub async fn add_scope(
     (StatusCode::NO_CONTENT, "").into_response()
 }
 
+#[derive(Debug, serde::Deserialize)]
+pub struct TokenParams {
+    /// limits the key, e.g. `repository:foo:pull repository:bar:push`.
+    /// without it the key grants nothing
+    scope: Option<String>,
+}
+
+/// POST /users/:email/tokens
+/// creates an API key for the user, minted by an admin or the user
+/// themselves. The key is limited to the `?scope=` and to the repositories
+/// listed in a JSON body of `KeyScope`s, a key with neither grants nothing.
+/// Either way it never grants more than the user holds.
 pub async fn generate_token(
     Path(email): Path<String>,
     DbConn(mut conn): DbConn,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(auth): Extension<Auth>,
+    Query(params): Query<TokenParams>,
+    body: Bytes,
 ) -> impl IntoResponse {
-    let token = crate::database::generate_secret(&mut conn, None, &email)
-        .await
-        .unwrap();
-    (
-        StatusCode::OK,
-        serde_json::to_string(&crate::auth::TokenResponse::new(&token)).unwrap(),
-    )
-        .into_response()
+    let user_id = match database::user_id(&mut conn, &email).await {
+        Ok(Some(id)) => id,
+        Ok(None) => return (StatusCode::NOT_FOUND, "user not found").into_response(),
+        Err(err) => {
+            tracing::error!("unable to look up user {}: {}", email, err);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "unable to create API key",
+            )
+                .into_response();
+        }
+    };
+    if !auth.is_global_admin() && auth.subject() != Some(user_id.as_str()) {
+        return ErrorResponse::from_code(
+            &Code::Denied,
+            "only admins can create API keys for other users",
+        )
+        .into_response();
+    }
+    let listed = if body.iter().all(u8::is_ascii_whitespace) {
+        Vec::new()
+    } else {
+        match serde_json::from_slice::<Vec<KeyScope>>(&body) {
+            Ok(listed) => listed,
+            Err(err) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    format!("invalid key scopes: {err}"),
+                )
+                    .into_response()
+            }
+        }
+    };
+    let requested = params
+        .scope
+        .filter(|scope| !scope.trim().is_empty())
+        .into_iter()
+        .chain(
+            listed
+                .iter()
+                .map(|key| format!("repository:{}:{}", key.repo, key.scope.join(","))),
+        )
+        .collect::<Vec<_>>();
+    let scope = if requested.is_empty() {
+        UserScope::default()
+    } else {
+        match qualify_scopes(&requested.join(" "), &config).parse::<UserScope>() {
+            Ok(scope) => scope,
+            Err(err) => return (StatusCode::BAD_REQUEST, err).into_response(),
+        }
+    };
+    match database::generate_secret(&mut conn, None, &email, None, Some(&scope)).await {
+        Ok(token) => (
+            StatusCode::OK,
+            serde_json::to_string(&crate::auth::TokenResponse::new(&token)).unwrap(),
+        )
+            .into_response(),
+        Err(err) => {
+            tracing::error!("unable to create API key for {}: {}", email, err);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "unable to create API key",
+            )
+                .into_response()
+        }
+    }
 }
@@ -1,8 +1,46 @@
-use crate::{auth::UserInfo, storage_driver::StorageError};
+use crate::{
+    auth::UserInfo,
+    codes::{NAMESPACE_REGEX, REFERENCE_REGEX},
+    storage_driver::StorageError,
+};
 use crate::{Action, UserScope};
 use base64::{alphabet::URL_SAFE, Engine};
-use http::{header::CONTENT_RANGE, HeaderMap};
-use sha2::{Digest, Sha256};
+use hmac::{Hmac, Mac};
+use http::{
+    header::{CONTENT_RANGE, RANGE},
+    HeaderMap, HeaderValue,
+};
+use lazy_static::lazy_static;
+use regex::Regex;
+use sha2::{Digest, Sha256, Sha512};
+use std::{
+    borrow::Cow,
+    future::Future,
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+lazy_static! {
+    /// the repository name grammar from the distribution spec
+    static ref REPOSITORY_NAME: Regex =
+        Regex::new(&format!("^{NAMESPACE_REGEX}$")).expect("invalid repository name pattern");
+    static ref TAG: Regex = Regex::new(&format!("^{REFERENCE_REGEX}$")).expect("invalid tag pattern");
+}
+
+/// `Cache-Control` for content addressed responses, which never change.
+/// Only a public repository's content may be kept by shared caches like a CDN,
+/// anything else would be served past the registry's auth.
+pub fn immutable_cache_control(public: bool) -> HeaderValue {
+    if public {
+        HeaderValue::from_static("public, max-age=31536000, immutable")
+    } else {
+        HeaderValue::from_static("private, max-age=31536000, immutable")
+    }
+}
+
+/// `Cache-Control` for responses that can change, like a manifest pulled by
+/// tag, so caches check the ETag before reusing them
+pub const REVALIDATE_CACHE_CONTROL: HeaderValue = HeaderValue::from_static("no-cache");
 
 pub fn calculate_digest(data: &[u8]) -> String {
     let mut hasher = Sha256::new();
@@ -10,24 +48,124 @@ pub fn calculate_digest(data: &[u8]) -> String {
     format!("sha256:{:x}", hasher.finalize())
 }
 
+/// Incremental hasher for the algorithms a blob digest may name
+pub enum DigestHasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+impl Default for DigestHasher {
+    fn default() -> Self {
+        Self::Sha256(Sha256::new())
+    }
+}
+
+impl DigestHasher {
+    /// Picks the hasher for the algorithm of a `<algorithm>:<hex>` digest,
+    /// or `None` when the algorithm is unsupported or the hex could never
+    /// be its output
+    pub fn for_digest(digest: &str) -> Option<Self> {
+        let (algorithm, hex) = digest.split_once(':')?;
+        let (hasher, len) = match algorithm {
+            "sha256" => (Self::Sha256(Sha256::new()), 64),
+            "sha512" => (Self::Sha512(Sha512::new()), 128),
+            _ => return None,
+        };
+        let well_formed = hex.len() == len
+            && hex
+                .bytes()
+                .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b));
+        well_formed.then_some(hasher)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Sha256(hasher) => hasher.update(data),
+            Self::Sha512(hasher) => hasher.update(data),
+        }
+    }
+
+    pub fn finalize(self) -> String {
+        match self {
+            Self::Sha256(hasher) => format!("sha256:{:x}", hasher.finalize()),
+            Self::Sha512(hasher) => format!("sha512:{:x}", hasher.finalize()),
+        }
+    }
+}
+
+/// Checks data against a digest, hashing with the algorithm the digest names
 pub fn validate_digest(data: &[u8], digest: &str) -> Result<(), StorageError> {
-    let calculated_digest = calculate_digest(data);
+    let mut hasher = DigestHasher::for_digest(digest).ok_or(StorageError::DigestError)?;
+    hasher.update(data);
+    let calculated_digest = hasher.finalize();
     if !calculated_digest.eq(digest) {
         return Err(StorageError::DigestError);
     }
     Ok(())
 }
 
+/// Whether a relative path stays below the directory it is joined onto,
+/// i.e. it is not empty and has no root, prefix or `..` components
 pub fn path_is_valid(path: &str) -> bool {
-    let path = std::path::Path::new(path);
-    let mut components = path.components().peekable();
+    let mut components = std::path::Path::new(path).components().peekable();
+    components.peek().is_some()
+        && components.all(|component| matches!(component, std::path::Component::Normal(_)))
+}
 
-    if let Some(first) = components.peek() {
-        if !matches!(first, std::path::Component::Normal(_)) {
-            return false;
-        }
+/// Whether a name is allowed for a repository, lowercase path components
+/// joined by `/`, separated within by `.`, `_`, `__` or dashes
+pub fn is_valid_repository_name(name: &str) -> bool {
+    name.len() <= 255 && REPOSITORY_NAME.is_match(name)
+}
+
+/// Awaits `fut`, logging a warning when it takes longer than `threshold`
+pub async fn log_if_slow<F: Future>(threshold: Option<Duration>, what: &str, fut: F) -> F::Output {
+    let Some(threshold) = threshold else {
+        return fut.await;
+    };
+    let started = Instant::now();
+    let output = fut.await;
+    let elapsed = started.elapsed();
+    if elapsed > threshold {
+        warn!(
+            latency_ms = elapsed.as_millis() as u64,
+            "slow query: {what}"
+        );
+    }
+    output
+}
+
+/// Whether a manifest reference is a well-formed tag or digest
+pub fn is_valid_reference(reference: &str) -> bool {
+    TAG.is_match(reference) || DigestHasher::for_digest(reference).is_some()
+}
+
+/// Repository names may contain slashes, which reach the router encoded as
+/// `%2F` so the name stays a single `:name` segment
+pub fn decode_repository_name(segment: &str) -> Cow<'_, str> {
+    if segment.contains("%2F") || segment.contains("%2f") {
+        Cow::Owned(segment.replace("%2F", "/").replace("%2f", "/"))
+    } else {
+        Cow::Borrowed(segment)
     }
-    components.count() == 1
+}
+
+pub fn encode_repository_name(name: &str) -> String {
+    name.replace('/', "%2F")
+}
+
+/// Percent-encodes a query parameter value, only unreserved characters are
+/// left as they are
+pub fn encode_query_value(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                char::from(byte).to_string()
+            }
+            byte => format!("%{byte:02X}"),
+        })
+        .collect()
 }
 
 pub fn base64_decode(data: &str) -> Result<String, String> {
@@ -40,6 +178,83 @@ pub fn base64_decode(data: &str) -> Result<String, String> {
     String::from_utf8(decoded).map_err(|_| String::from("Invalid base64"))
 }
 
+/// Decodes a Basic auth payload into its `user:password` parts
+pub fn parse_basic_credentials(token: &str) -> Result<(String, String), String> {
+    let decoded = base64_decode(token)?;
+    match decoded.split_once(':') {
+        Some((user, password)) => Ok((user.to_string(), password.to_string())),
+        None => Err(String::from("Invalid basic auth credentials")),
+    }
+}
+
+/// Converts a registry digest (`sha256:<hex>`) into an RFC 9530
+/// `Repr-Digest` value (`sha-256=:<base64>:`)
+pub fn repr_digest(digest: &str) -> Option<String> {
+    let (algorithm, hex_digest) = digest.split_once(':')?;
+    let algorithm = match algorithm {
+        "sha256" => "sha-256",
+        "sha512" => "sha-512",
+        _ => return None,
+    };
+    let bytes = hex::decode(hex_digest).ok()?;
+    Some(format!(
+        "{}=:{}:",
+        algorithm,
+        base64::engine::general_purpose::STANDARD.encode(bytes)
+    ))
+}
+
+/// A single range from a `Range: bytes=...` request header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteRange {
+    /// `bytes=<start>-<end>`, both inclusive
+    Bounded(u64, u64),
+    /// `bytes=<start>-`
+    From(u64),
+    /// `bytes=-<len>`, the last `len` bytes
+    Suffix(u64),
+}
+
+impl ByteRange {
+    /// Resolves the range against an object of `size` bytes into an
+    /// inclusive start and end, or `None` when it can't be satisfied
+    pub fn resolve(self, size: u64) -> Option<(u64, u64)> {
+        let last = size.checked_sub(1)?;
+        let (start, end) = match self {
+            Self::Bounded(start, end) => (start, end.min(last)),
+            Self::From(start) => (start, last),
+            Self::Suffix(0) => return None,
+            Self::Suffix(len) => (size.saturating_sub(len), last),
+        };
+        (start <= end).then_some((start, end))
+    }
+}
+
+/// Parses a single byte range from the `Range` header. Multiple ranges,
+/// other units and malformed values give `None`, so the whole object is
+/// served instead.
+pub fn parse_range(headers: &HeaderMap) -> Option<ByteRange> {
+    let spec = headers
+        .get(RANGE)?
+        .to_str()
+        .ok()?
+        .trim()
+        .strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    match (start.trim(), end.trim()) {
+        ("", "") => None,
+        ("", len) => len.parse().ok().map(ByteRange::Suffix),
+        (start, "") => start.parse().ok().map(ByteRange::From),
+        (start, end) => {
+            let (start, end) = (start.parse().ok()?, end.parse().ok()?);
+            (start <= end).then_some(ByteRange::Bounded(start, end))
+        }
+    }
+}
+
 pub fn parse_content_length(headers: &HeaderMap) -> i64 {
     headers
         .get("Content-Length")
@@ -131,17 +346,12 @@ where
                 // scope can be between one to three parts
                 // scopes can also be pull,push,delete
                 if parts.len() == 3 {
-                    if parts[2].split(',').count() > 1 {
-                        let greatest = parts[2]
-                            .split(',')
-                            .map(|s| Action::from_str(s).unwrap_or(Action::Pull))
-                            .max_by(|a, b| a.cmp(b))
-                            .unwrap();
-                        map.0.insert(parts[1].to_string(), greatest);
-                    }
+                    let action = Action::from_str(parts[2]).map_err(de::Error::custom)?;
+                    // the same repository listed twice keeps its highest action
                     map.0
                         .entry(parts[1].to_string())
-                        .or_insert(Action::from_str(parts[2]).unwrap());
+                        .and_modify(|granted| *granted = (*granted).max(action))
+                        .or_insert(action);
                 } else {
                     return Err(de::Error::custom("Invalid scope format"));
                 }
@@ -153,3 +363,37 @@ where
 
     deserializer.deserialize_seq(ScopesVisitor)
 }
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn upload_mac(secret: &str, name: &str, session_id: &str, expires: i64) -> HmacSha256 {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("hmac accepts keys of any length");
+    mac.update(format!("{name}:{session_id}:{expires}").as_bytes());
+    mac
+}
+
+/// hex encoded HMAC-SHA256 over the repository, upload session and expiry
+pub fn sign_upload(secret: &str, name: &str, session_id: &str, expires: i64) -> String {
+    hex::encode(
+        upload_mac(secret, name, session_id, expires)
+            .finalize()
+            .into_bytes(),
+    )
+}
+
+/// checks a signature produced by `sign_upload`, in constant time
+pub fn verify_upload_signature(
+    secret: &str,
+    name: &str,
+    session_id: &str,
+    expires: i64,
+    signature: &str,
+) -> bool {
+    match hex::decode(signature) {
+        Ok(bytes) => upload_mac(secret, name, session_id, expires)
+            .verify_slice(&bytes)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
@@ -0,0 +1,205 @@
+mod common;
+
+use axum::http::{Request, StatusCode};
+use common::{admin, basic_auth, body_bytes, test_app_with, RequestExt, TestApp};
+use floundr::{config::Config, Action};
+
+/// Mints a key for `email` as the admin, returning its secret and client id
+async fn mint_key(app: &TestApp, email: &str, scope: &str) -> (String, String) {
+    let res = app
+        .send(
+            admin(Request::post(format!(
+                "/users/{email}/tokens?scope={scope}"
+            )))
+            .empty(),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::OK, "unable to mint a key");
+    let body: serde_json::Value =
+        serde_json::from_slice(&body_bytes(res).await).expect("token response is json");
+    let secret = body["token"].as_str().expect("key secret").to_string();
+    let client_id = sqlx::query_scalar("SELECT client_id FROM clients WHERE secret = ?")
+        .bind(&secret)
+        .fetch_one(&app.pool)
+        .await
+        .expect("minted key is stored");
+    (secret, client_id)
+}
+
+async fn list_tags(app: &TestApp, key: &str, repo: &str) -> StatusCode {
+    app.send(
+        Request::get(format!("/v2/{repo}/tags/list"))
+            .header("authorization", format!("Bearer {key}"))
+            .empty(),
+    )
+    .await
+    .status()
+}
+
+#[tokio::test]
+async fn revoked_keys_stop_authenticating() {
+    // revoking a leaked key must work on an append-only registry too
+    let app = test_app_with(Config {
+        disable_delete: true,
+        ..Default::default()
+    })
+    .await;
+    app.create_repository("app").await;
+    let (key, client_id) = mint_key(&app, "floundr_admin", "repository:app:pull").await;
+    assert_eq!(list_tags(&app, &key, "app").await, StatusCode::OK);
+
+    let res = app
+        .send(admin(Request::delete(format!("/auth/clients/{client_id}"))).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    assert_eq!(list_tags(&app, &key, "app").await, StatusCode::UNAUTHORIZED);
+
+    let res = app
+        .send(admin(Request::delete(format!("/auth/clients/{client_id}"))).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn only_admins_revoke_keys() {
+    let app = test_app_with(Config::default()).await;
+    app.create_user("user@example.com", "password1").await;
+    let (_, client_id) = mint_key(&app, "user@example.com", "repository:app:pull").await;
+
+    let res = app
+        .send(
+            Request::delete(format!("/auth/clients/{client_id}"))
+                .header("authorization", basic_auth("user@example.com", "password1"))
+                .empty(),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn deleting_a_user_revokes_their_keys() {
+    let app = test_app_with(Config::default()).await;
+    app.create_repository("app").await;
+    app.create_user("user@example.com", "password1").await;
+    app.grant("user@example.com", "app", Action::Pull).await;
+    let (key, client_id) = mint_key(&app, "user@example.com", "repository:app:pull").await;
+    sqlx::query("INSERT INTO tokens (account, token) VALUES (?, ?)")
+        .bind("user@example.com")
+        .bind("issued-token")
+        .execute(&app.pool)
+        .await
+        .expect("unable to store a token");
+    assert_eq!(list_tags(&app, &key, "app").await, StatusCode::OK);
+
+    let res = app
+        .send(admin(Request::delete("/users/user@example.com")).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    assert_eq!(list_tags(&app, &key, "app").await, StatusCode::UNAUTHORIZED);
+    let (clients, tokens): (i64, i64) = sqlx::query_as(
+        "SELECT (SELECT COUNT(*) FROM clients WHERE client_id = ?), (SELECT COUNT(*) FROM tokens)",
+    )
+    .bind(&client_id)
+    .fetch_one(&app.pool)
+    .await
+    .expect("unable to count keys");
+    assert_eq!((clients, tokens), (0, 0));
+
+    let res = app
+        .send(admin(Request::delete("/users/user@example.com")).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn keys_minted_without_a_scope_grant_nothing() {
+    let app = test_app_with(Config::default()).await;
+    app.create_repository("app").await;
+    app.create_user("user@example.com", "password1").await;
+    app.grant("user@example.com", "app", Action::Pull).await;
+
+    let (key, _) = mint_key(&app, "user@example.com", "").await;
+    assert_eq!(list_tags(&app, &key, "app").await, StatusCode::FORBIDDEN);
+    for request in [
+        Request::delete("/users/floundr_admin"),
+        Request::delete("/repositories/app"),
+    ] {
+        let res = app
+            .send(
+                request
+                    .header("authorization", format!("Bearer {key}"))
+                    .empty(),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    }
+    let res = app
+        .send(admin(Request::get("/v2/app/tags/list")).empty())
+        .await;
+    assert_eq!(
+        res.status(),
+        StatusCode::OK,
+        "the repository is still there"
+    );
+}
+
+#[tokio::test]
+async fn users_mint_keys_for_themselves_within_their_grants() {
+    let app = test_app_with(Config::default()).await;
+    app.create_repository("app").await;
+    app.create_user("user@example.com", "password1").await;
+    app.create_user("other@example.com", "password1").await;
+    app.grant("user@example.com", "app", Action::Pull).await;
+    let user = basic_auth("user@example.com", "password1");
+
+    let res = app
+        .send(
+            Request::post("/users/other@example.com/tokens?scope=repository:app:pull")
+                .header("authorization", &user)
+                .empty(),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    let res = app
+        .send(
+            Request::post("/users/user@example.com/tokens?scope=repository:app:push")
+                .header("authorization", &user)
+                .empty(),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: serde_json::Value =
+        serde_json::from_slice(&body_bytes(res).await).expect("token response is json");
+    let key = body["token"].as_str().expect("key secret");
+    // the key asked for push, but its owner may only pull
+    assert_eq!(list_tags(&app, key, "app").await, StatusCode::OK);
+    let (status, _) = app
+        .push_blob(&format!("Bearer {key}"), "app", b"not allowed")
+        .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn namespaced_admins_mint_keys_only_for_themselves() {
+    let app = test_app_with(Config::default()).await;
+    let team_admin = app.create_namespaced_user("team@example.com", true).await;
+    app.create_user("user@example.com", "password1").await;
+
+    let res = app
+        .send(
+            Request::post("/users/user@example.com/tokens?scope=repository:app:pull")
+                .header("authorization", &team_admin)
+                .empty(),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let res = app
+        .send(
+            Request::post("/users/team@example.com/tokens?scope=repository:team/app:pull")
+                .header("authorization", &team_admin)
+                .empty(),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+}
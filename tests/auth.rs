@@ -0,0 +1,331 @@
+mod common;
+
+use axum::http::{Request, StatusCode};
+use base64::Engine;
+use common::{
+    admin, basic_auth, body_bytes, header, sha256_digest, test_app, test_app_with, RequestExt,
+    TestApp, OCI_MANIFEST,
+};
+use floundr::{auth::MAX_AUTH_HEADER_LEN, config::Config, Action};
+
+#[tokio::test]
+async fn anonymous_requests_to_private_repositories_are_challenged() {
+    let app = test_app().await;
+    app.create_repository("private").await;
+
+    let res = app
+        .send(Request::get("/v2/private/tags/list").empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    assert!(header(&res, "www-authenticate").is_some_and(|value| value.starts_with("Bearer ")));
+}
+
+#[tokio::test]
+async fn pull_only_users_are_forbidden_from_pushing() {
+    let app = test_app().await;
+    app.create_repository("app").await;
+    app.create_user("reader@example.com", "password1").await;
+    app.grant("reader@example.com", "app", Action::Pull).await;
+    let reader = basic_auth("reader@example.com", "password1");
+
+    let res = app
+        .send(
+            Request::get("/v2/app/tags/list")
+                .header("authorization", &reader)
+                .empty(),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let blob = b"not allowed";
+    let res = app
+        .send(
+            Request::post(format!(
+                "/v2/app/blobs/uploads/?digest={}",
+                sha256_digest(blob)
+            ))
+            .header("authorization", &reader)
+            .bytes(blob.to_vec()),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    assert!(header(&res, "www-authenticate").is_none());
+    let body: serde_json::Value =
+        serde_json::from_slice(&body_bytes(res).await).expect("error body is json");
+    assert_eq!(body["code"], "Denied");
+}
+
+#[tokio::test]
+async fn push_grants_include_pull() {
+    let app = test_app().await;
+    app.create_repository("app").await;
+    app.create_user("writer@example.com", "password1").await;
+    app.grant("writer@example.com", "app", Action::Push).await;
+    let writer = basic_auth("writer@example.com", "password1");
+
+    let (status, digest) = app.push_blob(&writer, "app", b"pushed by a writer").await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(app.pull_blob(&writer, "app", &digest).await, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn delete_grants_include_push_and_pull() {
+    let app = test_app().await;
+    app.create_repository("app").await;
+    app.create_user("owner@example.com", "password1").await;
+    app.grant("owner@example.com", "app", Action::Delete).await;
+    let owner = basic_auth("owner@example.com", "password1");
+
+    let (status, digest) = app.push_blob(&owner, "app", b"pushed by an owner").await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(app.pull_blob(&owner, "app", &digest).await, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn public_repositories_can_be_pulled_anonymously() {
+    let app = test_app().await;
+    app.create_repository_with("public", true).await;
+    let manifest = app.push_image("public", "latest").await;
+
+    let res = app
+        .send(
+            Request::get("/v2/public/manifests/latest")
+                .header("accept", OCI_MANIFEST)
+                .empty(),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(body_bytes(res).await, manifest.as_bytes());
+
+    // public only opens pulls, pushing still needs credentials
+    let blob = b"anonymous push";
+    let res = app
+        .send(
+            Request::post(format!(
+                "/v2/public/blobs/uploads/?digest={}",
+                sha256_digest(blob)
+            ))
+            .bytes(blob.to_vec()),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn failed_logins_are_rate_limited() {
+    let app = test_app_with(Config {
+        rate_limit: Some(4),
+        ..Default::default()
+    })
+    .await;
+    // authenticated requests count against their subject, not the address
+    app.create_repository("app").await;
+    for _ in 0..3 {
+        let res = app
+            .send(admin(Request::get("/v2/app/tags/list")).empty())
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    for _ in 0..2 {
+        let res = app
+            .send(
+                Request::get("/v2/app/tags/list")
+                    .header(
+                        "authorization",
+                        basic_auth("floundr_admin", "wrong-password1"),
+                    )
+                    .empty(),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+    let login = || {
+        Request::post("/auth/login")
+            .header("content-type", "application/json")
+            .bytes(r#"{"email":"floundr_admin","password":"wrong-password1"}"#)
+    };
+    for _ in 0..2 {
+        assert_eq!(app.send(login()).await.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    let res = app.send(login()).await;
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(header(&res, "retry-after").is_some());
+}
+
+#[tokio::test]
+async fn concurrent_failed_logins_share_the_limit() {
+    let app = test_app_with(Config {
+        rate_limit: Some(3),
+        ..Default::default()
+    })
+    .await;
+    let attempts = (0..10).map(|_| {
+        app.send(
+            Request::get("/v2/default/tags/list")
+                .header(
+                    "authorization",
+                    basic_auth("floundr_admin", "wrong-password1"),
+                )
+                .empty(),
+        )
+    });
+    let statuses = futures::future::join_all(attempts)
+        .await
+        .into_iter()
+        .map(|res| res.status())
+        .collect::<Vec<_>>();
+    let refused = statuses
+        .iter()
+        .filter(|status| **status == StatusCode::TOO_MANY_REQUESTS)
+        .count();
+    assert_eq!(refused, 7, "{statuses:?}");
+}
+
+#[tokio::test]
+async fn malformed_basic_credentials_are_refused() {
+    let app = test_app().await;
+    // the seeded public repository lets anonymous callers through, so a 401
+    // means the header itself was refused
+    let res = app
+        .send(Request::get("/v2/default/tags/list").empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let colonless = format!(
+        "Basic {}",
+        base64::engine::general_purpose::STANDARD.encode("admin@example.com")
+    );
+    let oversized = format!("Bearer {}", "a".repeat(MAX_AUTH_HEADER_LEN));
+    for value in [
+        "Basic ",
+        "Basic",
+        colonless.as_str(),
+        "Basic not-base64!",
+        "Token abc",
+        oversized.as_str(),
+    ] {
+        let res = app
+            .send(Request::get("/v2/").header("authorization", value).empty())
+            .await;
+        assert_eq!(
+            res.status(),
+            StatusCode::UNAUTHORIZED,
+            "{value:.20} was accepted"
+        );
+        assert!(header(&res, "www-authenticate").is_some());
+    }
+}
+
+#[tokio::test]
+async fn tokens_without_grants_are_refused_admin_routes() {
+    let app = test_app().await;
+    app.create_repository("app").await;
+    app.create_user("reader@example.com", "password1").await;
+    app.grant("reader@example.com", "app", Action::Pull).await;
+
+    // a scope the user doesn't hold leaves the token with no grants at all
+    let res = app
+        .send(
+            Request::get("/auth/token?service=floundr&scope=repository:nothere:pull")
+                .header(
+                    "authorization",
+                    basic_auth("reader@example.com", "password1"),
+                )
+                .empty(),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: serde_json::Value =
+        serde_json::from_slice(&body_bytes(res).await).expect("token response is json");
+    assert_eq!(body["access"], serde_json::json!([]));
+    let bearer = format!("Bearer {}", body["token"].as_str().expect("a token"));
+
+    for request in [
+        Request::delete("/repositories/app?purge=true"),
+        Request::post("/repositories/other/true"),
+        Request::delete("/users/floundr_admin"),
+        Request::get("/users"),
+        Request::get("/auth/clients"),
+    ] {
+        let request = request.header("authorization", &bearer).empty();
+        let route = format!("{} {}", request.method(), request.uri());
+        let res = app.send(request).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN, "{route} was allowed");
+    }
+    let res = app
+        .send(admin(Request::get("/v2/app/tags/list")).empty())
+        .await;
+    assert_eq!(
+        res.status(),
+        StatusCode::OK,
+        "the repository is still there"
+    );
+}
+
+async fn tags_as(app: &TestApp, agent: Option<&str>) -> StatusCode {
+    let mut builder = Request::get("/v2/default/tags/list");
+    if let Some(agent) = agent {
+        builder = builder.header("user-agent", agent);
+    }
+    app.send(builder.empty()).await.status()
+}
+
+#[tokio::test]
+async fn clients_are_filtered_by_user_agent() {
+    let app = test_app_with(Config {
+        user_agent_allow: vec![String::from("Docker/")],
+        user_agent_deny: vec![String::from("docker/1.")],
+        ..Default::default()
+    })
+    .await;
+    let docker = "docker/24.0.7 go/go1.20.10 os/linux arch/amd64";
+    assert_eq!(tags_as(&app, Some(docker)).await, StatusCode::OK);
+    assert_eq!(
+        tags_as(&app, Some("docker/1.13.1")).await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        tags_as(&app, Some("curl/8.5.0")).await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(tags_as(&app, None).await, StatusCode::FORBIDDEN);
+
+    let app = test_app_with(Config {
+        user_agent_deny: vec![String::from("curl")],
+        ..Default::default()
+    })
+    .await;
+    assert_eq!(
+        tags_as(&app, Some("curl/8.5.0")).await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(tags_as(&app, Some(docker)).await, StatusCode::OK);
+    assert_eq!(tags_as(&app, None).await, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn login_tokens_keep_the_users_namespace() {
+    let app = test_app().await;
+    app.create_repository("team%2Fapp").await;
+    app.create_repository("other").await;
+    // namespaced users created from the cli are admins of their namespace
+    app.create_namespaced_user("tenant@example.com", true).await;
+    let res = app
+        .send(
+            Request::post("/auth/login")
+                .header("content-type", "application/json")
+                .bytes(r#"{"email":"tenant@example.com","password":"password1"}"#),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: serde_json::Value =
+        serde_json::from_slice(&body_bytes(res).await).expect("token response is json");
+    let token = format!("Bearer {}", body["token"].as_str().expect("a token"));
+
+    let (status, _) = app.push_blob(&token, "team%2Fapp", b"inside").await;
+    assert_eq!(status, StatusCode::CREATED);
+    let (status, _) = app.push_blob(&token, "other", b"outside").await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+}
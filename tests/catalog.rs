@@ -0,0 +1,77 @@
+mod common;
+
+use axum::http::{Request, StatusCode};
+use common::{admin, body_bytes, header, next_page, test_app, RequestExt};
+
+#[tokio::test]
+async fn anonymous_callers_page_through_public_repositories() {
+    let app = test_app().await;
+    app.create_repository_with("alpha", true).await;
+    app.create_repository("private").await;
+    app.create_repository_with("zulu", true).await;
+
+    // the seeded `default` repository is public too
+    let res = app.send(Request::get("/v2/_catalog?n=2").empty()).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        header(&res, "link"),
+        Some(r#"</v2/_catalog?n=2&last=default>; rel="next""#)
+    );
+    let page: serde_json::Value =
+        serde_json::from_slice(&body_bytes(res).await).expect("catalog is json");
+    assert_eq!(
+        page["repositories"],
+        serde_json::json!(["alpha", "default"])
+    );
+
+    let res = app
+        .send(Request::get("/v2/_catalog?n=2&last=default").empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(header(&res, "link").is_none());
+    let page: serde_json::Value =
+        serde_json::from_slice(&body_bytes(res).await).expect("catalog is json");
+    assert_eq!(page["repositories"], serde_json::json!(["zulu"]));
+}
+
+#[tokio::test]
+async fn anonymous_catalog_has_the_spec_shape() {
+    let app = test_app().await;
+    app.create_repository_with("app", true).await;
+
+    let res = app.send(Request::get("/v2/_catalog").empty()).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(header(&res, "content-type"), Some("application/json"));
+    let body: serde_json::Value =
+        serde_json::from_slice(&body_bytes(res).await).expect("catalog is json");
+    assert_eq!(
+        body,
+        serde_json::json!({ "repositories": ["app", "default"] })
+    );
+}
+
+#[tokio::test]
+async fn nested_names_page_in_bytewise_order() {
+    let app = test_app().await;
+    for name in ["app-frontend", "app%2Fbackend", "app"] {
+        app.create_repository(name).await;
+    }
+
+    let mut seen = Vec::new();
+    let mut next = Some(String::from("/v2/_catalog?n=1"));
+    while let Some(url) = next.take() {
+        let res = app.send(admin(Request::get(&url)).empty()).await;
+        assert_eq!(res.status(), StatusCode::OK, "{url} didn't resolve");
+        next = next_page(&res);
+        let page: serde_json::Value =
+            serde_json::from_slice(&body_bytes(res).await).expect("catalog is json");
+        let page = page["repositories"].as_array().expect("a list of names");
+        assert_eq!(page.len(), 1, "{url} returned {page:?}");
+        seen.extend(
+            page.iter()
+                .map(|name| name.as_str().unwrap_or_default().to_string()),
+        );
+    }
+    // `-` sorts before `/`, so the nested name comes after its sibling
+    assert_eq!(seen, ["app", "app-frontend", "app/backend", "default"]);
+}
@@ -0,0 +1,78 @@
+use std::process::{Command, Output};
+use tempfile::TempDir;
+
+/// Runs the floundr binary against a database and storage in `dir`
+fn floundr(dir: &TempDir, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_floundr"))
+        .current_dir(dir.path())
+        .env("JWT_SECRET_KEY", "floundr-test-secret")
+        .env("LOG_LEVEL", "error")
+        .arg("--db-path")
+        .arg(dir.path().join("floundr.db"))
+        .arg("--storage-path")
+        .arg(dir.path().join("storage"))
+        .args(args)
+        .output()
+        .expect("unable to run floundr")
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+async fn count(dir: &TempDir, query: &str) -> i64 {
+    let url = format!("sqlite://{}", dir.path().join("floundr.db").display());
+    let pool = sqlx::SqlitePool::connect(&url)
+        .await
+        .expect("unable to open the database");
+    sqlx::query_scalar(query)
+        .fetch_one(&pool)
+        .await
+        .expect("unable to count rows")
+}
+
+#[tokio::test]
+async fn new_repo_rejects_invalid_and_duplicate_names() {
+    let dir = tempfile::tempdir().expect("unable to create temp dir");
+    let output = floundr(&dir, &["new-repo", "Not_Valid!"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("Cannot create repository Not_Valid!"));
+
+    let output = floundr(&dir, &["new-repo", "team/app", "--dry-run"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let query = "SELECT COUNT(*) FROM repositories WHERE name = 'team/app'";
+    assert_eq!(count(&dir, query).await, 0);
+
+    let output = floundr(&dir, &["new-repo", "team/app"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(count(&dir, query).await, 1);
+
+    let output = floundr(&dir, &["new-repo", "team/app"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("already exists"));
+}
+
+#[tokio::test]
+async fn new_user_rejects_invalid_and_duplicate_users() {
+    let dir = tempfile::tempdir().expect("unable to create temp dir");
+    let output = floundr(
+        &dir,
+        &["new-user", "user@example.com", "--password", "short"],
+    );
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("Cannot create user user@example.com"));
+
+    let args = ["new-user", "user@example.com", "--password", "password1"];
+    let output = floundr(&dir, &[&args[..], &["--dry-run"]].concat());
+    assert!(output.status.success(), "{}", stderr(&output));
+    let query = "SELECT COUNT(*) FROM users WHERE email = 'user@example.com'";
+    assert_eq!(count(&dir, query).await, 0);
+
+    let output = floundr(&dir, &args);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(count(&dir, query).await, 1);
+
+    let output = floundr(&dir, &args);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("already exists"));
+}
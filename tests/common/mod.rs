@@ -0,0 +1,270 @@
+//! Shared harness for the integration tests: a router built by
+//! `register_routes` over a sqlite database and local storage that live in a
+//! temporary directory, driven in-process with `tower::ServiceExt::oneshot`.
+#![allow(dead_code)]
+
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+    response::Response,
+    Router,
+};
+use base64::Engine;
+use floundr::{
+    config::Config, database::initdb, endpoints::register_routes, storage::LocalStorageDriver,
+    storage_driver::Backend, Action,
+};
+use http_body_util::BodyExt;
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use std::{path::Path, sync::Arc};
+use tempfile::TempDir;
+use tower::ServiceExt;
+
+/// secret the test config signs tokens and upload urls with
+pub const TEST_SECRET: &str = "floundr-test-secret";
+
+pub const OCI_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+
+/// test passwords are hashed at the cheapest cost, verifying one at the
+/// default cost takes about a second in a debug build
+const TEST_BCRYPT_COST: u32 = 4;
+
+/// A running app, the temporary directory is removed once this is dropped
+pub struct TestApp {
+    pub router: Router,
+    pub pool: SqlitePool,
+    pub storage: Arc<Backend>,
+    pub config: Arc<Config>,
+    pub dir: TempDir,
+}
+
+/// Builds the app with the default config, see `test_app_with`
+pub async fn test_app() -> TestApp {
+    test_app_with(Config::default()).await
+}
+
+/// Builds the app around `config`, only its secret is filled in when empty.
+/// The database is migrated and seeded like a fresh install, so the default
+/// `floundr_admin:admin` account exists, with a cheaply hashed password.
+pub async fn test_app_with(config: Config) -> TestApp {
+    test_app_on(config, |dir| {
+        Backend::Local(LocalStorageDriver::new(&dir.join("storage")))
+    })
+    .await
+}
+
+/// Builds the app like `test_app_with`, over the storage `backend` returns
+/// for the temporary directory
+pub async fn test_app_on(mut config: Config, backend: impl FnOnce(&Path) -> Backend) -> TestApp {
+    if config.jwt_secret.is_empty() {
+        config.jwt_secret = TEST_SECRET.to_string();
+    }
+    let dir = tempfile::tempdir().expect("unable to create temp dir");
+    let db_path = dir.path().join("floundr.db");
+    let pool = initdb(&db_path.to_string_lossy(), &config).await;
+    sqlx::query("UPDATE users SET password = ? WHERE email = 'floundr_admin'")
+        .bind(test_hash("admin"))
+        .execute(&pool)
+        .await
+        .expect("unable to rehash the admin password");
+    let storage = Arc::new(backend(dir.path()));
+    let config = Arc::new(config);
+    let router = register_routes(pool.clone(), Arc::clone(&storage), Arc::clone(&config));
+    TestApp {
+        router,
+        pool,
+        storage,
+        config,
+        dir,
+    }
+}
+
+impl TestApp {
+    /// Sends one request through the router
+    pub async fn send(&self, req: Request<Body>) -> Response {
+        self.router
+            .clone()
+            .oneshot(req)
+            .await
+            .expect("router is infallible")
+    }
+
+    /// Creates a private repository as the admin
+    pub async fn create_repository(&self, name: &str) {
+        self.create_repository_with(name, false).await
+    }
+
+    /// Creates a repository as the admin, public ones can be pulled anonymously
+    pub async fn create_repository_with(&self, name: &str, public: bool) {
+        let res = self
+            .send(admin(Request::post(format!("/repositories/{name}/{public}"))).empty())
+            .await;
+        assert!(
+            res.status().is_success(),
+            "unable to create {name}: {}",
+            res.status()
+        );
+    }
+
+    /// Creates a non-admin user, straight in the database so its password
+    /// can be hashed cheaply
+    pub async fn create_user(&self, email: &str, password: &str) {
+        sqlx::query("INSERT INTO users (id, email, password, is_admin) VALUES (?, ?, ?, FALSE)")
+            .bind(uuid::Uuid::new_v4().to_string())
+            .bind(email)
+            .bind(test_hash(password))
+            .execute(&self.pool)
+            .await
+            .unwrap_or_else(|err| panic!("unable to create {email}: {err}"));
+    }
+
+    /// Creates `email` with its repositories confined to the `team`
+    /// namespace, returning its basic credentials
+    pub async fn create_namespaced_user(&self, email: &str, is_admin: bool) -> String {
+        self.create_user(email, "password1").await;
+        sqlx::query("UPDATE users SET namespace = 'team', is_admin = ? WHERE email = ?")
+            .bind(is_admin)
+            .bind(email)
+            .execute(&self.pool)
+            .await
+            .expect("unable to set the namespace");
+        basic_auth(email, "password1")
+    }
+
+    /// Pushes a blob in a single request as `auth`, returning the status and
+    /// the blob's digest
+    pub async fn push_blob(&self, auth: &str, repo: &str, blob: &[u8]) -> (StatusCode, String) {
+        let digest = sha256_digest(blob);
+        let res = self
+            .send(
+                Request::post(format!("/v2/{repo}/blobs/uploads/?digest={digest}"))
+                    .header("authorization", auth)
+                    .bytes(blob.to_vec()),
+            )
+            .await;
+        (res.status(), digest)
+    }
+
+    pub async fn pull_blob(&self, auth: &str, repo: &str, digest: &str) -> StatusCode {
+        self.send(
+            Request::get(format!("/v2/{repo}/blobs/{digest}"))
+                .header("authorization", auth)
+                .empty(),
+        )
+        .await
+        .status()
+    }
+
+    /// Pushes an image with a config and no layers to `repo:tag` as the
+    /// admin, returning its manifest
+    pub async fn push_image(&self, repo: &str, tag: &str) -> String {
+        let config = format!(
+            r#"{{"architecture":"amd64","os":"linux","rootfs":{{"type":"layers","diff_ids":[]}},"tag":"{tag}"}}"#
+        );
+        let (status, config_digest) = self.push_blob(&admin_auth(), repo, config.as_bytes()).await;
+        assert_eq!(status, StatusCode::CREATED, "unable to push config");
+        let manifest = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": OCI_MANIFEST,
+            "config": {
+                "mediaType": "application/vnd.oci.image.config.v1+json",
+                "size": config.len(),
+                "digest": config_digest,
+            },
+            "layers": [],
+        })
+        .to_string();
+        let res = self
+            .send(
+                admin(Request::put(format!("/v2/{repo}/manifests/{tag}")))
+                    .header("content-type", OCI_MANIFEST)
+                    .bytes(manifest.clone()),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::CREATED, "unable to push manifest");
+        manifest
+    }
+
+    /// Grants a user `action` on a repository, stored the way
+    /// `repository_scopes` records it, with every lower action included
+    pub async fn grant(&self, email: &str, repo: &str, action: Action) {
+        sqlx::query(
+            "INSERT INTO repository_scopes (user_id, repository_id, push, pull, del)
+             SELECT u.id, r.id, ?, TRUE, ? FROM users u, repositories r
+             WHERE u.email = ? AND r.name = ?",
+        )
+        .bind(action != Action::Pull)
+        .bind(action == Action::Delete)
+        .bind(email)
+        .bind(repo)
+        .execute(&self.pool)
+        .await
+        .expect("unable to grant scope");
+    }
+}
+
+fn test_hash(password: &str) -> String {
+    bcrypt::hash(password, TEST_BCRYPT_COST).expect("unable to hash password")
+}
+
+/// `Authorization` value for the seeded admin account
+pub fn admin_auth() -> String {
+    basic_auth("floundr_admin", "admin")
+}
+
+pub fn basic_auth(user: &str, password: &str) -> String {
+    format!(
+        "Basic {}",
+        base64::engine::general_purpose::STANDARD.encode(format!("{user}:{password}"))
+    )
+}
+
+/// Adds the admin credentials to a request
+pub fn admin(builder: axum::http::request::Builder) -> axum::http::request::Builder {
+    builder.header("authorization", admin_auth())
+}
+
+/// Finishes request builders without spelling out the body each time
+pub trait RequestExt {
+    fn empty(self) -> Request<Body>;
+    fn bytes(self, body: impl Into<Body>) -> Request<Body>;
+}
+
+impl RequestExt for axum::http::request::Builder {
+    fn empty(self) -> Request<Body> {
+        self.body(Body::empty()).expect("invalid request")
+    }
+    fn bytes(self, body: impl Into<Body>) -> Request<Body> {
+        self.body(body.into()).expect("invalid request")
+    }
+}
+
+pub async fn body_bytes(res: Response) -> Vec<u8> {
+    res.into_body()
+        .collect()
+        .await
+        .expect("unable to read body")
+        .to_bytes()
+        .to_vec()
+}
+
+pub fn header<'a>(res: &'a Response, name: &str) -> Option<&'a str> {
+    res.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+}
+
+/// The url of the next page from a `Link: <url>; rel="next"` header
+pub fn next_page(res: &Response) -> Option<String> {
+    header(res, "link").map(|link| {
+        link.strip_prefix('<')
+            .and_then(|link| link.split_once('>'))
+            .map(|(url, _)| url.to_string())
+            .expect("link is <url>; rel=\"next\"")
+    })
+}
+
+pub fn sha256_digest(data: &[u8]) -> String {
+    format!("sha256:{}", hex::encode(Sha256::digest(data)))
+}
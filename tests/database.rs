@@ -0,0 +1,100 @@
+mod common;
+
+use axum::http::{Request, StatusCode};
+use common::{admin, basic_auth, body_bytes, test_app, test_app_with, RequestExt};
+use floundr::config::{Config, DEFAULT_DB_IDLE_TIMEOUT, DEFAULT_DB_MAX_LIFETIME};
+use std::time::Duration;
+
+#[tokio::test]
+async fn connections_use_wal_and_enforce_foreign_keys() {
+    let app = test_app().await;
+    let mut conn = app
+        .pool
+        .acquire()
+        .await
+        .expect("unable to acquire connection");
+    let journal_mode: String = sqlx::query_scalar("PRAGMA journal_mode")
+        .fetch_one(&mut *conn)
+        .await
+        .expect("unable to read journal mode");
+    assert_eq!(journal_mode, "wal");
+    let foreign_keys: i64 = sqlx::query_scalar("PRAGMA foreign_keys")
+        .fetch_one(&mut *conn)
+        .await
+        .expect("unable to read foreign keys");
+    assert_eq!(foreign_keys, 1);
+    let orphan =
+        sqlx::query("INSERT INTO tags (manifest_id, repository_id, tag) VALUES (-1, -1, 'x')")
+            .execute(&mut *conn)
+            .await;
+    assert!(orphan.is_err(), "orphaned tag was accepted");
+}
+
+#[tokio::test]
+async fn optimizing_reclaims_free_pages() {
+    let app = test_app().await;
+    // leave a couple of megabytes of free pages behind
+    sqlx::query("CREATE TABLE scratch (data BLOB)")
+        .execute(&app.pool)
+        .await
+        .expect("unable to create a scratch table");
+    for _ in 0..32 {
+        sqlx::query("INSERT INTO scratch (data) VALUES (randomblob(65536))")
+            .execute(&app.pool)
+            .await
+            .expect("unable to fill the scratch table");
+    }
+    sqlx::query("DROP TABLE scratch")
+        .execute(&app.pool)
+        .await
+        .expect("unable to drop the scratch table");
+
+    let res = app
+        .send(admin(Request::post("/admin/optimize")).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let result: serde_json::Value =
+        serde_json::from_slice(&body_bytes(res).await).expect("optimize reports json");
+    let before = result["size_before"].as_u64().expect("size before");
+    let after = result["size_after"].as_u64().expect("size after");
+    assert!(before >= 2 * 1024 * 1024, "only {before} bytes before");
+    assert!(after < before / 2, "{before} -> {after} bytes");
+
+    app.create_user("user@example.com", "password1").await;
+    let res = app
+        .send(
+            Request::post("/admin/optimize")
+                .header("authorization", basic_auth("user@example.com", "password1"))
+                .empty(),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn idle_connections_are_recycled() {
+    let app = test_app_with(Config {
+        db_idle_timeout: 1,
+        db_max_lifetime: 0,
+        ..Default::default()
+    })
+    .await;
+    let options = app.pool.options();
+    assert_eq!(options.get_idle_timeout(), Some(Duration::from_secs(1)));
+    assert_eq!(options.get_max_lifetime(), None);
+    // every connection has gone idle once the startup work is done
+    assert!(app.pool.size() > 0);
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    assert_eq!(app.pool.size(), 0);
+
+    let app = test_app().await;
+    let options = app.pool.options();
+    assert_eq!(
+        options.get_idle_timeout(),
+        Some(Duration::from_secs(DEFAULT_DB_IDLE_TIMEOUT))
+    );
+    assert_eq!(
+        options.get_max_lifetime(),
+        Some(Duration::from_secs(DEFAULT_DB_MAX_LIFETIME))
+    );
+}
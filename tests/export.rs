@@ -0,0 +1,123 @@
+mod common;
+
+use axum::http::{Request, StatusCode};
+use common::{
+    admin, admin_auth, body_bytes, header, sha256_digest, test_app, RequestExt, TestApp,
+    OCI_MANIFEST,
+};
+use futures::StreamExt;
+use std::collections::HashMap;
+use tokio::io::AsyncReadExt;
+
+/// Pushes an image with one layer to `repo:tag`, returning its manifest
+async fn push_layered_image(app: &TestApp, repo: &str, tag: &str) -> String {
+    let layer = b"layer contents".to_vec();
+    let config =
+        br#"{"architecture":"amd64","os":"linux","rootfs":{"type":"layers","diff_ids":[]}}"#;
+    for blob in [&layer[..], &config[..]] {
+        let (status, _) = app.push_blob(&admin_auth(), repo, blob).await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+    let manifest = serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": OCI_MANIFEST,
+        "config": {
+            "mediaType": "application/vnd.oci.image.config.v1+json",
+            "size": config.len(),
+            "digest": sha256_digest(config),
+        },
+        "layers": [{
+            "mediaType": "application/vnd.oci.image.layer.v1.tar+gzip",
+            "size": layer.len(),
+            "digest": sha256_digest(&layer),
+        }],
+    })
+    .to_string();
+    let res = app
+        .send(
+            admin(Request::put(format!("/v2/{repo}/manifests/{tag}")))
+                .header("content-type", OCI_MANIFEST)
+                .bytes(manifest.clone()),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    manifest
+}
+
+/// Every file in a tarball by its path
+async fn unpack(tarball: &[u8]) -> HashMap<String, Vec<u8>> {
+    let mut archive = tokio_tar::Archive::new(tarball);
+    let mut entries = archive.entries().expect("export is a tarball");
+    let mut files = HashMap::new();
+    while let Some(entry) = entries.next().await {
+        let mut entry = entry.expect("readable tar entry");
+        let path = entry
+            .path()
+            .expect("entry has a path")
+            .to_string_lossy()
+            .into_owned();
+        let mut data = Vec::new();
+        entry
+            .read_to_end(&mut data)
+            .await
+            .expect("readable tar entry");
+        files.insert(path, data);
+    }
+    files
+}
+
+#[tokio::test]
+async fn exported_repositories_can_be_pushed_back() {
+    let app = test_app().await;
+    app.create_repository("app").await;
+    let manifest = push_layered_image(&app, "app", "latest").await;
+
+    let res = app
+        .send(admin(Request::get("/repositories/app/export")).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(header(&res, "content-type"), Some("application/x-tar"));
+    let files = unpack(&body_bytes(res).await).await;
+    assert!(files.contains_key("oci-layout"));
+    let index: serde_json::Value =
+        serde_json::from_slice(&files["index.json"]).expect("index.json is json");
+    let descriptor = &index["manifests"][0];
+    let digest = descriptor["digest"].as_str().expect("manifest digest");
+    assert_eq!(digest, sha256_digest(manifest.as_bytes()));
+    let tag = descriptor["annotations"]["org.opencontainers.image.ref.name"]
+        .as_str()
+        .expect("tagged manifest");
+
+    // every blob the manifest names is in the layout, so pushing them and
+    // the manifest recreates the image elsewhere
+    app.create_repository("copy").await;
+    let blob = |digest: &str| files.get(&format!("blobs/{}", digest.replacen(':', "/", 1)));
+    let exported: serde_json::Value =
+        serde_json::from_slice(blob(digest).expect("manifest in the layout")).unwrap();
+    let referenced = std::iter::once(&exported["config"])
+        .chain(exported["layers"].as_array().unwrap())
+        .map(|descriptor| descriptor["digest"].as_str().unwrap());
+    for digest in referenced {
+        let data = blob(digest).unwrap_or_else(|| panic!("{digest} missing from the export"));
+        let (status, pushed) = app.push_blob(&admin_auth(), "copy", data).await;
+        assert_eq!((status, pushed.as_str()), (StatusCode::CREATED, digest));
+    }
+    let res = app
+        .send(
+            admin(Request::put(format!("/v2/copy/manifests/{tag}")))
+                .header("content-type", descriptor["mediaType"].as_str().unwrap())
+                .bytes(blob(digest).unwrap().clone()),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+
+    let res = app
+        .send(
+            admin(Request::get("/v2/copy/manifests/latest"))
+                .header("accept", OCI_MANIFEST)
+                .empty(),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(body_bytes(res).await, manifest.into_bytes());
+}
@@ -0,0 +1,241 @@
+mod common;
+
+use axum::http::{Request, StatusCode};
+use bytes::Bytes;
+use common::{admin, body_bytes, sha256_digest, test_app, RequestExt, TestApp, OCI_MANIFEST};
+use floundr::grpc::{
+    BlobChunk, GetManifestRequest, ListTagsRequest, PullBlobRequest, PutManifestRequest,
+    RegistryClient, RegistryServer, RegistryService,
+};
+use futures::StreamExt;
+use tokio::net::TcpListener;
+use tonic::{metadata::MetadataValue, transport::Channel, Code};
+
+/// Serves the app's registry over gRPC on a free local port, returning a
+/// client connected to it
+async fn grpc_client(app: &TestApp) -> RegistryClient<Channel> {
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("unable to bind a port");
+    let addr = listener.local_addr().expect("bound address");
+    let incoming = futures::stream::unfold(listener, |listener| async move {
+        let conn = listener.accept().await.map(|(stream, _)| stream);
+        Some((conn, listener))
+    });
+    let service = RegistryService::new(app.pool.clone(), app.storage.clone(), app.config.clone());
+    tokio::spawn(
+        tonic::transport::Server::builder()
+            .add_service(RegistryServer::new(service))
+            .serve_with_incoming(incoming),
+    );
+    RegistryClient::connect(format!("http://{addr}"))
+        .await
+        .expect("unable to connect to the grpc server")
+}
+
+/// A bearer token for the admin from the HTTP token endpoint
+async fn admin_token(app: &TestApp) -> MetadataValue<tonic::metadata::Ascii> {
+    let res = app
+        .send(
+            admin(Request::get(
+                "/auth/token?service=floundr&scope=repository:*:*",
+            ))
+            .empty(),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: serde_json::Value =
+        serde_json::from_slice(&body_bytes(res).await).expect("token response is json");
+    format!("Bearer {}", body["token"].as_str().expect("a token"))
+        .parse()
+        .expect("tokens are valid metadata")
+}
+
+fn authorized<T>(message: T, token: &MetadataValue<tonic::metadata::Ascii>) -> tonic::Request<T> {
+    let mut request = tonic::Request::new(message);
+    request
+        .metadata_mut()
+        .insert("authorization", token.clone());
+    request
+}
+
+#[tokio::test]
+async fn images_push_and_pull_over_grpc() {
+    let app = test_app().await;
+    let mut client = grpc_client(&app).await;
+    let token = admin_token(&app).await;
+
+    let layer = vec![7u8; 100 * 1024];
+    let layer_digest = sha256_digest(&layer);
+    let chunks = layer
+        .chunks(30 * 1024)
+        .enumerate()
+        .map(|(idx, data)| BlobChunk {
+            name: if idx == 0 {
+                "app".into()
+            } else {
+                String::new()
+            },
+            digest: if idx == 0 {
+                layer_digest.clone()
+            } else {
+                String::new()
+            },
+            data: Bytes::copy_from_slice(data),
+        })
+        .collect::<Vec<_>>();
+    let pushed = client
+        .push_blob(authorized(futures::stream::iter(chunks), &token))
+        .await
+        .expect("blob push failed")
+        .into_inner();
+    assert_eq!(pushed.digest, layer_digest);
+    assert_eq!(pushed.size, layer.len() as u64);
+
+    let config =
+        br#"{"architecture":"amd64","os":"linux","rootfs":{"type":"layers","diff_ids":[]}}"#;
+    let config_chunk = BlobChunk {
+        name: "app".into(),
+        digest: sha256_digest(config),
+        data: Bytes::from_static(config),
+    };
+    client
+        .push_blob(authorized(futures::stream::iter([config_chunk]), &token))
+        .await
+        .expect("config push failed");
+
+    let manifest = serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": OCI_MANIFEST,
+        "config": {
+            "mediaType": "application/vnd.oci.image.config.v1+json",
+            "size": config.len(),
+            "digest": sha256_digest(config),
+        },
+        "layers": [{
+            "mediaType": "application/vnd.oci.image.layer.v1.tar+gzip",
+            "size": layer.len(),
+            "digest": layer_digest,
+        }],
+    })
+    .to_string();
+    let manifest_digest = sha256_digest(manifest.as_bytes());
+    let put = client
+        .put_manifest(authorized(
+            PutManifestRequest {
+                name: "app".into(),
+                reference: "latest".into(),
+                media_type: OCI_MANIFEST.into(),
+                content: Bytes::from(manifest.clone()),
+            },
+            &token,
+        ))
+        .await
+        .expect("manifest push failed")
+        .into_inner();
+    assert_eq!(put.digest, manifest_digest);
+    // moving the tag leaves the first manifest reachable only by digest
+    let retagged = manifest.replace(
+        "\"schemaVersion\":2",
+        "\"schemaVersion\":2,\"annotations\":{}",
+    );
+    let retagged_digest = client
+        .put_manifest(authorized(
+            PutManifestRequest {
+                name: "app".into(),
+                reference: "latest".into(),
+                media_type: OCI_MANIFEST.into(),
+                content: Bytes::from(retagged.clone()),
+            },
+            &token,
+        ))
+        .await
+        .expect("retagging failed")
+        .into_inner()
+        .digest;
+    assert_ne!(retagged_digest, manifest_digest);
+
+    let tags = client
+        .list_tags(authorized(ListTagsRequest { name: "app".into() }, &token))
+        .await
+        .expect("listing tags failed")
+        .into_inner();
+    assert_eq!(tags.tags, ["latest"]);
+
+    for (reference, content) in [
+        ("latest".to_string(), &retagged),
+        (retagged_digest, &retagged),
+        (manifest_digest, &manifest),
+    ] {
+        let pulled = client
+            .get_manifest(authorized(
+                GetManifestRequest {
+                    name: "app".into(),
+                    reference: reference.clone(),
+                },
+                &token,
+            ))
+            .await
+            .unwrap_or_else(|err| panic!("pulling {reference} failed: {err}"))
+            .into_inner();
+        assert_eq!(pulled.content, content.as_bytes());
+        assert_eq!(pulled.media_type, OCI_MANIFEST);
+    }
+
+    let mut stream = client
+        .pull_blob(authorized(
+            PullBlobRequest {
+                name: "app".into(),
+                digest: layer_digest.clone(),
+            },
+            &token,
+        ))
+        .await
+        .expect("blob pull failed")
+        .into_inner();
+    let mut pulled = Vec::new();
+    while let Some(chunk) = stream.next().await {
+        pulled.extend_from_slice(&chunk.expect("blob chunk").data);
+    }
+    assert_eq!(pulled, layer);
+
+    // and the HTTP API sees the same image
+    let res = app
+        .send(admin(Request::get(format!("/v2/app/blobs/{layer_digest}"))).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn grpc_holds_names_and_credentials_to_the_http_rules() {
+    let app = test_app().await;
+    let mut client = grpc_client(&app).await;
+    let token = admin_token(&app).await;
+
+    for name in ["App", "../app", "app//nested", ""] {
+        let err = client
+            .list_tags(authorized(ListTagsRequest { name: name.into() }, &token))
+            .await
+            .expect_err("invalid names are refused");
+        assert_eq!(err.code(), Code::InvalidArgument, "{name:?} was accepted");
+    }
+    let err = client
+        .get_manifest(authorized(
+            GetManifestRequest {
+                name: "default".into(),
+                reference: "../latest".into(),
+            },
+            &token,
+        ))
+        .await
+        .expect_err("invalid references are refused");
+    assert_eq!(err.code(), Code::InvalidArgument);
+
+    let err = client
+        .list_tags(tonic::Request::new(ListTagsRequest {
+            name: "default".into(),
+        }))
+        .await
+        .expect_err("anonymous callers are refused");
+    assert_eq!(err.code(), Code::Unauthenticated);
+}
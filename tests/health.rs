@@ -0,0 +1,75 @@
+mod common;
+
+use axum::http::{Request, StatusCode};
+use common::{admin, admin_auth, body_bytes, header, test_app, RequestExt, TestApp};
+
+async fn readyz(app: &TestApp) -> StatusCode {
+    app.send(Request::get("/readyz").empty()).await.status()
+}
+
+#[tokio::test]
+async fn writes_are_refused_while_storage_is_unwritable() {
+    let app = test_app().await;
+    app.create_repository("app").await;
+    assert!(app.storage.probe_health().await);
+    assert_eq!(readyz(&app).await, StatusCode::OK);
+
+    // a plain file where the base path should be can't be written under,
+    // even by root
+    let base = app.dir.path().join("storage");
+    let moved = app.dir.path().join("storage.moved");
+    std::fs::rename(&base, &moved).expect("unable to move the storage away");
+    std::fs::write(&base, b"not a directory").expect("unable to block the base path");
+    assert!(!app.storage.probe_health().await);
+
+    assert_eq!(readyz(&app).await, StatusCode::SERVICE_UNAVAILABLE);
+    let res = app
+        .send(admin(Request::post("/v2/app/blobs/uploads/")).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let (status, _) = app.push_blob(&admin_auth(), "app", b"a layer").await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    // reads aren't refused up front
+    let res = app
+        .send(admin(Request::get("/v2/app/tags/list")).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    // nor are writes that only touch the database
+    let res = app
+        .send(admin(Request::post("/repositories/other/false")).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let res = app
+        .send(admin(Request::post("/v2/auth/token")).empty())
+        .await;
+    assert_ne!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let res = app
+        .send(
+            admin(Request::post("/repositories/app/promote"))
+                .header("content-type", "application/json")
+                .bytes(r#"{"to_repo":"other","tags":[]}"#),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    std::fs::remove_file(&base).expect("unable to unblock the base path");
+    std::fs::rename(&moved, &base).expect("unable to restore the storage");
+    assert!(app.storage.probe_health().await);
+    assert_eq!(readyz(&app).await, StatusCode::OK);
+    let (status, _) = app.push_blob(&admin_auth(), "app", b"a layer").await;
+    assert_eq!(status, StatusCode::CREATED);
+}
+
+#[tokio::test]
+async fn unknown_paths_get_a_json_error() {
+    let app = test_app().await;
+    let res = app
+        .send(admin(Request::patch("/no/such/route")).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    assert_eq!(header(&res, "content-type"), Some("application/json"));
+    let body: serde_json::Value =
+        serde_json::from_slice(&body_bytes(res).await).expect("errors are json");
+    assert_eq!(body["code"], "Unsupported");
+    assert_eq!(body["detail"], "no route for PATCH /no/such/route");
+}
@@ -0,0 +1,152 @@
+mod common;
+
+use axum::http::{Request, StatusCode};
+use common::{admin, admin_auth, basic_auth, test_app, test_app_with, RequestExt};
+use floundr::{
+    config::Config,
+    log_stream::{LogEvent, LogStreamLayer, LOG_EVENTS, LOG_STREAM_CAPACITY},
+    util::log_if_slow,
+};
+use http_body_util::BodyExt;
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    sync::broadcast::{self, error::TryRecvError},
+    time::sleep,
+};
+use tracing::Level;
+use tracing_subscriber::layer::SubscriberExt;
+
+#[tokio::test]
+async fn subscribers_receive_redacted_events() {
+    let _subscriber = tracing::subscriber::set_default(
+        tracing_subscriber::registry().with(LogStreamLayer::new(LOG_EVENTS.clone())),
+    );
+    let app = test_app_with(Config {
+        enable_log_stream: true,
+        ..Default::default()
+    })
+    .await;
+    let res = app
+        .send(admin(Request::get("/admin/logs?level=warn")).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let mut body = res.into_body();
+
+    tracing::info!("below the requested level");
+    tracing::warn!(password = "hunter2", "login failed for Basic dXNlcjpwYXNz");
+    let frame = body
+        .frame()
+        .await
+        .expect("the stream is open")
+        .expect("unable to read the stream");
+    let frame = String::from_utf8(frame.into_data().expect("a data frame").to_vec())
+        .expect("events are text");
+    let data = frame
+        .lines()
+        .find_map(|line| line.strip_prefix("data: "))
+        .expect("an event with data");
+    assert!(frame.starts_with("event: log"), "{frame}");
+    let event: serde_json::Value = serde_json::from_str(data).expect("events are json");
+    assert_eq!(event["level"], "WARN");
+    assert_eq!(event["message"], "login failed for Basic <redacted>");
+    assert_eq!(event["fields"]["password"], "<redacted>");
+}
+
+#[tokio::test]
+async fn the_log_stream_is_opt_in_and_admin_only() {
+    let app = test_app().await;
+    let res = app.send(admin(Request::get("/admin/logs")).empty()).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    let app = test_app_with(Config {
+        enable_log_stream: true,
+        ..Default::default()
+    })
+    .await;
+    app.create_user("user@example.com", "password1").await;
+    let res = app
+        .send(
+            Request::get("/admin/logs")
+                .header("authorization", basic_auth("user@example.com", "password1"))
+                .empty(),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let res = app
+        .send(admin(Request::get("/admin/logs?level=loud")).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+}
+
+/// Every event received so far, skipping past any the channel dropped
+fn drain(events: &mut broadcast::Receiver<Arc<LogEvent>>) -> Vec<Arc<LogEvent>> {
+    let mut received = Vec::new();
+    loop {
+        match events.try_recv() {
+            Ok(event) => received.push(event),
+            Err(TryRecvError::Lagged(_)) => continue,
+            Err(_) => return received,
+        }
+    }
+}
+
+#[tokio::test]
+async fn slow_requests_and_queries_are_logged() {
+    let (sender, mut events) = broadcast::channel(LOG_STREAM_CAPACITY);
+    let _subscriber = tracing::subscriber::set_default(
+        tracing_subscriber::registry().with(LogStreamLayer::new(sender)),
+    );
+    let app = test_app_with(Config {
+        slow_log_ms: Some(1),
+        ..Default::default()
+    })
+    .await;
+    app.create_repository("app").await;
+    drain(&mut events);
+
+    // hashing and storing a few megabytes takes well over a millisecond
+    let (status, _) = app
+        .push_blob(&admin_auth(), "app", &vec![7u8; 8 * 1024 * 1024])
+        .await;
+    assert_eq!(status, StatusCode::CREATED);
+    let slow = drain(&mut events)
+        .into_iter()
+        .find(|event| event.message == "slow request")
+        .expect("the request was logged as slow");
+    assert_eq!(slow.level, Level::WARN);
+    assert_eq!(slow.fields["method"], "POST");
+    assert!(slow.fields["latency_ms"].as_u64().is_some_and(|ms| ms >= 1));
+
+    let threshold = Some(Duration::from_millis(5));
+    log_if_slow(
+        threshold,
+        "a sleepy query",
+        sleep(Duration::from_millis(20)),
+    )
+    .await;
+    log_if_slow(None, "an unwatched query", sleep(Duration::from_millis(20))).await;
+    let logged = drain(&mut events)
+        .iter()
+        .map(|event| event.message.clone())
+        .filter(|message| message.starts_with("slow query"))
+        .collect::<Vec<_>>();
+    assert_eq!(logged, ["slow query: a sleepy query"]);
+}
+
+#[tokio::test]
+async fn namespaced_admins_cannot_stream_logs() {
+    let app = test_app_with(Config {
+        enable_log_stream: true,
+        ..Default::default()
+    })
+    .await;
+    let team_admin = app.create_namespaced_user("team@example.com", true).await;
+    let res = app
+        .send(
+            Request::get("/admin/logs")
+                .header("authorization", team_admin)
+                .empty(),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+}
@@ -0,0 +1,987 @@
+mod common;
+
+use axum::{
+    http::{request::Builder, Request, StatusCode},
+    response::Response,
+};
+use common::{
+    admin, admin_auth, body_bytes, header, sha256_digest, test_app, test_app_with, RequestExt,
+    TestApp, OCI_MANIFEST,
+};
+use floundr::config::{Config, ManifestDeletePolicy, ReferrerDeletePolicy};
+
+const OCI_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+const OCI_ARTIFACT: &str = "application/vnd.oci.artifact.manifest.v1+json";
+const EMPTY_CONFIG: &str = "application/vnd.oci.empty.v1+json";
+
+async fn put_manifest(app: &TestApp, builder: Builder, manifest: &str) -> StatusCode {
+    app.send(builder.bytes(manifest.to_string())).await.status()
+}
+
+async fn stored_media_type(app: &TestApp, repo: &str, tag: &str) -> Option<String> {
+    let res = app
+        .send(
+            admin(Request::get(format!("/v2/{repo}/manifests/{tag}")))
+                .header("accept", OCI_MANIFEST)
+                .empty(),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    header(&res, "content-type").map(String::from)
+}
+
+#[tokio::test]
+async fn manifests_of_unknown_media_types_are_refused() {
+    let app = test_app().await;
+    app.create_repository("app").await;
+    let manifest = app.push_image("app", "latest").await;
+
+    let builder = admin(Request::put("/v2/app/manifests/v1")).header("content-type", "text/plain");
+    assert_eq!(
+        put_manifest(&app, builder, &manifest).await,
+        StatusCode::BAD_REQUEST
+    );
+
+    // parameters and case don't change the media type
+    let builder = admin(Request::put("/v2/app/manifests/v1")).header(
+        "content-type",
+        "Application/Vnd.Oci.Image.Manifest.V1+Json; charset=utf-8",
+    );
+    assert_eq!(
+        put_manifest(&app, builder, &manifest).await,
+        StatusCode::CREATED
+    );
+    assert_eq!(
+        stored_media_type(&app, "app", "v1").await.as_deref(),
+        Some(OCI_MANIFEST)
+    );
+}
+
+#[tokio::test]
+async fn manifests_without_a_content_type_get_the_default_media_type() {
+    let app = test_app_with(Config {
+        default_media_type: OCI_MANIFEST.to_string(),
+        ..Default::default()
+    })
+    .await;
+    app.create_repository("app").await;
+    let manifest = app.push_image("app", "latest").await;
+
+    let builder = admin(Request::put("/v2/app/manifests/v1"));
+    assert_eq!(
+        put_manifest(&app, builder, &manifest).await,
+        StatusCode::CREATED
+    );
+    assert_eq!(
+        stored_media_type(&app, "app", "v1").await.as_deref(),
+        Some(OCI_MANIFEST)
+    );
+}
+
+async fn delete_manifest(app: &TestApp, repo: &str, reference: &str) -> StatusCode {
+    app.send(admin(Request::delete(format!("/v2/{repo}/manifests/{reference}"))).empty())
+        .await
+        .status()
+}
+
+/// An app under `policy` holding `app:by-tag` and `app:by-digest`, returning
+/// the digest of the latter
+async fn delete_policy_app(policy: ManifestDeletePolicy) -> (TestApp, String) {
+    let app = test_app_with(Config {
+        manifest_delete: policy,
+        ..Default::default()
+    })
+    .await;
+    app.create_repository("app").await;
+    app.push_image("app", "by-tag").await;
+    let manifest = app.push_image("app", "by-digest").await;
+    (app, sha256_digest(manifest.as_bytes()))
+}
+
+#[tokio::test]
+async fn manifests_are_deleted_by_tag_or_digest_by_default() {
+    let (app, digest) = delete_policy_app(ManifestDeletePolicy::TagAndDigest).await;
+    assert_eq!(
+        delete_manifest(&app, "app", "by-tag").await,
+        StatusCode::ACCEPTED
+    );
+    assert_eq!(
+        delete_manifest(&app, "app", &digest).await,
+        StatusCode::ACCEPTED
+    );
+}
+
+#[tokio::test]
+async fn digest_only_deletion_refuses_tags() {
+    let (app, digest) = delete_policy_app(ManifestDeletePolicy::DigestOnly).await;
+    assert_eq!(
+        delete_manifest(&app, "app", "by-tag").await,
+        StatusCode::METHOD_NOT_ALLOWED
+    );
+    assert_eq!(
+        delete_manifest(&app, "app", &digest).await,
+        StatusCode::ACCEPTED
+    );
+}
+
+#[tokio::test]
+async fn disabled_deletion_refuses_every_reference() {
+    let (app, digest) = delete_policy_app(ManifestDeletePolicy::Disabled).await;
+    assert_eq!(
+        delete_manifest(&app, "app", "by-tag").await,
+        StatusCode::METHOD_NOT_ALLOWED
+    );
+    assert_eq!(
+        delete_manifest(&app, "app", &digest).await,
+        StatusCode::METHOD_NOT_ALLOWED
+    );
+    assert_eq!(
+        stored_media_type(&app, "app", "by-tag").await.as_deref(),
+        Some(OCI_MANIFEST)
+    );
+}
+
+/// Pushes a single-platform image with its own layer plus `shared`, tagged
+/// `arch`, returning its manifest and the digests of its config and own layer
+async fn push_platform_image(app: &TestApp, arch: &str, shared: &[u8]) -> (String, [String; 2]) {
+    let config = format!(
+        r#"{{"architecture":"{arch}","os":"linux","rootfs":{{"type":"layers","diff_ids":[]}}}}"#
+    );
+    let layer = format!("{arch} layer");
+    for blob in [config.as_bytes(), layer.as_bytes(), shared] {
+        let (status, _) = app.push_blob(&admin_auth(), "app", blob).await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+    let layers = [layer.as_bytes(), shared].map(|blob| {
+        serde_json::json!({
+            "mediaType": "application/vnd.oci.image.layer.v1.tar+gzip",
+            "size": blob.len(),
+            "digest": sha256_digest(blob),
+        })
+    });
+    let manifest = serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": OCI_MANIFEST,
+        "config": {
+            "mediaType": "application/vnd.oci.image.config.v1+json",
+            "size": config.len(),
+            "digest": sha256_digest(config.as_bytes()),
+        },
+        "layers": layers,
+    })
+    .to_string();
+    let builder = admin(Request::put(format!("/v2/app/manifests/{arch}")))
+        .header("content-type", OCI_MANIFEST);
+    assert_eq!(
+        put_manifest(app, builder, &manifest).await,
+        StatusCode::CREATED
+    );
+    let blobs = [
+        sha256_digest(config.as_bytes()),
+        sha256_digest(layer.as_bytes()),
+    ];
+    (manifest, blobs)
+}
+
+#[tokio::test]
+async fn closures_walk_through_indexes() {
+    let app = test_app().await;
+    app.create_repository("app").await;
+    let shared = b"a base layer both platforms share";
+    let amd64 = push_platform_image(&app, "amd64", shared).await;
+    let arm64 = push_platform_image(&app, "arm64", shared).await;
+    let manifests = [(&amd64.0, "amd64"), (&arm64.0, "arm64")].map(|(manifest, arch)| {
+        serde_json::json!({
+            "mediaType": OCI_MANIFEST,
+            "size": manifest.len(),
+            "digest": sha256_digest(manifest.as_bytes()),
+            "platform": {"architecture": arch, "os": "linux"},
+        })
+    });
+    let index = serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": OCI_INDEX,
+        "manifests": manifests,
+    })
+    .to_string();
+    let builder = admin(Request::put("/v2/app/manifests/latest")).header("content-type", OCI_INDEX);
+    assert_eq!(
+        put_manifest(&app, builder, &index).await,
+        StatusCode::CREATED
+    );
+
+    let mut expected = vec![sha256_digest(index.as_bytes()), sha256_digest(shared)];
+    for (manifest, blobs) in [amd64, arm64] {
+        expected.push(sha256_digest(manifest.as_bytes()));
+        expected.extend(blobs);
+    }
+    expected.sort();
+
+    let res = app
+        .send(admin(Request::get("/repositories/app/manifests/latest/closure")).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: serde_json::Value =
+        serde_json::from_slice(&body_bytes(res).await).expect("closure is json");
+    let mut digests = body["digests"]
+        .as_array()
+        .expect("a list of digests")
+        .iter()
+        .map(|digest| digest.as_str().unwrap_or_default().to_string())
+        .collect::<Vec<_>>();
+    digests.sort();
+    assert_eq!(digests, expected);
+
+    let res = app
+        .send(
+            admin(Request::get("/repositories/app/manifests/latest/closure"))
+                .header("accept", "text/plain")
+                .empty(),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = String::from_utf8(body_bytes(res).await).expect("closure is text");
+    let mut digests = body.lines().collect::<Vec<_>>();
+    digests.sort();
+    assert_eq!(digests, expected);
+}
+
+#[tokio::test]
+async fn manifests_are_stored_byte_for_byte() {
+    let app = test_app_with(Config {
+        max_manifest_size: 2048,
+        ..Default::default()
+    })
+    .await;
+    app.create_repository("app").await;
+    let compact = app.push_image("app", "compact").await;
+    // formatting is part of the content, the digest covers it as sent
+    let value: serde_json::Value = serde_json::from_str(&compact).expect("manifest is json");
+    let pretty = format!(
+        "{}\n",
+        serde_json::to_string_pretty(&value).expect("manifest serializes")
+    );
+    let res = app
+        .send(
+            admin(Request::put("/v2/app/manifests/pretty"))
+                .header("content-type", OCI_MANIFEST)
+                .bytes(pretty.clone()),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let digest = sha256_digest(pretty.as_bytes());
+    assert_eq!(header(&res, "docker-content-digest"), Some(digest.as_str()));
+
+    let (file_path, size): (String, i64) =
+        sqlx::query_as("SELECT file_path, size FROM manifests WHERE digest = ?")
+            .bind(&digest)
+            .fetch_one(&app.pool)
+            .await
+            .expect("the manifest is recorded");
+    assert_eq!(
+        std::fs::read(file_path).expect("stored file"),
+        pretty.as_bytes()
+    );
+    assert_eq!(size, pretty.len() as i64);
+    let res = app
+        .send(
+            admin(Request::get(format!("/v2/app/manifests/{digest}")))
+                .header("accept", OCI_MANIFEST)
+                .empty(),
+        )
+        .await;
+    assert_eq!(body_bytes(res).await, pretty.as_bytes());
+
+    let padded = format!("{compact}{}", " ".repeat(2048));
+    let builder =
+        admin(Request::put("/v2/app/manifests/padded")).header("content-type", OCI_MANIFEST);
+    assert_eq!(
+        put_manifest(&app, builder, &padded).await,
+        StatusCode::PAYLOAD_TOO_LARGE
+    );
+}
+
+#[tokio::test]
+async fn each_manifest_kind_is_validated_on_its_own_write_path() {
+    let app = test_app().await;
+    app.create_repository("app").await;
+    let image = app.push_image("app", "latest").await;
+    let index = serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": OCI_INDEX,
+        "manifests": [{
+            "mediaType": OCI_MANIFEST,
+            "size": image.len(),
+            "digest": sha256_digest(image.as_bytes()),
+        }],
+    })
+    .to_string();
+
+    // an image sent as an index, and an index sent as an image
+    let builder = admin(Request::put("/v2/app/manifests/v1")).header("content-type", OCI_INDEX);
+    assert_eq!(
+        put_manifest(&app, builder, &image).await,
+        StatusCode::BAD_REQUEST
+    );
+    let builder = admin(Request::put("/v2/app/manifests/v1")).header("content-type", OCI_MANIFEST);
+    assert_eq!(
+        put_manifest(&app, builder, &index).await,
+        StatusCode::BAD_REQUEST
+    );
+    let builder = admin(Request::put("/v2/app/manifests/v1")).header("content-type", OCI_INDEX);
+    assert_eq!(
+        put_manifest(&app, builder, &index).await,
+        StatusCode::CREATED
+    );
+
+    let (status, blob) = app.push_blob(&admin_auth(), "app", b"a signature").await;
+    assert_eq!(status, StatusCode::CREATED);
+    let artifact = |artifact_type: &str| {
+        serde_json::json!({
+            "mediaType": OCI_ARTIFACT,
+            "artifactType": artifact_type,
+            "blobs": [{
+                "mediaType": "application/octet-stream",
+                "size": 11,
+                "digest": blob,
+            }],
+        })
+        .to_string()
+    };
+    let builder = admin(Request::put("/v2/app/manifests/sig")).header("content-type", OCI_ARTIFACT);
+    assert_eq!(
+        put_manifest(&app, builder, &artifact("")).await,
+        StatusCode::BAD_REQUEST
+    );
+    let signature = artifact("application/vnd.example.signature");
+    let builder = admin(Request::put("/v2/app/manifests/sig")).header("content-type", OCI_ARTIFACT);
+    assert_eq!(
+        put_manifest(&app, builder, &signature).await,
+        StatusCode::CREATED
+    );
+
+    let kinds: Vec<(String, Option<String>)> = sqlx::query_as(
+        "SELECT media_type, artifact_type FROM manifests WHERE digest IN (?, ?, ?) ORDER BY media_type",
+    )
+    .bind(sha256_digest(image.as_bytes()))
+    .bind(sha256_digest(index.as_bytes()))
+    .bind(sha256_digest(signature.as_bytes()))
+    .fetch_all(&app.pool)
+    .await
+    .expect("the manifests are recorded");
+    assert_eq!(
+        kinds,
+        [
+            (
+                OCI_ARTIFACT.to_string(),
+                Some("application/vnd.example.signature".to_string())
+            ),
+            (OCI_INDEX.to_string(), None),
+            (
+                OCI_MANIFEST.to_string(),
+                Some("application/vnd.oci.image.config.v1+json".to_string())
+            ),
+        ]
+    );
+}
+
+/// Pushes a two-layer image whose config lists `diff_ids`, returning the
+/// recorded diff ID of each layer
+async fn recorded_diff_ids(app: &TestApp, diff_ids: [&str; 2]) -> Vec<serde_json::Value> {
+    let config = serde_json::json!({
+        "architecture": "amd64",
+        "os": "linux",
+        "rootfs": {"type": "layers", "diff_ids": diff_ids},
+    })
+    .to_string();
+    let layers = [b"first layer".as_slice(), b"second layer"];
+    for blob in [config.as_bytes()].into_iter().chain(layers) {
+        let (status, _) = app.push_blob(&admin_auth(), "app", blob).await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+    let layers = layers.map(|blob| {
+        serde_json::json!({
+            "mediaType": "application/vnd.oci.image.layer.v1.tar+gzip",
+            "size": blob.len(),
+            "digest": sha256_digest(blob),
+        })
+    });
+    let manifest = serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": OCI_MANIFEST,
+        "config": {
+            "mediaType": "application/vnd.oci.image.config.v1+json",
+            "size": config.len(),
+            "digest": sha256_digest(config.as_bytes()),
+        },
+        "layers": layers,
+    })
+    .to_string();
+    let builder =
+        admin(Request::put("/v2/app/manifests/latest")).header("content-type", OCI_MANIFEST);
+    assert_eq!(
+        put_manifest(app, builder, &manifest).await,
+        StatusCode::CREATED
+    );
+    let res = app
+        .send(admin(Request::get("/repositories/app/manifests/latest")).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let details: serde_json::Value =
+        serde_json::from_slice(&body_bytes(res).await).expect("details are json");
+    details["layers"]
+        .as_array()
+        .expect("a list of layers")
+        .iter()
+        .map(|layer| layer["diff_id"].clone())
+        .collect()
+}
+
+#[tokio::test]
+async fn diff_ids_are_captured_from_the_config() {
+    let diff_ids = [
+        "sha256:1111111111111111111111111111111111111111111111111111111111111111",
+        "sha256:2222222222222222222222222222222222222222222222222222222222222222",
+    ];
+    let app = test_app_with(Config {
+        store_diff_ids: true,
+        ..Default::default()
+    })
+    .await;
+    app.create_repository("app").await;
+    assert_eq!(
+        recorded_diff_ids(&app, diff_ids).await,
+        diff_ids.map(serde_json::Value::from)
+    );
+
+    let app = test_app().await;
+    app.create_repository("app").await;
+    assert_eq!(
+        recorded_diff_ids(&app, diff_ids).await,
+        [serde_json::Value::Null, serde_json::Value::Null]
+    );
+}
+
+#[tokio::test]
+async fn public_manifest_details_are_readable_anonymously() {
+    let app = test_app().await;
+    app.create_repository_with("open", true).await;
+    app.create_repository("app").await;
+    app.push_image("open", "latest").await;
+    app.push_image("app", "latest").await;
+
+    for path in ["manifests/latest", "manifests/latest/closure"] {
+        let res = app
+            .send(Request::get(format!("/repositories/open/{path}")).empty())
+            .await;
+        assert_eq!(
+            res.status(),
+            StatusCode::OK,
+            "{path} of a public repository"
+        );
+        let res = app
+            .send(Request::get(format!("/repositories/app/{path}")).empty())
+            .await;
+        assert_eq!(
+            res.status(),
+            StatusCode::UNAUTHORIZED,
+            "{path} of a private repository"
+        );
+    }
+}
+
+async fn pull_manifest(app: &TestApp, reference: &str, accept: &str, agent: &str) -> Response {
+    app.send(
+        admin(Request::get(format!("/v2/app/manifests/{reference}")))
+            .header("accept", accept)
+            .header("user-agent", agent)
+            .empty(),
+    )
+    .await
+}
+
+#[tokio::test]
+async fn manifests_are_negotiated_from_the_accept_header() {
+    let app = test_app().await;
+    app.create_repository("app").await;
+    let image = app.push_image("app", "latest").await;
+    let docker = "application/vnd.docker.distribution.manifest.v2+json";
+
+    let res = pull_manifest(&app, "latest", docker, "curl/8.0").await;
+    assert_eq!(res.status(), StatusCode::NOT_ACCEPTABLE);
+    let accept = format!("{OCI_MANIFEST};q=0, */*");
+    let res = pull_manifest(&app, "latest", &accept, "curl/8.0").await;
+    assert_eq!(res.status(), StatusCode::NOT_ACCEPTABLE);
+
+    let accept = format!("{docker}, {OCI_MANIFEST};q=0.5");
+    let res = pull_manifest(&app, "latest", &accept, "curl/8.0").await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(header(&res, "content-type"), Some(OCI_MANIFEST));
+    assert_eq!(body_bytes(res).await, image.as_bytes());
+    let res = pull_manifest(&app, "latest", "application/*", "curl/8.0").await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    // an index the client can't take resolves to its platform's image
+    let shared = b"a base layer both platforms share";
+    let amd64 = push_platform_image(&app, "amd64", shared).await.0;
+    let arm64 = push_platform_image(&app, "arm64", shared).await.0;
+    let manifests = [(&amd64, "amd64"), (&arm64, "arm64")].map(|(manifest, arch)| {
+        serde_json::json!({
+            "mediaType": OCI_MANIFEST,
+            "size": manifest.len(),
+            "digest": sha256_digest(manifest.as_bytes()),
+            "platform": {"architecture": arch, "os": "linux"},
+        })
+    });
+    let index = serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": OCI_INDEX,
+        "manifests": manifests,
+    })
+    .to_string();
+    let builder = admin(Request::put("/v2/app/manifests/multi")).header("content-type", OCI_INDEX);
+    assert_eq!(
+        put_manifest(&app, builder, &index).await,
+        StatusCode::CREATED
+    );
+    let agent = "docker/24.0.0 go/go1.20 os/linux arch/arm64";
+    let res = pull_manifest(&app, "multi", OCI_MANIFEST, agent).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(body_bytes(res).await, arm64.as_bytes());
+    // without a platform there's no single child to choose
+    let res = pull_manifest(&app, "multi", OCI_MANIFEST, "curl/8.0").await;
+    assert_eq!(res.status(), StatusCode::NOT_ACCEPTABLE);
+    let res = pull_manifest(&app, "multi", OCI_INDEX, "curl/8.0").await;
+    assert_eq!(body_bytes(res).await, index.as_bytes());
+}
+
+/// Pushes an OCI image manifest with the given config descriptor and no
+/// layers to `app:latest`, returning the status and error code
+async fn push_with_config(app: &TestApp, config: serde_json::Value) -> (StatusCode, String) {
+    let manifest = serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": OCI_MANIFEST,
+        "config": config,
+        "layers": [],
+    })
+    .to_string();
+    let res = app
+        .send(
+            admin(Request::put("/v2/app/manifests/latest"))
+                .header("content-type", OCI_MANIFEST)
+                .bytes(manifest),
+        )
+        .await;
+    let status = res.status();
+    let body: serde_json::Value =
+        serde_json::from_slice(&body_bytes(res).await).unwrap_or_default();
+    (
+        status,
+        body["code"].as_str().unwrap_or_default().to_string(),
+    )
+}
+
+async fn validating_app() -> TestApp {
+    let app = test_app_with(Config {
+        validate_config: true,
+        ..Default::default()
+    })
+    .await;
+    app.create_repository("app").await;
+    app
+}
+
+#[tokio::test]
+async fn configs_have_to_fit_their_manifest() {
+    let app = validating_app().await;
+    let config = br#"{"architecture":"amd64","os":"linux"}"#;
+    let (_, digest) = app.push_blob(&admin_auth(), "app", config).await;
+
+    // a Docker config in an OCI manifest
+    let (status, code) = push_with_config(
+        &app,
+        serde_json::json!({
+            "mediaType": "application/vnd.docker.container.image.v1+json",
+            "size": config.len(),
+            "digest": digest,
+        }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(code, "ManifestInvalid");
+
+    // the empty config has to describe `{}`
+    let (status, code) = push_with_config(
+        &app,
+        serde_json::json!({
+            "mediaType": EMPTY_CONFIG,
+            "size": config.len(),
+            "digest": digest,
+        }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(code, "ManifestInvalid");
+    // and then needn't be uploaded
+    let (status, _) = push_with_config(
+        &app,
+        serde_json::json!({
+            "mediaType": EMPTY_CONFIG,
+            "size": 2,
+            "digest": sha256_digest(b"{}"),
+        }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+}
+
+#[tokio::test]
+async fn configs_have_to_be_stored() {
+    let app = validating_app().await;
+    let config = br#"{"architecture":"amd64","os":"linux"}"#;
+    let (status, code) = push_with_config(
+        &app,
+        serde_json::json!({
+            "mediaType": "application/vnd.oci.image.config.v1+json",
+            "size": config.len(),
+            "digest": sha256_digest(config),
+        }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(code, "ManifestBlobUnknown");
+    let manifests: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM manifests")
+        .fetch_one(&app.pool)
+        .await
+        .expect("unable to count manifests");
+    assert_eq!(manifests, 0);
+
+    let (status, _) = app.push_blob(&admin_auth(), "app", config).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let (status, _) = push_with_config(
+        &app,
+        serde_json::json!({
+            "mediaType": "application/vnd.oci.image.config.v1+json",
+            "size": config.len(),
+            "digest": sha256_digest(config),
+        }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+}
+
+#[tokio::test]
+async fn manifests_with_missing_layers_are_refused() {
+    let app = test_app().await;
+    app.create_repository("app").await;
+    app.create_repository("other").await;
+    let config = br#"{"architecture":"amd64","os":"linux"}"#;
+    let (_, config_digest) = app.push_blob(&admin_auth(), "app", config).await;
+    let (_, present) = app
+        .push_blob(&admin_auth(), "app", b"a present layer")
+        .await;
+    // held by another repository, which doesn't count
+    let (_, elsewhere) = app
+        .push_blob(&admin_auth(), "other", b"a layer elsewhere")
+        .await;
+    let layer = |digest: &str| {
+        serde_json::json!({
+            "mediaType": "application/vnd.oci.image.layer.v1.tar+gzip",
+            "size": 15,
+            "digest": digest,
+        })
+    };
+    let manifest = serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": OCI_MANIFEST,
+        "config": {
+            "mediaType": "application/vnd.oci.image.config.v1+json",
+            "size": config.len(),
+            "digest": config_digest,
+        },
+        "layers": [layer(&present), layer(&elsewhere)],
+    })
+    .to_string();
+    let res = app
+        .send(
+            admin(Request::put("/v2/app/manifests/latest"))
+                .header("content-type", OCI_MANIFEST)
+                .bytes(manifest),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    let body: serde_json::Value =
+        serde_json::from_slice(&body_bytes(res).await).expect("errors are json");
+    assert_eq!(body["code"], "ManifestBlobUnknown");
+
+    let ref_counts: Vec<i64> =
+        sqlx::query_scalar("SELECT ref_count FROM blobs WHERE digest IN (?, ?) ORDER BY digest")
+            .bind(&present)
+            .bind(&elsewhere)
+            .fetch_all(&app.pool)
+            .await
+            .expect("the blobs are recorded");
+    assert_eq!(ref_counts, [0, 0]);
+    let manifests: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM manifests")
+        .fetch_one(&app.pool)
+        .await
+        .expect("unable to count manifests");
+    assert_eq!(manifests, 0);
+}
+
+/// Pushes a signature artifact whose subject is `manifest`, returning it
+async fn push_signature(app: &TestApp, manifest: &str, tag: &str) -> String {
+    let (status, blob) = app.push_blob(&admin_auth(), "app", tag.as_bytes()).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let signature = serde_json::json!({
+        "mediaType": OCI_ARTIFACT,
+        "artifactType": "application/vnd.example.signature",
+        "blobs": [{
+            "mediaType": "application/octet-stream",
+            "size": tag.len(),
+            "digest": blob,
+        }],
+        "subject": {
+            "mediaType": OCI_MANIFEST,
+            "size": manifest.len(),
+            "digest": sha256_digest(manifest.as_bytes()),
+        },
+    })
+    .to_string();
+    let builder = admin(Request::put(format!("/v2/app/manifests/{tag}")))
+        .header("content-type", OCI_ARTIFACT);
+    assert_eq!(
+        put_manifest(app, builder, &signature).await,
+        StatusCode::CREATED
+    );
+    signature
+}
+
+async fn referrers(app: &TestApp, manifest: &str) -> Vec<String> {
+    let subject = sha256_digest(manifest.as_bytes());
+    let res = app
+        .send(admin(Request::get(format!("/v2/app/referrers/{subject}"))).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let index: serde_json::Value =
+        serde_json::from_slice(&body_bytes(res).await).expect("referrers are json");
+    let mut digests = index["manifests"]
+        .as_array()
+        .expect("a list of manifests")
+        .iter()
+        .map(|referrer| referrer["digest"].as_str().unwrap_or_default().to_string())
+        .collect::<Vec<_>>();
+    digests.sort();
+    digests
+}
+
+#[tokio::test]
+async fn deleted_referrers_leave_the_referrers_list() {
+    let app = test_app().await;
+    app.create_repository("app").await;
+    let image = app.push_image("app", "latest").await;
+    let first = sha256_digest(push_signature(&app, &image, "first").await.as_bytes());
+    let second = sha256_digest(push_signature(&app, &image, "second").await.as_bytes());
+    let mut both = vec![first.clone(), second.clone()];
+    both.sort();
+    assert_eq!(referrers(&app, &image).await, both);
+
+    assert_eq!(
+        delete_manifest(&app, "app", &first).await,
+        StatusCode::ACCEPTED
+    );
+    assert_eq!(referrers(&app, &image).await, [second.as_str()]);
+
+    // by default the remaining referrer outlives its subject
+    assert_eq!(
+        delete_manifest(&app, "app", "latest").await,
+        StatusCode::ACCEPTED
+    );
+    assert_eq!(referrers(&app, &image).await, [second]);
+}
+
+#[tokio::test]
+async fn referrers_can_be_deleted_with_their_subject() {
+    let app = test_app_with(Config {
+        referrer_delete: ReferrerDeletePolicy::Cascade,
+        ..Default::default()
+    })
+    .await;
+    app.create_repository("app").await;
+    let image = app.push_image("app", "latest").await;
+    let signature = push_signature(&app, &image, "sig").await;
+    assert_eq!(
+        delete_manifest(&app, "app", "latest").await,
+        StatusCode::ACCEPTED
+    );
+    assert!(referrers(&app, &image).await.is_empty());
+    let res = app
+        .send(
+            admin(Request::get(format!(
+                "/v2/app/manifests/{}",
+                sha256_digest(signature.as_bytes())
+            )))
+            .empty(),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn deleting_a_manifest_leaves_its_layers_to_garbage_collection() {
+    let app = test_app().await;
+    app.create_repository("app").await;
+    let shared = b"a base layer both platforms share";
+    let (_, [config, layer]) = push_platform_image(&app, "amd64", shared).await;
+    let (_, [_, other]) = push_platform_image(&app, "arm64", shared).await;
+    let ref_count = |digest: String| {
+        let pool = app.pool.clone();
+        async move {
+            sqlx::query_scalar::<_, i64>("SELECT ref_count FROM blobs WHERE digest = ?")
+                .bind(digest)
+                .fetch_optional(&pool)
+                .await
+                .expect("unable to read ref count")
+        }
+    };
+    assert_eq!(ref_count(sha256_digest(shared)).await, Some(2));
+
+    assert_eq!(
+        delete_manifest(&app, "app", "amd64").await,
+        StatusCode::ACCEPTED
+    );
+    // released, but only removed once collected
+    assert_eq!(ref_count(layer.clone()).await, Some(0));
+    assert_eq!(ref_count(sha256_digest(shared)).await, Some(1));
+    assert_eq!(
+        app.pull_blob(&admin_auth(), "app", &layer).await,
+        StatusCode::OK
+    );
+
+    // past the grace period given to blobs pushed ahead of their manifest
+    sqlx::query("UPDATE blobs SET created_at = datetime('now', '-2 days')")
+        .execute(&app.pool)
+        .await
+        .expect("unable to age blobs");
+    let mut conn = app
+        .pool
+        .acquire()
+        .await
+        .expect("unable to acquire connection");
+    let removed = app
+        .storage
+        .run_garbage_collection(&mut conn)
+        .await
+        .expect("garbage collection failed");
+    drop(conn);
+    assert_eq!(removed, 2);
+    for digest in [config, layer] {
+        assert_eq!(ref_count(digest.clone()).await, None);
+        assert_eq!(
+            app.pull_blob(&admin_auth(), "app", &digest).await,
+            StatusCode::NOT_FOUND
+        );
+    }
+    for digest in [other, sha256_digest(shared)] {
+        assert_eq!(
+            app.pull_blob(&admin_auth(), "app", &digest).await,
+            StatusCode::OK
+        );
+    }
+}
+
+#[tokio::test]
+async fn pushes_are_checked_against_their_declared_digest() {
+    let app = test_app().await;
+    app.create_repository("app").await;
+    let manifest = app.push_image("app", "latest").await;
+    let push = |digest: String| {
+        app.send(
+            admin(Request::put("/v2/app/manifests/v2"))
+                .header("content-type", OCI_MANIFEST)
+                .header("docker-content-digest", digest)
+                .bytes(manifest.clone()),
+        )
+    };
+
+    let res = push(sha256_digest(b"some other manifest")).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let body: serde_json::Value =
+        serde_json::from_slice(&body_bytes(res).await).expect("errors are json");
+    assert_eq!(body["code"], "DigestInvalid");
+    let tags: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM tags WHERE tag = 'v2'")
+        .fetch_one(&app.pool)
+        .await
+        .expect("unable to count tags");
+    assert_eq!(tags, 0);
+
+    let digest = sha256_digest(manifest.as_bytes());
+    let res = push(digest.clone()).await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    assert_eq!(header(&res, "docker-content-digest"), Some(digest.as_str()));
+}
+
+#[tokio::test]
+async fn corrupted_manifests_are_caught_when_pulled_by_digest() {
+    let app = test_app_with(Config {
+        verify_manifests: true,
+        ..Default::default()
+    })
+    .await;
+    app.create_repository("app").await;
+    let manifest = app.push_image("app", "latest").await;
+    let digest = sha256_digest(manifest.as_bytes());
+    let file_path: String = sqlx::query_scalar("SELECT file_path FROM manifests WHERE digest = ?")
+        .bind(&digest)
+        .fetch_one(&app.pool)
+        .await
+        .expect("the manifest is recorded");
+    std::fs::write(file_path, format!("{manifest} ")).expect("unable to corrupt the manifest");
+
+    let res = app
+        .send(admin(Request::get(format!("/v2/app/manifests/{digest}"))).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert!(header(&res, "warning").is_some_and(|warning| warning.starts_with("299")));
+    let body: serde_json::Value =
+        serde_json::from_slice(&body_bytes(res).await).expect("errors are json");
+    assert_eq!(body["code"], "ManifestInvalid");
+    // a tag promises no particular content, so it isn't checked
+    let res = app
+        .send(admin(Request::get("/v2/app/manifests/latest")).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn manifest_heads_match_their_gets() {
+    let app = test_app().await;
+    app.create_repository("app").await;
+    let manifest = app.push_image("app", "latest").await;
+    let digest = sha256_digest(manifest.as_bytes());
+    for reference in ["latest", digest.as_str()] {
+        let path = format!("/v2/app/manifests/{reference}");
+        let get = app.send(admin(Request::get(&path)).empty()).await;
+        let head = app.send(admin(Request::head(&path)).empty()).await;
+        assert_eq!(head.status(), StatusCode::OK, "{reference}");
+        for name in ["docker-content-digest", "content-type", "content-length"] {
+            assert_eq!(
+                header(&head, name),
+                header(&get, name),
+                "{name} of {reference}"
+            );
+        }
+        assert_eq!(
+            header(&head, "docker-content-digest"),
+            Some(digest.as_str())
+        );
+        let length = manifest.len().to_string();
+        assert_eq!(header(&head, "content-length"), Some(length.as_str()));
+        assert!(body_bytes(head).await.is_empty());
+        assert_eq!(body_bytes(get).await, manifest.as_bytes());
+    }
+    let res = app
+        .send(admin(Request::head("/v2/app/manifests/missing")).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
@@ -0,0 +1,125 @@
+mod common;
+
+use common::test_app;
+use floundr::{
+    config::Config,
+    database::{initdb, migrate_fresh, verify_schema, EXPECTED_SCHEMA, MIGRATIONS, TABLES},
+};
+use sqlx::{Connection, SqliteConnection};
+
+#[test]
+fn tables_match_the_embedded_schema() {
+    let mut tables = TABLES.clone();
+    let mut expected: Vec<&str> = EXPECTED_SCHEMA.iter().map(|(table, _)| *table).collect();
+    tables.sort_unstable();
+    expected.sort_unstable();
+    assert_eq!(tables, expected);
+}
+
+#[tokio::test]
+async fn migrate_fresh_recreates_an_empty_database() {
+    let app = test_app().await;
+    app.create_repository("app").await;
+    // run from elsewhere, the schema must not be looked up on disk
+    std::env::set_current_dir(app.dir.path()).expect("unable to change directory");
+
+    let mut conn = app
+        .pool
+        .acquire()
+        .await
+        .expect("unable to acquire connection");
+    migrate_fresh(&mut conn, None, None)
+        .await
+        .expect("fresh migration failed");
+    verify_schema(&mut conn)
+        .await
+        .expect("schema is incomplete");
+    let repositories: i64 =
+        sqlx::query_scalar("SELECT COUNT(*) FROM repositories WHERE name = 'app'")
+            .fetch_one(&mut *conn)
+            .await
+            .expect("unable to count repositories");
+    assert_eq!(repositories, 0);
+    let admins: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users WHERE is_admin = TRUE")
+        .fetch_one(&mut *conn)
+        .await
+        .expect("unable to count admins");
+    assert_eq!(admins, 1);
+}
+
+#[tokio::test]
+async fn databases_from_before_migrations_are_upgraded() {
+    let dir = tempfile::tempdir().expect("unable to create temp dir");
+    let path = dir.path().join("floundr.db");
+    let path = path.to_string_lossy();
+    // a database created by the original schema, before later columns existed
+    let mut conn = SqliteConnection::connect(&format!("sqlite://{path}?mode=rwc"))
+        .await
+        .expect("unable to create database");
+    sqlx::query(MIGRATIONS[0])
+        .execute(&mut conn)
+        .await
+        .expect("unable to create the original schema");
+    sqlx::query(
+        "INSERT INTO manifests (repository_id, digest, media_type, file_path, size, schema_version)
+         VALUES (1, 'sha256:abc', 'application/vnd.oci.image.manifest.v1+json', 'default/manifests/abc', 2, 2)",
+    )
+    .execute(&mut conn)
+    .await
+    .expect("unable to insert manifest");
+    conn.close().await.expect("unable to close database");
+
+    let pool = initdb(&path, &Config::default()).await;
+    let mut conn = pool.acquire().await.expect("unable to acquire connection");
+    verify_schema(&mut conn)
+        .await
+        .expect("upgraded schema is incomplete");
+    let version: i64 = sqlx::query_scalar("PRAGMA user_version")
+        .fetch_one(&mut *conn)
+        .await
+        .expect("unable to read user_version");
+    assert_eq!(version, MIGRATIONS.len() as i64);
+    let digests: Vec<String> = sqlx::query_scalar("SELECT digest FROM manifests")
+        .fetch_all(&mut *conn)
+        .await
+        .expect("unable to read manifests");
+    assert_eq!(digests, ["sha256:abc"]);
+
+    // running again finds nothing left to apply
+    drop(conn);
+    pool.close().await;
+    initdb(&path, &Config::default()).await;
+}
+
+#[tokio::test]
+async fn schemas_missing_columns_are_rejected_by_name() {
+    let app = test_app().await;
+    let mut conn = app
+        .pool
+        .acquire()
+        .await
+        .expect("unable to acquire connection");
+    sqlx::query("ALTER TABLE manifest_layers DROP COLUMN diff_id")
+        .execute(&mut *conn)
+        .await
+        .expect("unable to drop column");
+    assert_eq!(
+        verify_schema(&mut conn).await,
+        Err(String::from(
+            "table `manifest_layers` is missing column `diff_id`"
+        ))
+    );
+
+    sqlx::query("DROP TABLE tokens")
+        .execute(&mut *conn)
+        .await
+        .expect("unable to drop table");
+    sqlx::query("ALTER TABLE manifest_layers ADD COLUMN diff_id TEXT DEFAULT NULL")
+        .execute(&mut *conn)
+        .await
+        .expect("unable to restore column");
+    assert_eq!(
+        verify_schema(&mut conn).await,
+        Err(String::from("missing table `tokens`"))
+    );
+}
@@ -0,0 +1,596 @@
+mod common;
+
+use axum::http::{Request, StatusCode};
+use base64::Engine;
+use common::{
+    admin, admin_auth, basic_auth, body_bytes, header, sha256_digest, test_app, test_app_with,
+    RequestExt,
+};
+use floundr::{config::Config, Action};
+use sha2::{Digest, Sha256};
+
+/// Pushes `data` through a chunked upload session, returning its digest
+async fn push_chunked(app: &common::TestApp, name: &str, data: &[u8], chunk: usize) -> String {
+    let digest = sha256_digest(data);
+    let res = app
+        .send(admin(Request::post(format!("/v2/{name}/blobs/uploads/"))).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::ACCEPTED);
+    let mut location = header(&res, "location")
+        .expect("upload session has a location")
+        .to_string();
+    for (idx, part) in data.chunks(chunk).enumerate() {
+        let start = idx * chunk;
+        let res = app
+            .send(
+                admin(Request::patch(&location))
+                    .header("content-type", "application/octet-stream")
+                    .header("content-length", part.len())
+                    .header(
+                        "content-range",
+                        format!("{}-{}", start, start + part.len() - 1),
+                    )
+                    .bytes(part.to_vec()),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::ACCEPTED, "chunk {idx} rejected");
+        let received = format!("0-{}", start + part.len() - 1);
+        assert_eq!(header(&res, "range"), Some(received.as_str()));
+        location = header(&res, "location")
+            .expect("chunk response has a location")
+            .to_string();
+    }
+    let separator = if location.contains('?') { '&' } else { '?' };
+    let res = app
+        .send(
+            admin(Request::put(format!(
+                "{location}{separator}digest={digest}"
+            )))
+            .empty(),
+        )
+        .await;
+    assert_eq!(
+        res.status(),
+        StatusCode::CREATED,
+        "closing the session failed"
+    );
+    digest
+}
+
+#[tokio::test]
+async fn push_then_pull_an_image() {
+    let app = test_app().await;
+    app.create_repository("app").await;
+
+    let layer = b"a layer pushed in a few chunks".to_vec();
+    let config =
+        br#"{"architecture":"amd64","os":"linux","rootfs":{"type":"layers","diff_ids":[]}}"#;
+    let layer_digest = push_chunked(&app, "app", &layer, 8).await;
+    let config_digest = push_chunked(&app, "app", config, 32).await;
+
+    let manifest = serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.manifest.v1+json",
+        "config": {
+            "mediaType": "application/vnd.oci.image.config.v1+json",
+            "size": config.len(),
+            "digest": config_digest,
+        },
+        "layers": [{
+            "mediaType": "application/vnd.oci.image.layer.v1.tar+gzip",
+            "size": layer.len(),
+            "digest": layer_digest,
+        }],
+    })
+    .to_string();
+    let res = app
+        .send(
+            admin(Request::put("/v2/app/manifests/latest"))
+                .header("content-type", "application/vnd.oci.image.manifest.v1+json")
+                .bytes(manifest.clone()),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let manifest_digest = sha256_digest(manifest.as_bytes());
+    assert_eq!(
+        header(&res, "docker-content-digest"),
+        Some(manifest_digest.as_str())
+    );
+
+    let res = app
+        .send(
+            admin(Request::get("/v2/app/manifests/latest"))
+                .header("accept", "application/vnd.oci.image.manifest.v1+json")
+                .empty(),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(body_bytes(res).await, manifest.as_bytes());
+
+    let res = app
+        .send(admin(Request::get(format!("/v2/app/blobs/{layer_digest}"))).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(body_bytes(res).await, layer);
+
+    let res = app
+        .send(admin(Request::get(format!("/v2/app/blobs/{config_digest}"))).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(body_bytes(res).await, config);
+}
+
+#[tokio::test]
+async fn mounting_a_blob_creates_the_target_repository() {
+    let app = test_app().await;
+    app.create_repository("base").await;
+    let (status, digest) = app.push_blob(&admin_auth(), "base", b"shared layer").await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    let res = app
+        .send(
+            admin(Request::post(format!(
+                "/v2/app/blobs/uploads/?mount={digest}&from=base"
+            )))
+            .empty(),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let location = format!("/v2/app/blobs/{digest}");
+    assert_eq!(header(&res, "location"), Some(location.as_str()));
+    assert_eq!(
+        app.pull_blob(&admin_auth(), "app", &digest).await,
+        StatusCode::OK
+    );
+}
+
+#[tokio::test]
+async fn mounting_needs_push_access_to_the_new_repository() {
+    let app = test_app().await;
+    app.create_repository("base").await;
+    let (_, digest) = app.push_blob(&admin_auth(), "base", b"shared layer").await;
+    app.create_user("reader@example.com", "password1").await;
+    app.grant("reader@example.com", "base", Action::Pull).await;
+
+    let res = app
+        .send(
+            Request::post(format!("/v2/app/blobs/uploads/?mount={digest}&from=base"))
+                .header(
+                    "authorization",
+                    basic_auth("reader@example.com", "password1"),
+                )
+                .empty(),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let repositories: i64 =
+        sqlx::query_scalar("SELECT COUNT(*) FROM repositories WHERE name = 'app'")
+            .fetch_one(&app.pool)
+            .await
+            .expect("unable to count repositories");
+    assert_eq!(repositories, 0);
+}
+
+#[tokio::test]
+async fn deleting_a_mounted_blob_keeps_the_other_copies() {
+    let app = test_app().await;
+    app.create_repository("base").await;
+    let (_, digest) = app.push_blob(&admin_auth(), "base", b"shared layer").await;
+    let res = app
+        .send(
+            admin(Request::post(format!(
+                "/v2/app/blobs/uploads/?mount={digest}&from=base"
+            )))
+            .empty(),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let file_path: String = sqlx::query_scalar("SELECT DISTINCT file_path FROM blobs")
+        .fetch_one(&app.pool)
+        .await
+        .expect("both repositories share the stored blob");
+
+    let res = app
+        .send(admin(Request::delete(format!("/v2/base/blobs/{digest}"))).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::ACCEPTED);
+    assert_eq!(
+        app.pull_blob(&admin_auth(), "base", &digest).await,
+        StatusCode::NOT_FOUND
+    );
+    assert_eq!(
+        app.pull_blob(&admin_auth(), "app", &digest).await,
+        StatusCode::OK
+    );
+
+    // the last copy takes the stored object with it
+    let res = app
+        .send(admin(Request::delete(format!("/v2/app/blobs/{digest}"))).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::ACCEPTED);
+    assert!(!std::path::Path::new(&file_path).exists());
+}
+
+/// Every file under `dir`, at any depth
+fn files_under(dir: &std::path::Path) -> Vec<std::path::PathBuf> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .flat_map(|entry| {
+            let path = entry.path();
+            if path.is_dir() {
+                files_under(&path)
+            } else {
+                vec![path]
+            }
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn uploads_failing_their_digest_leave_no_file_behind() {
+    let app = test_app().await;
+    app.create_repository("app").await;
+    let storage = app.dir.path().join("storage");
+    let before = files_under(&storage);
+
+    let declared = sha256_digest(b"the content that was promised");
+    let res = app
+        .send(
+            admin(Request::post(format!(
+                "/v2/app/blobs/uploads/?digest={declared}"
+            )))
+            .bytes(b"something else entirely".to_vec()),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(files_under(&storage), before);
+    assert_eq!(
+        app.pull_blob(&admin_auth(), "app", &declared).await,
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn blob_lookups_list_every_repository_holding_it() {
+    let app = test_app().await;
+    app.create_repository("base").await;
+    app.create_repository("app").await;
+    let blob = b"a layer shared between repositories";
+    let (status, digest) = app.push_blob(&admin_auth(), "base", blob).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let res = app
+        .send(
+            admin(Request::post(format!(
+                "/v2/app/blobs/uploads/?mount={digest}&from=base"
+            )))
+            .empty(),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+
+    let res = app
+        .send(admin(Request::get(format!("/admin/blobs/{digest}"))).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let info: serde_json::Value =
+        serde_json::from_slice(&body_bytes(res).await).expect("blob info is json");
+    assert_eq!(info["digest"], digest.as_str());
+    assert_eq!(info["size"], blob.len());
+    let repositories = info["references"]
+        .as_array()
+        .expect("a list of references")
+        .iter()
+        .map(|reference| {
+            assert!(reference["file_path"]
+                .as_str()
+                .is_some_and(|path| !path.is_empty()));
+            reference["repository"].as_str().unwrap_or_default()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(repositories, ["app", "base"]);
+
+    let unknown = sha256_digest(b"never pushed");
+    let res = app
+        .send(admin(Request::get(format!("/admin/blobs/{unknown}"))).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    app.create_user("user@example.com", "password1").await;
+    let res = app
+        .send(
+            Request::get(format!("/admin/blobs/{digest}"))
+                .header("authorization", basic_auth("user@example.com", "password1"))
+                .empty(),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    // a namespaced admin only sees the repositories within its namespace
+    sqlx::query("UPDATE users SET namespace = 'base', is_admin = TRUE WHERE email = ?")
+        .bind("user@example.com")
+        .execute(&app.pool)
+        .await
+        .expect("unable to set the namespace");
+    let res = app
+        .send(
+            Request::get(format!("/admin/blobs/{digest}"))
+                .header("authorization", basic_auth("user@example.com", "password1"))
+                .empty(),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let info: serde_json::Value =
+        serde_json::from_slice(&body_bytes(res).await).expect("blob info is json");
+    assert_eq!(info["references"].as_array().map(Vec::len), Some(1));
+    assert_eq!(info["references"][0]["repository"], "base");
+}
+
+#[tokio::test]
+async fn blobs_carry_a_repr_digest_when_enabled() {
+    let blob = b"a layer with an RFC 9530 digest";
+    let expected = format!(
+        "sha-256=:{}:",
+        base64::engine::general_purpose::STANDARD.encode(Sha256::digest(blob))
+    );
+    for enabled in [true, false] {
+        let app = test_app_with(Config {
+            repr_digest: enabled,
+            ..Default::default()
+        })
+        .await;
+        app.create_repository("app").await;
+        let (status, digest) = app.push_blob(&admin_auth(), "app", blob).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let uri = format!("/v2/app/blobs/{digest}");
+        for request in [Request::get(&uri), Request::head(&uri)] {
+            let res = app.send(admin(request).empty()).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(header(&res, "docker-content-digest"), Some(digest.as_str()));
+            assert_eq!(
+                header(&res, "repr-digest"),
+                enabled.then_some(expected.as_str())
+            );
+        }
+    }
+}
+
+#[tokio::test]
+async fn blob_lookups_stay_within_the_repository() {
+    let app = test_app().await;
+    app.create_repository("a").await;
+    app.create_repository("b").await;
+    let (status, digest) = app.push_blob(&admin_auth(), "a", b"only in a").await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    let head = |repo: &str| admin(Request::head(format!("/v2/{repo}/blobs/{digest}"))).empty();
+    assert_eq!(app.send(head("b")).await.status(), StatusCode::NOT_FOUND);
+    let res = app
+        .send(admin(Request::delete(format!("/v2/b/blobs/{digest}"))).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    assert_eq!(app.send(head("a")).await.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn deletes_answer_202_with_an_empty_body() {
+    let app = test_app().await;
+    app.create_repository("app").await;
+    let manifest = app.push_image("app", "latest").await;
+    let (_, blob) = app.push_blob(&admin_auth(), "app", b"an unused blob").await;
+    for path in [
+        format!("/v2/app/manifests/{}", sha256_digest(manifest.as_bytes())),
+        format!("/v2/app/blobs/{blob}"),
+    ] {
+        let res = app.send(admin(Request::delete(&path)).empty()).await;
+        assert_eq!(res.status(), StatusCode::ACCEPTED, "{path}");
+        assert_eq!(header(&res, "content-length"), Some("0"), "{path}");
+        assert!(body_bytes(res).await.is_empty(), "{path}");
+    }
+}
+
+#[tokio::test]
+async fn unmountable_blobs_fall_back_to_an_upload_session() {
+    let app = test_app().await;
+    app.create_repository("base").await;
+    app.create_repository("secret").await;
+    app.create_repository("app").await;
+    let (_, digest) = app
+        .push_blob(&admin_auth(), "secret", b"secret layer")
+        .await;
+    let missing = sha256_digest(b"never pushed");
+    app.create_user("writer@example.com", "password1").await;
+    app.grant("writer@example.com", "app", Action::Push).await;
+    let writer = basic_auth("writer@example.com", "password1");
+
+    // missing from the source, or from a source the user can't pull
+    for (auth, mount, from) in [
+        (admin_auth(), &missing, "base"),
+        (writer.clone(), &digest, "secret"),
+    ] {
+        let res = app
+            .send(
+                Request::post(format!("/v2/app/blobs/uploads/?mount={mount}&from={from}"))
+                    .header("authorization", auth)
+                    .empty(),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::ACCEPTED, "{from}");
+        let location = header(&res, "location").expect("a session location");
+        assert!(location.starts_with("/v2/app/blobs/uploads/"), "{location}");
+    }
+    assert_eq!(
+        app.pull_blob(&admin_auth(), "app", &digest).await,
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn namespaced_admins_cant_mount_from_other_tenants() {
+    let app = test_app().await;
+    app.create_repository("other%2Fapp").await;
+    app.create_repository("team%2Fapp").await;
+    let (_, digest) = app
+        .push_blob(&admin_auth(), "other%2Fapp", b"another tenant's layer")
+        .await;
+    app.create_user("admin@example.com", "password1").await;
+    sqlx::query("UPDATE users SET namespace = 'team', is_admin = TRUE WHERE email = ?")
+        .bind("admin@example.com")
+        .execute(&app.pool)
+        .await
+        .expect("unable to set the namespace");
+    let tenant = basic_auth("admin@example.com", "password1");
+
+    // neither naming the other tenant's repository nor leaving the source out
+    for query in [
+        format!("mount={digest}&from=other%2Fapp"),
+        format!("mount={digest}"),
+    ] {
+        let res = app
+            .send(
+                Request::post(format!("/v2/team%2Fapp/blobs/uploads/?{query}"))
+                    .header("authorization", &tenant)
+                    .empty(),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::ACCEPTED, "{query}");
+    }
+    assert_eq!(
+        app.pull_blob(&tenant, "team%2Fapp", &digest).await,
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn content_is_cached_by_how_it_was_addressed() {
+    let app = test_app().await;
+    app.create_repository("app").await;
+    app.create_repository_with("open", true).await;
+    let manifest = app.push_image("app", "latest").await;
+    let digest = sha256_digest(manifest.as_bytes());
+    let (_, blob) = app.push_blob(&admin_auth(), "app", b"a cached layer").await;
+    let (_, public_blob) = app
+        .push_blob(&admin_auth(), "open", b"a cached layer")
+        .await;
+    let get = |path: String| app.send(admin(Request::get(path)).empty());
+
+    let res = get(format!("/v2/app/blobs/{blob}")).await;
+    assert_eq!(
+        header(&res, "cache-control"),
+        Some("private, max-age=31536000, immutable")
+    );
+    let res = get(format!("/v2/open/blobs/{public_blob}")).await;
+    assert_eq!(
+        header(&res, "cache-control"),
+        Some("public, max-age=31536000, immutable")
+    );
+
+    let etag = format!("\"{digest}\"");
+    let res = get(String::from("/v2/app/manifests/latest")).await;
+    assert_eq!(header(&res, "cache-control"), Some("no-cache"));
+    assert_eq!(header(&res, "etag"), Some(etag.as_str()));
+    let res = get(format!("/v2/app/manifests/{digest}")).await;
+    assert_eq!(
+        header(&res, "cache-control"),
+        Some("private, max-age=31536000, immutable")
+    );
+    assert_eq!(header(&res, "etag"), Some(etag.as_str()));
+
+    let res = app
+        .send(
+            admin(Request::get("/v2/app/manifests/latest"))
+                .header("if-none-match", &etag)
+                .empty(),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+    assert!(body_bytes(res).await.is_empty());
+}
+
+#[tokio::test]
+async fn the_empty_blob_round_trips() {
+    let app = test_app().await;
+    app.create_repository("app").await;
+    let empty = sha256_digest(b"");
+    // an open session's empty chunk shares the digest, but isn't the blob
+    let res = app
+        .send(admin(Request::post("/v2/app/blobs/uploads/")).empty())
+        .await;
+    let location = header(&res, "location")
+        .expect("upload session has a location")
+        .to_string();
+    let res = app
+        .send(
+            admin(Request::patch(&location))
+                .header("content-type", "application/octet-stream")
+                .empty(),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::ACCEPTED);
+    let chunks: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM blobs WHERE digest = ? AND upload_session_id IS NOT NULL",
+    )
+    .bind(&empty)
+    .fetch_one(&app.pool)
+    .await
+    .expect("unable to count chunks");
+    assert_eq!(chunks, 1);
+    assert_eq!(
+        app.pull_blob(&admin_auth(), "app", &empty).await,
+        StatusCode::NOT_FOUND
+    );
+
+    let separator = if location.contains('?') { '&' } else { '?' };
+    let res = app
+        .send(admin(Request::put(format!("{location}{separator}digest={empty}"))).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let res = app
+        .send(admin(Request::get(format!("/v2/app/blobs/{empty}"))).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(header(&res, "content-length"), Some("0"));
+    assert!(body_bytes(res).await.is_empty());
+
+    app.create_repository("other").await;
+    let (status, digest) = app.push_blob(&admin_auth(), "other", b"").await;
+    assert_eq!(status, StatusCode::CREATED);
+    let res = app
+        .send(admin(Request::head(format!("/v2/other/blobs/{digest}"))).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(header(&res, "content-length"), Some("0"));
+}
+
+#[tokio::test]
+async fn malformed_names_and_references_are_rejected() {
+    let app = test_app().await;
+    app.create_repository("app").await;
+    app.push_image("app", "latest").await;
+    let long_tag = "t".repeat(129);
+    for (path, code) in [
+        ("/v2/App/manifests/latest", "NameInvalid"),
+        ("/v2/app--/tags/list", "NameInvalid"),
+        ("/v2/app/manifests/-latest", "ManifestInvalid"),
+        (&format!("/v2/app/manifests/{long_tag}"), "ManifestInvalid"),
+        ("/v2/app/manifests/md5:abc", "ManifestInvalid"),
+        ("/v2/app/blobs/not-a-digest", "DigestInvalid"),
+    ] {
+        let res = app.send(admin(Request::get(path)).empty()).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{path}");
+        let body: serde_json::Value =
+            serde_json::from_slice(&body_bytes(res).await).expect("errors are json");
+        assert_eq!(body["code"], code, "{path}");
+    }
+    let res = app
+        .send(admin(Request::post("/repositories/Not_Valid/false")).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    let tag = "t".repeat(128);
+    let res = app
+        .send(admin(Request::get(format!("/v2/app/manifests/{tag}"))).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
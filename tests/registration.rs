@@ -0,0 +1,151 @@
+mod common;
+
+use axum::http::{request::Builder, Request, StatusCode};
+use common::{admin, basic_auth, test_app_with, RequestExt, TestApp};
+use floundr::config::{Config, RegistrationPolicy};
+
+async fn registration_app(policy: RegistrationPolicy) -> TestApp {
+    test_app_with(Config {
+        allow_registration: policy,
+        ..Default::default()
+    })
+    .await
+}
+
+async fn register(app: &TestApp, builder: Builder, email: &str, is_admin: bool) -> StatusCode {
+    let body = serde_json::json!({
+        "email": email,
+        "password": "password1",
+        "confirm_password": "password1",
+        "is_admin": is_admin,
+    });
+    app.send(
+        builder
+            .header("content-type", "application/json")
+            .bytes(body.to_string()),
+    )
+    .await
+    .status()
+}
+
+/// whether the registered user is an admin, None when there is no such user
+async fn registered_admin(app: &TestApp, email: &str) -> Option<bool> {
+    sqlx::query_scalar("SELECT is_admin FROM users WHERE email = ?")
+        .bind(email)
+        .fetch_optional(&app.pool)
+        .await
+        .expect("unable to look up user")
+}
+
+#[tokio::test]
+async fn registering_as_an_admin_is_downgraded_unless_allowed() {
+    let app = registration_app(RegistrationPolicy::User).await;
+    let status = register(
+        &app,
+        Request::post("/auth/register"),
+        "anon@example.com",
+        true,
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(
+        registered_admin(&app, "anon@example.com").await,
+        Some(false)
+    );
+
+    // an admin is downgraded the same way under `user`
+    let status = register(
+        &app,
+        admin(Request::post("/auth/register")),
+        "other@example.com",
+        true,
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(
+        registered_admin(&app, "other@example.com").await,
+        Some(false)
+    );
+
+    // and an anonymous caller under `admin`
+    let app = registration_app(RegistrationPolicy::Admin).await;
+    let status = register(
+        &app,
+        Request::post("/auth/register"),
+        "anon@example.com",
+        true,
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(
+        registered_admin(&app, "anon@example.com").await,
+        Some(false)
+    );
+}
+
+#[tokio::test]
+async fn registration_is_off_by_default() {
+    let app = registration_app(RegistrationPolicy::Off).await;
+    let status = register(
+        &app,
+        Request::post("/auth/register"),
+        "anon@example.com",
+        false,
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    // admins can't register anyone either, they create users instead
+    let status = register(
+        &app,
+        admin(Request::post("/auth/register")),
+        "other@example.com",
+        false,
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(registered_admin(&app, "anon@example.com").await, None);
+    assert_eq!(registered_admin(&app, "other@example.com").await, None);
+}
+
+#[tokio::test]
+async fn user_registration_opens_regular_accounts() {
+    let app = registration_app(RegistrationPolicy::User).await;
+    let status = register(
+        &app,
+        Request::post("/auth/register"),
+        "anon@example.com",
+        false,
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(
+        registered_admin(&app, "anon@example.com").await,
+        Some(false)
+    );
+
+    let res = app
+        .send(
+            Request::get("/v2/")
+                .header("authorization", basic_auth("anon@example.com", "password1"))
+                .empty(),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn admin_registration_lets_admins_create_admins() {
+    let app = registration_app(RegistrationPolicy::Admin).await;
+    let status = register(
+        &app,
+        admin(Request::post("/auth/register")),
+        "other@example.com",
+        true,
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(
+        registered_admin(&app, "other@example.com").await,
+        Some(true)
+    );
+}
@@ -0,0 +1,478 @@
+mod common;
+
+use axum::http::{Request, StatusCode};
+use common::{
+    admin, admin_auth, basic_auth, body_bytes, header, sha256_digest, test_app_with, RequestExt,
+    TestApp, OCI_MANIFEST,
+};
+use floundr::{config::Config, Action};
+
+/// recovery window the soft delete tests run with
+const WINDOW: u64 = 3600;
+
+async fn soft_delete_app() -> TestApp {
+    test_app_with(Config {
+        repo_recovery_window: Some(WINDOW),
+        ..Default::default()
+    })
+    .await
+}
+
+async fn pull_manifest(app: &TestApp, repo: &str, tag: &str) -> (StatusCode, Vec<u8>) {
+    let res = app
+        .send(
+            admin(Request::get(format!("/v2/{repo}/manifests/{tag}")))
+                .header("accept", OCI_MANIFEST)
+                .empty(),
+        )
+        .await;
+    (res.status(), body_bytes(res).await)
+}
+
+/// Pulls `repo:latest` with the given credentials
+async fn pull_manifest_as(app: &TestApp, auth: &str, repo: &str) -> (StatusCode, Vec<u8>) {
+    let res = app
+        .send(
+            Request::get(format!("/v2/{repo}/manifests/latest"))
+                .header("authorization", auth)
+                .header("accept", OCI_MANIFEST)
+                .empty(),
+        )
+        .await;
+    (res.status(), body_bytes(res).await)
+}
+
+async fn delete_repository(app: &TestApp, repo: &str) -> StatusCode {
+    app.send(admin(Request::delete(format!("/repositories/{repo}"))).empty())
+        .await
+        .status()
+}
+
+async fn restore_repository(app: &TestApp, repo: &str) -> StatusCode {
+    app.send(admin(Request::post(format!("/repositories/{repo}/restore"))).empty())
+        .await
+        .status()
+}
+
+#[tokio::test]
+async fn soft_deleted_names_can_be_created_again() {
+    let app = soft_delete_app().await;
+    app.create_repository("app").await;
+    let manifest = app.push_image("app", "latest").await;
+
+    assert_eq!(delete_repository(&app, "app").await, StatusCode::OK);
+    assert_eq!(
+        pull_manifest(&app, "app", "latest").await.0,
+        StatusCode::NOT_FOUND
+    );
+    assert_eq!(restore_repository(&app, "app").await, StatusCode::OK);
+    assert_eq!(
+        pull_manifest(&app, "app", "latest").await,
+        (StatusCode::OK, manifest.into_bytes())
+    );
+
+    assert_eq!(delete_repository(&app, "app").await, StatusCode::OK);
+    app.create_repository("app").await;
+    // the new repository starts out empty
+    assert_eq!(
+        pull_manifest(&app, "app", "latest").await.0,
+        StatusCode::NOT_FOUND
+    );
+    // and the deleted one can't be restored over it
+    assert_eq!(restore_repository(&app, "app").await, StatusCode::CONFLICT);
+}
+
+#[tokio::test]
+async fn manifest_deletes_leave_soft_deleted_repositories_alone() {
+    let app = soft_delete_app().await;
+    app.create_repository("app").await;
+    let manifest = app.push_image("app", "latest").await;
+    assert_eq!(delete_repository(&app, "app").await, StatusCode::OK);
+    app.create_repository("app").await;
+
+    // the tag only exists in the deleted repository
+    let res = app
+        .send(admin(Request::delete("/v2/app/manifests/latest")).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    // the same image lands at the same path, deleting it here keeps the file
+    app.push_image("app", "latest").await;
+    let res = app
+        .send(admin(Request::delete("/v2/app/manifests/latest")).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::ACCEPTED);
+
+    sqlx::query("DELETE FROM repositories WHERE deleted_at IS NULL")
+        .execute(&app.pool)
+        .await
+        .expect("unable to drop the new repository");
+    assert_eq!(restore_repository(&app, "app").await, StatusCode::OK);
+    assert_eq!(
+        pull_manifest(&app, "app", "latest").await,
+        (StatusCode::OK, manifest.into_bytes())
+    );
+}
+
+#[tokio::test]
+async fn purging_a_reused_name_keeps_the_live_repository() {
+    let app = soft_delete_app().await;
+    app.create_repository("app").await;
+    app.push_image("app", "latest").await;
+    assert_eq!(delete_repository(&app, "app").await, StatusCode::OK);
+
+    // the same image lands at the same paths in the new repository
+    app.create_repository("app").await;
+    let manifest = app.push_image("app", "latest").await;
+    let config_digest = serde_json::from_str::<serde_json::Value>(&manifest).unwrap()["config"]
+        ["digest"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    sqlx::query(
+        "UPDATE repositories SET deleted_at = datetime('now', '-2 hours') WHERE deleted_at IS NOT NULL",
+    )
+    .execute(&app.pool)
+    .await
+    .expect("unable to expire the deleted repository");
+    let mut conn = app
+        .pool
+        .acquire()
+        .await
+        .expect("unable to acquire connection");
+    let purged = app
+        .storage
+        .purge_expired_repositories(&mut conn, WINDOW)
+        .await
+        .expect("purge failed");
+    assert_eq!(purged, 1);
+
+    assert_eq!(
+        pull_manifest(&app, "app", "latest").await,
+        (StatusCode::OK, manifest.into_bytes())
+    );
+    assert_eq!(
+        app.pull_blob(&admin_auth(), "app", &config_digest).await,
+        StatusCode::OK
+    );
+}
+
+#[tokio::test]
+async fn deleting_a_repository_ends_its_upload_sessions() {
+    let app = test_app_with(Config::default()).await;
+    app.create_repository("app").await;
+    let res = app
+        .send(admin(Request::post("/v2/app/blobs/uploads/")).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::ACCEPTED);
+
+    assert_eq!(delete_repository(&app, "app").await, StatusCode::OK);
+    let uploads: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM uploads")
+        .fetch_one(&app.pool)
+        .await
+        .expect("unable to count uploads");
+    assert_eq!(uploads, 0);
+    // the name is free again
+    app.create_repository("app").await;
+}
+
+#[tokio::test]
+async fn repository_listings_stay_within_the_callers_namespace() {
+    let app = test_app_with(Config::default()).await;
+    app.create_repository("team%2Fapp").await;
+    app.create_repository("other").await;
+    app.create_user("user@example.com", "password1").await;
+    sqlx::query("UPDATE users SET namespace = 'team' WHERE email = ?")
+        .bind("user@example.com")
+        .execute(&app.pool)
+        .await
+        .expect("unable to set the namespace");
+
+    let res = app
+        .send(
+            Request::get("/repositories")
+                .header("authorization", basic_auth("user@example.com", "password1"))
+                .empty(),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: serde_json::Value =
+        serde_json::from_slice(&body_bytes(res).await).expect("repository list is json");
+    let names = body["repositories"]
+        .as_array()
+        .expect("a list of repositories")
+        .iter()
+        .map(|repo| repo["name"].as_str().unwrap_or_default())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["team/app"]);
+}
+
+#[tokio::test]
+async fn tenants_cant_pull_or_push_outside_their_namespace() {
+    let app = test_app_with(Config::default()).await;
+    app.create_repository("team%2Fapp").await;
+    app.create_repository("other").await;
+    app.push_image("team%2Fapp", "latest").await;
+    app.push_image("other", "latest").await;
+    let user = app.create_namespaced_user("user@example.com", false).await;
+    // even explicit grants don't reach past the prefix
+    app.grant("user@example.com", "team/app", Action::Push)
+        .await;
+    app.grant("user@example.com", "other", Action::Push).await;
+
+    let (status, _) = pull_manifest_as(&app, &user, "team%2Fapp").await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = pull_manifest_as(&app, &user, "other").await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(
+        app.push_blob(&user, "team%2Fapp", b"a layer").await.0,
+        StatusCode::CREATED
+    );
+    assert_eq!(
+        app.push_blob(&user, "other", b"a layer").await.0,
+        StatusCode::FORBIDDEN
+    );
+}
+
+#[tokio::test]
+async fn namespaces_dont_reach_tenants_sharing_their_prefix() {
+    let app = test_app_with(Config::default()).await;
+    app.create_repository("tenant-a%2Fapp").await;
+    app.create_repository("tenant-ab%2Fapp").await;
+    app.push_image("tenant-a%2Fapp", "latest").await;
+    app.push_image("tenant-ab%2Fapp", "latest").await;
+    app.create_user("user@example.com", "password1").await;
+    sqlx::query("UPDATE users SET namespace = 'tenant-a', is_admin = TRUE WHERE email = ?")
+        .bind("user@example.com")
+        .execute(&app.pool)
+        .await
+        .expect("unable to set the namespace");
+    let user = basic_auth("user@example.com", "password1");
+
+    let (status, _) = pull_manifest_as(&app, &user, "tenant-a%2Fapp").await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = pull_manifest_as(&app, &user, "tenant-ab%2Fapp").await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(
+        app.push_blob(&user, "tenant-ab%2Fapp", b"a layer").await.0,
+        StatusCode::FORBIDDEN
+    );
+}
+
+#[tokio::test]
+async fn namespaced_admins_only_manage_their_own_repositories() {
+    let app = test_app_with(Config {
+        repo_recovery_window: Some(WINDOW),
+        ..Default::default()
+    })
+    .await;
+    app.create_repository("team%2Fapp").await;
+    app.create_repository("other").await;
+    app.push_image("other", "latest").await;
+    app.create_repository("gone").await;
+    assert_eq!(delete_repository(&app, "gone").await, StatusCode::OK);
+    let tenant = app.create_namespaced_user("admin@example.com", true).await;
+
+    for request in [
+        Request::delete("/repositories/other"),
+        Request::post("/repositories/gone/restore"),
+        Request::get("/repositories/other/export"),
+        Request::get("/repositories/other/manifests/latest"),
+        Request::get("/repositories/other/manifests/latest/closure"),
+        Request::post("/repositories/elsewhere/true"),
+    ] {
+        let request = request.header("authorization", &tenant).empty();
+        let route = format!("{} {}", request.method(), request.uri());
+        let res = app.send(request).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN, "{route} was allowed");
+    }
+    let res = app
+        .send(
+            Request::delete("/repositories/team%2Fapp")
+                .header("authorization", &tenant)
+                .empty(),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn deleting_a_repository_carries_on_past_missing_files() {
+    let app = test_app_with(Config::default()).await;
+    app.create_repository("app").await;
+    app.push_image("app", "latest").await;
+    let blob: String = sqlx::query_scalar("SELECT file_path FROM blobs")
+        .fetch_one(&app.pool)
+        .await
+        .expect("the config blob is stored");
+    let manifest: String = sqlx::query_scalar("SELECT file_path FROM manifests")
+        .fetch_one(&app.pool)
+        .await
+        .expect("the manifest is stored");
+
+    // one file is already gone, another can't be removed as a file at all
+    std::fs::remove_file(&blob).expect("unable to remove the blob");
+    std::fs::remove_file(&manifest).expect("unable to remove the manifest");
+    std::fs::create_dir_all(std::path::Path::new(&manifest).join("stuck"))
+        .expect("unable to block the manifest path");
+
+    let res = app
+        .send(admin(Request::delete("/repositories/app")).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: serde_json::Value =
+        serde_json::from_slice(&body_bytes(res).await).expect("partial deletes report json");
+    assert_eq!(body["failed_files"], serde_json::json!([manifest]));
+
+    let res = app
+        .send(admin(Request::get("/v2/app/tags/list")).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    let rows: i64 = sqlx::query_scalar(
+        "SELECT (SELECT COUNT(*) FROM blobs) + (SELECT COUNT(*) FROM manifests)",
+    )
+    .fetch_one(&app.pool)
+    .await
+    .expect("unable to count rows");
+    assert_eq!(rows, 0);
+}
+
+#[tokio::test]
+async fn unqualified_names_resolve_to_the_default_namespace() {
+    let app = test_app_with(Config {
+        default_namespace: Some(String::from("library")),
+        ..Default::default()
+    })
+    .await;
+    app.create_repository("library%2Fubuntu").await;
+    let manifest = app.push_image("library%2Fubuntu", "latest").await;
+
+    assert_eq!(
+        pull_manifest(&app, "ubuntu", "latest").await,
+        (StatusCode::OK, manifest.clone().into_bytes())
+    );
+    let res = app
+        .send(admin(Request::get("/v2/ubuntu/tags/list")).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: serde_json::Value =
+        serde_json::from_slice(&body_bytes(res).await).expect("tag list is json");
+    assert_eq!(body["name"], "library/ubuntu");
+
+    // pushes land in the qualified repository too
+    app.push_image("ubuntu", "v2").await;
+    assert_eq!(
+        pull_manifest(&app, "library%2Fubuntu", "v2").await.0,
+        StatusCode::OK
+    );
+    // names that already have a namespace are left alone
+    assert_eq!(
+        pull_manifest(&app, "other%2Fubuntu", "latest").await.0,
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn every_delete_is_refused_when_deletes_are_disabled() {
+    let app = test_app_with(Config {
+        disable_delete: true,
+        ..Default::default()
+    })
+    .await;
+    app.create_repository("app").await;
+    let manifest = app.push_image("app", "latest").await;
+    let (_, blob) = app.push_blob(&admin_auth(), "app", b"a kept layer").await;
+    app.create_user("user@example.com", "password1").await;
+
+    for path in [
+        format!("/v2/app/manifests/{}", sha256_digest(manifest.as_bytes())),
+        String::from("/v2/app/manifests/latest"),
+        format!("/v2/app/blobs/{blob}"),
+        String::from("/repositories/app"),
+        String::from("/users/user@example.com"),
+    ] {
+        let res = app.send(admin(Request::delete(&path)).empty()).await;
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED, "{path}");
+        assert!(
+            header(&res, "warning").is_some_and(|warning| warning.starts_with("299")),
+            "{path}"
+        );
+    }
+    assert_eq!(
+        app.pull_blob(&admin_auth(), "app", &blob).await,
+        StatusCode::OK
+    );
+    let res = app
+        .send(admin(Request::get("/v2/app/manifests/latest")).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let users: i64 =
+        sqlx::query_scalar("SELECT COUNT(*) FROM users WHERE email = 'user@example.com'")
+            .fetch_one(&app.pool)
+            .await
+            .expect("unable to count users");
+    assert_eq!(users, 1);
+
+    let config = Config {
+        jwt_secret: String::from("secret"),
+        disable_delete: true,
+        gc_interval: Some(60),
+        ..Default::default()
+    };
+    assert!(config.validate().is_err());
+}
+
+#[tokio::test]
+async fn users_list_the_repositories_they_can_reach() {
+    let app = test_app_with(Config::default()).await;
+    for name in ["app", "other", "hidden"] {
+        app.create_repository(name).await;
+    }
+    app.create_repository_with("open", true).await;
+    app.create_user("user@example.com", "password1").await;
+    app.grant("user@example.com", "app", Action::Push).await;
+    app.grant("user@example.com", "other", Action::Pull).await;
+    app.grant("user@example.com", "open", Action::Delete).await;
+
+    let res = app
+        .send(
+            Request::get("/repositories/mine")
+                .header("authorization", basic_auth("user@example.com", "password1"))
+                .empty(),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: serde_json::Value =
+        serde_json::from_slice(&body_bytes(res).await).expect("repositories are json");
+    assert_eq!(
+        body["repositories"],
+        serde_json::json!([
+            {"name": "app", "is_public": false, "action": "push"},
+            {"name": "default", "is_public": true, "action": "pull"},
+            {"name": "open", "is_public": true, "action": "delete"},
+            {"name": "other", "is_public": false, "action": "pull"},
+        ])
+    );
+
+    // a namespaced user sees nothing outside its prefix, public or not
+    app.create_repository("team%2Fapp").await;
+    app.grant("user@example.com", "team/app", Action::Pull)
+        .await;
+    sqlx::query("UPDATE users SET namespace = 'team/' WHERE email = 'user@example.com'")
+        .execute(&app.pool)
+        .await
+        .expect("unable to set the namespace");
+    let res = app
+        .send(
+            Request::get("/repositories/mine")
+                .header("authorization", basic_auth("user@example.com", "password1"))
+                .empty(),
+        )
+        .await;
+    let body: serde_json::Value =
+        serde_json::from_slice(&body_bytes(res).await).expect("repositories are json");
+    assert_eq!(
+        body["repositories"],
+        serde_json::json!([{"name": "team/app", "is_public": false, "action": "pull"}])
+    );
+}
@@ -0,0 +1,287 @@
+mod common;
+
+use axum::{
+    body::Bytes,
+    extract::{DefaultBodyLimit, Path, Query, State},
+    http::{header, HeaderMap, Method, Request, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{any, get},
+    Router,
+};
+use common::{admin, admin_auth, body_bytes, sha256_digest, test_app_on, RequestExt, TestApp};
+use floundr::{
+    config::Config,
+    s3::{S3Settings, S3StorageDriver},
+    storage_driver::Backend,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// Just enough of S3 for the driver: objects, copies, listings and
+/// multipart uploads, all held in memory
+#[derive(Default)]
+struct MockS3 {
+    objects: Mutex<HashMap<String, Vec<u8>>>,
+    /// parts of the multipart uploads in progress, by upload id
+    uploads: Mutex<HashMap<String, BTreeMap<u16, Vec<u8>>>>,
+    /// refuse every UploadPart while set
+    fail_parts: AtomicBool,
+}
+
+type Params = Query<HashMap<String, String>>;
+
+async fn object(
+    State(s3): State<Arc<MockS3>>,
+    Path((_, key)): Path<(String, String)>,
+    Query(params): Params,
+    method: Method,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let upload_id = params.get("uploadId").cloned();
+    match (method, upload_id) {
+        (Method::POST, None) if params.contains_key("uploads") => {
+            let id = uuid::Uuid::new_v4().to_string();
+            s3.uploads
+                .lock()
+                .unwrap()
+                .insert(id.clone(), BTreeMap::new());
+            format!(
+                "<InitiateMultipartUploadResult xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\">\
+                 <UploadId>{id}</UploadId></InitiateMultipartUploadResult>"
+            )
+            .into_response()
+        }
+        (Method::PUT, Some(id)) => {
+            if s3.fail_parts.load(Ordering::Relaxed) {
+                return StatusCode::SERVICE_UNAVAILABLE.into_response();
+            }
+            let part: u16 = params["partNumber"].parse().unwrap();
+            match s3.uploads.lock().unwrap().get_mut(&id) {
+                Some(parts) => parts.insert(part, body.to_vec()),
+                None => return StatusCode::NOT_FOUND.into_response(),
+            };
+            ([(header::ETAG, format!("\"{id}-{part}\""))], "").into_response()
+        }
+        (Method::POST, Some(id)) => match s3.uploads.lock().unwrap().remove(&id) {
+            Some(parts) => {
+                let data = parts.into_values().flatten().collect();
+                s3.objects.lock().unwrap().insert(key, data);
+                "<CompleteMultipartUploadResult></CompleteMultipartUploadResult>".into_response()
+            }
+            None => StatusCode::NOT_FOUND.into_response(),
+        },
+        (Method::DELETE, Some(id)) => {
+            s3.uploads.lock().unwrap().remove(&id);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        (Method::PUT, None) => {
+            let mut objects = s3.objects.lock().unwrap();
+            let data = match headers.get("x-amz-copy-source") {
+                Some(source) => {
+                    let source = source.to_str().unwrap().replace("%3A", ":");
+                    let (_, source) = source[1..].split_once('/').unwrap();
+                    match objects.get(source) {
+                        Some(data) => data.clone(),
+                        None => return StatusCode::NOT_FOUND.into_response(),
+                    }
+                }
+                None => body.to_vec(),
+            };
+            objects.insert(key, data);
+            "<CopyObjectResult></CopyObjectResult>".into_response()
+        }
+        (Method::DELETE, None) => {
+            s3.objects.lock().unwrap().remove(&key);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        (Method::GET | Method::HEAD, None) => {
+            let Some(data) = s3.objects.lock().unwrap().get(&key).cloned() else {
+                return StatusCode::NOT_FOUND.into_response();
+            };
+            let range = headers
+                .get(header::RANGE)
+                .and_then(|range| range.to_str().ok()?.strip_prefix("bytes="))
+                .and_then(|range| range.split_once('-'))
+                .map(|(start, end)| (start.parse().unwrap(), end.parse::<usize>().unwrap()));
+            match range {
+                Some((start, end)) => (
+                    StatusCode::PARTIAL_CONTENT,
+                    data[start..=end.min(data.len() - 1)].to_vec(),
+                )
+                    .into_response(),
+                None => data.into_response(),
+            }
+        }
+        _ => StatusCode::METHOD_NOT_ALLOWED.into_response(),
+    }
+}
+
+async fn list(State(s3): State<Arc<MockS3>>, Query(params): Params) -> String {
+    let prefix = params.get("prefix").map(String::as_str).unwrap_or_default();
+    let contents: String = s3
+        .objects
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(key, _)| key.starts_with(prefix))
+        .map(|(key, data)| {
+            format!(
+                "<Contents><Key>{key}</Key><Size>{}</Size></Contents>",
+                data.len()
+            )
+        })
+        .collect();
+    format!("<ListBucketResult>{contents}</ListBucketResult>")
+}
+
+/// Serves a mock S3 on a local port, returning an app whose storage is
+/// the S3 driver pointed at it
+async fn s3_app() -> (TestApp, Arc<MockS3>) {
+    let s3 = Arc::new(MockS3::default());
+    let router = Router::new()
+        .route("/:bucket/", get(list))
+        .route("/:bucket/*key", any(object))
+        .layer(DefaultBodyLimit::disable())
+        .with_state(Arc::clone(&s3));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("unable to bind the mock s3");
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, router).await });
+    let settings = S3Settings {
+        bucket: Some("floundr".into()),
+        region: "us-east-1".into(),
+        endpoint: Some(endpoint),
+        access_key: Some("access".into()),
+        secret_key: Some("secret".into()),
+    };
+    let driver = S3StorageDriver::new(&settings).expect("unable to build the s3 driver");
+    let app = test_app_on(Config::default(), |_| Backend::S3(Box::new(driver))).await;
+    app.create_repository("app").await;
+    (app, s3)
+}
+
+/// Opens an upload session, returning its location
+async fn open_session(app: &TestApp) -> String {
+    let res = app
+        .send(admin(Request::post("/v2/app/blobs/uploads/")).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::ACCEPTED);
+    common::header(&res, "location")
+        .expect("upload session has a location")
+        .to_string()
+}
+
+async fn patch_chunk(app: &TestApp, location: &str, start: usize, chunk: &[u8]) -> StatusCode {
+    app.send(
+        admin(Request::patch(location))
+            .header("content-type", "application/octet-stream")
+            .header("content-length", chunk.len())
+            .header(
+                "content-range",
+                format!("{}-{}", start, start + chunk.len() - 1),
+            )
+            .bytes(chunk.to_vec()),
+    )
+    .await
+    .status()
+}
+
+/// a blob a little over one part, so it takes two parts to upload
+fn large_blob() -> Vec<u8> {
+    (0..9 * 1024 * 1024).map(|i| (i % 251) as u8).collect()
+}
+
+#[tokio::test]
+async fn chunked_uploads_are_combined_into_one_blob() {
+    let (app, s3) = s3_app().await;
+    let blob = large_blob();
+    let digest = sha256_digest(&blob);
+    let location = open_session(&app).await;
+    let (first, second) = blob.split_at(5 * 1024 * 1024);
+    assert_eq!(
+        patch_chunk(&app, &location, 0, first).await,
+        StatusCode::ACCEPTED
+    );
+    assert_eq!(
+        patch_chunk(&app, &location, first.len(), second).await,
+        StatusCode::ACCEPTED
+    );
+    let separator = if location.contains('?') { '&' } else { '?' };
+    let res = app
+        .send(
+            admin(Request::put(format!(
+                "{location}{separator}digest={digest}"
+            )))
+            .empty(),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+
+    // the parts were assembled and moved to the blob's key
+    let key = format!("app/blobs/{digest}");
+    assert_eq!(s3.objects.lock().unwrap().get(&key), Some(&blob));
+    assert!(s3.uploads.lock().unwrap().is_empty());
+    assert_eq!(s3.objects.lock().unwrap().len(), 1);
+
+    let res = app
+        .send(admin(Request::get(format!("/v2/app/blobs/{digest}"))).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(body_bytes(res).await, blob);
+    let res = app
+        .send(
+            admin(Request::get(format!("/v2/app/blobs/{digest}")))
+                .header("range", "bytes=10-19")
+                .empty(),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(body_bytes(res).await, blob[10..20]);
+}
+
+#[tokio::test]
+async fn small_blobs_are_stored_with_one_put() {
+    let (app, s3) = s3_app().await;
+    let (status, digest) = app.push_blob(&admin_auth(), "app", b"a small layer").await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(
+        s3.objects
+            .lock()
+            .unwrap()
+            .get(&format!("app/blobs/{digest}")),
+        Some(&b"a small layer".to_vec())
+    );
+    assert_eq!(
+        app.pull_blob(&admin_auth(), "app", &digest).await,
+        StatusCode::OK
+    );
+}
+
+#[tokio::test]
+async fn failed_chunks_end_the_session() {
+    let (app, s3) = s3_app().await;
+    let blob = large_blob();
+    let location = open_session(&app).await;
+    s3.fail_parts.store(true, Ordering::Relaxed);
+    assert_eq!(
+        patch_chunk(&app, &location, 0, &blob).await,
+        StatusCode::NOT_FOUND
+    );
+
+    // the parts S3 held were dropped, and the session can't go on
+    assert!(s3.uploads.lock().unwrap().is_empty());
+    s3.fail_parts.store(false, Ordering::Relaxed);
+    assert_eq!(
+        patch_chunk(&app, &location, 0, &blob).await,
+        StatusCode::GONE
+    );
+    let res = app.send(admin(Request::get(&location)).empty()).await;
+    assert_eq!(res.status(), StatusCode::GONE);
+}
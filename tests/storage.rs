@@ -0,0 +1,54 @@
+mod common;
+
+use common::test_app;
+
+#[tokio::test]
+async fn sizing_stops_at_pathologically_deep_trees() {
+    let app = test_app().await;
+    let root = app.storage.base_path().join("deep");
+    let mut dir = root.clone();
+    for level in 0..200 {
+        dir = dir.join(format!("level-{level}"));
+    }
+    std::fs::create_dir_all(&dir).expect("unable to build the tree");
+    std::fs::write(root.join("shallow"), [0u8; 10]).expect("unable to write a file");
+    std::fs::write(dir.join("buried"), [0u8; 1000]).expect("unable to write a file");
+
+    // the scan gives up long before the bottom, rather than walking it all
+    assert_eq!(app.storage.get_dir_size(&root).await, 10);
+
+    // and the result is reused for a while instead of scanning again
+    std::fs::write(root.join("later"), [0u8; 5]).expect("unable to write a file");
+    assert_eq!(app.storage.get_dir_size(&root).await, 10);
+}
+
+#[tokio::test]
+async fn names_escaping_the_storage_root_create_nothing() {
+    let app = test_app().await;
+    let mut conn = app
+        .pool
+        .acquire()
+        .await
+        .expect("unable to acquire connection");
+    let outside = app.dir.path().join("escape");
+    for name in ["../escape", "app/../../escape", &outside.to_string_lossy()] {
+        assert!(
+            app.storage
+                .create_repository(&mut conn, name, false)
+                .await
+                .is_err(),
+            "{name}"
+        );
+        assert!(
+            app.storage.new_session(&mut conn, name).await.is_err(),
+            "{name}"
+        );
+    }
+    assert!(!outside.exists());
+    let repositories: i64 =
+        sqlx::query_scalar("SELECT COUNT(*) FROM repositories WHERE name LIKE '%escape%'")
+            .fetch_one(&mut *conn)
+            .await
+            .expect("unable to count repositories");
+    assert_eq!(repositories, 0);
+}
@@ -0,0 +1,290 @@
+mod common;
+
+use axum::http::{Request, StatusCode};
+use common::{
+    admin, admin_auth, body_bytes, header, next_page, sha256_digest, test_app, RequestExt, TestApp,
+    OCI_MANIFEST,
+};
+
+async fn manifest_tags(app: &TestApp, repo: &str, digest: &str) -> (StatusCode, Vec<u8>) {
+    let res = app
+        .send(admin(Request::get(format!("/v2/{repo}/manifests/{digest}/tags"))).empty())
+        .await;
+    (res.status(), body_bytes(res).await)
+}
+
+#[tokio::test]
+async fn manifests_list_the_tags_pointing_at_them() {
+    let app = test_app().await;
+    app.create_repository("app").await;
+    let manifest = app.push_image("app", "latest").await;
+    let res = app
+        .send(
+            admin(Request::put("/v2/app/manifests/v1"))
+                .header("content-type", OCI_MANIFEST)
+                .bytes(manifest.clone()),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let digest = sha256_digest(manifest.as_bytes());
+
+    let (status, body) = manifest_tags(&app, "app", &digest).await;
+    assert_eq!(status, StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_slice(&body).expect("tags are json");
+    assert_eq!(body["tags"], serde_json::json!(["latest", "v1"]));
+
+    let unknown = sha256_digest(b"no such manifest");
+    assert_eq!(
+        manifest_tags(&app, "app", &unknown).await.0,
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn manifests_pushed_by_digest_are_untagged() {
+    let app = test_app().await;
+    app.create_repository("app").await;
+    let manifest = app.push_image("app", "latest").await;
+    let mut untagged: serde_json::Value =
+        serde_json::from_str(&manifest).expect("manifest is json");
+    untagged["annotations"] = serde_json::json!({"pushed": "by digest"});
+    let untagged = untagged.to_string();
+    let digest = sha256_digest(untagged.as_bytes());
+    let res = app
+        .send(
+            admin(Request::put(format!("/v2/app/manifests/{digest}")))
+                .header("content-type", OCI_MANIFEST)
+                .bytes(untagged),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+
+    let (status, body) = manifest_tags(&app, "app", &digest).await;
+    assert_eq!(status, StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_slice(&body).expect("tags are json");
+    assert_eq!(body["tags"], serde_json::json!([]));
+    let res = app
+        .send(admin(Request::get("/v2/app/tags/list")).empty())
+        .await;
+    let body: serde_json::Value =
+        serde_json::from_slice(&body_bytes(res).await).expect("tags are json");
+    assert_eq!(body["tags"], serde_json::json!(["latest"]));
+}
+
+#[tokio::test]
+async fn untagged_manifests_can_be_pulled_by_digest() {
+    let app = test_app().await;
+    app.create_repository("app").await;
+    let manifest = app.push_image("app", "latest").await;
+    let mut untagged: serde_json::Value =
+        serde_json::from_str(&manifest).expect("manifest is json");
+    untagged["annotations"] = serde_json::json!({"pushed": "by digest"});
+    let untagged = untagged.to_string();
+    let digest = sha256_digest(untagged.as_bytes());
+    let res = app
+        .send(
+            admin(Request::put(format!("/v2/app/manifests/{digest}")))
+                .header("content-type", OCI_MANIFEST)
+                .bytes(untagged.clone()),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+
+    let res = app
+        .send(admin(Request::get(format!("/v2/app/manifests/{digest}"))).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(body_bytes(res).await, untagged.as_bytes());
+    let res = app
+        .send(admin(Request::head(format!("/v2/app/manifests/{digest}"))).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(header(&res, "docker-content-digest"), Some(digest.as_str()));
+}
+
+#[tokio::test]
+async fn database_errors_are_not_reported_as_unknown_manifests() {
+    let app = test_app().await;
+    app.create_repository("app").await;
+    let manifest = app.push_image("app", "latest").await;
+    sqlx::query("ALTER TABLE tags RENAME TO tags_gone")
+        .execute(&app.pool)
+        .await
+        .expect("unable to break the tags table");
+
+    let digest = sha256_digest(manifest.as_bytes());
+    assert_eq!(
+        manifest_tags(&app, "app", &digest).await.0,
+        StatusCode::INTERNAL_SERVER_ERROR
+    );
+}
+
+#[tokio::test]
+async fn tag_pages_follow_their_links_without_gaps() {
+    let app = test_app().await;
+    // nested names are percent-encoded in the path, the links must be too
+    app.create_repository("team%2Fapp").await;
+    let manifest = app.push_image("team%2Fapp", "a").await;
+    let tags = ('a'..='z').map(String::from).collect::<Vec<_>>();
+    for tag in &tags[1..] {
+        let res = app
+            .send(
+                admin(Request::put(format!("/v2/team%2Fapp/manifests/{tag}")))
+                    .header("content-type", OCI_MANIFEST)
+                    .bytes(manifest.clone()),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+    }
+
+    let mut seen = Vec::new();
+    let mut next = Some(String::from("/v2/team%2Fapp/tags/list?n=5"));
+    while let Some(url) = next.take() {
+        let res = app.send(admin(Request::get(&url)).empty()).await;
+        assert_eq!(res.status(), StatusCode::OK, "{url} didn't resolve");
+        next = next_page(&res);
+        let body: serde_json::Value =
+            serde_json::from_slice(&body_bytes(res).await).expect("tag list is json");
+        assert_eq!(body["name"], "team/app");
+        let page = body["tags"].as_array().expect("a list of tags");
+        assert!(page.len() <= 5);
+        seen.extend(
+            page.iter()
+                .map(|tag| tag.as_str().unwrap_or_default().to_string()),
+        );
+    }
+    assert_eq!(seen, tags);
+}
+
+/// Fetches `url` as the admin, returning the Link header and the list under `key`
+async fn page(app: &TestApp, url: &str, key: &str) -> (Option<String>, serde_json::Value) {
+    let res = app.send(admin(Request::get(url)).empty()).await;
+    assert_eq!(res.status(), StatusCode::OK, "{url} failed");
+    let link = next_page(&res);
+    let body: serde_json::Value =
+        serde_json::from_slice(&body_bytes(res).await).expect("page is json");
+    (link, body[key].clone())
+}
+
+#[tokio::test]
+async fn empty_pages_are_answered_without_links() {
+    let app = test_app().await;
+    app.create_repository("app").await;
+    for tag in ["a", "b"] {
+        app.push_image("app", tag).await;
+    }
+    let empty = serde_json::json!([]);
+
+    for (url, key) in [
+        ("/v2/app/tags/list?n=0", "tags"),
+        ("/v2/app/tags/list?last=b", "tags"),
+        ("/v2/app/tags/list?n=5&last=zzz", "tags"),
+        ("/v2/_catalog?n=0", "repositories"),
+        ("/v2/_catalog?last=default", "repositories"),
+        ("/v2/_catalog?n=5&last=zzz", "repositories"),
+    ] {
+        assert_eq!(page(&app, url, key).await, (None, empty.clone()), "{url}");
+    }
+    // a full last page has no link either
+    assert_eq!(
+        page(&app, "/v2/app/tags/list?n=2", "tags").await,
+        (None, serde_json::json!(["a", "b"]))
+    );
+    assert_eq!(
+        page(&app, "/v2/app/tags/list?n=1&last=a", "tags").await,
+        (None, serde_json::json!(["b"]))
+    );
+}
+
+async fn promote(app: &TestApp, request: serde_json::Value) -> (StatusCode, serde_json::Value) {
+    let res = app
+        .send(
+            admin(Request::post("/repositories/staging/promote"))
+                .header("content-type", "application/json")
+                .bytes(request.to_string()),
+        )
+        .await;
+    let status = res.status();
+    let body = serde_json::from_slice(&body_bytes(res).await).expect("promotions are json");
+    (status, body)
+}
+
+async fn pull_tag(app: &TestApp, repo: &str, tag: &str) -> (StatusCode, Vec<u8>) {
+    let res = app
+        .send(admin(Request::get(format!("/v2/{repo}/manifests/{tag}"))).empty())
+        .await;
+    (res.status(), body_bytes(res).await)
+}
+
+#[tokio::test]
+async fn several_tags_are_promoted_together_or_not_at_all() {
+    let app = test_app().await;
+    app.create_repository("staging").await;
+    app.create_repository("prod").await;
+    let v1 = app.push_image("staging", "v1").await;
+    let v2 = app.push_image("staging", "v2").await;
+
+    let (status, body) = promote(
+        &app,
+        serde_json::json!({"to_repo": "prod", "tags": ["v1", "v2"]}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["promoted"], true);
+    for (tag, manifest) in [("v1", &v1), ("v2", &v2)] {
+        assert_eq!(
+            pull_tag(&app, "prod", tag).await,
+            (StatusCode::OK, manifest.as_bytes().to_vec())
+        );
+        let config: serde_json::Value = serde_json::from_str(manifest).expect("manifest is json");
+        let config = config["config"]["digest"]
+            .as_str()
+            .expect("a config digest");
+        assert_eq!(
+            app.pull_blob(&admin_auth(), "prod", config).await,
+            StatusCode::OK
+        );
+    }
+
+    // one failing tag rolls back the rest
+    let v3 = app.push_image("staging", "v3").await;
+    let res = app
+        .send(
+            admin(Request::put("/v2/staging/manifests/v1"))
+                .header("content-type", OCI_MANIFEST)
+                .bytes(v3.clone()),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let (status, body) = promote(
+        &app,
+        serde_json::json!({"to_repo": "prod", "tags": ["v3", "v1", "missing"]}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(body["promoted"], false);
+    let errors = body["tags"]
+        .as_array()
+        .expect("a result per tag")
+        .iter()
+        .map(|tag| !tag["error"].is_null())
+        .collect::<Vec<_>>();
+    assert_eq!(errors, [false, true, true]);
+    assert_eq!(pull_tag(&app, "prod", "v3").await.0, StatusCode::NOT_FOUND);
+    assert_eq!(
+        pull_tag(&app, "prod", "v1").await,
+        (StatusCode::OK, v1.as_bytes().to_vec())
+    );
+
+    let (status, _) = promote(
+        &app,
+        serde_json::json!({"to_repo": "prod", "tags": ["v3", "v1"], "overwrite": true}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        pull_tag(&app, "prod", "v3").await,
+        (StatusCode::OK, v3.as_bytes().to_vec())
+    );
+    assert_eq!(pull_tag(&app, "prod", "v1").await.1, v3.as_bytes());
+}
@@ -0,0 +1,254 @@
+mod common;
+
+use axum::http::{Request, StatusCode};
+use common::{
+    admin, admin_auth, basic_auth, body_bytes, test_app, test_app_with, RequestExt, TestApp,
+};
+use floundr::{
+    auth::{Auth, Claims},
+    config::Config,
+    Action,
+};
+
+/// Signs the admin in with `offline_token=true`, returning the refresh token
+async fn offline_token(app: &TestApp) -> String {
+    let res = app
+        .send(
+            admin(Request::get(
+                "/auth/token?service=floundr&offline_token=true&scope=repository:default:pull",
+            ))
+            .empty(),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: serde_json::Value =
+        serde_json::from_slice(&body_bytes(res).await).expect("token response is json");
+    body["refresh_token"]
+        .as_str()
+        .expect("offline tokens carry a refresh token")
+        .to_string()
+}
+
+/// Exchanges a refresh token, returning the status and the response body
+async fn exchange(app: &TestApp, refresh_token: &str) -> (StatusCode, serde_json::Value) {
+    let res = app
+        .send(
+            Request::post("/auth/token")
+                .header("content-type", "application/x-www-form-urlencoded")
+                .bytes(format!(
+                    "grant_type=refresh_token&refresh_token={refresh_token}"
+                )),
+        )
+        .await;
+    let status = res.status();
+    let body = body_bytes(res).await;
+    (status, serde_json::from_slice(&body).unwrap_or_default())
+}
+
+#[tokio::test]
+async fn refresh_tokens_are_exchanged_once() {
+    let app = test_app().await;
+    let refresh_token = offline_token(&app).await;
+    let stored: Vec<String> = sqlx::query_scalar("SELECT token_hash FROM refresh_tokens")
+        .fetch_all(&app.pool)
+        .await
+        .expect("unable to read refresh tokens");
+    assert_eq!(stored.len(), 1);
+    assert_ne!(stored[0], refresh_token, "refresh tokens are stored hashed");
+
+    let (status, body) = exchange(&app, &refresh_token).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body["token"].is_string());
+    let rotated = body["refresh_token"]
+        .as_str()
+        .expect("an exchange hands out a new refresh token")
+        .to_string();
+    assert_ne!(rotated, refresh_token);
+
+    assert_eq!(
+        exchange(&app, &refresh_token).await.0,
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(exchange(&app, &rotated).await.0, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn unknown_and_expired_refresh_tokens_are_refused() {
+    let app = test_app().await;
+    assert_eq!(
+        exchange(&app, "not-a-refresh-token").await.0,
+        StatusCode::UNAUTHORIZED
+    );
+
+    let refresh_token = offline_token(&app).await;
+    sqlx::query("UPDATE refresh_tokens SET expires_at = datetime('now', '-1 second')")
+        .execute(&app.pool)
+        .await
+        .expect("unable to expire the refresh token");
+    assert_eq!(
+        exchange(&app, &refresh_token).await.0,
+        StatusCode::UNAUTHORIZED
+    );
+}
+
+/// Requests a token for `scope` with the given credentials, returning the token
+/// and the access it was granted
+async fn token_for(app: &TestApp, authorization: &str, scope: &str) -> (String, serde_json::Value) {
+    let res = app
+        .send(
+            Request::get(format!("/auth/token?service=floundr&scope={scope}"))
+                .header("authorization", authorization)
+                .empty(),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: serde_json::Value =
+        serde_json::from_slice(&body_bytes(res).await).expect("token response is json");
+    let token = body["token"].as_str().expect("a token").to_string();
+    (token, body["access"].clone())
+}
+
+#[tokio::test]
+async fn tokens_can_be_narrowed_to_part_of_their_scope() {
+    let app = test_app().await;
+    app.create_repository("app").await;
+    app.create_user("user@example.com", "password1").await;
+    app.grant("user@example.com", "app", Action::Push).await;
+
+    let (token, access) = token_for(
+        &app,
+        &basic_auth("user@example.com", "password1"),
+        "repository:app:pull,push",
+    )
+    .await;
+    let mut actions = access[0]["actions"]
+        .as_array()
+        .expect("granted actions")
+        .clone();
+    actions.sort_by_key(|action| action.to_string());
+    assert_eq!(actions, ["pull", "push"]);
+
+    let (narrow, access) = token_for(&app, &format!("Bearer {token}"), "repository:app:pull").await;
+    assert_eq!(
+        access,
+        serde_json::json!([{"type": "repository", "name": "app", "actions": ["pull"]}])
+    );
+    let bearer = format!("Bearer {narrow}");
+    let res = app
+        .send(
+            Request::get("/v2/app/tags/list")
+                .header("authorization", &bearer)
+                .empty(),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let (status, _) = app.push_blob(&bearer, "app", b"not in scope").await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn narrowing_never_widens_a_token() {
+    let app = test_app().await;
+    app.create_repository("app").await;
+    app.create_repository("mine").await;
+    app.create_user("user@example.com", "password1").await;
+    app.grant("user@example.com", "app", Action::Pull).await;
+    app.grant("user@example.com", "mine", Action::Delete).await;
+    let user = basic_auth("user@example.com", "password1");
+
+    // nothing requested is held, so nothing is granted
+    let (empty, access) = token_for(&app, &user, "repository:nothere:pull").await;
+    assert_eq!(access, serde_json::json!([]));
+    // every grant left is a delete grant
+    let (deleter, _) = token_for(&app, &user, "repository:mine:delete").await;
+
+    for token in [empty, deleter] {
+        let bearer = format!("Bearer {token}");
+        let res = app
+            .send(
+                Request::get("/v2/app/tags/list")
+                    .header("authorization", &bearer)
+                    .empty(),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        for request in [
+            Request::delete("/users/floundr_admin"),
+            Request::delete("/repositories/app"),
+        ] {
+            let res = app
+                .send(request.header("authorization", &bearer).empty())
+                .await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        }
+    }
+    let res = app.send(admin(Request::get("/users")).empty()).await;
+    let users: serde_json::Value =
+        serde_json::from_slice(&body_bytes(res).await).expect("user list is json");
+    assert_eq!(users.as_array().map(Vec::len), Some(2));
+}
+
+/// Signs the admin in for a token, returning it as a bearer credential
+async fn admin_bearer(app: &TestApp) -> String {
+    let (token, _) = token_for(app, &admin_auth(), "repository:app:pull").await;
+    format!("Bearer {token}")
+}
+
+#[tokio::test]
+async fn tokens_expire_after_their_ttl() {
+    let app = test_app().await;
+    app.create_repository("app").await;
+    let bearer = admin_bearer(&app).await;
+    let res = app
+        .send(
+            Request::get("/v2/app/tags/list")
+                .header("authorization", &bearer)
+                .empty(),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    // a zero ttl makes tokens expire the moment they are issued
+    let app = test_app_with(Config {
+        token_ttl: 0,
+        ..Default::default()
+    })
+    .await;
+    app.create_repository("app").await;
+    let bearer = admin_bearer(&app).await;
+    let res = app
+        .send(
+            Request::get("/v2/app/tags/list")
+                .header("authorization", &bearer)
+                .empty(),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[test]
+fn multi_action_scopes_round_trip_through_claims() {
+    let exp = chrono::Utc::now().timestamp() + 3600;
+    let claims: Claims = serde_json::from_value(serde_json::json!({
+        "sub": "user",
+        "exp": exp,
+        "is_admin": false,
+        "scopes": ["repository:app:pull,push,delete", "repository:other:push,pull"],
+    }))
+    .expect("multi-action scopes deserialize");
+    let serialized = serde_json::to_value(&claims).expect("claims serialize");
+    let mut scopes = serialized["scopes"]
+        .as_array()
+        .expect("scopes serialize as a list")
+        .clone();
+    scopes.sort_by_key(|scope| scope.to_string());
+    assert_eq!(scopes, ["repository:app:delete", "repository:other:push"]);
+
+    let claims: Claims = serde_json::from_value(serialized).expect("claims deserialize again");
+    let auth = Auth {
+        claims: Some(claims),
+    };
+    assert!(auth.can("app", Action::Delete));
+    assert!(auth.can("other", Action::Push));
+    assert!(!auth.can("other", Action::Delete));
+}
@@ -0,0 +1,38 @@
+mod common;
+
+use axum::http::{Request, StatusCode};
+use common::{body_bytes, header, test_app, test_app_with, RequestExt};
+use floundr::config::Config;
+
+#[tokio::test]
+async fn the_dashboard_is_served_when_enabled() {
+    let app = test_app_with(Config {
+        enable_ui: true,
+        ..Default::default()
+    })
+    .await;
+    // the assets are public, the dashboard logs in itself
+    let res = app.send(Request::get("/ui/").empty()).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(header(&res, "content-type").is_some_and(|t| t.starts_with("text/html")));
+    let page = String::from_utf8(body_bytes(res).await).expect("the page is text");
+    assert!(page.contains("app.js"));
+
+    let res = app.send(Request::get("/ui/app.js").empty()).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(header(&res, "content-type").is_some_and(|t| t.contains("javascript")));
+    let res = app.send(Request::get("/").empty()).await;
+    assert_eq!(res.status(), StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(header(&res, "location"), Some("/ui/"));
+    let res = app.send(Request::get("/ui/missing.js").empty()).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn the_dashboard_is_off_by_default() {
+    let app = test_app().await;
+    for path in ["/", "/ui/", "/ui/app.js"] {
+        let res = app.send(Request::get(path).empty()).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND, "{path}");
+    }
+}
@@ -0,0 +1,398 @@
+mod common;
+
+use axum::http::{Request, StatusCode};
+use common::{
+    admin, admin_auth, body_bytes, header, sha256_digest, test_app, test_app_with, RequestExt,
+    TestApp, TEST_SECRET,
+};
+use floundr::{config::Config, util::sign_upload};
+use sha2::{Digest, Sha512};
+
+/// Asks for a signed upload url for `repo`, returning its location
+async fn authorize_upload(app: &TestApp, repo: &str) -> String {
+    let res = app
+        .send(admin(Request::post(format!("/v2/{repo}/blobs/uploads/authorize"))).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::ACCEPTED);
+    header(&res, "location")
+        .expect("signed upload has a location")
+        .to_string()
+}
+
+/// Uploads `blob` to a signed url in one PUT, without credentials
+async fn put_signed(app: &TestApp, location: &str, blob: &[u8]) -> StatusCode {
+    let digest = sha256_digest(blob);
+    app.send(
+        Request::put(format!("{location}&digest={digest}"))
+            .header("content-type", "application/octet-stream")
+            .header("content-length", blob.len())
+            .bytes(blob.to_vec()),
+    )
+    .await
+    .status()
+}
+
+#[tokio::test]
+async fn signed_upload_urls_work_once_without_credentials() {
+    let app = test_app().await;
+    app.create_repository("app").await;
+    let location = authorize_upload(&app, "app").await;
+
+    let blob = b"a layer pushed through a signed url";
+    assert_eq!(put_signed(&app, &location, blob).await, StatusCode::CREATED);
+    assert_eq!(
+        app.pull_blob(&admin_auth(), "app", &sha256_digest(blob))
+            .await,
+        StatusCode::OK
+    );
+    // the session is closed, so the url can't be replayed
+    assert_eq!(
+        put_signed(&app, &location, b"another layer").await,
+        StatusCode::UNAUTHORIZED
+    );
+}
+
+#[tokio::test]
+async fn signed_upload_urls_work_for_nested_repositories() {
+    let app = test_app().await;
+    app.create_repository("team%2Fapp").await;
+    let location = authorize_upload(&app, "team%2Fapp").await;
+    assert!(location.starts_with("/v2/team%2Fapp/"), "{location}");
+
+    let blob = b"a layer pushed to a nested repository";
+    assert_eq!(put_signed(&app, &location, blob).await, StatusCode::CREATED);
+    assert_eq!(
+        app.pull_blob(&admin_auth(), "team%2Fapp", &sha256_digest(blob))
+            .await,
+        StatusCode::OK
+    );
+
+    // unqualified names are signed for the repository they resolve to
+    let app = test_app_with(Config {
+        default_namespace: Some(String::from("library")),
+        ..Default::default()
+    })
+    .await;
+    app.create_repository("library%2Fubuntu").await;
+    let location = authorize_upload(&app, "ubuntu").await;
+    assert_eq!(put_signed(&app, &location, blob).await, StatusCode::CREATED);
+}
+
+#[tokio::test]
+async fn tampered_and_expired_upload_urls_are_refused() {
+    let app = test_app().await;
+    app.create_repository("app").await;
+    app.create_repository("other").await;
+    let location = authorize_upload(&app, "app").await;
+    let (path, _) = location.split_once('?').expect("signed url has a query");
+    let session_id = path.rsplit('/').next().expect("url names the session");
+    let blob = b"a layer pushed through a signed url";
+
+    let expires = chrono::Utc::now().timestamp() - 60;
+    let signature = sign_upload(TEST_SECRET, "app", session_id, expires);
+    let expired = format!("{path}?expires={expires}&signature={signature}");
+    assert_eq!(
+        put_signed(&app, &expired, blob).await,
+        StatusCode::UNAUTHORIZED
+    );
+
+    let expires = chrono::Utc::now().timestamp() + 3600;
+    let forged = sign_upload("not-the-secret", "app", session_id, expires);
+    let tampered = format!("{path}?expires={expires}&signature={forged}");
+    assert_eq!(
+        put_signed(&app, &tampered, blob).await,
+        StatusCode::UNAUTHORIZED
+    );
+
+    // the signature is bound to the repository it was issued for
+    let moved = location.replacen("/v2/app/", "/v2/other/", 1);
+    assert_eq!(
+        put_signed(&app, &moved, blob).await,
+        StatusCode::UNAUTHORIZED
+    );
+
+    // none of that spent the genuine url
+    assert_eq!(put_signed(&app, &location, blob).await, StatusCode::CREATED);
+}
+
+/// Opens an upload session for `repo`, returning the response status and location
+async fn open_session(app: &TestApp, repo: &str) -> (StatusCode, Option<String>) {
+    let res = app
+        .send(admin(Request::post(format!("/v2/{repo}/blobs/uploads/"))).empty())
+        .await;
+    (res.status(), header(&res, "location").map(String::from))
+}
+
+#[tokio::test]
+async fn finished_upload_sessions_free_their_slot() {
+    let app = test_app_with(Config {
+        max_concurrent_uploads: Some(2),
+        ..Default::default()
+    })
+    .await;
+    app.create_repository("app").await;
+    app.create_repository("other").await;
+    let (status, first) = open_session(&app, "app").await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    let (status, second) = open_session(&app, "app").await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    assert_eq!(
+        open_session(&app, "app").await.0,
+        StatusCode::TOO_MANY_REQUESTS
+    );
+    // the limit is per repository
+    assert_eq!(open_session(&app, "other").await.0, StatusCode::ACCEPTED);
+
+    let blob = b"a layer finishing its upload";
+    let first = first.expect("upload session has a location");
+    let separator = if first.contains('?') { '&' } else { '?' };
+    let res = app
+        .send(
+            admin(Request::put(format!(
+                "{first}{separator}digest={}",
+                sha256_digest(blob)
+            )))
+            .header("content-type", "application/octet-stream")
+            .header("content-length", blob.len())
+            .bytes(blob.to_vec()),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    assert_eq!(open_session(&app, "app").await.0, StatusCode::ACCEPTED);
+    assert_eq!(
+        open_session(&app, "app").await.0,
+        StatusCode::TOO_MANY_REQUESTS
+    );
+
+    // as does cancelling one
+    let second = second.expect("upload session has a location");
+    let res = app.send(admin(Request::delete(&second)).empty()).await;
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    assert_eq!(open_session(&app, "app").await.0, StatusCode::ACCEPTED);
+
+    // and letting them expire
+    sqlx::query("UPDATE uploads SET created_at = datetime('now', '-2 hours')")
+        .execute(&app.pool)
+        .await
+        .expect("unable to age the upload sessions");
+    assert_eq!(open_session(&app, "app").await.0, StatusCode::ACCEPTED);
+}
+
+#[tokio::test]
+async fn monolithic_uploads_are_verified_with_sha512() {
+    let app = test_app().await;
+    app.create_repository("app").await;
+    let blob = b"a blob addressed by sha512";
+    let digest = format!("sha512:{}", hex::encode(Sha512::digest(blob)));
+
+    let wrong = format!("sha512:{}", hex::encode(Sha512::digest(b"something else")));
+    let res = app
+        .send(
+            admin(Request::post(format!(
+                "/v2/app/blobs/uploads/?digest={wrong}"
+            )))
+            .bytes(blob.to_vec()),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    let res = app
+        .send(
+            admin(Request::post(format!(
+                "/v2/app/blobs/uploads/?digest={digest}"
+            )))
+            .bytes(blob.to_vec()),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let res = app
+        .send(admin(Request::get(format!("/v2/app/blobs/{digest}"))).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(body_bytes(res).await, blob);
+}
+
+/// Sends one chunk of an upload session, returning the response status
+async fn patch_chunk(app: &TestApp, location: &str, start: usize, chunk: &[u8]) -> StatusCode {
+    app.send(
+        admin(Request::patch(location))
+            .header("content-type", "application/octet-stream")
+            .header("content-length", chunk.len())
+            .header(
+                "content-range",
+                format!("{}-{}", start, start + chunk.len() - 1),
+            )
+            .bytes(chunk.to_vec()),
+    )
+    .await
+    .status()
+}
+
+#[tokio::test]
+async fn tampered_chunks_invalidate_the_session() {
+    let app = test_app().await;
+    app.create_repository("app").await;
+    let (_, location) = open_session(&app, "app").await;
+    let location = location.expect("upload session has a location");
+    assert_eq!(
+        patch_chunk(&app, &location, 0, b"first chunk").await,
+        StatusCode::ACCEPTED
+    );
+    let status = || app.send(admin(Request::get(&location)).empty());
+    assert_eq!(status().await.status(), StatusCode::NO_CONTENT);
+
+    // same size, different bytes, so only the digest check can notice
+    let chunk: String =
+        sqlx::query_scalar("SELECT file_path FROM blobs WHERE upload_session_id IS NOT NULL")
+            .fetch_one(&app.pool)
+            .await
+            .expect("the chunk is recorded");
+    std::fs::write(chunk, b"FIRST CHUNK").expect("unable to tamper with the chunk");
+
+    assert_eq!(status().await.status(), StatusCode::GONE);
+    assert_eq!(
+        patch_chunk(&app, &location, 11, b"second chunk").await,
+        StatusCode::GONE
+    );
+    let separator = if location.contains('?') { '&' } else { '?' };
+    let res = app
+        .send(
+            admin(Request::put(format!(
+                "{location}{separator}digest={}",
+                sha256_digest(b"first chunk")
+            )))
+            .empty(),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::GONE);
+}
+
+#[tokio::test]
+async fn uploads_are_found_by_digest() {
+    let app = test_app().await;
+    app.create_repository("app").await;
+    let lookup = |digest: String| {
+        app.send(
+            admin(Request::get(format!(
+                "/v2/app/blobs/uploads/?digest={digest}"
+            )))
+            .empty(),
+        )
+    };
+    let (_, stored) = app.push_blob(&admin_auth(), "app", b"a stored layer").await;
+    let res = lookup(stored.clone()).await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let location = format!("/v2/app/blobs/{stored}");
+    assert_eq!(header(&res, "location"), Some(location.as_str()));
+
+    // a session declares the digest it is uploading when it's opened
+    let blob = b"a layer halfway through its upload";
+    let digest = sha256_digest(blob);
+    let res = app
+        .send(
+            admin(Request::post("/v2/app/blobs/uploads/"))
+                .header("docker-content-digest", &digest)
+                .empty(),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::ACCEPTED);
+    let session = header(&res, "location")
+        .expect("upload session has a location")
+        .to_string();
+    assert_eq!(
+        patch_chunk(&app, &session, 0, &blob[..10]).await,
+        StatusCode::ACCEPTED
+    );
+    let res = lookup(digest.clone()).await;
+    assert_eq!(res.status(), StatusCode::ACCEPTED);
+    let found = header(&res, "location").expect("the session's location");
+    assert!(session.starts_with(found), "{found} is not {session}");
+    assert_eq!(header(&res, "range"), Some("0-9"));
+
+    assert_eq!(
+        lookup(sha256_digest(b"never uploaded")).await.status(),
+        StatusCode::NOT_FOUND
+    );
+    assert_eq!(
+        lookup(String::from("not-a-digest")).await.status(),
+        StatusCode::BAD_REQUEST
+    );
+}
+
+/// The directory holding the chunks of the session at `location`
+fn session_dir(app: &TestApp, repo: &str, location: &str) -> std::path::PathBuf {
+    let session = location
+        .split('?')
+        .next()
+        .and_then(|path| path.rsplit('/').next())
+        .expect("a session id");
+    app.dir
+        .path()
+        .join("storage")
+        .join(repo)
+        .join("blobs")
+        .join(session)
+}
+
+#[tokio::test]
+async fn chunks_that_cannot_be_recorded_are_removed() {
+    let app = test_app().await;
+    app.create_repository("app").await;
+    let (_, location) = open_session(&app, "app").await;
+    let location = location.expect("upload session has a location");
+    sqlx::query(
+        "CREATE TRIGGER no_chunks BEFORE INSERT ON blobs WHEN NEW.upload_session_id IS NOT NULL \
+         BEGIN SELECT RAISE(ABORT, 'no chunks'); END",
+    )
+    .execute(&app.pool)
+    .await
+    .expect("unable to refuse chunk rows");
+
+    let status = patch_chunk(&app, &location, 0, b"an unrecorded chunk").await;
+    assert!(!status.is_success(), "{status}");
+    let chunks = std::fs::read_dir(session_dir(&app, "app", &location))
+        .expect("the session is still open")
+        .count();
+    assert_eq!(chunks, 0);
+}
+
+#[tokio::test]
+async fn chunked_uploads_are_combined_in_order() {
+    let app = test_app().await;
+    app.create_repository("app").await;
+    let (_, location) = open_session(&app, "app").await;
+    let location = location.expect("upload session has a location");
+    let blob = b"a layer uploaded in three chunks";
+    let digest = sha256_digest(blob);
+    for (start, chunk) in [(0, &blob[..10]), (10, &blob[10..20]), (20, &blob[20..])] {
+        assert_eq!(
+            patch_chunk(&app, &location, start, chunk).await,
+            StatusCode::ACCEPTED
+        );
+    }
+    let separator = if location.contains('?') { '&' } else { '?' };
+    let res = app
+        .send(
+            admin(Request::put(format!(
+                "{location}{separator}digest={digest}"
+            )))
+            .empty(),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+
+    let res = app
+        .send(admin(Request::get(format!("/v2/app/blobs/{digest}"))).empty())
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(body_bytes(res).await, blob);
+
+    // the chunks and their rows gave way to the blob's
+    assert!(!session_dir(&app, "app", &location).exists());
+    let rows: Vec<(String, Option<String>)> =
+        sqlx::query_as("SELECT digest, upload_session_id FROM blobs")
+            .fetch_all(&app.pool)
+            .await
+            .expect("unable to list blobs");
+    assert_eq!(rows, [(digest, None)]);
+}
@@ -0,0 +1,96 @@
+mod common;
+
+use axum::http::{Request, StatusCode};
+use common::{admin_auth, basic_auth, body_bytes, test_app, RequestExt, TestApp};
+
+async fn post_user(
+    app: &TestApp,
+    authorization: &str,
+    email: &str,
+    password: &str,
+) -> (StatusCode, Vec<u8>) {
+    let res = app
+        .send(
+            Request::post("/users")
+                .header("authorization", authorization)
+                .header("content-type", "application/json")
+                .bytes(
+                    serde_json::json!({"email": email, "password": password, "is_admin": false})
+                        .to_string(),
+                ),
+        )
+        .await;
+    (res.status(), body_bytes(res).await)
+}
+
+#[tokio::test]
+async fn only_admins_create_users() {
+    let app = test_app().await;
+    app.create_user("user@example.com", "password1").await;
+
+    let user = basic_auth("user@example.com", "password1");
+    let (status, _) = post_user(&app, &user, "new@example.com", "password123").await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let admin = admin_auth();
+    let (status, body) = post_user(&app, &admin, "new@example.com", "password123").await;
+    assert_eq!(status, StatusCode::CREATED);
+    let body: serde_json::Value = serde_json::from_slice(&body).expect("created user is json");
+    let id = body["id"].as_str().expect("the new user's id");
+    let stored: String = sqlx::query_scalar("SELECT id FROM users WHERE email = ?")
+        .bind("new@example.com")
+        .fetch_one(&app.pool)
+        .await
+        .expect("the user was stored");
+    assert_eq!(id, stored);
+
+    // the new account can sign in
+    let res = app
+        .send(
+            Request::get("/v2/")
+                .header(
+                    "authorization",
+                    basic_auth("new@example.com", "password123"),
+                )
+                .empty(),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let (status, _) = post_user(&app, &admin, "new@example.com", "password123").await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    let (status, _) = post_user(&app, &admin, "weak@example.com", "short").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn namespaced_admins_cannot_manage_users() {
+    let app = test_app().await;
+    let team_admin = app.create_namespaced_user("team@example.com", true).await;
+    app.create_user("user@example.com", "password1").await;
+
+    let (status, _) = post_user(&app, &team_admin, "new@example.com", "password123").await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let res = app
+        .send(
+            Request::delete("/users/user@example.com")
+                .header("authorization", &team_admin)
+                .empty(),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let res = app
+        .send(
+            Request::get("/users")
+                .header("authorization", &team_admin)
+                .empty(),
+        )
+        .await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let remaining: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users WHERE email = ?")
+        .bind("user@example.com")
+        .fetch_one(&app.pool)
+        .await
+        .expect("unable to count users");
+    assert_eq!(remaining, 1);
+}
@@ -1,8 +1,9 @@
 use crate::{
-    events::AppEventHandler,
+    events::{AppEvent, AppEventHandler},
     requests::{
-        create_new_api_key, create_new_user, create_repository, delete_repository, delete_user,
-        get_all_users, get_manifests, get_repositories, get_tokens,
+        check_server_status, create_new_api_key, create_new_user, create_repository,
+        delete_api_key, delete_repository, delete_user, get_all_users, get_manifests,
+        get_repositories, get_tokens,
     },
     screens::{self, InputType, ScreenType},
     ConfigFile, Theme,
@@ -12,7 +13,7 @@ use lazy_static::lazy_static;
 use ratatui::{
     backend::CrosstermBackend,
     crossterm::{
-        event::{self, Event, KeyCode, KeyEvent},
+        event::{Event, KeyCode, KeyEvent},
         terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
         ExecutableCommand,
     },
@@ -26,6 +27,7 @@ use shared::{AuthClient, ImageManifest, RegisterUserRequest, Repo};
 use std::{
     io::{self, stdout},
     sync::{Arc, OnceLock, RwLock},
+    time::{Duration, Instant},
 };
 use tracing::{error, info};
 use tui_input::{backend::crossterm::EventHandler, Input};
@@ -45,6 +47,7 @@ pub struct App {
     pub mode: Mode,
     pub input: Input,
     pub buffer: Vec<String>,
+    pub last_status_check: Instant,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -53,6 +56,19 @@ pub enum Mode {
     Insert,
 }
 
+/// Result of the last health check against the server, shown in the header
+#[derive(Debug, Default, Clone)]
+pub struct ServerStatus {
+    pub reachable: bool,
+    pub api_version: Option<String>,
+    pub version: Option<String>,
+    /// the token expired and logging in again failed
+    pub session_expired: bool,
+}
+
+/// How often the header re-checks whether the server is reachable
+pub const STATUS_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
 #[derive(Debug, Default, Deserialize, Clone)]
 pub struct RepositoryList {
     pub repositories: Vec<Repo>,
@@ -77,8 +93,16 @@ lazy_static! {
     pub static ref MANIFESTS: Arc<DashMap<String, ImageManifest>> = Arc::new(DashMap::new());
     pub static ref USERS: Arc<RwLock<Vec<UserResponse>>> = Arc::new(RwLock::new(Vec::new()));
     pub static ref CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
-    pub static ref HEADERS: OnceLock<HeaderMap> = OnceLock::new();
+    pub static ref HEADERS: Arc<RwLock<HeaderMap>> = Arc::new(RwLock::new(HeaderMap::new()));
     pub static ref ACTIVE_KEYS: Arc<RwLock<Vec<AuthClient>>> = Arc::new(RwLock::new(Vec::new()));
+    pub static ref SERVER_STATUS: Arc<RwLock<ServerStatus>> =
+        Arc::new(RwLock::new(ServerStatus::default()));
+}
+
+/// looks up an active key by the number it is listed under, starting from 1
+pub fn active_key(idx: &str) -> Option<AuthClient> {
+    let idx = idx.trim().parse::<usize>().ok()?.checked_sub(1)?;
+    ACTIVE_KEYS.read().unwrap().get(idx).cloned()
 }
 
 pub static DEFAULT_SCREENS: &[screens::ScreenType] = &[
@@ -96,12 +120,17 @@ impl Default for App {
             .expect("config file not properly loaded")
             .clone();
         info!("Using Floundr URL: {}", url);
+        let current_screen = config
+            .last_screen
+            .as_ref()
+            .and_then(|screen| DEFAULT_SCREENS.iter().position(|s| s == screen))
+            .unwrap_or(0);
         Self {
             url,
             config,
             running: true,
             cursor: 0,
-            current_screen: 0,
+            current_screen,
             current_action: None,
             screen_stack: DEFAULT_SCREENS.to_vec(),
             state: ratatui::widgets::ListState::default(),
@@ -109,6 +138,7 @@ impl Default for App {
             mode: Mode::Normal,
             input: Input::default(),
             buffer: Vec::new(),
+            last_status_check: Instant::now(),
         }
     }
 }
@@ -189,9 +219,10 @@ impl App {
             }
             InputType::CreateApiKey => {
                 let url = self.url.clone();
+                let scopes = self.buffer.pop().unwrap_or_default();
                 let name = self.buffer.pop().unwrap_or_default();
                 tokio::spawn(async move {
-                    let _ = create_new_api_key(url, name).await;
+                    let _ = create_new_api_key(url, name, &scopes).await;
                 });
             }
             InputType::DeleteUser => {
@@ -201,6 +232,17 @@ impl App {
                     let _ = delete_user(url, user).await;
                 });
             }
+            InputType::DeleteApiKey => {
+                let key = self.buffer.pop().and_then(|idx| active_key(&idx));
+                let Some(AuthClient { client_id, .. }) = key else {
+                    error!("No key found at the given index");
+                    return;
+                };
+                let url = self.url.clone();
+                tokio::spawn(async move {
+                    let _ = delete_api_key(url, client_id).await;
+                });
+            }
             InputType::DeleteRepo => {
                 let repo = self.buffer.pop().unwrap_or_default();
                 let url = self.url.clone();
@@ -253,9 +295,43 @@ impl App {
         Ok(())
     }
 
+    /// Called on every `AppEvent::Tick`, refreshes the server status in
+    /// the background once `STATUS_CHECK_INTERVAL` has passed
+    pub fn tick(&mut self) {
+        if self.last_status_check.elapsed() < STATUS_CHECK_INTERVAL {
+            return;
+        }
+        self.last_status_check = Instant::now();
+        let url = self.url.clone();
+        tokio::spawn(async move {
+            check_server_status(&url).await;
+        });
+    }
+
     fn quit(&mut self) {
         self.running = false;
     }
+
+    /// Restores the cursor saved in the config, clamped to what the current
+    /// screen holds now (e.g. after repositories were deleted). Call once the
+    /// screen's data has been fetched.
+    pub fn restore_cursor(&mut self) {
+        let len = self.screen_stack[self.current_screen].get_cursor_len();
+        self.cursor = match self.config.last_cursor {
+            Some(cursor) if len > 0 => cursor.min(len - 1),
+            _ => 0,
+        };
+        self.scrollbar = ratatui::widgets::ScrollbarState::new(len).position(self.cursor);
+    }
+
+    /// Saves the active screen and cursor to the config file
+    pub fn save_position(&mut self) {
+        self.config.last_screen = Some(self.screen_stack[self.current_screen].clone());
+        self.config.last_cursor = Some(self.cursor);
+        if let Err(err) = self.config.save() {
+            error!("unable to save tui config: {:?}", err);
+        }
+    }
 }
 
 pub fn get_items(curr: usize, selected: Option<usize>) -> Vec<ratatui::widgets::ListItem<'static>> {
@@ -331,11 +407,13 @@ impl Tui {
         Ok(())
     }
 
-    pub fn handle_events(&mut self) -> io::Result<bool> {
-        if let Event::Key(key) = event::read()? {
-            let _ = self.handle_key(key).is_ok();
+    pub async fn handle_events(&mut self) -> AppResult<()> {
+        match self.events.next().await {
+            Some(AppEvent::Key(key)) => self.handle_key(key)?,
+            Some(AppEvent::Tick) => self.app.tick(),
+            _ => {}
         }
-        Ok(false)
+        Ok(())
     }
 
     pub fn draw(&mut self) -> AppResult<()> {
@@ -349,6 +427,7 @@ impl Tui {
 
     pub async fn fetch_data(&mut self) -> AppResult<()> {
         let url = self.app.url.clone();
+        check_server_status(&url).await;
         if let Err(err) = get_repositories(&url).await {
             error!("Unable to fetch repos: {:?}", err);
         }
@@ -375,15 +454,14 @@ impl Tui {
                     'l' => self.app.shuffle_screen_right(),
                     'i' => self.app.insert_mode(),
                     'd' => match self.app.screen_stack[self.app.current_screen] {
-                        ScreenType::Users => {
-                            if self.app.state.selected().is_some() {
-                                self.app.set_action(InputType::DeleteUser);
-                            }
+                        ScreenType::Users if self.app.state.selected() == Some(2) => {
+                            self.app.set_action(InputType::DeleteApiKey);
+                        }
+                        ScreenType::Users if self.app.state.selected().is_some() => {
+                            self.app.set_action(InputType::DeleteUser);
                         }
-                        ScreenType::Repos => {
-                            if self.app.state.selected().is_some() {
-                                self.app.set_action(InputType::DeleteRepo);
-                            }
+                        ScreenType::Repos if self.app.state.selected().is_some() => {
+                            self.app.set_action(InputType::DeleteRepo);
                         }
                         _ => {}
                     },
@@ -423,6 +501,7 @@ impl Tui {
     }
 
     pub fn exit(&mut self) -> AppResult<()> {
+        self.app.save_position();
         let _ = disable_raw_mode();
         self.terminal.show_cursor()?;
         stdout().execute(LeaveAlternateScreen)?;
@@ -11,6 +11,11 @@ pub struct ConfigFile {
     pub email: Option<String>,
     pub password: Option<String>,
     pub theme: Option<Theme>,
+    /// screen and cursor the TUI was left on, restored on the next launch
+    #[serde(default)]
+    pub last_screen: Option<screens::ScreenType>,
+    #[serde(default)]
+    pub last_cursor: Option<usize>,
 }
 
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
@@ -40,22 +45,29 @@ impl Default for ConfigFile {
             password: Some(String::from("admin")),
             theme: Some(Theme::default()),
             secret: None,
+            last_screen: None,
+            last_cursor: None,
         }
     }
 }
 
 impl ConfigFile {
+    fn config_dir() -> std::path::PathBuf {
+        std::path::PathBuf::from(
+            std::env::var("FLOUNDR_HOME").unwrap_or(
+                dirs::data_local_dir()
+                    .expect("unable to find XDG_DATA_HOME, please set FLOUNDR_HOME env variable")
+                    .join("floundr")
+                    .to_string_lossy()
+                    .to_string(),
+            ),
+        )
+    }
+
     pub fn load() -> Self {
-        let dir = std::env::var("FLOUNDR_HOME").unwrap_or(
-            dirs::data_local_dir()
-                .expect("unable to find XDG_DATA_HOME, please set FLOUNDR_HOME env variable")
-                .join("floundr")
-                .to_string_lossy()
-                .to_string(),
-        );
-        let config_path = std::path::Path::new(&dir);
+        let config_path = Self::config_dir();
         if !config_path.exists() {
-            std::fs::create_dir_all(config_path).expect("unable to create config directory");
+            std::fs::create_dir_all(&config_path).expect("unable to create config directory");
             let file = std::fs::File::create(config_path.join("floundr_tui.yml"))
                 .expect("unable to create config file");
             let config = Self::default();
@@ -71,4 +83,10 @@ impl ConfigFile {
             config
         }
     }
+
+    pub fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let file = std::fs::File::create(Self::config_dir().join("floundr_tui.yml"))?;
+        serde_yaml::to_writer(file, self)?;
+        Ok(())
+    }
 }
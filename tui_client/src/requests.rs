@@ -1,9 +1,21 @@
-use crate::app::{
-    AppResult, RepositoryList, ACTIVE_KEYS, CLIENT, GLOBAL_REPO_LIST, HEADERS, MANIFESTS, USERS,
+use crate::{
+    app::{
+        AppResult, RepositoryList, ServerStatus, ACTIVE_KEYS, CLIENT, GLOBAL_REPO_LIST, HEADERS,
+        MANIFESTS, SERVER_STATUS, USERS,
+    },
+    ConfigFile,
 };
-use reqwest::Response;
-use shared::{AuthClient, ImageManifest, RegisterUserRequest, UserResponse};
-use tracing::{debug, info};
+use base64::{alphabet::URL_SAFE, engine::GeneralPurposeConfig, Engine};
+use reqwest::{
+    header::{HeaderMap, HeaderValue, AUTHORIZATION, USER_AGENT},
+    Client, RequestBuilder, Response, StatusCode,
+};
+use shared::{
+    AuthClient, ImageManifest, KeyScope, RegisterUserRequest, UserResponse, API_VERSION_HEADER,
+    FLOUNDR_VERSION_HEADER,
+};
+use std::{sync::OnceLock, time::Duration};
+use tracing::{debug, error, info};
 
 pub async fn get_manifests(url: &str) -> AppResult<()> {
     let repos = GLOBAL_REPO_LIST.read().unwrap().repositories.clone();
@@ -19,6 +31,48 @@ pub async fn get_manifests(url: &str) -> AppResult<()> {
     Ok(())
 }
 
+/// Health checks should fail fast rather than stall the status widget
+const STATUS_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Checks `/healthz` and `/v2/` and records the result in `SERVER_STATUS`.
+/// The server counts as reachable when `/healthz` answers, `/v2/` supplies
+/// the versions and renews the session when the token has expired.
+pub async fn check_server_status(url: &str) {
+    let client = CLIENT.get().unwrap();
+    let mut status = ServerStatus::default();
+    let health = client
+        .get(format!("{}/healthz", url))
+        .timeout(STATUS_TIMEOUT)
+        .send()
+        .await
+        .and_then(|res| res.error_for_status());
+    match health {
+        Ok(_) => status.reachable = true,
+        Err(err) => debug!("server status check failed: {:?}", err),
+    }
+    if status.reachable {
+        match send_request(|client| client.get(format!("{}/v2/", url)).timeout(STATUS_TIMEOUT))
+            .await
+        {
+            Ok(res) => {
+                let header = |name: &str| {
+                    res.headers()
+                        .get(name)
+                        .and_then(|v| v.to_str().ok())
+                        .map(String::from)
+                };
+                status.api_version = header(API_VERSION_HEADER);
+                status.version = header(FLOUNDR_VERSION_HEADER);
+            }
+            Err(err) => debug!("unable to fetch server version: {:?}", err),
+        }
+    }
+    let mut current = SERVER_STATUS.write().unwrap();
+    // owned by `send_request`, which knows whether the last login attempt failed
+    status.session_expired = current.session_expired;
+    *current = status;
+}
+
 pub async fn get_repositories(url: &str) -> AppResult<()> {
     let resp = send_get_request(format!("{}/repositories", url)).await?;
     let repos: RepositoryList = resp.json().await?;
@@ -28,22 +82,15 @@ pub async fn get_repositories(url: &str) -> AppResult<()> {
 }
 
 pub async fn create_repository(url: String, name: String, public: bool) -> AppResult<()> {
-    let client = CLIENT.get().unwrap();
-    let res = client
-        .post(format!("{}/repositories/{}/{}", url, name, public))
-        .send()
-        .await?;
-    if res.status().is_success() {
-        let new = client.get(format!("{}/repositories", url)).send().await?;
-        let repos: RepositoryList = new.json().await.expect("failed to parse repos");
-        *GLOBAL_REPO_LIST.write().unwrap() = repos;
-        info!("Repository created successfully");
-        Ok(())
-    } else {
-        debug!("{:?}", res);
+    let create_url = format!("{}/repositories/{}/{}", url, name, public);
+    if let Err(err) = send_request(|client| client.post(&create_url)).await {
+        debug!("{:?}", err);
         info!("Failed to create repository");
-        Err("Failed to create repository".into())
+        return Err("Failed to create repository".into());
     }
+    get_repositories(&url).await?;
+    info!("Repository created successfully");
+    Ok(())
 }
 
 pub async fn get_all_users(url: &str) -> AppResult<()> {
@@ -54,9 +101,26 @@ pub async fn get_all_users(url: &str) -> AppResult<()> {
     Ok(())
 }
 
-pub async fn create_new_api_key(url: String, name: String) -> AppResult<()> {
+/// Parses `repo:pull,push other:pull` into the scopes an API key is limited to
+fn parse_key_scopes(input: &str) -> Vec<KeyScope> {
+    input
+        .split_whitespace()
+        .filter_map(|entry| match entry.rsplit_once(':') {
+            Some((repo, actions)) => Some(KeyScope {
+                repo: repo.to_string(),
+                scope: actions.split(',').map(String::from).collect(),
+            }),
+            None => {
+                info!("ignoring key scope without actions: {}", entry);
+                None
+            }
+        })
+        .collect()
+}
+
+pub async fn create_new_api_key(url: String, name: String, scopes: &str) -> AppResult<()> {
     let url = format!("{}/users/{}/tokens", url, &name);
-    let resp = send_post_request(url, String::new()).await?;
+    let resp = send_post_request(url, parse_key_scopes(scopes)).await?;
     if resp.status().is_success() {
         info!("API key created successfully");
         Ok(())
@@ -71,40 +135,50 @@ pub async fn send_post_request<T>(url: String, json: T) -> AppResult<Response>
 where
     T: serde::Serialize + std::fmt::Debug,
 {
-    let client = CLIENT.get().unwrap();
-    let headers = HEADERS.get().unwrap();
-    let req = client
-        .post(url)
-        .headers(headers.to_owned())
-        .json(&json)
-        .build()?;
-    info!("sending request {:?}", req);
-    let res = client.execute(req).await?;
-    if res.status().is_success() {
-        Ok(res)
-    } else {
-        Err("Failed to fetch data".into())
-    }
+    send_request(|client| client.post(&url).json(&json)).await
 }
+
 pub async fn send_get_request(url: String) -> AppResult<Response> {
-    let client = CLIENT.get().unwrap();
-    let headers = HEADERS.get().unwrap();
-    let req = client.get(url).headers(headers.to_owned()).build()?;
-    info!("sending request {:?}", req);
-    let res = client.execute(req).await?;
-    if res.status().is_success() {
-        Ok(res)
-    } else {
-        Err("Failed to fetch data".into())
-    }
+    send_request(|client| client.get(&url)).await
 }
 
 pub async fn send_delete_request(url: String) -> AppResult<Response> {
+    send_request(|client| client.delete(&url)).await
+}
+
+/// Sends the request with the current session headers. A `401` means the
+/// token has expired, so log in again and retry the request once.
+async fn send_request<F>(build: F) -> AppResult<Response>
+where
+    F: Fn(&Client) -> RequestBuilder,
+{
     let client = CLIENT.get().unwrap();
-    let headers = HEADERS.get().unwrap();
-    let req = client.delete(url).headers(headers.to_owned()).build()?;
+    let res = execute(client, &build).await?;
+    if res.status() == StatusCode::UNAUTHORIZED {
+        info!("token rejected, logging in again");
+        if let Err(err) = refresh_session().await {
+            error!("unable to refresh session: {:?}", err);
+            SERVER_STATUS.write().unwrap().session_expired = true;
+            return Err(SESSION_EXPIRED.into());
+        }
+        SERVER_STATUS.write().unwrap().session_expired = false;
+        let res = execute(client, &build).await?;
+        return check_status(res);
+    }
+    check_status(res)
+}
+
+async fn execute<F>(client: &Client, build: &F) -> AppResult<Response>
+where
+    F: Fn(&Client) -> RequestBuilder,
+{
+    let headers = HEADERS.read().unwrap().clone();
+    let req = build(client).headers(headers).build()?;
     info!("sending request {:?}", req);
-    let res = client.execute(req).await?;
+    Ok(client.execute(req).await?)
+}
+
+fn check_status(res: Response) -> AppResult<Response> {
     if res.status().is_success() {
         Ok(res)
     } else {
@@ -112,6 +186,78 @@ pub async fn send_delete_request(url: String) -> AppResult<Response> {
     }
 }
 
+pub const SESSION_EXPIRED: &str = "session expired, please re-authenticate";
+
+/// What the session was opened with, kept so an expired token can be renewed
+struct Credentials {
+    url: String,
+    secret: Option<String>,
+    email: String,
+    password: String,
+}
+
+static CREDENTIALS: OnceLock<Credentials> = OnceLock::new();
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+struct TokenResponse {
+    token: String,
+}
+
+/// Exchanges the configured api key, or email and password, for a bearer
+/// token and remembers them for `refresh_session`
+pub async fn login(url: &str, config: &ConfigFile) -> AppResult<()> {
+    let creds = CREDENTIALS.get_or_init(|| Credentials {
+        url: url.to_string(),
+        secret: config.secret.clone(),
+        email: config
+            .email
+            .clone()
+            .unwrap_or_else(|| String::from("floundr_admin")),
+        password: config
+            .password
+            .clone()
+            .unwrap_or_else(|| String::from("admin")),
+    });
+    request_token(creds).await
+}
+
+async fn refresh_session() -> AppResult<()> {
+    let creds = CREDENTIALS.get().ok_or("no session to refresh")?;
+    request_token(creds).await
+}
+
+async fn request_token(creds: &Credentials) -> AppResult<()> {
+    let user_agent = HeaderValue::from_static("floundr-tui");
+    let auth = match creds.secret.as_ref() {
+        Some(secret) => {
+            info!("using api key/bearer auth");
+            format!("Bearer {}", secret)
+        }
+        None => {
+            let mut value = String::new();
+            base64::engine::GeneralPurpose::new(&URL_SAFE, GeneralPurposeConfig::default())
+                .encode_string(format!("{}:{}", creds.email, creds.password), &mut value);
+            format!("Basic {}", value)
+        }
+    };
+    let response = Client::new()
+        .get(format!("{}/auth/token", creds.url))
+        .header(USER_AGENT, user_agent.clone())
+        .header(AUTHORIZATION, HeaderValue::from_str(&auth)?)
+        .send()
+        .await?
+        .error_for_status()?;
+    let body: TokenResponse = response.json().await?;
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        AUTHORIZATION,
+        HeaderValue::from_str(&format!("Bearer {}", body.token))?,
+    );
+    headers.insert(USER_AGENT, user_agent);
+    *HEADERS.write().unwrap() = headers;
+    Ok(())
+}
+
 pub async fn create_new_user(url: String, user: RegisterUserRequest) -> AppResult<Response> {
     info!("sending user {:?}", user);
     let res = send_post_request(format!("{}/auth/register", &url), user).await?;
@@ -135,6 +281,16 @@ pub async fn delete_user(url: String, email: String) -> AppResult<()> {
     }
 }
 
+pub async fn delete_api_key(url: String, client_id: String) -> AppResult<()> {
+    let res = send_delete_request(format!("{}/auth/clients/{}", url, client_id)).await?;
+    if res.status().is_success() {
+        info!("API key deleted successfully");
+        get_tokens(&url).await
+    } else {
+        Err("Failed to delete API key".into())
+    }
+}
+
 pub async fn delete_repository(url: String, repo: String) -> AppResult<()> {
     let url = format!("{}/repositories/{}", url, repo);
     let res = send_delete_request(url).await?;
@@ -4,7 +4,7 @@ pub mod manifests;
 pub mod repos;
 pub mod users;
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub enum ScreenType {
     Home,
     Users,
@@ -27,5 +27,6 @@ pub enum InputType {
     CreateApiKey,
     NewUser,
     DeleteUser,
+    DeleteApiKey,
     DeleteRepo,
 }
@@ -102,15 +102,20 @@ pub fn repository_screen(frame: &mut Frame, app: &mut App) {
 fn render_repo_details(frame: &mut Frame, chunks: Vec<ratatui::layout::Rect>, repo: &shared::Repo) {
     let details_chunks = Layout::default()
         .direction(Direction::Vertical)
-        .constraints([Constraint::Length(8), Constraint::Min(0)].as_ref())
+        .constraints([Constraint::Length(9), Constraint::Min(0)].as_ref())
         .split(chunks[1]);
 
+    let quota = match repo.max_bytes {
+        Some(bytes) => format!("~{}MB", (bytes as f64 / 1024.0 / 1024.0).round()),
+        None => String::from("unlimited"),
+    };
     let text = format!(
-        "Name: {}\nPublic: {}\nFile Path: {}\nDisk Usage: ~{}MB\nTotal Layers: {}\nDriver: {}",
+        "Name: {}\nPublic: {}\nFile Path: {}\nDisk Usage: ~{}MB\nQuota: {}\nTotal Layers: {}\nDriver: {}",
         repo.name,
         repo.is_public,
         repo.file_path,
         repo.calculate_mb().round(),
+        quota,
         repo.num_layers,
         repo.driver,
     );
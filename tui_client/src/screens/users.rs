@@ -1,5 +1,5 @@
 use crate::{
-    app::{get_items, App, Mode, USERS},
+    app::{active_key, get_items, App, Mode, GLOBAL_REPO_LIST, SERVER_STATUS, USERS},
     screens::InputType,
 };
 use ratatui::{
@@ -19,11 +19,53 @@ pub fn render_header(frame: &mut Frame, header: &str) {
         .constraints([Constraint::Length(3), Constraint::Min(0)].as_ref())
         .split(size);
 
+    let header_chunks = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Percentage(50), Constraint::Percentage(50)].as_ref())
+        .split(header_chunk[0]);
+
     let header = Paragraph::new(header)
         .style(Style::default().bg(Color::Black).fg(Color::White))
         .block(Block::default().borders(Borders::ALL).title("Navigation"));
 
-    frame.render_widget(header, header_chunk[0]);
+    frame.render_widget(header, header_chunks[0]);
+    render_server_status(frame, header_chunks[1]);
+}
+
+/// Reachability, version and storage driver of the server, refreshed on tick
+fn render_server_status(frame: &mut Frame, area: ratatui::layout::Rect) {
+    let status = SERVER_STATUS.read().unwrap().clone();
+    let (indicator, color) = if status.session_expired {
+        ("● session expired, please re-authenticate", Color::Red)
+    } else if status.reachable {
+        ("● online", Color::Green)
+    } else {
+        ("● unreachable", Color::Red)
+    };
+    let driver = GLOBAL_REPO_LIST
+        .read()
+        .unwrap()
+        .repositories
+        .first()
+        .map(|r| r.driver.clone());
+    let details = if status.reachable && !status.session_expired {
+        format!(
+            "  floundr {} ({})  driver: {}",
+            status.version.as_deref().unwrap_or("unknown"),
+            status.api_version.as_deref().unwrap_or("unknown"),
+            driver.as_deref().unwrap_or("n/a"),
+        )
+    } else {
+        String::new()
+    };
+    let line = ratatui::text::Line::from(vec![
+        ratatui::text::Span::styled(indicator, Style::default().fg(color)),
+        ratatui::text::Span::raw(details),
+    ]);
+    let widget = Paragraph::new(line)
+        .style(Style::default().bg(Color::Black).fg(Color::White))
+        .block(Block::default().borders(Borders::ALL).title("Server"));
+    frame.render_widget(widget, area);
 }
 
 pub fn user_management_screen(frame: &mut Frame, app: &mut App) {
@@ -168,10 +210,18 @@ pub fn user_management_screen(frame: &mut Frame, app: &mut App) {
                 let prompt = match app.mode {
                     Mode::Normal => String::default(),
                     Mode::Insert => match app.buffer.len() {
-                        0 => format!("Enter # key to delete: {}", app.input.value()),
+                        0 => match active_key(app.input.value()) {
+                            Some(key) => format!(
+                                "Enter # key to delete: {} ({} | {})",
+                                app.input.value(),
+                                key.client_id,
+                                key.email
+                            ),
+                            None => format!("Enter # key to delete: {}", app.input.value()),
+                        },
                         _ => {
                             app.normal_mode();
-                            app.handle_input(InputType::DeleteUser);
+                            app.handle_input(InputType::DeleteApiKey);
                             String::from("Key deleted")
                         }
                     },
@@ -224,7 +274,11 @@ fn render_create_api_key(frame: &mut Frame, app: &mut App, area: ratatui::layout
     } else {
         match app.buffer.len() {
             0 => format!("Enter email address of user:\n {}", app.input.value()),
-            1 => {
+            1 => format!(
+                "Limit to repositories (e.g. repo:pull,push other:pull), *:* for everything the user holds:\n {}",
+                app.input.value()
+            ),
+            2 => {
                 app.normal_mode();
                 app.handle_input(InputType::CreateApiKey);
                 String::from("API key created")
@@ -0,0 +1,163 @@
+// Dashboard for floundr. Everything shown here comes from the registry's
+// JSON endpoints; the bearer token from /auth/token is kept in sessionStorage.
+"use strict";
+
+const TOKEN_KEY = "floundr-token";
+
+function token() {
+  return sessionStorage.getItem(TOKEN_KEY);
+}
+
+async function api(path) {
+  const headers = {};
+  if (token()) {
+    headers["Authorization"] = `Bearer ${token()}`;
+  }
+  const res = await fetch(path, { headers });
+  if (res.status === 401) {
+    logout();
+    throw new Error("session expired, log in again");
+  }
+  if (!res.ok) {
+    throw new Error(`${path}: ${res.status} ${await res.text()}`);
+  }
+  return res.json();
+}
+
+function encodeName(name) {
+  return name.split("/").map(encodeURIComponent).join("%2F");
+}
+
+function formatBytes(bytes) {
+  const units = ["B", "KiB", "MiB", "GiB", "TiB"];
+  let i = 0;
+  while (bytes >= 1024 && i < units.length - 1) {
+    bytes /= 1024;
+    i++;
+  }
+  return `${bytes.toFixed(i ? 1 : 0)} ${units[i]}`;
+}
+
+function cell(row, text, className) {
+  const td = row.insertCell();
+  td.textContent = text;
+  if (className) {
+    td.className = className;
+  }
+  return td;
+}
+
+function link(text, onclick) {
+  const a = document.createElement("a");
+  a.textContent = text;
+  a.addEventListener("click", onclick);
+  return a;
+}
+
+function showError(err) {
+  document.getElementById("error").textContent = err.message;
+}
+
+async function login(event) {
+  event.preventDefault();
+  const form = event.target;
+  const credentials = btoa(`${form.email.value}:${form.password.value}`);
+  const res = await fetch("/auth/token", {
+    headers: { Authorization: `Basic ${credentials}` },
+  });
+  if (!res.ok) {
+    showError(new Error("invalid email or password"));
+    return;
+  }
+  const body = await res.json();
+  sessionStorage.setItem(TOKEN_KEY, body.token);
+  form.reset();
+  showError(new Error(""));
+  render();
+}
+
+function logout() {
+  sessionStorage.removeItem(TOKEN_KEY);
+  render();
+}
+
+async function loadRepositories() {
+  const { repositories } = await api("/repositories");
+  const body = document.querySelector("#repositories tbody");
+  body.replaceChildren();
+  for (const repo of repositories) {
+    const row = body.insertRow();
+    row.insertCell().append(link(repo.name, () => loadTags(repo.name)));
+    cell(row, repo.is_public ? "yes" : "no");
+    cell(row, repo.tag_count);
+    cell(row, repo.manifest_count);
+    cell(row, repo.blob_count);
+    cell(row, formatBytes(repo.disk_usage));
+  }
+}
+
+async function loadTags(name) {
+  const section = document.getElementById("tags");
+  const { tags } = await api(`/v2/${encodeName(name)}/tags/list`);
+  section.querySelector("h2").textContent = `${name} tags`;
+  const list = section.querySelector("ul");
+  list.replaceChildren();
+  for (const tag of tags) {
+    const item = document.createElement("li");
+    item.append(link(tag, () => loadManifest(name, tag)));
+    list.append(item);
+  }
+  document.getElementById("manifest").hidden = true;
+  section.hidden = false;
+}
+
+async function loadManifest(name, reference) {
+  const section = document.getElementById("manifest");
+  const details = await api(
+    `/repositories/${encodeName(name)}/manifests/${encodeURIComponent(reference)}`,
+  );
+  section.querySelector("h2").textContent = `${name}:${reference}`;
+  const fields = section.querySelector("dl");
+  fields.replaceChildren();
+  for (const [label, value] of [
+    ["Digest", details.digest],
+    ["Media type", details.media_type],
+    ["Size", formatBytes(details.size)],
+    ["Created", details.created_at ?? "unknown"],
+    ["Tags", details.tags.join(", ")],
+  ]) {
+    const dt = document.createElement("dt");
+    dt.textContent = label;
+    const dd = document.createElement("dd");
+    dd.textContent = value;
+    fields.append(dt, dd);
+  }
+  const layers = section.querySelector("tbody");
+  layers.replaceChildren();
+  for (const layer of details.layers) {
+    const row = layers.insertRow();
+    cell(row, layer.digest, "digest");
+    cell(row, layer.media_type);
+    cell(row, formatBytes(layer.size));
+    cell(row, layer.diff_id ?? "", "digest");
+  }
+  section.hidden = false;
+}
+
+function render() {
+  const loggedIn = token() !== null;
+  document.getElementById("login").hidden = loggedIn;
+  document.getElementById("dashboard").hidden = !loggedIn;
+  const session = document.getElementById("session");
+  session.replaceChildren();
+  if (loggedIn) {
+    session.append(link("Log out", logout));
+    loadRepositories().catch(showError);
+  }
+}
+
+document.getElementById("login").addEventListener("submit", (event) => {
+  login(event).catch(showError);
+});
+window.addEventListener("unhandledrejection", (event) => showError(event.reason));
+render();